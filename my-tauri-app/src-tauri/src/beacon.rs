@@ -0,0 +1,231 @@
+// Discovery over UDP broadcast, for networks where mDNS finds nothing
+//
+// Some routers and access points filter multicast, so mDNS neither hears
+// nor is heard. Alongside it we listen for beacons on a port of our own:
+// small datagrams carrying the same properties we announce over mDNS,
+// signed with our signing key and stamped with the time. Once mDNS has
+// gone a while without finding anyone we start broadcasting beacons too,
+// on every IPv4 network we're on. A device we hear a beacon from but
+// don't know yet is answered directly, so it finds us even if our
+// broadcasts don't reach it.
+//
+// A beacon is only believed if its signature holds under the signing key
+// it carries, that key matches the one a paired device was paired with,
+// and it is newer than the last beacon heard under the same key.
+//
+// The listener is a task on the async runtime, which ends when discovery
+// is stopped or the app shuts down.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use if_addrs::IfAddr;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{add_announced, group, net, PeerContext};
+
+// Port beacons are sent to and listened for on
+const BEACON_PORT: u16 = 8890;
+
+// How often we broadcast once we've fallen back to beacons
+const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long mDNS gets to find someone before we broadcast too
+const FALLBACK_AFTER: Duration = Duration::from_secs(10);
+
+// How far a beacon's timestamp may be from our clock
+const BEACON_WINDOW_SECS: i64 = 60;
+
+// Largest beacon we'll read
+const MAX_BEACON: usize = 8192;
+
+// What a beacon says about the device sending it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub name: String,
+    pub port: u16,
+    // The same key/value pairs as our mDNS TXT record
+    pub properties: Vec<(String, String)>,
+    pub timestamp: i64,
+}
+
+impl Announcement {
+    fn property(&self, key: &str) -> Option<String> {
+        self.properties.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Beacon {
+    announcement: Announcement,
+    signature: String,
+}
+
+// Handle on the running beacon listener
+pub struct Beacons {
+    stop: CancellationToken,
+    // Disconnected once the listener has ended and let go of the port
+    finished: mpsc::Receiver<()>,
+}
+
+impl Beacons {
+    // Stop listening and broadcasting, waiting for the port to be let go
+    pub fn stop(self) {
+        self.stop.cancel();
+        let _ = self.finished.recv();
+    }
+}
+
+// Stamp and sign an announcement
+fn seal(announcement: &Announcement, key: &SigningKey) -> Vec<u8> {
+    let mut announcement = announcement.clone();
+    announcement.timestamp = chrono::Utc::now().timestamp();
+    let signed = serde_json::to_vec(&announcement).unwrap_or_default();
+    let signature = key.sign(&signed);
+    let beacon = Beacon {
+        announcement,
+        signature: base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()),
+    };
+    serde_json::to_vec(&beacon).unwrap_or_default()
+}
+
+// Check a beacon's signature under the signing key it announces
+fn open(bytes: &[u8]) -> Result<Announcement, String> {
+    let beacon: Beacon = serde_json::from_slice(bytes).map_err(|_| "Malformed beacon")?;
+    let engine = base64::engine::general_purpose::STANDARD;
+    let key_bytes = beacon.announcement.property("sk")
+        .and_then(|key| engine.decode(key).ok())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+        .ok_or("Malformed signing key")?;
+    let key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| "Malformed signing key")?;
+    let signature_bytes = engine.decode(&beacon.signature)
+        .ok()
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes.as_slice()).ok())
+        .ok_or("Malformed signature")?;
+    let signed = serde_json::to_vec(&beacon.announcement).unwrap_or_default();
+    key.verify(&signed, &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| "Invalid signature".to_string())?;
+    Ok(beacon.announcement)
+}
+
+// Where to broadcast: everyone on each of our IPv4 networks
+fn broadcast_addresses() -> Vec<SocketAddr> {
+    let mut targets = vec![SocketAddr::from((Ipv4Addr::BROADCAST, BEACON_PORT))];
+    for interface in net::local_interfaces() {
+        if let IfAddr::V4(v4) = interface.addr {
+            if let Some(broadcast) = v4.broadcast {
+                let target = SocketAddr::from((broadcast, BEACON_PORT));
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+    }
+    targets
+}
+
+// Start listening for beacons, broadcasting our own `announcement` if
+// `mdns_found` is still unset once mDNS has had its chance
+pub fn start(announcement: Announcement, mdns_found: Arc<AtomicBool>, ctx: &PeerContext) -> std::io::Result<Beacons> {
+    let socket = UdpSocket::bind(("0.0.0.0", BEACON_PORT))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+
+    let stop = ctx.tasks.child_token();
+    let stopped = stop.clone();
+    let (done, finished) = mpsc::channel::<()>();
+    let ctx = ctx.clone();
+    tauri::async_runtime::spawn(async move {
+        let _done = done;
+        let socket = match tokio::net::UdpSocket::from_std(socket) {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Beacon listener failed: {}", e);
+                return;
+            }
+        };
+        let started = Instant::now();
+        let mut last_broadcast: Option<Instant> = None;
+        // Woken regularly to broadcast if it's time
+        let mut ticks = tokio::time::interval(Duration::from_secs(1));
+        // Newest timestamp heard under each signing key
+        let mut latest: HashMap<String, i64> = HashMap::new();
+        let mut buffer = vec![0u8; MAX_BEACON];
+
+        loop {
+            let (len, from) = tokio::select! {
+                _ = stopped.cancelled() => break,
+                _ = ticks.tick() => {
+                    let fallen_back = !mdns_found.load(Ordering::SeqCst) && started.elapsed() >= FALLBACK_AFTER;
+                    if fallen_back && last_broadcast.is_none_or(|at| at.elapsed() >= BEACON_INTERVAL) {
+                        let beacon = seal(&announcement, &ctx.signing_key);
+                        for target in broadcast_addresses() {
+                            let _ = socket.send_to(&beacon, target).await;
+                        }
+                        last_broadcast = Some(Instant::now());
+                    }
+                    continue;
+                }
+                received = socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+            };
+            let heard = match open(&buffer[..len]) {
+                Ok(heard) => heard,
+                Err(reason) => {
+                    eprintln!("Ignoring beacon from {}: {}", from, reason);
+                    continue;
+                }
+            };
+
+            // Our own broadcasts come back to us
+            let id = heard.property("id").unwrap_or_default();
+            if id == ctx.device_id {
+                continue;
+            }
+            if !group::is_ours(heard.property("grp").as_deref()) {
+                continue;
+            }
+            if (chrono::Utc::now().timestamp() - heard.timestamp).abs() > BEACON_WINDOW_SECS {
+                continue;
+            }
+            let signing_key = heard.property("sk").unwrap_or_default();
+            if latest.get(&signing_key).is_some_and(|&timestamp| timestamp >= heard.timestamp) {
+                continue;
+            }
+            latest.insert(signing_key.clone(), heard.timestamp);
+
+            // A paired device has to sign with the key it paired with
+            let public_key = heard.property("pk").unwrap_or_default();
+            let paired_key = ctx.trusted_devices.lock()
+                .get(&public_key)
+                .map(|device| device.signing_key.clone())
+                .filter(|key| !key.is_empty());
+            if paired_key.is_some_and(|key| key != signing_key) {
+                eprintln!("Ignoring beacon from {}: signing key does not match paired device", from);
+                continue;
+            }
+
+            // Answer devices that don't know us yet, so they needn't rely
+            // on our broadcasts reaching them
+            if !ctx.devices.lock().contains_key(&id) {
+                let _ = socket.send_to(&seal(&announcement, &ctx.signing_key), SocketAddr::new(from.ip(), BEACON_PORT)).await;
+            }
+
+            // Where the beacon came from is the one address sure to work
+            let property = |key: &str| heard.property(key);
+            add_announced(&heard.name, vec![from.ip()], heard.port, &property, &ctx);
+        }
+    });
+
+    Ok(Beacons { stop, finished })
+}
@@ -0,0 +1,293 @@
+// Finding nearby devices over Bluetooth LE, without any Wi-Fi
+//
+// Each device offers a GATT service with one readable characteristic, the
+// handoff: its id, name, keys, fingerprint, port and addresses. Scanning
+// for the service and reading the handoff tells us who is nearby and how
+// to reach them once there is a network between us. Until then they are
+// listed as nearby, for the user to pair with or to bring onto a hotspot;
+// as soon as one of their addresses is on a network we're on, they go into
+// the device table like any other discovered device and files go over TCP.
+//
+// Scanning works wherever btleplug does. btleplug can't advertise, so the
+// service is offered through BlueZ over D-Bus, which makes us findable on
+// Linux only. Bluetooth support is the `ble` feature, since it needs the
+// system's Bluetooth libraries to build.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "ble")]
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ble")]
+use uuid::Uuid;
+
+use crate::PeerContext;
+
+// Our GATT service, and the characteristic carrying the handoff
+#[cfg(feature = "ble")]
+const SERVICE_UUID: Uuid = Uuid::from_u128(0x6a3f_1c20_8e4b_4d8a_9c1e_52f0_7b3d_0001);
+#[cfg(feature = "ble")]
+const HANDOFF_UUID: Uuid = Uuid::from_u128(0x6a3f_1c20_8e4b_4d8a_9c1e_52f0_7b3d_0002);
+
+// How often nearby devices are checked for a network we share
+#[cfg(feature = "ble")]
+const HANDOFF_INTERVAL: Duration = Duration::from_secs(10);
+
+// Addresses put in the handoff; a characteristic holds at most 512 bytes
+pub const MAX_HANDOFF_ADDRESSES: usize = 4;
+
+// What a device tells others about itself over Bluetooth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handoff {
+    pub id: String,
+    pub name: String,
+    pub public_key: String,
+    pub signing_key: String,
+    pub fingerprint: String,
+    pub port: u16,
+    pub addresses: Vec<String>,
+}
+
+// A device found over Bluetooth that we share no network with yet
+#[derive(Debug, Clone, Serialize)]
+pub struct NearbyDevice {
+    #[serde(flatten)]
+    pub handoff: Handoff,
+    pub last_seen: String,
+}
+
+pub type Nearby = Arc<Mutex<HashMap<String, NearbyDevice>>>;
+
+// Handle on running Bluetooth discovery
+pub struct Bluetooth {
+    stop: Arc<AtomicBool>,
+}
+
+impl Bluetooth {
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Start offering our handoff and scanning for others'
+#[cfg(not(feature = "ble"))]
+pub fn start(_handoff: Handoff, _ctx: PeerContext, _nearby: Nearby) -> Result<Bluetooth, String> {
+    Err("Built without Bluetooth support".to_string())
+}
+
+#[cfg(feature = "ble")]
+pub fn start(handoff: Handoff, ctx: PeerContext, nearby: Nearby) -> Result<Bluetooth, String> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let value = serde_json::to_vec(&handoff).map_err(|e| e.to_string())?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let stopped = stop.clone();
+        let name = handoff.name.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = bluez::advertise(value, name, stopped) {
+                eprintln!("Bluetooth advertising failed: {}", e);
+            }
+        });
+    }
+    #[cfg(not(target_os = "linux"))]
+    drop(value);
+
+    let stopped = stop.clone();
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => return eprintln!("Bluetooth scanning failed: {}", e),
+        };
+        if let Err(e) = runtime.block_on(scan::run(ctx, nearby, stopped)) {
+            eprintln!("Bluetooth scanning failed: {}", e);
+        }
+    });
+
+    Ok(Bluetooth { stop })
+}
+
+// Put nearby devices that are now on a network we're on in the device
+// table, where transfers can reach them
+#[cfg(feature = "ble")]
+fn hand_off(ctx: &PeerContext, nearby: &Nearby) {
+    let interfaces = crate::net::local_interfaces();
+    nearby.lock().retain(|_, device| {
+        let handoff = &device.handoff;
+        let addresses: Vec<std::net::IpAddr> = handoff.addresses.iter()
+            .filter_map(|address| address.parse().ok())
+            .collect();
+        if !crate::net::shares_subnet(&addresses, &interfaces) {
+            return true;
+        }
+        let property = |key: &str| match key {
+            "id" => Some(handoff.id.clone()),
+            "pk" => Some(handoff.public_key.clone()),
+            "sk" => Some(handoff.signing_key.clone()),
+            "fp" => Some(handoff.fingerprint.clone()),
+            _ => None,
+        };
+        crate::add_announced(&handoff.name, addresses, handoff.port, &property, ctx);
+        println!("📡 {} is on our network now", handoff.name);
+        false
+    });
+}
+
+#[cfg(feature = "ble")]
+mod scan {
+    use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+    use btleplug::platform::{Adapter, Manager, PeripheralId};
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tauri::Emitter;
+
+    use super::{hand_off, Handoff, Nearby, NearbyDevice, HANDOFF_INTERVAL, HANDOFF_UUID, SERVICE_UUID};
+    use crate::PeerContext;
+
+    fn ble_error(e: btleplug::Error) -> String {
+        format!("Bluetooth error: {}", e)
+    }
+
+    // Connect to a device offering our service and read its handoff
+    async fn read_handoff(central: &Adapter, id: &PeripheralId) -> Result<Handoff, String> {
+        let peripheral = central.peripheral(id).await.map_err(ble_error)?;
+        peripheral.connect().await.map_err(ble_error)?;
+        let value = async {
+            peripheral.discover_services().await.map_err(ble_error)?;
+            let characteristic = peripheral.characteristics()
+                .into_iter()
+                .find(|c| c.uuid == HANDOFF_UUID)
+                .ok_or("Device offers no handoff")?;
+            peripheral.read(&characteristic).await.map_err(ble_error)
+        }
+        .await;
+        let _ = peripheral.disconnect().await;
+        serde_json::from_slice(&value?).map_err(|_| "Malformed handoff".to_string())
+    }
+
+    pub async fn run(ctx: PeerContext, nearby: Nearby, stop: Arc<AtomicBool>) -> Result<(), String> {
+        let manager = Manager::new().await.map_err(ble_error)?;
+        let central = manager.adapters().await.map_err(ble_error)?
+            .into_iter()
+            .next()
+            .ok_or("No Bluetooth adapter")?;
+        let mut events = central.events().await.map_err(ble_error)?;
+        central.start_scan(ScanFilter { services: vec![SERVICE_UUID] }).await.map_err(ble_error)?;
+
+        let mut handoff_check = tokio::time::interval(HANDOFF_INTERVAL);
+        while !stop.load(Ordering::SeqCst) {
+            tokio::select! {
+                event = events.next() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    let CentralEvent::DeviceDiscovered(id) = event else {
+                        continue;
+                    };
+                    let handoff = match read_handoff(&central, &id).await {
+                        Ok(handoff) => handoff,
+                        Err(e) => {
+                            eprintln!("Couldn't read handoff: {}", e);
+                            continue;
+                        }
+                    };
+                    if handoff.id == ctx.device_id {
+                        continue;
+                    }
+                    let device = NearbyDevice {
+                        handoff,
+                        last_seen: chrono::Local::now().format("%H:%M:%S").to_string(),
+                    };
+                    let _ = ctx.app.emit("ble://nearby", &device);
+                    nearby.lock().insert(device.handoff.id.clone(), device);
+                    hand_off(&ctx, &nearby);
+                }
+                _ = handoff_check.tick() => hand_off(&ctx, &nearby),
+            }
+        }
+
+        let _ = central.stop_scan().await;
+        Ok(())
+    }
+}
+
+// Offering our handoff through BlueZ: a GATT application with our service
+// and an advertisement for it, both objects we serve on the system bus
+#[cfg(all(feature = "ble", target_os = "linux"))]
+mod bluez {
+    use dbus::arg::PropMap;
+    use dbus::blocking::Connection;
+    use dbus::message::MatchRule;
+    use dbus::{Message, Path};
+    use dbus_crossroads::Crossroads;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{HANDOFF_UUID, SERVICE_UUID};
+
+    // The first adapter, which is the only one on nearly every machine
+    const ADAPTER_PATH: &str = "/org/bluez/hci0";
+    const APP_PATH: &str = "/org/reality/ble";
+    const SERVICE_PATH: &str = "/org/reality/ble/service0";
+    const CHARACTERISTIC_PATH: &str = "/org/reality/ble/service0/handoff";
+    const ADVERTISEMENT_PATH: &str = "/org/reality/ble/advertisement0";
+
+    // Ask BlueZ to do something without waiting for the answer, since it
+    // calls back into objects we can only serve once we're not blocked
+    fn request(conn: &Connection, interface: &str, method: &str, path: &str) -> Result<(), String> {
+        let message = Message::new_method_call("org.bluez", ADAPTER_PATH, interface, method)?
+            .append2(Path::from(path), PropMap::new());
+        dbus::channel::Sender::send(conn, message).map_err(|_| format!("Couldn't call {}", method))?;
+        Ok(())
+    }
+
+    pub fn advertise(handoff: Vec<u8>, name: String, stop: Arc<AtomicBool>) -> Result<(), String> {
+        let conn = Connection::new_system().map_err(|e| e.to_string())?;
+        let mut cr = Crossroads::new();
+
+        let service = cr.register("org.bluez.GattService1", |b| {
+            b.property("UUID").get(|_, _: &mut ()| Ok(SERVICE_UUID.to_string()));
+            b.property("Primary").get(|_, _| Ok(true));
+        });
+        let characteristic = cr.register("org.bluez.GattCharacteristic1", |b| {
+            b.property("UUID").get(|_, _: &mut Vec<u8>| Ok(HANDOFF_UUID.to_string()));
+            b.property("Service").get(|_, _| Ok(Path::from(SERVICE_PATH)));
+            b.property("Flags").get(|_, _| Ok(vec!["read".to_string()]));
+            // Long values are read in pieces, from the offset asked for
+            b.method("ReadValue", ("options",), ("value",), |_, value: &mut Vec<u8>, (options,): (PropMap,)| {
+                let offset = options.get("offset").and_then(|v| v.0.as_u64()).unwrap_or(0) as usize;
+                Ok((value.get(offset..).unwrap_or_default().to_vec(),))
+            });
+        });
+        let advertisement = cr.register("org.bluez.LEAdvertisement1", move |b| {
+            b.property("Type").get(|_, _: &mut ()| Ok("peripheral".to_string()));
+            b.property("ServiceUUIDs").get(|_, _| Ok(vec![SERVICE_UUID.to_string()]));
+            let name = name.clone();
+            b.property("LocalName").get(move |_, _| Ok(name.clone()));
+            b.method("Release", (), (), |_, _, (): ()| Ok(()));
+        });
+        let object_manager = cr.object_manager();
+        cr.insert(APP_PATH, &[object_manager], ());
+        cr.insert(SERVICE_PATH, &[service], ());
+        cr.insert(CHARACTERISTIC_PATH, &[characteristic], handoff);
+        cr.insert(ADVERTISEMENT_PATH, &[advertisement], ());
+
+        conn.start_receive(MatchRule::new_method_call(), Box::new(move |message, conn| {
+            let _ = cr.handle_message(message, conn);
+            true
+        }));
+        request(&conn, "org.bluez.GattManager1", "RegisterApplication", APP_PATH)?;
+        request(&conn, "org.bluez.LEAdvertisingManager1", "RegisterAdvertisement", ADVERTISEMENT_PATH)?;
+
+        // BlueZ drops both once we leave the bus
+        while !stop.load(Ordering::SeqCst) {
+            conn.process(Duration::from_secs(1)).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
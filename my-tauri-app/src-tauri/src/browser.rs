@@ -0,0 +1,266 @@
+// Sending a file to a plain browser tab, for someone without the app
+//
+// Sharing to a browser starts a small HTTP server on a random port and
+// gives back a link to it, with a QR code, that has a one-off token in its
+// path. The link loads a page that sets up a WebRTC connection to us: it
+// posts its offer back to the same server, which is the signalling
+// endpoint, and gets our answer. The file then goes over a data channel,
+// encrypted with DTLS, in pieces the page puts back together and saves.
+//
+// Only host candidates are gathered, so this works between devices on the
+// same network, which is also the only place the link can be opened from.
+// The signalling itself is plain HTTP; the token keeps other devices out,
+// but someone able to rewrite traffic on the network could put themselves
+// in the middle, so this is for files you'd hand over on a USB stick.
+//
+// Each link is good for one download and goes unused after LINK_LIFETIME.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+use tiny_http::{Method, Response, Server};
+use tokio::sync::Notify;
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::http;
+
+const PAGE: &str = include_str!("browser_page.html");
+
+// How long a link waits to be opened
+const LINK_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+// How often the server looks up from waiting for requests
+const SERVER_POLL: Duration = Duration::from_secs(1);
+
+// Pieces the file is sent in; browsers differ on anything bigger
+const PIECE_SIZE: usize = 16 * 1024;
+
+// How much may sit unsent in the data channel before we wait for it
+const MAX_BUFFERED: usize = 1024 * 1024;
+const BUFFER_POLL: Duration = Duration::from_millis(10);
+
+// How long the page has to confirm it got everything
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Largest offer a page may post
+const MAX_OFFER: u64 = 64 * 1024;
+
+// Where a link's download has got to
+const WAITING: u8 = 0;
+const SENDING: u8 = 1;
+const SENT: u8 = 2;
+const FAILED: u8 = 3;
+
+// A link a browser can fetch a file from
+#[derive(Debug, Clone, Serialize)]
+pub struct BrowserLink {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub urls: Vec<String>,
+    pub qr_svg: String,
+    // Unix seconds after which the link can no longer be opened
+    pub expires_at: i64,
+}
+
+// Sent as `browser://finished` once a link is done with
+#[derive(Debug, Clone, Serialize)]
+struct BrowserOutcome {
+    id: String,
+    filename: String,
+    sent: bool,
+}
+
+// A link being served
+pub struct BrowserShare {
+    pub link: BrowserLink,
+    stop: Arc<AtomicBool>,
+}
+
+impl BrowserShare {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub type BrowserShares = Arc<Mutex<HashMap<String, BrowserShare>>>;
+
+fn rtc_error(e: webrtc::Error) -> String {
+    format!("WebRTC error: {}", e)
+}
+
+// Start serving `path` to the first browser that opens the link
+pub fn share(path: &Path, app: AppHandle, shares: BrowserShares) -> Result<BrowserLink, String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("Only files can be sent to a browser".to_string());
+    }
+    let filename = path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or("Invalid file path")?;
+
+    let http::Page { server, path: page_path, urls, qr_svg } = http::serve_page("/s")?;
+    // WebRTC runs its own tasks, which need workers of their own
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let link = BrowserLink {
+        id: uuid::Uuid::new_v4().to_string(),
+        filename,
+        size: metadata.len(),
+        urls,
+        qr_svg,
+        expires_at: (chrono::Utc::now() + LINK_LIFETIME).timestamp(),
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    shares.lock().insert(link.id.clone(), BrowserShare { link: link.clone(), stop: stop.clone() });
+
+    let (path, id) = (path.to_path_buf(), link.id.clone());
+    std::thread::spawn(move || {
+        let sent = serve(&server, &page_path, &path, &stop, &runtime);
+        if let Some(share) = shares.lock().remove(&id) {
+            let filename = share.link.filename;
+            let _ = app.emit("browser://finished", BrowserOutcome { id, filename, sent });
+        }
+    });
+    Ok(link)
+}
+
+// Answer requests for the link until its file has gone, it has expired or
+// it was cancelled. Gives back whether the file was sent.
+fn serve(server: &Server, page_path: &str, path: &Path, stop: &AtomicBool, runtime: &tokio::runtime::Runtime) -> bool {
+    let expires = Instant::now() + LINK_LIFETIME;
+    let offer_path = format!("{}/offer", page_path);
+    let progress = Arc::new(AtomicU8::new(WAITING));
+    let mut peer: Option<Arc<RTCPeerConnection>> = None;
+
+    loop {
+        let state = progress.load(Ordering::SeqCst);
+        if stop.load(Ordering::SeqCst) || state >= SENT || (state == WAITING && Instant::now() >= expires) {
+            break;
+        }
+        let mut request = match server.recv_timeout(SERVER_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(_) => break,
+        };
+
+        let url = request.url().to_string();
+        let response = if peer.is_some() && (url == page_path || url == offer_path) {
+            Response::from_string("This link has already been used").with_status_code(410)
+        } else if *request.method() == Method::Get && url == page_path {
+            let html = http::header("Content-Type", "text/html; charset=utf-8");
+            Response::from_string(PAGE).with_header(html)
+        } else if *request.method() == Method::Post && url == offer_path {
+            let mut offer = String::new();
+            let answered = request.as_reader()
+                .take(MAX_OFFER)
+                .read_to_string(&mut offer)
+                .map_err(|e| e.to_string())
+                .and_then(|_| runtime.block_on(answer(offer, path.to_path_buf(), progress.clone())));
+            match answered {
+                Ok((sdp, connection)) => {
+                    peer = Some(connection);
+                    Response::from_string(sdp)
+                }
+                Err(e) => {
+                    eprintln!("Browser connection failed: {}", e);
+                    Response::from_string(e).with_status_code(500)
+                }
+            }
+        } else {
+            Response::from_string("Not found").with_status_code(404)
+        };
+        let _ = request.respond(response);
+    }
+
+    if let Some(peer) = peer {
+        let _ = runtime.block_on(peer.close());
+    }
+    progress.load(Ordering::SeqCst) == SENT
+}
+
+// Answer a page's offer, sending the file down the first data channel it
+// opens
+async fn answer(offer: String, path: PathBuf, progress: Arc<AtomicU8>) -> Result<(String, Arc<RTCPeerConnection>), String> {
+    let api = APIBuilder::new().build();
+    let peer = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await.map_err(rtc_error)?);
+
+    peer.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+        let (path, progress) = (path.clone(), progress.clone());
+        Box::pin(async move {
+            // The page says when it has everything
+            let received = Arc::new(Notify::new());
+            let confirmed = received.clone();
+            channel.on_message(Box::new(move |_| {
+                confirmed.notify_one();
+                Box::pin(async {})
+            }));
+
+            let opened = channel.clone();
+            channel.on_open(Box::new(move || {
+                Box::pin(async move {
+                    if progress.compare_exchange(WAITING, SENDING, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                        return;
+                    }
+                    let outcome = send_file(&opened, &path, &received).await;
+                    if let Err(e) = &outcome {
+                        eprintln!("Sending to browser failed: {}", e);
+                    }
+                    progress.store(if outcome.is_ok() { SENT } else { FAILED }, Ordering::SeqCst);
+                })
+            }));
+        })
+    }));
+
+    let offer = RTCSessionDescription::offer(offer).map_err(rtc_error)?;
+    peer.set_remote_description(offer).await.map_err(rtc_error)?;
+    let answer = peer.create_answer(None).await.map_err(rtc_error)?;
+    // Our candidates go in the answer, as the page can't be sent any later
+    let mut gathered = peer.gathering_complete_promise().await;
+    peer.set_local_description(answer).await.map_err(rtc_error)?;
+    let _ = gathered.recv().await;
+    let local = peer.local_description().await.ok_or("No local description")?;
+    Ok((local.sdp, peer))
+}
+
+// Send the file's name and size, then the file, then wait for the page to
+// say it has it all
+async fn send_file(channel: &RTCDataChannel, path: &Path, received: &Notify) -> Result<(), String> {
+    let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let meta = serde_json::json!({ "name": name, "size": size }).to_string();
+    channel.send_text(meta).await.map_err(rtc_error)?;
+
+    let mut piece = vec![0u8; PIECE_SIZE];
+    loop {
+        let read = file.read(&mut piece).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        while channel.buffered_amount().await > MAX_BUFFERED {
+            tokio::time::sleep(BUFFER_POLL).await;
+        }
+        channel.send(&Bytes::copy_from_slice(&piece[..read])).await.map_err(rtc_error)?;
+    }
+
+    tokio::time::timeout(RECEIPT_TIMEOUT, received.notified())
+        .await
+        .map_err(|_| "The browser didn't confirm it got the file".to_string())
+}
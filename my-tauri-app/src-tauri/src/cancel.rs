@@ -0,0 +1,53 @@
+// Cancellation of running transfers
+//
+// Each transfer registers a token under its id. Cancelling sets a flag the
+// chunk loops check and shuts the socket down, so a thread blocked on a
+// read wakes up straight away instead of waiting for the peer.
+
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+#[derive(Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Remember the socket of the current connection so `cancel` can close it
+    pub fn attach(&self, stream: TcpStream) {
+        if self.is_cancelled() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        *self.stream.lock() = Some(stream);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.stream.lock().as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+pub type CancelTokens = Arc<Mutex<HashMap<String, Arc<CancelToken>>>>;
+
+// Create and register a token for a new transfer
+pub fn register(tokens: &CancelTokens, transfer_id: &str) -> Arc<CancelToken> {
+    let token = Arc::new(CancelToken::default());
+    tokens.lock().insert(transfer_id.to_string(), token.clone());
+    token
+}
+
+// Drop a finished transfer's token
+pub fn unregister(tokens: &CancelTokens, transfer_id: &str) {
+    tokens.lock().remove(transfer_id);
+}
@@ -0,0 +1,257 @@
+// How packet headers are put on the wire
+//
+// Headers used to go out as JSON, which is slow to parse and makes small
+// control packets several times their size. Between devices that speak
+// the same header schema they now go out in postcard, a compact binary
+// encoding, behind a two-byte schema number. Postcard lays fields out by
+// position, so both ends have to agree on every field of the header: the
+// schema is said in the protocol hello, bumped whenever PacketHeader
+// changes, and devices with different schemas, or from before schemas,
+// go on talking JSON, where fields are named and missing ones defaulted.
+//
+// Either way a header over MAX_HEADER_SIZE is refused before it is parsed,
+// and anything that doesn't decode is an error, never a partial header.
+// Both decoders refuse strings that aren't valid UTF-8. A header that
+// decodes is then held to limits on what it may carry: names and paths of
+// a sane length without control characters, a file hash that is a BLAKE3
+// hash in hex and nothing else, since it names files we keep, and no more
+// chunk hashes than the file has chunks. Failing any of it fails the read, which
+// closes the connection.
+
+use std::io::{Error, ErrorKind};
+
+use crate::PacketHeader;
+
+// Version of PacketHeader's layout; bump it with every change to its fields
+pub const HEADER_SCHEMA: u16 = 2;
+
+// Largest header either way, big enough for the chunk hashes of a file of
+// a few hundred gigabytes or a long remote listing
+pub const MAX_HEADER_SIZE: usize = 16 * 1024 * 1024;
+
+// Longest file or device name, and longest path, in bytes
+const MAX_NAME: usize = 1024;
+const MAX_PATH: usize = 4096;
+
+// Most entries in a remote listing, and relays in a path
+const MAX_ENTRIES: usize = 100_000;
+const MAX_PATH_HOPS: usize = 64;
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "Header too large")
+}
+
+pub fn encode_header(header: &PacketHeader, binary: bool) -> std::io::Result<Vec<u8>> {
+    let encoded = if binary {
+        postcard::to_extend(header, HEADER_SCHEMA.to_be_bytes().to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Header encoding error: {}", e)))?
+    } else {
+        serde_json::to_vec(header)?
+    };
+    if encoded.len() > MAX_HEADER_SIZE {
+        return Err(too_large());
+    }
+    Ok(encoded)
+}
+
+pub fn decode_header(bytes: &[u8], binary: bool) -> std::io::Result<PacketHeader> {
+    if bytes.len() > MAX_HEADER_SIZE {
+        return Err(too_large());
+    }
+    let header = if binary { decode_binary(bytes)? } else { serde_json::from_slice(bytes)? };
+    check_header(&header).map_err(|reason| Error::new(ErrorKind::InvalidData, reason))?;
+    Ok(header)
+}
+
+fn decode_binary(bytes: &[u8]) -> std::io::Result<PacketHeader> {
+    let (schema, body) = bytes.split_first_chunk::<2>()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated header"))?;
+    if u16::from_be_bytes(*schema) != HEADER_SCHEMA {
+        return Err(Error::new(ErrorKind::InvalidData, "Header in an unknown schema"));
+    }
+    let (header, rest) = postcard::take_from_bytes(body)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Malformed header: {}", e)))?;
+    if !rest.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "Trailing bytes after header"));
+    }
+    Ok(header)
+}
+
+// Whether text is a BLAKE3 hash as we write them: 64 lowercase hex digits
+pub fn is_hash(text: &str) -> bool {
+    text.len() == 64 && text.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn check_text(field: &str, value: &str, max_len: usize) -> Result<(), String> {
+    if value.len() > max_len {
+        return Err(format!("{} too long", field));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!("Control characters in {}", field));
+    }
+    Ok(())
+}
+
+// Hold a decoded header to what any honest peer would send
+fn check_header(header: &PacketHeader) -> Result<(), String> {
+    check_text("packet type", &header.packet_type, MAX_NAME)?;
+    check_text("source", &header.source, MAX_NAME)?;
+    check_text("filename", &header.filename, MAX_NAME)?;
+    check_text("relative path", &header.relative_path, MAX_PATH)?;
+    check_text("sync folder", &header.sync_folder, MAX_PATH)?;
+    check_text("request path", &header.request_path, MAX_PATH)?;
+    check_text("destination", &header.destination, MAX_NAME)?;
+    if !header.file_hash.is_empty() && !is_hash(&header.file_hash) {
+        return Err("Malformed file hash".to_string());
+    }
+    if header.text.len() > crate::messages::MAX_MESSAGE_LEN {
+        return Err("Message too long".to_string());
+    }
+    let chunks = header.file_size.div_ceil(crate::CHUNK_SIZE as u64);
+    if header.chunk_hashes.len() as u64 > chunks.max(1) {
+        return Err("More chunk hashes than chunks".to_string());
+    }
+    if header.entries.len() > MAX_ENTRIES {
+        return Err("Listing too long".to_string());
+    }
+    for entry in &header.entries {
+        check_text("entry name", &entry.name, MAX_NAME)?;
+        check_text("entry path", &entry.path, MAX_PATH)?;
+    }
+    let hops = header.path.len().max(header.via.as_ref().map_or(0, Vec::len));
+    if hops > MAX_PATH_HOPS {
+        return Err("Route too long".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharing::RemoteEntry;
+    use proptest::prelude::*;
+
+    // Headers within the limits, with a spread of their fields set
+    fn header() -> impl Strategy<Value = PacketHeader> {
+        let entry = ("\\PC{0,20}", "\\PC{0,40}", any::<bool>(), any::<u64>(), any::<i64>())
+            .prop_map(|(name, path, is_dir, size, modified)| RemoteEntry { name, path, is_dir, size, modified });
+        (
+            ("[A-Z_]{1,20}", "\\PC{0,40}", "\\PC{0,60}", "\\PC{0,60}"),
+            (4 * crate::CHUNK_SIZE as u64..u64::MAX / 2, prop::collection::vec("[0-9a-f]{64}", 0..4)),
+            (prop::collection::vec(entry, 0..4), "\\PC{0,200}"),
+            (any::<u32>(), -1e9..1e9f64, prop::collection::vec("[0-9a-f-]{36}", 0..4), any::<Option<Vec<String>>>()),
+            (any::<i64>(), "[0-9a-f-]{36}", "[A-Za-z0-9+/=]{0,44}", "[A-Za-z0-9+/=]{0,88}"),
+        )
+            .prop_filter("route too long", |(_, _, _, (_, _, _, via), _)| via.as_ref().is_none_or(|via| via.len() <= MAX_PATH_HOPS))
+            .prop_map(|(names, file, listing, route, signed)| {
+                let (packet_type, source, filename, relative_path) = names;
+                let (file_size, chunk_hashes) = file;
+                let (entries, text) = listing;
+                let (hops, cost, path, via) = route;
+                let (timestamp, nonce, signing_key, signature) = signed;
+                PacketHeader {
+                    packet_type,
+                    source,
+                    filename,
+                    relative_path,
+                    file_size,
+                    chunk_hashes,
+                    entries,
+                    text,
+                    hops,
+                    cost,
+                    path,
+                    via,
+                    timestamp,
+                    nonce,
+                    signing_key,
+                    signature,
+                    ..Default::default()
+                }
+            })
+    }
+
+    // Encoded in binary, which lays out every field, so equal bytes mean
+    // equal headers
+    fn binary(header: &PacketHeader) -> Vec<u8> {
+        encode_header(header, true).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn binary_round_trips(header in header()) {
+            let decoded = decode_header(&binary(&header), true).unwrap();
+            prop_assert_eq!(binary(&decoded), binary(&header));
+        }
+
+        #[test]
+        fn json_round_trips(header in header()) {
+            let decoded = decode_header(&encode_header(&header, false).unwrap(), false).unwrap();
+            prop_assert_eq!(binary(&decoded), binary(&header));
+        }
+
+        // Whatever arrives is a header that passes the checks or an error
+        #[test]
+        fn arbitrary_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..4096)) {
+            for binary in [true, false] {
+                if let Ok(header) = decode_header(&bytes, binary) {
+                    prop_assert!(check_header(&header).is_ok());
+                }
+            }
+        }
+
+        #[test]
+        fn arbitrary_bodies_never_panic(body in prop::collection::vec(any::<u8>(), 0..4096)) {
+            let mut bytes = HEADER_SCHEMA.to_be_bytes().to_vec();
+            bytes.extend(body);
+            let _ = decode_binary(&bytes);
+        }
+
+        // A decodable header carrying what the checks forbid is refused
+        #[test]
+        fn control_characters_are_refused(header in header(), at in any::<prop::sample::Index>()) {
+            let mut header = header;
+            let mut source: Vec<char> = header.source.chars().collect();
+            source.insert(at.index(source.len() + 1), '\n');
+            header.source = source.into_iter().collect();
+            prop_assert!(check_header(&header).is_err());
+            prop_assert!(decode_binary(&binary(&header)).is_ok());
+            prop_assert!(decode_header(&binary(&header), true).is_err());
+        }
+
+        // A file hash names files we keep, so one that could lead out of
+        // their folder is refused however it's encoded
+        #[test]
+        fn traversal_hashes_are_refused(
+            header in header(),
+            hash in prop_oneof![
+                Just("../trusted_devices".to_string()),
+                Just("/etc/passwd".to_string()),
+                Just("..\\..\\settings".to_string()),
+                "[0-9a-f]{1,63}",
+                "[0-9A-F]{64}",
+            ],
+        ) {
+            let header = PacketHeader { file_hash: hash, ..header };
+            prop_assert!(decode_header(&binary(&header), true).is_err());
+            prop_assert!(decode_header(&encode_header(&header, false).unwrap(), false).is_err());
+        }
+
+        #[test]
+        fn hashes_are_let_through(header in header(), hash in "[0-9a-f]{64}") {
+            let header = PacketHeader { file_hash: hash, ..header };
+            prop_assert!(decode_header(&binary(&header), true).is_ok());
+        }
+
+        #[test]
+        fn more_chunk_hashes_than_chunks_are_refused(chunks in 1u64..8, extra in 1usize..4) {
+            let header = PacketHeader {
+                packet_type: "FILE_TRANSFER".to_string(),
+                file_size: chunks * crate::CHUNK_SIZE as u64,
+                chunk_hashes: vec![String::new(); chunks as usize + extra],
+                ..Default::default()
+            };
+            prop_assert!(check_header(&header).is_err());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Optional per-chunk zstd compression
+//
+// The sender offers compression in the file header and the receiver turns
+// it on by echoing it back when accepting. Each chunk is then compressed
+// before encryption, and sent as-is whenever compressing didn't help.
+
+use std::borrow::Cow;
+
+// Value of the header's `compression` field when zstd is offered or agreed
+pub const ZSTD: &str = "zstd";
+
+const ZSTD_LEVEL: i32 = 3;
+
+// First byte of each chunk when compression is on
+const RAW_CHUNK: u8 = 0;
+const ZSTD_CHUNK: u8 = 1;
+
+// Formats that are already compressed and wouldn't shrink any further
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "7z", "rar", "zst", "lz4",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "mov", "avi", "webm", "m4v",
+    "docx", "xlsx", "pptx", "odt", "apk", "jar",
+];
+
+// Whether a file is likely to benefit from compression, judged by name
+pub fn worth_compressing(filename: &str) -> bool {
+    let extension = std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension {
+        Some(extension) => !COMPRESSED_EXTENSIONS.contains(&extension.as_str()),
+        None => true,
+    }
+}
+
+// Compress a chunk, falling back to the raw bytes if that is smaller
+pub fn pack(chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(chunk.len() + 1);
+    match zstd::bulk::compress(chunk, ZSTD_LEVEL) {
+        Ok(compressed) if compressed.len() < chunk.len() => {
+            frame.push(ZSTD_CHUNK);
+            frame.extend_from_slice(&compressed);
+        }
+        _ => {
+            frame.push(RAW_CHUNK);
+            frame.extend_from_slice(chunk);
+        }
+    }
+    frame
+}
+
+// Reverse `pack`, refusing to inflate past `max_len` bytes
+pub fn unpack(frame: &[u8], max_len: usize) -> Option<Cow<'_, [u8]>> {
+    let (&kind, payload) = frame.split_first()?;
+    match kind {
+        RAW_CHUNK => Some(Cow::Borrowed(payload)),
+        ZSTD_CHUNK => zstd::bulk::decompress(payload, max_len).ok().map(Cow::Owned),
+        _ => None,
+    }
+}
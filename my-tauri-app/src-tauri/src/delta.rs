@@ -0,0 +1,243 @@
+// Delta transfers for files the receiver already has a copy of
+//
+// When the destination file exists, the receiver sends rsync-style block
+// signatures (a rolling weak checksum plus a truncated BLAKE3 hash) with
+// its acceptance. The sender then encodes each chunk as references to
+// matching blocks of the old file plus literal bytes, and the receiver
+// rebuilds the chunk before checking its hash as usual.
+//
+// Signatures give away a fingerprint of what's in the file, so they're
+// only sent to the device the file came from, going by who each received
+// file was last received from (see Origins), or to a sender resuming its
+// own transfer.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::app_data_dir;
+
+// Operation tags in an encoded chunk
+const OP_LITERAL: u8 = 0;
+const OP_COPY: u8 = 1;
+
+// Received files whose sender is remembered, the longest ago forgotten
+const MAX_ORIGINS: usize = 2048;
+
+// Where received files were saved, with the signing key of the device
+// each last came from
+#[derive(Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Origins {
+    files: HashMap<PathBuf, Origin>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Origin {
+    signing_key: String,
+    received_at: i64,
+}
+
+pub type ReceivedFrom = Arc<Mutex<Origins>>;
+
+impl Origins {
+    // Whether the file at `path` was last received from `signing_key`
+    pub fn came_from(&self, path: &Path, signing_key: &str) -> bool {
+        !signing_key.is_empty() && self.files.get(path).is_some_and(|origin| origin.signing_key == signing_key)
+    }
+
+    fn insert(&mut self, path: PathBuf, signing_key: String) {
+        if self.files.len() >= MAX_ORIGINS && !self.files.contains_key(&path) {
+            let oldest = self.files.iter().min_by_key(|(_, origin)| origin.received_at).map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.files.remove(&oldest);
+            }
+        }
+        let received_at = chrono::Utc::now().timestamp_millis();
+        self.files.insert(path, Origin { signing_key, received_at });
+    }
+}
+
+fn origins_path() -> PathBuf {
+    app_data_dir().join("received_from.json")
+}
+
+pub fn load_origins() -> Origins {
+    std::fs::read(origins_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+// Note that the file saved at `path` came from the device with `signing_key`
+pub fn remember_origin(origins: &ReceivedFrom, path: &Path, signing_key: &str) {
+    let mut origins = origins.lock();
+    origins.insert(path.to_path_buf(), signing_key.to_string());
+    let saved = std::fs::create_dir_all(app_data_dir())
+        .and_then(|_| Ok(serde_json::to_vec(&*origins)?))
+        .and_then(|json| std::fs::write(origins_path(), json));
+    if let Err(e) = saved {
+        eprintln!("Failed to save where received files came from: {}", e);
+    }
+}
+
+// Roughly the square root of the file size, as rsync does, so the
+// signature list stays small for large files
+pub fn block_size_for(file_len: u64) -> u64 {
+    let root = (file_len as f64).sqrt() as u64;
+    (root.div_ceil(1024) * 1024).clamp(2048, 64 * 1024)
+}
+
+// rsync's rolling checksum over a window of bytes
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    // Slide the window one byte forward
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> String {
+    blake3::hash(block).to_hex()[..32].to_string()
+}
+
+// Signatures of every full block of an existing file
+pub fn signatures(file: &mut File, block_size: u64) -> std::io::Result<Vec<(u32, String)>> {
+    let mut signatures = Vec::new();
+    let mut block = vec![0u8; block_size as usize];
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+        let mut filled = 0;
+        while filled < block.len() {
+            match file.read(&mut block[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled < block.len() {
+            return Ok(signatures);
+        }
+        signatures.push((Rolling::new(&block).digest(), strong_hash(&block)));
+    }
+}
+
+// Receiver block signatures, indexed by weak checksum for lookup
+pub struct SignatureIndex {
+    block_size: usize,
+    by_weak: HashMap<u32, Vec<(u32, String)>>,
+}
+
+impl SignatureIndex {
+    pub fn new(block_size: u64, signatures: &[(u32, String)]) -> Self {
+        let mut by_weak: HashMap<u32, Vec<(u32, String)>> = HashMap::new();
+        for (index, (weak, strong)) in signatures.iter().enumerate() {
+            by_weak.entry(*weak).or_default().push((index as u32, strong.clone()));
+        }
+        SignatureIndex { block_size: block_size as usize, by_weak }
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates.iter().find(|(_, s)| *s == strong).map(|(index, _)| *index)
+    }
+}
+
+fn push_literal(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        encoded.push(OP_LITERAL);
+        encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(bytes);
+    }
+}
+
+// Encode a chunk as copies of the receiver's blocks and literal bytes
+pub fn encode(chunk: &[u8], index: &SignatureIndex) -> Vec<u8> {
+    let block_size = index.block_size;
+    let mut encoded = Vec::new();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = (chunk.len() >= block_size).then(|| Rolling::new(&chunk[..block_size]));
+
+    while let Some(current) = rolling {
+        let window = &chunk[pos..pos + block_size];
+        if let Some(block) = index.find(current.digest(), window) {
+            push_literal(&mut encoded, &chunk[literal_start..pos]);
+            encoded.push(OP_COPY);
+            encoded.extend_from_slice(&block.to_be_bytes());
+            pos += block_size;
+            literal_start = pos;
+            rolling = (pos + block_size <= chunk.len()).then(|| Rolling::new(&chunk[pos..pos + block_size]));
+            continue;
+        }
+
+        if pos + block_size < chunk.len() {
+            let mut next = current;
+            next.roll(chunk[pos], chunk[pos + block_size]);
+            rolling = Some(next);
+            pos += 1;
+        } else {
+            rolling = None;
+        }
+    }
+
+    push_literal(&mut encoded, &chunk[literal_start..]);
+    encoded
+}
+
+// Rebuild a chunk from its encoding and the old file, refusing anything
+// malformed or longer than `max_len`
+pub fn decode(encoded: &[u8], old: &mut File, block_size: u64, max_len: usize) -> Option<Vec<u8>> {
+    let mut chunk = Vec::new();
+    let mut rest = encoded;
+    while let Some((&op, tail)) = rest.split_first() {
+        match op {
+            OP_LITERAL => {
+                let len = u32::from_be_bytes(tail.get(..4)?.try_into().ok()?) as usize;
+                let bytes = tail.get(4..4 + len)?;
+                chunk.extend_from_slice(bytes);
+                rest = &tail[4 + len..];
+            }
+            OP_COPY => {
+                let block = u32::from_be_bytes(tail.get(..4)?.try_into().ok()?) as u64;
+                let start = chunk.len();
+                chunk.resize(start + block_size as usize, 0);
+                old.seek(SeekFrom::Start(block * block_size)).ok()?;
+                old.read_exact(&mut chunk[start..]).ok()?;
+                rest = &tail[4..];
+            }
+            _ => return None,
+        }
+        if chunk.len() > max_len {
+            return None;
+        }
+    }
+    Some(chunk)
+}
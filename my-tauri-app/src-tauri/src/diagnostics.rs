@@ -0,0 +1,266 @@
+// Working out why devices don't see each other
+//
+// `run_diagnostics` looks at each thing finding and reaching a device
+// depends on, and reports what it found rather than stopping at the first
+// failure:
+//
+// - whether the file server has a port, and accepts connections on it;
+// - whether multicast, which mDNS needs, gets out of and back into each of
+//   our IPv4 interfaces, and whether the mDNS daemon is running;
+// - which of our addresses we announce, and which fit in the TXT record;
+// - whether each device we know of accepts a connection, and answers as
+//   itself when asked who it is;
+// - and, for one device picked by the user, the round trip time and
+//   throughput of the link to it.
+//
+// Multicast is only looped back to ourselves, so a network that drops it
+// between devices still passes; beacons cover for that. Everything here
+// blocks, and is run on the blocking pool.
+
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tauri::{AppHandle, Manager};
+
+use crate::error::{Error, ErrorReport};
+use crate::{discovery_alive, heartbeat, known, links, net, AppState, Device};
+
+// The mDNS group, which is what needs to get through
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+// How long a looped back multicast datagram has to arrive
+const MULTICAST_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long our own server has to accept a connection
+const SERVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticsReport {
+    pub server: ServerCheck,
+    pub multicast: MulticastCheck,
+    pub interfaces: Vec<InterfaceCheck>,
+    pub peers: Vec<PeerCheck>,
+    pub throughput: Option<ThroughputCheck>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerCheck {
+    // The port we listen on, or would if the server isn't running
+    pub port: u16,
+    pub listening: bool,
+    // Whether a connection to it from this machine was accepted
+    pub accepting: bool,
+    pub error: Option<ErrorReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MulticastCheck {
+    pub discovery_enabled: bool,
+    pub mdns_running: bool,
+    pub interfaces: Vec<MulticastInterface>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MulticastInterface {
+    pub name: String,
+    pub address: String,
+    // Why multicast didn't come back on this interface, None if it did
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceCheck {
+    pub name: String,
+    pub address: String,
+    // Peers only hear of addresses outside the TXT record on the interface
+    // they heard us on
+    pub in_txt_record: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCheck {
+    pub device_id: String,
+    pub name: String,
+    pub status: String,
+    pub addresses: Vec<String>,
+    pub port: u16,
+    // Milliseconds to connect, or why connecting failed
+    pub tcp_ms: Option<f64>,
+    pub tcp_error: Option<String>,
+    // Milliseconds for it to answer who it is, or why it didn't
+    pub ping_ms: Option<f64>,
+    pub ping_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThroughputCheck {
+    pub device_id: String,
+    pub rtt_ms: Option<f64>,
+    pub throughput_bps: Option<f64>,
+    pub error: Option<String>,
+}
+
+// Run every check, measuring throughput to `throughput_to` if given
+pub fn run(app: &AppHandle, throughput_to: Option<&str>) -> DiagnosticsReport {
+    let state = app.state::<AppState>();
+    let interfaces = net::local_interfaces();
+    let peers: Vec<Device> = {
+        let devices = state.devices.lock();
+        let offline = known::offline(&state.known_devices.lock(), &devices);
+        devices.values().cloned().chain(offline).collect()
+    };
+
+    let throughput = throughput_to.map(|device_id| match peers.iter().find(|d| d.id == device_id) {
+        Some(device) => measure_throughput(device, app),
+        None => ThroughputCheck {
+            device_id: device_id.to_string(),
+            rtt_ms: None,
+            throughput_bps: None,
+            error: Some("Unknown device".to_string()),
+        },
+    });
+    let peers = std::thread::scope(|scope| {
+        let checks: Vec<_> = peers.iter().map(|device| scope.spawn(|| check_peer(device, app))).collect();
+        checks.into_iter().filter_map(|check| check.join().ok()).collect()
+    });
+
+    let discovery_enabled = state.settings.lock().discovery_enabled;
+    DiagnosticsReport {
+        server: check_server(&state),
+        multicast: MulticastCheck {
+            discovery_enabled,
+            mdns_running: discovery_alive(&state),
+            interfaces: interfaces
+                .iter()
+                .filter_map(|interface| match interface.ip() {
+                    IpAddr::V4(ip) => Some(MulticastInterface {
+                        name: interface.name.clone(),
+                        address: ip.to_string(),
+                        error: multicast_loops_back(ip).err().map(|e| e.to_string()),
+                    }),
+                    IpAddr::V6(_) => None,
+                })
+                .collect(),
+        },
+        interfaces: check_interfaces(&interfaces),
+        peers,
+        throughput,
+    }
+}
+
+// Connect to our own server if it's running, or see whether a port in
+// its range could be bound if it isn't
+fn check_server(state: &AppState) -> ServerCheck {
+    let server = state.server.lock().clone();
+    if !server.running {
+        let (first, count) = {
+            let settings = state.settings.lock();
+            (settings.server_port, settings.server_port_count)
+        };
+        let bound = net::bind_port_range(first, count);
+        return ServerCheck {
+            port: bound.as_ref().ok().and_then(|listeners| listeners.first()?.local_addr().ok()).map_or(first, |a| a.port()),
+            listening: false,
+            accepting: false,
+            error: Some(match bound {
+                Ok(_) => server.error.unwrap_or_else(|| Error::Internal("The file server isn't running".to_string()).report()),
+                Err(e) => Error::from(std::io::Error::new(e.kind(), format!("No port could be bound: {}", e))).report(),
+            }),
+        };
+    }
+    let own = SocketAddr::from((Ipv4Addr::LOCALHOST, server.port));
+    let accepted = TcpStream::connect_timeout(&own, SERVER_TIMEOUT);
+    ServerCheck {
+        port: server.port,
+        listening: true,
+        accepting: accepted.is_ok(),
+        error: accepted.err().map(|e| Error::from(e).report()),
+    }
+}
+
+// Send a datagram to the mDNS group out of the interface at `ip` and wait
+// for it to come back to us
+fn multicast_loops_back(ip: Ipv4Addr) -> std::io::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))?;
+    socket.join_multicast_v4(&MDNS_GROUP, &ip)?;
+    socket.set_multicast_if_v4(&ip)?;
+    socket.set_multicast_loop_v4(true)?;
+    let socket: std::net::UdpSocket = socket.into();
+    let port = socket.local_addr()?.port();
+
+    let token = uuid::Uuid::new_v4();
+    socket.send_to(token.as_bytes(), SocketAddrV4::new(MDNS_GROUP, port))?;
+    let deadline = Instant::now() + MULTICAST_TIMEOUT;
+    let mut buf = [0u8; 64];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Multicast didn't come back"));
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (len, _) = socket.recv_from(&mut buf)?;
+        if &buf[..len] == token.as_bytes() {
+            return Ok(());
+        }
+    }
+}
+
+fn check_interfaces(interfaces: &[if_addrs::Interface]) -> Vec<InterfaceCheck> {
+    let announced = net::own_addresses(interfaces);
+    let txt = net::txt_addresses(&announced);
+    let in_txt: Vec<IpAddr> = net::parse_txt_addresses(&txt).collect();
+    interfaces
+        .iter()
+        .map(|interface| InterfaceCheck {
+            name: interface.name.clone(),
+            address: interface.ip().to_string(),
+            in_txt_record: in_txt.contains(&interface.ip()),
+        })
+        .collect()
+}
+
+fn check_peer(device: &Device, app: &AppHandle) -> PeerCheck {
+    let addresses = net::device_addresses(device);
+    let started = Instant::now();
+    let tcp = net::tcp_connect(&addresses, device.port).map(|_| elapsed_ms(started));
+    let (ping_ms, ping_error) = if device.public_key.is_empty() {
+        (None, Some("Device doesn't announce an identity key".to_string()))
+    } else {
+        let ctx = app.state::<AppState>().peer_context(app.clone());
+        let started = Instant::now();
+        match heartbeat::ping(device, &ctx) {
+            Ok(_) => (Some(elapsed_ms(started)), None),
+            Err(e) => (None, Some(e.to_string())),
+        }
+    };
+    PeerCheck {
+        device_id: device.id.clone(),
+        name: device.name.clone(),
+        status: device.status.clone(),
+        addresses,
+        port: device.port,
+        tcp_ms: tcp.as_ref().ok().copied(),
+        tcp_error: tcp.err().map(|e| e.to_string()),
+        ping_ms,
+        ping_error,
+    }
+}
+
+fn measure_throughput(device: &Device, app: &AppHandle) -> ThroughputCheck {
+    let ctx = app.state::<AppState>().peer_context(app.clone());
+    let measured = links::measure(device, &ctx);
+    ThroughputCheck {
+        device_id: device.id.clone(),
+        rtt_ms: measured.as_ref().ok().map(|(rtt_ms, _)| *rtt_ms),
+        throughput_bps: measured.as_ref().ok().map(|(_, throughput_bps)| *throughput_bps),
+        error: measured.err().map(|e| e.to_string()),
+    }
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,237 @@
+// Download links, for handing a file to someone without the app
+//
+// Each link gets its own small HTTP server on a random port, and its path
+// carries a token nobody could guess (`/d/AbC123...`), so only someone
+// given the link finds the file. A link lasts until it has been downloaded
+// as many times as it allows, once by default, or until it expires or is
+// cancelled, after which its server goes away with it.
+//
+// A password, if there is one, is asked for with HTTP basic auth, which
+// every browser can prompt for; any user name will do. Like the rest of the
+// link it goes over plain HTTP, so it keeps out anyone who has only seen
+// the link, not anyone able to watch the network.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Emitter};
+use tiny_http::{Request, Response, Server};
+
+use crate::http;
+
+// How long a link lasts when not told otherwise, and at most
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const MAX_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// How often the server looks up from waiting for requests
+const SERVER_POLL: Duration = Duration::from_secs(1);
+
+// A link a browser can download a file from
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadLink {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub urls: Vec<String>,
+    pub qr_svg: String,
+    pub has_password: bool,
+    // Unix seconds after which the link stops working
+    pub expires_at: i64,
+    pub max_downloads: u32,
+    pub downloads: u32,
+}
+
+// Sent as `link://downloaded` after each download, and `link://finished`
+// once a link stops working
+#[derive(Debug, Clone, Serialize)]
+struct LinkEvent {
+    id: String,
+    filename: String,
+    downloads: u32,
+}
+
+// A link being served
+pub struct ServedLink {
+    link: DownloadLink,
+    downloads: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ServedLink {
+    pub fn link(&self) -> DownloadLink {
+        DownloadLink { downloads: self.downloads.load(Ordering::SeqCst), ..self.link.clone() }
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub type DownloadLinks = Arc<Mutex<HashMap<String, ServedLink>>>;
+
+// What a link serves and to whom
+struct Served {
+    path: PathBuf,
+    filename: String,
+    page_path: String,
+    password_hash: Option<[u8; 32]>,
+    max_downloads: u32,
+    // Downloads finished, and under way
+    downloads: Arc<AtomicU32>,
+    in_flight: AtomicU32,
+}
+
+fn hash_password(password: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"Reality download link")
+        .chain_update(password.as_bytes())
+        .finalize()
+        .into()
+}
+
+// Start serving `path` at a new link
+pub fn share(
+    path: &Path,
+    password: Option<String>,
+    lifetime: Option<Duration>,
+    max_downloads: Option<u32>,
+    app: AppHandle,
+    links: DownloadLinks,
+) -> Result<DownloadLink, String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("Only files can be shared by link".to_string());
+    }
+    let filename = path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or("Invalid file path")?;
+    let max_downloads = max_downloads.unwrap_or(1);
+    if max_downloads == 0 {
+        return Err("A link has to allow at least one download".to_string());
+    }
+    let lifetime = lifetime.unwrap_or(DEFAULT_LIFETIME);
+    if lifetime.is_zero() || lifetime > MAX_LIFETIME {
+        return Err("A link can last at most a week".to_string());
+    }
+    let password = password.filter(|password| !password.is_empty());
+
+    let http::Page { server, path: page_path, urls, qr_svg } = http::serve_page("/d")?;
+    let link = DownloadLink {
+        id: uuid::Uuid::new_v4().to_string(),
+        filename: filename.clone(),
+        size: metadata.len(),
+        urls,
+        qr_svg,
+        has_password: password.is_some(),
+        expires_at: (chrono::Utc::now() + lifetime).timestamp(),
+        max_downloads,
+        downloads: 0,
+    };
+    let served = Arc::new(Served {
+        path: path.to_path_buf(),
+        filename,
+        page_path,
+        password_hash: password.as_deref().map(hash_password),
+        max_downloads,
+        downloads: Arc::new(AtomicU32::new(0)),
+        in_flight: AtomicU32::new(0),
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    links.lock().insert(link.id.clone(), ServedLink {
+        link: link.clone(),
+        downloads: served.downloads.clone(),
+        stop: stop.clone(),
+    });
+
+    let id = link.id.clone();
+    std::thread::spawn(move || {
+        serve(&server, &id, &served, Instant::now() + lifetime, &stop, &app);
+        links.lock().remove(&id);
+        let downloads = served.downloads.load(Ordering::SeqCst);
+        let filename = served.filename.clone();
+        let _ = app.emit("link://finished", LinkEvent { id, filename, downloads });
+    });
+    Ok(link)
+}
+
+// Answer requests until the link has been used up, expires or is cancelled
+fn serve(server: &Server, id: &str, served: &Arc<Served>, expires: Instant, stop: &AtomicBool, app: &AppHandle) {
+    loop {
+        let used_up = served.downloads.load(Ordering::SeqCst) >= served.max_downloads;
+        if stop.load(Ordering::SeqCst) || used_up || Instant::now() >= expires {
+            break;
+        }
+        let request = match server.recv_timeout(SERVER_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(_) => break,
+        };
+
+        if request.url() != served.page_path {
+            let _ = request.respond(Response::from_string("Not found").with_status_code(404));
+            continue;
+        }
+        if !authorized(&request, served.password_hash.as_ref()) {
+            let challenge = http::header("WWW-Authenticate", "Basic realm=\"Reality\", charset=\"UTF-8\"");
+            let _ = request.respond(Response::from_string("Password required").with_status_code(401).with_header(challenge));
+            continue;
+        }
+        // Downloads under way hold their place, so no more start than allowed
+        let started = served.in_flight.fetch_add(1, Ordering::SeqCst);
+        if served.downloads.load(Ordering::SeqCst) + started >= served.max_downloads {
+            served.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let _ = request.respond(Response::from_string("This link has been used up").with_status_code(410));
+            continue;
+        }
+
+        let (served, app, id) = (served.clone(), app.clone(), id.to_string());
+        std::thread::spawn(move || {
+            // Counted before its place is given up, so none sneaks in between
+            let result = send_file(request, &served);
+            let downloads = match &result {
+                Ok(()) => served.downloads.fetch_add(1, Ordering::SeqCst) + 1,
+                Err(_) => served.downloads.load(Ordering::SeqCst),
+            };
+            served.in_flight.fetch_sub(1, Ordering::SeqCst);
+            match result {
+                Ok(()) => {
+                    let filename = served.filename.clone();
+                    let _ = app.emit("link://downloaded", LinkEvent { id, filename, downloads });
+                }
+                Err(e) => eprintln!("Download of {} failed: {}", served.filename, e),
+            }
+        });
+    }
+}
+
+// Whether a request may have the file: always without a password, and
+// with one when basic auth gives it
+fn authorized(request: &Request, password_hash: Option<&[u8; 32]>) -> bool {
+    match password_hash {
+        None => true,
+        Some(expected) => http::basic_credentials(request)
+            .is_some_and(|(_, password)| hash_password(&password) == *expected),
+    }
+}
+
+fn send_file(request: Request, served: &Served) -> std::io::Result<()> {
+    let file = File::open(&served.path)?;
+    let size = file.metadata()?.len();
+    let disposition = format!("attachment; filename*=UTF-8''{}", http::percent_encode(&served.filename));
+    let headers = [
+        http::header("Content-Type", "application/octet-stream"),
+        http::header("Content-Disposition", &disposition),
+    ];
+    let mut response = Response::new(200.into(), Vec::new(), file, Some(size as usize), None);
+    for header in headers {
+        response.add_header(header);
+    }
+    request.respond(response)
+}
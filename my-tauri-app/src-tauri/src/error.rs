@@ -0,0 +1,159 @@
+// Errors as the frontend sees them
+//
+// Every command fails with an Error, and failed transfers carry one too.
+// Each goes to the frontend as a machine-readable code, the message to
+// show and, where there is one, the detail it was made from:
+//
+//     { "code": "peer_offline", "message": "Peer unreachable: Connection refused", "context": "Connection refused" }
+//
+// so the UI can tell a peer that's gone from a full disk without parsing
+// messages. Codes are stable; messages may change.
+//
+// I/O errors are sorted into codes by their kind, so a refused connection
+// reads as an offline peer wherever it comes from. Errors still made as
+// strings deep down convert to `internal` and keep their message.
+
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Peer unreachable: {0}")]
+    PeerOffline(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Not enough disk space")]
+    DiskFull,
+    #[error("{0}")]
+    QuotaExceeded(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Rejected: {0}")]
+    Rejected(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("{0}")]
+    Corrupted(String),
+    // The peer speaks a protocol version we don't, or lacks a capability
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("{0}")]
+    Io(std::io::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::PeerOffline(_) => "peer_offline",
+            Error::NotFound(_) => "not_found",
+            Error::DiskFull => "disk_full",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::PermissionDenied(_) => "permission_denied",
+            Error::InvalidInput(_) => "invalid_input",
+            Error::Rejected(_) => "rejected",
+            Error::Cancelled => "cancelled",
+            Error::WrongPassword => "wrong_password",
+            Error::Corrupted(_) => "corrupted",
+            Error::Unsupported(_) => "unsupported",
+            Error::Io(_) => "io",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    // The detail the message was made from, where it adds something
+    fn context(&self) -> Option<String> {
+        match self {
+            Error::PeerOffline(detail) | Error::PermissionDenied(detail) | Error::Rejected(detail) => Some(detail.clone()),
+            Error::Io(e) => Some(format!("{:?}", e.kind())),
+            _ => None,
+        }
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            context: self.context(),
+        }
+    }
+}
+
+// An error as it's sent to the frontend and kept in transfer records
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub code: String,
+    pub message: String,
+    #[serde(default)]
+    pub context: Option<String>,
+}
+
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.report().serialize(serializer)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::TimedOut
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+            | ErrorKind::AddrNotAvailable => Error::PeerOffline(e.to_string()),
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => Error::DiskFull,
+            ErrorKind::PermissionDenied => Error::PermissionDenied(e.to_string()),
+            ErrorKind::NotFound => Error::NotFound(e.to_string()),
+            ErrorKind::InvalidInput => Error::InvalidInput(e.to_string()),
+            ErrorKind::Unsupported => Error::Unsupported(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<mdns_sd::Error> for Error {
+    fn from(e: mdns_sd::Error) -> Self {
+        Error::Internal(format!("Discovery error: {}", e))
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Internal(format!("History error: {}", e))
+    }
+}
+
+// Answering something that stopped waiting for an answer
+impl<T> From<std::sync::mpsc::SendError<T>> for Error {
+    fn from(_: std::sync::mpsc::SendError<T>) -> Self {
+        Error::NotFound("No longer waiting for an answer".to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Internal(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Internal(message.to_string())
+    }
+}
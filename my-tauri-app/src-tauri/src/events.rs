@@ -0,0 +1,114 @@
+// Transfer and device events pushed to the frontend
+//
+// The UI listens for these instead of polling `get_transfers`. A new
+// transfer is announced with its full record, as is the outcome once it
+// finishes; status changes and progress in between only carry the fields
+// that move. Progress events carry a list of these, and progress itself is
+// only sent every PROGRESS_INTERVAL, for all the transfers that moved at
+// once.
+// Records taken off the list are announced by id.
+//
+// Likewise for `get_devices`: a device is announced with its full record
+// when it comes into sight, again whenever anything shown of it changes,
+// and once more when it goes out of sight, after which devices we know are
+// still listed as offline.
+
+use serde::Serialize;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{AppState, Device, FileTransfer};
+
+pub const STARTED: &str = "transfer://started";
+pub const PROGRESS: &str = "transfer://progress";
+pub const COMPLETED: &str = "transfer://completed";
+pub const FAILED: &str = "transfer://failed";
+pub const REMOVED: &str = "transfer://removed";
+
+pub const DEVICE_FOUND: &str = "device://found";
+pub const DEVICE_UPDATED: &str = "device://updated";
+pub const DEVICE_LOST: &str = "device://lost";
+
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// The parts of a transfer that change while it runs
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferUpdate {
+    pub transfer_id: String,
+    pub status: String,
+    pub progress: u64,
+    pub speed_bps: u64,
+    pub eta_seconds: Option<u64>,
+}
+
+impl From<&FileTransfer> for TransferUpdate {
+    fn from(transfer: &FileTransfer) -> Self {
+        TransferUpdate {
+            transfer_id: transfer.id.clone(),
+            status: transfer.status.clone(),
+            progress: transfer.progress,
+            speed_bps: transfer.speed_bps,
+            eta_seconds: transfer.eta_seconds,
+        }
+    }
+}
+
+pub fn emit_update(app: &AppHandle, update: TransferUpdate) {
+    emit_updates(app, &[update]);
+}
+
+pub fn emit_updates(app: &AppHandle, updates: &[TransferUpdate]) {
+    if !updates.is_empty() {
+        let _ = app.emit(PROGRESS, updates);
+    }
+}
+
+// Announce a transfer record under `event`
+pub fn emit_record(app: &AppHandle, event: &str, transfer: &FileTransfer) {
+    let _ = app.emit(event, transfer);
+}
+
+pub fn emit_removed(app: &AppHandle, transfer_ids: &[String]) {
+    if !transfer_ids.is_empty() {
+        let _ = app.emit(REMOVED, transfer_ids);
+    }
+}
+
+// Announce a device as it is now in the device table, going by what the
+// table held for it before. Being heard from again alone isn't a change.
+pub fn emit_device(app: &AppHandle, before: Option<&Device>, device: &Device) {
+    let event = match before {
+        None => DEVICE_FOUND,
+        Some(before) if shown_alike(before, device) => return,
+        Some(_) => DEVICE_UPDATED,
+    };
+    let _ = app.emit(event, device);
+}
+
+fn shown_alike(before: &Device, after: &Device) -> bool {
+    let before = Device {
+        last_seen: after.last_seen.clone(),
+        last_heard: after.last_heard,
+        ..before.clone()
+    };
+    before == *after
+}
+
+// A device gone out of sight
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceLost {
+    pub device_id: String,
+    // How the UI lists it from now on, if we know it; devices we don't are
+    // no longer listed at all
+    pub offline: Option<Device>,
+}
+
+// Announce a device taken out of the device table
+pub fn emit_device_lost(app: &AppHandle, device: &Device) {
+    let known = app.state::<AppState>().known_devices.lock().contains_key(&device.id);
+    let lost = DeviceLost {
+        device_id: device.id.clone(),
+        offline: known.then(|| Device { status: "Offline".to_string(), ..device.clone() }),
+    };
+    let _ = app.emit(DEVICE_LOST, lost);
+}
@@ -0,0 +1,130 @@
+// Which files of a folder are sent or synced
+//
+// Rules are set per sync pair, or given with a folder being sent, and are
+// applied while the folder is walked, so a folder they leave out, such as
+// node_modules or a build directory, isn't even looked into.
+//
+// Patterns work as in a .gitignore: `*` matches within a name, `**` across
+// folders and `?` any one character; a pattern with a `/` other than at
+// its end is matched from the top of the folder, and any other against
+// every name at any depth; one ending in `/` only matches folders; and one
+// starting with `!` brings back what an earlier pattern left out. The last
+// pattern that matches decides. On top of the patterns, hidden files and
+// folders can be left out, and files over a size.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileRules {
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    // Leave out names starting with a dot
+    #[serde(default)]
+    pub skip_hidden: bool,
+    // Leave out files larger than this, in bytes
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+}
+
+struct Pattern {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    // Matched against the whole relative path rather than each name
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/').to_string();
+        (!glob.is_empty()).then_some(Pattern { glob, negated, dir_only, anchored })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+        glob(self.glob.as_bytes(), text.as_bytes())
+    }
+}
+
+// Rules ready to be checked against paths
+pub struct Filter {
+    patterns: Vec<Pattern>,
+    skip_hidden: bool,
+    max_file_size: Option<u64>,
+}
+
+impl Filter {
+    pub fn new(rules: &FileRules) -> Self {
+        Filter {
+            patterns: rules.patterns.iter().filter_map(|line| Pattern::parse(line)).collect(),
+            skip_hidden: rules.skip_hidden,
+            max_file_size: rules.max_file_size,
+        }
+    }
+
+    // Whether the file or folder at `relative`, a path within the folder
+    // with '/' between names, is left out. Its parents are taken to have
+    // been let in already, as they are on a walk.
+    pub fn ignores(&self, relative: &str, is_dir: bool) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        if self.skip_hidden && name.starts_with('.') {
+            return true;
+        }
+        self.patterns.iter()
+            .rev()
+            .find(|pattern| pattern.matches(relative, is_dir))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    // Whether the file at `relative` is left out, itself or along with
+    // one of the folders it's in
+    pub fn ignores_path(&self, relative: &str) -> bool {
+        let mut folders = relative.match_indices('/').map(|(end, _)| &relative[..end]);
+        folders.any(|folder| self.ignores(folder, true)) || self.ignores(relative, false)
+    }
+
+    pub fn too_large(&self, size: u64) -> bool {
+        self.max_file_size.is_some_and(|max| size > max)
+    }
+}
+
+// Match `text` against a glob, where `*` and `?` stay within a name and `**`
+// spans folders; `**/` also matches no folders at all
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            if let Some(after) = rest.strip_prefix(b"/") {
+                if glob(after, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|start| glob(rest, &text[start..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&start| start == 0 || text[start - 1] != b'/')
+            .any(|start| glob(rest, &text[start..])),
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
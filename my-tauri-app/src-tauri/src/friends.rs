@@ -0,0 +1,81 @@
+// Friend codes, for pairing ahead of time with a device that isn't around
+//
+// A friend code is our identity key written out for people to pass on:
+// its 32 bytes and a two-byte check, in Crockford's base32 and in groups
+// of five, so it reads out loud and a mistyped one is caught rather than
+// trusted. It stays the same as long as the identity key does.
+//
+// Entering a device's code trusts it straight away, as pairing would, while
+// it's out of sight. The connection handshake proves who holds the key, so
+// when the device connects to us, found on our network or through the
+// rendezvous server, it's recognised as paired without comparing codes.
+// Its signing key, its id and, unless the user named it, its name are
+// taken from what it sends over that connection, never from what it
+// announces, which anyone could claim.
+
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CHECK_LEN: usize = 2;
+const GROUP_LEN: usize = 5;
+
+// What a friend added without a name goes by until it's heard from
+pub const UNNAMED: &str = "Friend";
+
+fn check(key: &[u8]) -> [u8; CHECK_LEN] {
+    let digest = Sha256::digest(key);
+    [digest[0], digest[1]]
+}
+
+pub fn friend_code(key: &PublicKey) -> String {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes.extend_from_slice(&check(key.as_bytes()));
+
+    let mut digits = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            digits.push(ALPHABET[(buffer >> bits) as usize & 31]);
+        }
+    }
+    if bits > 0 {
+        digits.push(ALPHABET[(buffer << (5 - bits)) as usize & 31]);
+    }
+    digits
+        .chunks(GROUP_LEN)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// The identity key in a friend code, if it's a well-formed one. Case,
+// dashes and spaces don't matter, and letters easily mistaken for digits
+// are read as those digits.
+pub fn parse_friend_code(code: &str) -> Option<PublicKey> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in code.chars().filter(|c| !matches!(c, '-' | ' ')) {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+        let value = ALPHABET.iter().position(|&digit| char::from(digit) == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    let (key, sum) = bytes.split_at_checked(32)?;
+    if sum != check(key) {
+        return None;
+    }
+    <[u8; 32]>::try_from(key).ok().map(PublicKey::from)
+}
@@ -0,0 +1,84 @@
+// Network groups: devices that share a passphrase only see each other
+//
+// The passphrase is stretched with Argon2id into a group key. The key goes
+// into the prologue of every Noise handshake, so two devices in different
+// groups fail to connect at all, relays included. A short tag derived from
+// the key is appended to our mDNS instance name and carried in beacons,
+// and devices announcing another tag, or none, are left out of the list.
+// The tag is a hash of the stretched key, so it doesn't give the
+// passphrase away any more cheaply than a handshake would.
+//
+// With no passphrase the prologue is empty and there is no tag, which is
+// how devices from before groups existed behave.
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+// Fixed, since every member has to derive the same key
+const GROUP_SALT: &[u8] = b"Reality network group";
+
+// Hex digits of tag in an instance name
+const TAG_LEN: usize = 16;
+
+// The group key every handshake in the process is bound to. It's kept
+// here rather than passed along with the identity key, since there is
+// only ever one and every connection uses it.
+static GROUP_KEY: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+// Join the group with this passphrase, or leave groups with an empty one
+pub fn join(passphrase: &str) -> Result<(), String> {
+    let key = if passphrase.is_empty() {
+        Vec::new()
+    } else {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), GROUP_SALT, &mut key)
+            .map_err(|e| format!("Group key derivation error: {}", e))?;
+        key.to_vec()
+    };
+    *GROUP_KEY.lock() = key;
+    Ok(())
+}
+
+// Prologue for Noise handshakes
+pub fn prologue() -> Vec<u8> {
+    GROUP_KEY.lock().clone()
+}
+
+// Our group's tag, if we're in one
+pub fn tag() -> Option<String> {
+    let key = GROUP_KEY.lock();
+    if key.is_empty() {
+        return None;
+    }
+    let digest = Sha256::new()
+        .chain_update(b"Reality group tag")
+        .chain_update(&*key)
+        .finalize();
+    Some(digest.iter().take(TAG_LEN / 2).map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// The mDNS instance name to register under
+pub fn instance_name(device_name: &str) -> String {
+    match tag() {
+        Some(tag) => format!("{}#{}", device_name, tag),
+        None => device_name.to_string(),
+    }
+}
+
+// The tag in an instance name, if it has one
+fn instance_tag(instance: &str) -> Option<&str> {
+    instance.rsplit_once('#')
+        .map(|(_, tag)| tag)
+        .filter(|tag| tag.len() == TAG_LEN && tag.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Whether a device announcing this tag, or none, is in our group
+pub fn is_ours(tag: Option<&str>) -> bool {
+    tag.filter(|tag| !tag.is_empty()) == self::tag().as_deref()
+}
+
+// Whether a device registered under this mDNS instance name is in our group
+pub fn is_our_instance(instance: &str) -> bool {
+    is_ours(instance_tag(instance))
+}
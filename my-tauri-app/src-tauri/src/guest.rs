@@ -0,0 +1,235 @@
+// Guest sessions, for lending files to a device we won't see again
+//
+// A guest session listens on a port of its own under a throwaway identity:
+// new identity and signing keys and a new device id, made for the session
+// and never saved. It isn't announced, so only a device given its address
+// finds it, and the first device to connect is shown to the user, who lets
+// it in or not. From then on that device is the only one the session
+// answers; it's trusted by the session for as long as it lasts, can send
+// us files and browse and fetch what we share, and everything else that
+// connects is dropped unanswered. It's never added to our paired devices,
+// so nothing outside the session trusts it.
+//
+// The session ends after the time it was started for, or when the user
+// ends it. Its transfers never went into the
+// history, and on ending they're dropped from the list along with any
+// messages with the guest. Files the guest sent stay where they were saved.
+
+use ed25519_dalek::SigningKey;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::Error;
+use crate::pairing::{self, DevicePermissions, TrustedDevice};
+use crate::{
+    encode_public_key, messages, net, remove_transfers, serve_connections, AppState, PeerContext, APPROVAL_TIMEOUT,
+    PACKET_FILE_REQUEST, PACKET_FILE_TRANSFER, PACKET_HAS_FILE, PACKET_IDENTIFY, PACKET_LIST_FILES, PACKET_MESSAGE,
+    PACKET_PING, PACKET_STREAM_JOIN,
+};
+
+// Longest a session may be started for
+const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct GuestSession {
+    pub id: String,
+    pub identity_key: StaticSecret,
+    pub signing_key: SigningKey,
+    pub device_id: String,
+    port: u16,
+    expires_at: chrono::DateTime<chrono::Local>,
+    // The device let in, trusted here and nowhere else
+    guest: Mutex<Option<TrustedDevice>>,
+    // Transfers with the guest, kept out of the history
+    transfers: Mutex<Vec<String>>,
+    stop: CancellationToken,
+}
+
+pub type GuestSlot = Arc<Mutex<Option<Arc<GuestSession>>>>;
+
+// What the user is shown of a session, and gives the guest
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestSessionInfo {
+    pub id: String,
+    pub port: u16,
+    pub addresses: Vec<String>,
+    // Of the session's identity key, for the guest to check
+    pub fingerprint: String,
+    pub expires_at: String,
+    pub guest_name: Option<String>,
+}
+
+// A device asking to be let into the session
+#[derive(Debug, Clone, Serialize)]
+struct GuestRequest {
+    session_id: String,
+    device_name: String,
+    fingerprint: String,
+}
+
+impl GuestSession {
+    pub fn info(&self) -> GuestSessionInfo {
+        GuestSessionInfo {
+            id: self.id.clone(),
+            port: self.port,
+            addresses: net::own_addresses(&net::local_interfaces()).iter().map(|ip| ip.to_string()).collect(),
+            fingerprint: pairing::fingerprint(&PublicKey::from(&self.identity_key)),
+            expires_at: self.expires_at.to_rfc3339(),
+            guest_name: self.guest.lock().as_ref().map(|device| device.name.clone()),
+        }
+    }
+
+    // The device let in, as the session trusts it
+    pub fn admitted(&self) -> Option<TrustedDevice> {
+        self.guest.lock().clone()
+    }
+
+    pub fn ended(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+}
+
+// Start a session lasting `duration`, listening on a port of its own
+pub fn start(app: &AppHandle, duration: Duration) -> Result<GuestSessionInfo, Error> {
+    let state = app.state::<AppState>();
+    if duration.is_zero() || duration > MAX_DURATION {
+        return Err(Error::InvalidInput("A guest session lasts from a second to a day".to_string()));
+    }
+    let mut slot = state.guest_session.lock();
+    if slot.is_some() {
+        return Err(Error::InvalidInput("A guest session is already running".to_string()));
+    }
+
+    let listeners = net::bind_dual_stack(0)?;
+    let port = listeners[0].local_addr()?.port();
+    let session = Arc::new(GuestSession {
+        id: Uuid::new_v4().to_string(),
+        identity_key: StaticSecret::random_from_rng(OsRng),
+        signing_key: SigningKey::generate(&mut OsRng),
+        device_id: Uuid::new_v4().to_string(),
+        port,
+        expires_at: chrono::Local::now() + duration,
+        guest: Mutex::new(None),
+        transfers: Mutex::new(Vec::new()),
+        stop: state.tasks.child_token(),
+    });
+
+    for listener in listeners {
+        let ctx = state.guest_context(app.clone(), session.clone());
+        let stop = session.stop.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = serve_connections(listener, ctx, &stop).await {
+                eprintln!("Guest session stopped listening: {}", e);
+            }
+        });
+    }
+
+    // Ended by the clock unless the user ends it first
+    let (expiring, app_handle, stop) = (session.id.clone(), app.clone(), session.stop.clone());
+    tauri::async_runtime::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {
+                let _ = end(&app_handle, Some(&expiring));
+            }
+            _ = stop.cancelled() => {}
+        }
+    });
+
+    let info = session.info();
+    *slot = Some(session);
+    Ok(info)
+}
+
+// What a guest may do: send files, browse and fetch what we share, and
+// message us. Pairing for good, syncing and relaying aren't among it.
+pub fn allows(packet_type: &str) -> bool {
+    matches!(
+        packet_type,
+        PACKET_FILE_TRANSFER | PACKET_STREAM_JOIN | PACKET_LIST_FILES | PACKET_FILE_REQUEST | PACKET_HAS_FILE
+            | PACKET_MESSAGE | PACKET_PING | PACKET_IDENTIFY
+    )
+}
+
+// Whether a connection to the session may go on. The first device to
+// connect is put to the user; once one is let in, no other device is.
+pub fn admit(session: &GuestSession, peer: &PublicKey, name: &str, signing_key: &str, ctx: &PeerContext) -> bool {
+    let key = encode_public_key(peer);
+    if let Some(guest) = session.guest.lock().as_ref() {
+        return guest.public_key == key;
+    }
+
+    // One device is asked about at a time
+    let (tx, rx) = mpsc::channel();
+    {
+        let mut pending = ctx.pending_approvals.lock();
+        if pending.contains_key(&session.id) {
+            return false;
+        }
+        pending.insert(session.id.clone(), tx);
+    }
+    let _ = ctx.app.emit("guest://request", GuestRequest {
+        session_id: session.id.clone(),
+        device_name: name.to_string(),
+        fingerprint: pairing::fingerprint(peer),
+    });
+    let approved = rx.recv_timeout(APPROVAL_TIMEOUT).unwrap_or(false);
+    ctx.pending_approvals.lock().remove(&session.id);
+    if !approved || session.ended() {
+        return false;
+    }
+
+    // Another connection may have been let in while we waited
+    let mut guest = session.guest.lock();
+    if let Some(admitted) = guest.as_ref() {
+        return admitted.public_key == key;
+    }
+    *guest = Some(TrustedDevice {
+        public_key: key,
+        device_id: String::new(),
+        signing_key: signing_key.to_string(),
+        name: name.to_string(),
+        paired_at: chrono::Local::now().to_rfc3339(),
+        permissions: DevicePermissions { relay: false, ..DevicePermissions::default() },
+    });
+    drop(guest);
+    let _ = ctx.app.emit("guest://joined", session.info());
+    true
+}
+
+// Keep a transfer with the guest out of the history, dropping it from the
+// list too if the session has already ended
+pub fn keep_off_record(session: &GuestSession, transfer_id: &str, app: &AppHandle) {
+    if session.ended() {
+        remove_transfers(app, &app.state::<AppState>(), |t| t.id == transfer_id);
+    } else {
+        session.transfers.lock().push(transfer_id.to_string());
+    }
+}
+
+// End the running session, or only the one with `id` if given, and erase
+// what it left behind
+pub fn end(app: &AppHandle, id: Option<&str>) -> Result<(), Error> {
+    let state = app.state::<AppState>();
+    let session = {
+        let mut slot = state.guest_session.lock();
+        if slot.as_ref().is_none_or(|session| id.is_some_and(|id| session.id != id)) {
+            return Err(Error::NotFound("No guest session running".to_string()));
+        }
+        slot.take().unwrap()
+    };
+    session.stop.cancel();
+
+    if let Some(device) = session.guest.lock().take() {
+        messages::forget_peer(&mut state.messages.lock(), &device.public_key);
+    }
+    let transfers = std::mem::take(&mut *session.transfers.lock());
+    remove_transfers(app, &state, |t| transfers.contains(&t.id));
+    let _ = app.emit("guest://ended", &session.id);
+    Ok(())
+}
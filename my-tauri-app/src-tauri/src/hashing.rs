@@ -0,0 +1,143 @@
+// Hashing files before they're sent
+//
+// A file's header carries the BLAKE3 hash of the whole file and of each of
+// its chunks, so every byte has to be read before the first is sent. The
+// file is read once, a batch of chunks at a time: the chunks of a batch
+// are hashed side by side on the rayon pool, while the whole-file hash
+// takes the batch with BLAKE3's own parallel update.
+//
+// What was worked out is kept in a `HashCache`, keyed by path, size and
+// modification time, so a file sent again unchanged, to another device or
+// after a failed attempt, isn't read again. A file that changes while it's
+// being hashed isn't cached.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+use rayon::prelude::*;
+
+use crate::{read_chunk, CHUNK_SIZE};
+
+// Chunks read before they're hashed together
+const HASH_BATCH: usize = 16;
+
+// Files whose hashes are remembered; the least recently used go first
+const MAX_CACHED: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct FileHashes {
+    pub file_hash: String,
+    pub chunk_hashes: Vec<String>,
+}
+
+// The size and modification time a file had when it was hashed
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Version {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Version {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Version { size: metadata.len(), modified: metadata.modified().ok() })
+    }
+}
+
+struct Cached {
+    version: Version,
+    hashes: FileHashes,
+    last_used: u64,
+}
+
+#[derive(Default)]
+pub struct HashCache {
+    files: HashMap<PathBuf, Cached>,
+    uses: u64,
+}
+
+pub type HashedFiles = Arc<Mutex<HashCache>>;
+
+impl HashCache {
+    fn get(&mut self, path: &Path, version: Version) -> Option<FileHashes> {
+        self.uses += 1;
+        let cached = self.files.get_mut(path).filter(|cached| cached.version == version)?;
+        cached.last_used = self.uses;
+        Some(cached.hashes.clone())
+    }
+
+    fn insert(&mut self, path: PathBuf, version: Version, hashes: FileHashes) {
+        self.uses += 1;
+        if self.files.len() >= MAX_CACHED && !self.files.contains_key(&path) {
+            let oldest = self.files.iter().min_by_key(|(_, cached)| cached.last_used).map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.files.remove(&oldest);
+            }
+        }
+        self.files.insert(path, Cached { version, hashes, last_used: self.uses });
+    }
+}
+
+// The hashes of the file at `path`, worked out unless they're cached for
+// the file as it is now
+pub fn hash_file(cache: &HashedFiles, path: &Path) -> std::io::Result<FileHashes> {
+    let version = Version::of(path)?;
+    if let Some(hashes) = cache.lock().get(path, version) {
+        return Ok(hashes);
+    }
+    let hashes = hash_contents(path)?;
+    if Version::of(path)? == version {
+        cache.lock().insert(path.to_path_buf(), version, hashes.clone());
+    }
+    Ok(hashes)
+}
+
+// The hashes of the file at `path` if they're cached for the file as it is
+// now, without reading it
+pub fn cached(cache: &HashedFiles, path: &Path) -> Option<FileHashes> {
+    let version = Version::of(path).ok()?;
+    cache.lock().get(path, version)
+}
+
+// Remember hashes worked out some other way, such as by receiving the file
+pub fn remember(cache: &HashedFiles, path: &Path, hashes: FileHashes) {
+    if let Ok(version) = Version::of(path) {
+        cache.lock().insert(path.to_path_buf(), version, hashes);
+    }
+}
+
+fn hash_contents(path: &Path) -> std::io::Result<FileHashes> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut chunk_hashes = Vec::new();
+    let mut ended = false;
+
+    while !ended {
+        let mut batch = Vec::with_capacity(HASH_BATCH);
+        while batch.len() < HASH_BATCH && !ended {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            read_chunk(&mut file, &mut chunk)?;
+            // A short chunk is the last
+            ended = chunk.len() < CHUNK_SIZE;
+            if !chunk.is_empty() {
+                batch.push(chunk);
+            }
+        }
+
+        let (_, hashes) = rayon::join(
+            || {
+                for chunk in &batch {
+                    hasher.update_rayon(chunk);
+                }
+            },
+            || batch.par_iter().map(|chunk| blake3::hash(chunk).to_hex().to_string()).collect::<Vec<_>>(),
+        );
+        chunk_hashes.extend(hashes);
+    }
+
+    Ok(FileHashes { file_hash: hasher.finalize().to_hex().to_string(), chunk_hashes })
+}
@@ -0,0 +1,98 @@
+// Keeping track of which devices are still there
+//
+// mDNS only says a device has gone when it says goodbye, which a device
+// that crashes or drops off the network never does. So every device we
+// know of, in sight or not, is sent a small IDENTIFY every heartbeat
+// interval over an ordinary encrypted connection. A device that answers
+// as itself counts as heard from, and the answer carries its current name
+// and icon, so a device renamed since it was discovered shows up under its
+// new name; one that goes unheard for a while is
+// shown as stale, and after longer still is taken out of sight, with its
+// routes, until it answers again.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::settings::HeartbeatPolicy;
+use crate::net;
+use crate::{read_header, signing, write_header, Device, PacketHeader, PeerContext, PACKET_IDENTIFY, PACKET_IDENTITY};
+
+// How long a device has to answer a heartbeat
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Ask a device who it is, succeeding only if it answers as the device we
+// know, under the same identity key and device id. Gives back the answer.
+pub fn ping(device: &Device, ctx: &PeerContext) -> std::io::Result<PacketHeader> {
+    let mut channel = net::connect_device_timeout(device, &ctx.identity_key, HEARTBEAT_TIMEOUT)?;
+    if crate::encode_public_key(channel.peer_identity()) != device.public_key {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Device identity changed"));
+    }
+    let request = PacketHeader {
+        packet_type: PACKET_IDENTIFY.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &request, &ctx.signing_key)?;
+
+    channel.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+    let response = read_header(&mut channel)?;
+    let paired = ctx.trusted_devices.lock().get(&device.public_key).cloned();
+    signing::verify_header(&response, paired.as_ref())
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type != PACKET_IDENTITY || response.source_id != device.id {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Device did not identify itself"));
+    }
+    Ok(response)
+}
+
+// The device as it identified itself, with whatever name and icon it has
+// now. Devices too old to send an icon keep the one they had.
+pub fn identified(device: Device, identity: &PacketHeader) -> Device {
+    Device {
+        name: if identity.source.is_empty() { device.name.clone() } else { identity.source.clone() },
+        device_type: if identity.device_type.is_empty() { device.device_type.clone() } else { identity.device_type.clone() },
+        ..device
+    }
+}
+
+// Note a device answered, bringing it back into sight if it was out of it.
+// Gives back how it was before, if it was in sight, and how it is now.
+pub fn heard_from(devices: &mut HashMap<String, Device>, device: &Device, now: i64) -> (Option<Device>, Device) {
+    let before = devices.get(&device.id).cloned();
+    let entry = devices.entry(device.id.clone()).or_insert_with(|| device.clone());
+    entry.name = device.name.clone();
+    entry.device_type = device.device_type.clone();
+    entry.last_heard = now;
+    entry.status = "Available".to_string();
+    entry.last_seen = chrono::Local::now().format("%H:%M:%S").to_string();
+    (before, entry.clone())
+}
+
+// What a round of status updates changed
+pub struct StatusChanges {
+    // Devices that went stale or came back from it
+    pub changed: Vec<Device>,
+    // Devices taken out of sight
+    pub gone: Vec<Device>,
+}
+
+// Mark devices that have gone quiet as stale, and take those that have
+// been quiet too long out of sight
+pub fn update_statuses(devices: &mut HashMap<String, Device>, policy: &HeartbeatPolicy, now: i64) -> StatusChanges {
+    let mut changes = StatusChanges { changed: Vec::new(), gone: Vec::new() };
+    devices.retain(|_, device| {
+        let silent = now - device.last_heard;
+        if silent > policy.offline_after_secs as i64 {
+            changes.gone.push(device.clone());
+            return false;
+        }
+        let status = if silent > policy.stale_after_secs as i64 { "Stale" } else { "Available" };
+        if device.status != status {
+            device.status = status.to_string();
+            changes.changed.push(device.clone());
+        }
+        true
+    });
+    changes
+}
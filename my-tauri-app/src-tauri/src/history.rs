@@ -0,0 +1,359 @@
+// Transfer history
+//
+// Finished transfers are written to a SQLite database in the app data
+// directory, so the history survives restarts. Only a limited number of
+// finished transfers stay in memory; older ones are read back from the
+// database a page at a time.
+//
+// Alongside it are running totals per peer and per day, for the usage
+// statistics. They're added to as each transfer finishes and each relayed
+// connection closes, and are kept when the history is cleared.
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_data_dir, FileTransfer};
+
+// Entries returned per page of `get_transfer_history`
+const PAGE_SIZE: u32 = 50;
+
+// Column order shared by inserts and `entry_from_row`
+const COLUMNS: &str =
+    "id, filename, size, from_device, to_device, file_hash, status, outcome, batch_id, started_at, finished_at, saved_path";
+
+// A finished transfer as stored in the database
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub from_device: String,
+    pub to_device: String,
+    pub file_hash: Option<String>,
+    pub status: String,
+    // "completed" or "failed"
+    pub outcome: String,
+    pub batch_id: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: String,
+    pub saved_path: Option<String>,
+}
+
+// What to narrow the history down to; every field is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    // Device name on either end
+    pub peer: Option<String>,
+    pub outcome: Option<String>,
+    // Part of the file name
+    pub search: Option<String>,
+}
+
+// Days to total, as YYYY-MM-DD in local time, both ends included; either
+// may be left open
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StatsRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Totals {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub files_sent: u64,
+    pub files_received: u64,
+    pub failures: u64,
+    // Over the time bytes were moving, 0 if none were
+    pub average_speed_bps: u64,
+    // Bytes we passed on between other devices
+    pub relayed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerTotals {
+    pub peer: String,
+    pub totals: Totals,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DayTotals {
+    pub day: String,
+    pub totals: Totals,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Statistics {
+    pub totals: Totals,
+    pub by_peer: Vec<PeerTotals>,
+    // Oldest first, only days with anything in them
+    pub by_day: Vec<DayTotals>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    pub total: u64,
+    pub page: u32,
+    pub page_size: u32,
+}
+
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    // Open the history database, falling back to one in memory if the
+    // file can't be used
+    pub fn open() -> Self {
+        let db = std::fs::create_dir_all(app_data_dir())
+            .ok()
+            .and_then(|_| Connection::open(app_data_dir().join("history.db")).ok())
+            .filter(|db| create_schema(db).is_ok())
+            .unwrap_or_else(|| {
+                eprintln!("Transfer history could not be opened, keeping it in memory");
+                let db = Connection::open_in_memory().expect("in-memory database");
+                let _ = create_schema(&db);
+                db
+            });
+        History { db }
+    }
+
+    // Store a transfer that has just finished, counting it towards the
+    // totals for `peer`, the device on the other end
+    pub fn record(&self, transfer: &FileTransfer, outcome: &str, peer: &str) -> rusqlite::Result<()> {
+        let finished_at = chrono::Local::now();
+        self.db.execute(
+            &format!(
+                "INSERT OR REPLACE INTO transfers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                COLUMNS,
+            ),
+            params![
+                transfer.id,
+                transfer.filename,
+                transfer.size as i64,
+                transfer.from_device,
+                transfer.to_device,
+                transfer.file_hash,
+                transfer.status,
+                outcome,
+                transfer.batch_id,
+                transfer.started_at,
+                finished_at.to_rfc3339(),
+                transfer.saved_path,
+            ],
+        )?;
+
+        // Bytes that moved count whether or not the transfer went through
+        let sent = transfer.from_device == crate::THIS_DEVICE;
+        let completed = outcome == "completed";
+        let moving_secs = transfer
+            .started_at
+            .as_deref()
+            .and_then(|started| chrono::DateTime::parse_from_rfc3339(started).ok())
+            .map_or(0.0, |started| (finished_at.fixed_offset() - started).num_milliseconds().max(0) as f64 / 1000.0);
+        let mut add = Totals::default();
+        if sent {
+            add.bytes_sent = transfer.progress;
+            add.files_sent = u64::from(completed);
+        } else {
+            add.bytes_received = transfer.progress;
+            add.files_received = u64::from(completed);
+        }
+        add.failures = u64::from(!completed);
+        self.add_to_totals(&finished_at.format("%Y-%m-%d").to_string(), peer, &add, moving_secs)
+    }
+
+    // Count bytes we relayed for `peer`, the device that connected to us
+    pub fn record_relayed(&self, peer: &str, bytes: u64) -> rusqlite::Result<()> {
+        let day = chrono::Local::now().format("%Y-%m-%d").to_string();
+        self.add_to_totals(&day, peer, &Totals { relayed_bytes: bytes, ..Totals::default() }, 0.0)
+    }
+
+    fn add_to_totals(&self, day: &str, peer: &str, add: &Totals, moving_secs: f64) -> rusqlite::Result<()> {
+        self.db.execute(
+            "INSERT INTO daily_stats
+                (day, peer, bytes_sent, bytes_received, files_sent, files_received, failures, moving_secs, relayed_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (day, peer) DO UPDATE SET
+                bytes_sent = bytes_sent + excluded.bytes_sent,
+                bytes_received = bytes_received + excluded.bytes_received,
+                files_sent = files_sent + excluded.files_sent,
+                files_received = files_received + excluded.files_received,
+                failures = failures + excluded.failures,
+                moving_secs = moving_secs + excluded.moving_secs,
+                relayed_bytes = relayed_bytes + excluded.relayed_bytes",
+            params![
+                day,
+                peer,
+                add.bytes_sent as i64,
+                add.bytes_received as i64,
+                add.files_sent as i64,
+                add.files_received as i64,
+                add.failures as i64,
+                moving_secs,
+                add.relayed_bytes as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Totals over `range`, overall, per peer and per day
+    pub fn statistics(&self, range: &StatsRange) -> rusqlite::Result<Statistics> {
+        let totals = |group: &str| -> rusqlite::Result<Vec<(String, Totals)>> {
+            let mut statement = self.db.prepare(&format!(
+                "SELECT {}, SUM(bytes_sent), SUM(bytes_received), SUM(files_sent), SUM(files_received),
+                        SUM(failures), SUM(moving_secs), SUM(relayed_bytes)
+                 FROM daily_stats
+                 WHERE (?1 IS NULL OR day >= ?1) AND (?2 IS NULL OR day <= ?2)
+                 GROUP BY 1 ORDER BY 1",
+                group,
+            ))?;
+            let rows = statement
+                .query_map(params![range.from, range.to], |row| Ok((row.get(0)?, totals_from_row(row)?)))?
+                .collect();
+            rows
+        };
+        Ok(Statistics {
+            totals: totals("'all'")?.pop().map(|(_, totals)| totals).unwrap_or_default(),
+            by_peer: totals("peer")?.into_iter().map(|(peer, totals)| PeerTotals { peer, totals }).collect(),
+            by_day: totals("day")?.into_iter().map(|(day, totals)| DayTotals { day, totals }).collect(),
+        })
+    }
+
+    // One page of history, newest first, counting pages from 0
+    pub fn page(&self, filter: &HistoryFilter, page: u32) -> rusqlite::Result<HistoryPage> {
+        let peer = filter.peer.as_deref();
+        let outcome = filter.outcome.as_deref();
+        let search = filter.search.as_deref().map(|s| format!("%{}%", s));
+        let conditions = "(?1 IS NULL OR from_device = ?1 OR to_device = ?1)
+            AND (?2 IS NULL OR outcome = ?2)
+            AND (?3 IS NULL OR filename LIKE ?3)";
+
+        let total: i64 = self.db.query_row(
+            &format!("SELECT COUNT(*) FROM transfers WHERE {}", conditions),
+            params![peer, outcome, search],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = self.db.prepare(&format!(
+            "SELECT {} FROM transfers WHERE {} ORDER BY finished_at DESC LIMIT ?4 OFFSET ?5",
+            COLUMNS, conditions,
+        ))?;
+        let offset = page as i64 * PAGE_SIZE as i64;
+        let entries = statement
+            .query_map(params![peer, outcome, search, PAGE_SIZE, offset], entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(HistoryPage { entries, total: total as u64, page, page_size: PAGE_SIZE })
+    }
+
+    // Note where a received file ended up after it was saved, once it was
+    // let out of the quarantine or confirmed
+    pub fn resaved(&self, id: &str, status: &str, saved_path: &str) -> rusqlite::Result<()> {
+        self.db.execute(
+            "UPDATE transfers SET status = ?2, saved_path = ?3 WHERE id = ?1",
+            params![id, status, saved_path],
+        )?;
+        Ok(())
+    }
+
+    // Where a transfer's file was saved, if it's in the history and was
+    // received
+    pub fn saved_path(&self, id: &str) -> rusqlite::Result<Option<String>> {
+        self.db
+            .query_row("SELECT saved_path FROM transfers WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+    }
+
+    // Where completed incoming transfers with this content were saved
+    pub fn received_copies(&self, file_hash: &str) -> rusqlite::Result<Vec<String>> {
+        let mut statement = self.db.prepare(
+            "SELECT saved_path FROM transfers
+             WHERE file_hash = ?1 AND outcome = 'completed' AND saved_path IS NOT NULL",
+        )?;
+        let copies = statement
+            .query_map(params![file_hash], |row| row.get(0))?
+            .collect();
+        copies
+    }
+
+    pub fn clear(&self) -> rusqlite::Result<()> {
+        self.db.execute("DELETE FROM transfers", [])?;
+        Ok(())
+    }
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        filename: row.get(1)?,
+        size: row.get::<_, i64>(2)? as u64,
+        from_device: row.get(3)?,
+        to_device: row.get(4)?,
+        file_hash: row.get(5)?,
+        status: row.get(6)?,
+        outcome: row.get(7)?,
+        batch_id: row.get(8)?,
+        started_at: row.get(9)?,
+        finished_at: row.get(10)?,
+        saved_path: row.get(11)?,
+    })
+}
+
+// Columns 1 to 7 of a statistics query
+fn totals_from_row(row: &Row) -> rusqlite::Result<Totals> {
+    let count = |i: usize| -> rusqlite::Result<u64> { Ok(row.get::<_, Option<i64>>(i)?.unwrap_or(0) as u64) };
+    let (bytes_sent, bytes_received) = (count(1)?, count(2)?);
+    let moving_secs: f64 = row.get::<_, Option<f64>>(6)?.unwrap_or(0.0);
+    Ok(Totals {
+        bytes_sent,
+        bytes_received,
+        files_sent: count(3)?,
+        files_received: count(4)?,
+        failures: count(5)?,
+        average_speed_bps: if moving_secs > 0.0 { ((bytes_sent + bytes_received) as f64 / moving_secs) as u64 } else { 0 },
+        relayed_bytes: count(7)?,
+    })
+}
+
+fn create_schema(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS transfers (
+            id TEXT PRIMARY KEY,
+            filename TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            from_device TEXT NOT NULL,
+            to_device TEXT NOT NULL,
+            file_hash TEXT,
+            status TEXT NOT NULL,
+            outcome TEXT NOT NULL,
+            batch_id TEXT,
+            started_at TEXT,
+            finished_at TEXT NOT NULL,
+            saved_path TEXT
+        );
+        CREATE INDEX IF NOT EXISTS transfers_finished_at ON transfers (finished_at);
+        CREATE TABLE IF NOT EXISTS daily_stats (
+            day TEXT NOT NULL,
+            peer TEXT NOT NULL,
+            bytes_sent INTEGER NOT NULL DEFAULT 0,
+            bytes_received INTEGER NOT NULL DEFAULT 0,
+            files_sent INTEGER NOT NULL DEFAULT 0,
+            files_received INTEGER NOT NULL DEFAULT 0,
+            failures INTEGER NOT NULL DEFAULT 0,
+            moving_secs REAL NOT NULL DEFAULT 0,
+            relayed_bytes INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (day, peer)
+        );",
+    )?;
+
+    // Databases created before saved paths were recorded lack the column
+    if db.prepare("SELECT saved_path FROM transfers LIMIT 0").is_err() {
+        db.execute_batch("ALTER TABLE transfers ADD COLUMN saved_path TEXT")?;
+    }
+    db.execute_batch("CREATE INDEX IF NOT EXISTS transfers_file_hash ON transfers (file_hash)")
+}
@@ -0,0 +1,174 @@
+// Sharing without a shared network, through a hotspot one device hosts
+//
+// The hosting device starts a Wi-Fi hotspot, or asks its user to, and shows
+// a QR code carrying what it takes to join and to reach it: the network's
+// name and password, our addresses, port and keys, and a one-off pairing
+// token. The device that scans it joins the network, trusts the keys it
+// scanned, since scanning is as good as comparing codes, and sends a
+// PAIR_REQUEST carrying the token so the host trusts it back without
+// asking. The token pairs that one device; any other that scans the code
+// still joins the network but is asked about as usual. Discovery then starts over on the new network.
+//
+// Starting a hotspot or joining a network is done through the system's own
+// tools where there are any: NetworkManager on Linux, netsh on Windows and
+// networksetup on macOS, which can only join.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::net;
+
+// What a join QR code starts with
+const PAYLOAD_PREFIX: &str = "reality-hotspot:";
+
+// How long to wait for an address on a network we've just joined
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+const JOIN_POLL: Duration = Duration::from_millis(500);
+
+// Everything a device needs to join our hotspot and pair with us
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinPayload {
+    pub ssid: String,
+    pub password: String,
+    pub token: String,
+    pub name: String,
+    pub id: String,
+    pub public_key: String,
+    pub signing_key: String,
+    pub port: u16,
+    pub addresses: Vec<String>,
+}
+
+impl JoinPayload {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        format!("{}{}", PAYLOAD_PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json))
+    }
+
+    pub fn decode(payload: &str) -> Result<Self, String> {
+        let encoded = payload.trim().strip_prefix(PAYLOAD_PREFIX).ok_or("Not a hotspot code")?;
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded)
+            .map_err(|_| "Malformed hotspot code")?;
+        serde_json::from_slice(&json).map_err(|_| "Malformed hotspot code".to_string())
+    }
+}
+
+// The hotspot we're hosting
+#[derive(Debug, Clone, Serialize)]
+pub struct Hotspot {
+    pub ssid: String,
+    pub password: String,
+    // Whether we started it, rather than leaving it to the user
+    pub started: bool,
+    pub payload: String,
+    // The payload as a QR code, in SVG
+    pub qr_svg: String,
+    #[serde(skip)]
+    pub token: String,
+}
+
+// A network name and password for a new hotspot, and a pairing token
+pub fn credentials() -> (String, String, String) {
+    let mut bytes = [0u8; 2];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let ssid = format!("Reality-{:02X}{:02X}", bytes[0], bytes[1]);
+    let password = Uuid::new_v4().simple().to_string()[..12].to_string();
+    (ssid, password, Uuid::new_v4().to_string())
+}
+
+pub fn qr_svg(payload: &str) -> Result<String, String> {
+    let code = QrCode::new(payload.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(code.render::<svg::Color>().min_dimensions(256, 256).build())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program).args(args).output()
+        .map_err(|e| format!("{}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+// Start a hotspot, if the system lets us
+pub fn start(ssid: &str, password: &str) -> Result<(), String> {
+    match std::env::consts::OS {
+        "linux" => run("nmcli", &["device", "wifi", "hotspot", "ssid", ssid, "password", password]),
+        "windows" => {
+            let ssid = format!("ssid={}", ssid);
+            let key = format!("key={}", password);
+            run("netsh", &["wlan", "set", "hostednetwork", "mode=allow", &ssid, &key])?;
+            run("netsh", &["wlan", "start", "hostednetwork"])
+        }
+        os => Err(format!("Starting a hotspot isn't supported on {}", os)),
+    }
+}
+
+pub fn stop() -> Result<(), String> {
+    match std::env::consts::OS {
+        "linux" => run("nmcli", &["connection", "down", "Hotspot"]),
+        "windows" => run("netsh", &["wlan", "stop", "hostednetwork"]),
+        _ => Ok(()),
+    }
+}
+
+// Join the hotspot in a payload, if the system lets us
+pub fn join(payload: &JoinPayload) -> Result<(), String> {
+    let (ssid, password) = (payload.ssid.as_str(), payload.password.as_str());
+    match std::env::consts::OS {
+        "linux" => run("nmcli", &["device", "wifi", "connect", ssid, "password", password]),
+        "macos" => run("networksetup", &["-setairportnetwork", "en0", ssid, password]),
+        "windows" => {
+            // netsh only joins networks it has a profile for
+            let profile = format!(
+                r#"<?xml version="1.0"?>
+<WLANProfile xmlns="http://www.microsoft.com/networking/WLAN/profile/v1">
+<name>{ssid}</name>
+<SSIDConfig><SSID><name>{ssid}</name></SSID></SSIDConfig>
+<connectionType>ESS</connectionType>
+<connectionMode>manual</connectionMode>
+<MSM><security>
+<authEncryption><authentication>WPA2PSK</authentication><encryption>AES</encryption><useOneX>false</useOneX></authEncryption>
+<sharedKey><keyType>passPhrase</keyType><protected>false</protected><keyMaterial>{password}</keyMaterial></sharedKey>
+</security></MSM>
+</WLANProfile>"#
+            );
+            let path = std::env::temp_dir().join(format!("{}.xml", ssid));
+            std::fs::write(&path, profile).map_err(|e| e.to_string())?;
+            let filename = format!("filename={}", path.display());
+            let result = run("netsh", &["wlan", "add", "profile", &filename]);
+            let _ = std::fs::remove_file(&path);
+            result?;
+            let name = format!("name={}", ssid);
+            run("netsh", &["wlan", "connect", &name])
+        }
+        os => Err(format!("Joining a network isn't supported on {}", os)),
+    }
+}
+
+// Wait until one of the host's addresses is on a network we're on, giving
+// back those addresses as we'd try them
+pub fn wait_for_network(payload: &JoinPayload) -> Result<Vec<String>, String> {
+    let announced: Vec<std::net::IpAddr> = payload.addresses.iter()
+        .filter_map(|address| address.parse().ok())
+        .collect();
+    let deadline = Instant::now() + JOIN_TIMEOUT;
+    loop {
+        let interfaces = net::local_interfaces();
+        if net::shares_subnet(&announced, &interfaces) {
+            return Ok(net::order_addresses(announced.into_iter(), &interfaces));
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Didn't get onto {} in time", payload.ssid));
+        }
+        std::thread::sleep(JOIN_POLL);
+    }
+}
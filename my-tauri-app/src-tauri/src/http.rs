@@ -0,0 +1,89 @@
+// Bits shared by the HTTP servers we run for browsers and other programs
+// that don't speak our protocol: the upload page, links to files, and the
+// WebDAV export
+
+use base64::Engine;
+use rand::RngCore;
+use tiny_http::{Header, Request, Server};
+
+use crate::{hotspot, net};
+
+// A page of ours being served
+pub struct Page {
+    pub server: Server,
+    // Its path, with a token of its own in it
+    pub path: String,
+    // One URL for each of our IPv4 addresses, best first
+    pub urls: Vec<String>,
+    // The first of them as a QR code, in SVG
+    pub qr_svg: String,
+}
+
+// Start serving a page under `prefix`, on any free port
+pub fn serve_page(prefix: &str) -> Result<Page, String> {
+    let server = Server::http("0.0.0.0:0").map_err(|e| e.to_string())?;
+    let port = server.server_addr().to_ip().map(|addr| addr.port()).ok_or("No port to serve on")?;
+    let path = format!("{}/{}", prefix, token());
+    let urls = net::http_urls(port, &path);
+    let first = urls.first().ok_or("No IPv4 address to serve on")?;
+    let qr_svg = hotspot::qr_svg(first)?;
+    Ok(Page { server, path, urls, qr_svg })
+}
+
+// A random token for a path or password, long enough that nobody guesses it
+pub fn token() -> String {
+    let mut token = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut token);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token)
+}
+
+pub fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("Invalid header")
+}
+
+// The user name and password a request gives with basic auth
+pub fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let encoded = request.headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Basic "))?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Text for a URL path segment, or a file name as RFC 5987 wants it in a
+// header: everything but unreserved characters escaped
+pub fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// A URL path as text, or None if its escapes aren't valid UTF-8
+pub fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,62 @@
+// Devices we've seen before, kept across restarts
+//
+// Every device that identifies itself is remembered in the app data
+// directory with its id, name, keys and last address. After a restart they
+// are listed as offline until they're discovered again, so the usual
+// machines are always there to pick from. Only the devices map holds
+// devices that are actually in sight; this is just what the UI shows for
+// the rest.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{app_data_dir, Device};
+
+pub type KnownDevices = HashMap<String, Device>;
+
+fn known_devices_path() -> PathBuf {
+    app_data_dir().join("known_devices.json")
+}
+
+pub fn load_known_devices() -> KnownDevices {
+    let devices: Vec<Device> = std::fs::read(known_devices_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default();
+    devices.into_iter().map(|device| (device.id.clone(), device)).collect()
+}
+
+pub fn save_known_devices(known: &KnownDevices) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let devices: Vec<&Device> = known.values().collect();
+    std::fs::write(known_devices_path(), serde_json::to_vec_pretty(&devices)?)
+}
+
+// Note a device as seen just now, saving the list when there's something
+// new about it beyond the time
+pub fn remember(known: &mut KnownDevices, device: &Device) {
+    let changed = known.get(&device.id).is_none_or(|k| {
+        k.name != device.name
+            || k.device_type != device.device_type
+            || k.ip != device.ip
+            || k.addresses != device.addresses
+            || k.port != device.port
+            || k.public_key != device.public_key
+            || k.signing_key != device.signing_key
+    });
+    known.insert(device.id.clone(), device.clone());
+    if changed {
+        if let Err(e) = save_known_devices(known) {
+            eprintln!("Failed to save known devices: {}", e);
+        }
+    }
+}
+
+// Devices we know of but can't see right now, as the UI lists them
+pub fn offline(known: &KnownDevices, in_sight: &HashMap<String, Device>) -> Vec<Device> {
+    known
+        .values()
+        .filter(|device| !in_sight.contains_key(&device.id))
+        .map(|device| Device { status: "Offline".to_string(), ..device.clone() })
+        .collect()
+}
@@ -0,0 +1,86 @@
+// Files handed to us from outside the window
+//
+// Files reach us as arguments when they're dragged onto the app's icon or
+// opened with it, and from the command line as
+//
+//     reality send <file>... [--to <device>]
+//
+// where the device is a name or id. Either way they become a pending send
+// that waits for the UI to pick where they go, with the device named on
+// the command line picked already. Arguments given to a second launch are
+// forwarded here by the instance already running.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter};
+use uuid::Uuid;
+
+pub const PENDING: &str = "send://pending";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingSend {
+    pub id: String,
+    // Absolute paths of files and folders
+    pub paths: Vec<String>,
+    // The device named with --to, as given
+    pub to: Option<String>,
+}
+
+pub type PendingSends = Arc<Mutex<Vec<PendingSend>>>;
+
+// The send asked for by a launch's arguments, the first being the program.
+// Relative paths are taken from `cwd`; ones that don't exist are left out.
+pub fn parse(args: &[String], cwd: &Path) -> Option<PendingSend> {
+    let mut args = args.iter().skip(1).peekable();
+    if args.peek().is_some_and(|arg| *arg == "send") {
+        args.next();
+    }
+    let mut paths = Vec::new();
+    let mut to = None;
+    while let Some(arg) = args.next() {
+        if arg == "--to" {
+            to = args.next().cloned();
+        } else if let Some(device) = arg.strip_prefix("--to=") {
+            to = Some(device.to_string());
+        } else if !arg.starts_with('-') {
+            let path = cwd.join(arg);
+            if path.exists() {
+                paths.push(path.to_string_lossy().into_owned());
+            } else {
+                eprintln!("Not sending {}: no such file", arg);
+            }
+        }
+    }
+    pending(paths, to)
+}
+
+// The send for files opened with us through Finder, which come as an event
+// rather than as arguments
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn opened(paths: impl IntoIterator<Item = std::path::PathBuf>) -> Option<PendingSend> {
+    let paths = paths
+        .into_iter()
+        .filter(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    pending(paths, None)
+}
+
+fn pending(paths: Vec<String>, to: Option<String>) -> Option<PendingSend> {
+    if paths.is_empty() {
+        return None;
+    }
+    Some(PendingSend {
+        id: Uuid::new_v4().to_string(),
+        paths,
+        to,
+    })
+}
+
+// Queue a send for the UI and tell it there's one waiting
+pub fn add(app: &AppHandle, pending: &PendingSends, send: PendingSend) {
+    pending.lock().push(send.clone());
+    let _ = app.emit(PENDING, &send);
+}
@@ -0,0 +1,129 @@
+// Link quality to direct neighbours
+//
+// Every route exchange is preceded by a PING to the neighbour. Its PONG
+// gives the round trip time, and a PROBE_SIZE frame sent straight after
+// gives a rough throughput. Both are smoothed over time, and probes that
+// fail count towards the link's loss rate. A link's cost is roughly the
+// milliseconds it would take to move one chunk across it, so paths can be
+// compared whatever their hop count.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::net;
+use crate::transport::SecureChannel;
+use crate::{
+    read_header, signing, write_header, write_rejection, write_response, Device, PacketHeader, PeerContext, CHUNK_SIZE,
+    PACKET_PING, PACKET_PONG,
+};
+
+// Bytes sent to measure throughput
+const PROBE_SIZE: usize = 256 * 1024;
+
+// How long a neighbour has to answer a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Weight given to each new measurement
+const SMOOTHING: f64 = 0.3;
+
+// Cost of a link that hasn't been measured yet
+pub const DEFAULT_LINK_COST: f64 = 100.0;
+
+// Measurements for one neighbour, by device id
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkMetrics {
+    pub rtt_ms: f64,
+    pub throughput_bps: f64,
+    // Share of recent probes that failed, from 0 to 1
+    pub loss: f64,
+    pub probes: u32,
+}
+
+pub type Links = HashMap<String, LinkMetrics>;
+
+impl LinkMetrics {
+    fn record(&mut self, rtt_ms: f64, throughput_bps: f64) {
+        if self.probes == 0 {
+            self.rtt_ms = rtt_ms;
+            self.throughput_bps = throughput_bps;
+        } else {
+            self.rtt_ms += SMOOTHING * (rtt_ms - self.rtt_ms);
+            self.throughput_bps += SMOOTHING * (throughput_bps - self.throughput_bps);
+        }
+        self.loss *= 1.0 - SMOOTHING;
+        self.probes += 1;
+    }
+
+    fn record_failure(&mut self) {
+        self.loss += SMOOTHING * (1.0 - self.loss);
+        self.probes += 1;
+    }
+
+    // Milliseconds to get a chunk across, allowing for the retries losses
+    // would cost
+    pub fn cost(&self) -> f64 {
+        if self.probes == 0 || self.throughput_bps <= 0.0 {
+            return DEFAULT_LINK_COST;
+        }
+        let transfer_ms = CHUNK_SIZE as f64 * 1000.0 / self.throughput_bps;
+        (self.rtt_ms + transfer_ms) / (1.0 - self.loss).max(0.1)
+    }
+}
+
+// Cost of the link to a neighbour, by device id
+pub fn link_cost(links: &Links, id: &str) -> f64 {
+    links.get(id).map_or(DEFAULT_LINK_COST, LinkMetrics::cost)
+}
+
+// Measure the link to a neighbour and fold the result into its metrics
+pub fn probe_link(neighbour: &Device, ctx: &PeerContext) {
+    let result = measure(neighbour, ctx);
+    let mut links = ctx.links.lock();
+    let metrics = links.entry(neighbour.id.clone()).or_default();
+    match result {
+        Ok((rtt_ms, throughput_bps)) => metrics.record(rtt_ms, throughput_bps),
+        Err(e) => {
+            eprintln!("Probing the link to {} failed: {}", neighbour.name, e);
+            metrics.record_failure();
+        }
+    }
+}
+
+// Round trip time in milliseconds and throughput in bytes per second
+pub fn measure(neighbour: &Device, ctx: &PeerContext) -> std::io::Result<(f64, f64)> {
+    let mut channel = net::connect_device(neighbour, &ctx.identity_key)?;
+    channel.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let ping = PacketHeader {
+        packet_type: PACKET_PING.to_string(),
+        source: ctx.device_name(),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    write_header(&mut channel, &ping, &ctx.signing_key)?;
+    let response = read_header(&mut channel)?;
+    let rtt = started.elapsed();
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type != PACKET_PONG {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Neighbour doesn't answer pings"));
+    }
+
+    let started = Instant::now();
+    channel.send(&vec![0u8; PROBE_SIZE])?;
+    channel.recv()?;
+    let elapsed = started.elapsed().as_secs_f64().max(0.001);
+    Ok((rtt.as_secs_f64() * 1000.0, PROBE_SIZE as f64 / elapsed))
+}
+
+// Answer a PING, then take in the throughput probe that follows
+pub fn answer_ping(mut channel: SecureChannel, trusted: bool, ctx: PeerContext) -> std::io::Result<()> {
+    if ctx.settings.lock().accept_policy.decide(trusted) == crate::AcceptDecision::Reject {
+        return write_rejection(&mut channel, "Unknown device", &ctx);
+    }
+    write_response(&mut channel, PACKET_PONG, &ctx)?;
+    channel.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    channel.recv()?;
+    channel.send(&[1])
+}
@@ -2,15 +2,17 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 // use tauri::Manager;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use tauri::State;
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State};
 use uuid::Uuid;
-use mdns_sd::{ServiceDaemon, ServiceInfo, ServiceEvent};
+use mdns_sd::{DaemonStatus, ServiceDaemon, ServiceInfo, ServiceEvent};
 // use std::time::Duration;
 
 // Encryption imports
@@ -19,17 +21,138 @@ use chacha20poly1305::{
     ChaCha20Poly1305, Key, Nonce
 };
 use rand::RngCore;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use base64::Engine;
+use ed25519_dalek::SigningKey;
+
+mod beacon;
+mod ble;
+mod browser;
+mod cancel;
+mod codec;
+mod compression;
+mod delta;
+mod diagnostics;
+mod download_links;
+mod error;
+mod events;
+mod filters;
+mod friends;
+mod group;
+mod guest;
+mod hashing;
+mod heartbeat;
+mod history;
+mod hotspot;
+mod http;
+mod known;
+mod launch;
+mod links;
+mod manual;
+mod messages;
+mod net;
+mod notify;
+mod outbox;
+mod pairing;
+mod parallel;
+mod passthrough;
+mod pipeline;
+mod portmap;
+mod protocol;
+mod progress;
+mod quarantine;
+mod queue;
+mod quota;
+mod relay;
+mod resume;
+mod routing;
+mod screening;
+mod settings;
+mod sharing;
+mod signing;
+mod sync;
+mod tasks;
+mod throttle;
+mod topology;
+mod transport;
+mod tray;
+mod upload;
+mod versions;
+mod wan;
+mod webdav;
+use cancel::{CancelToken, CancelTokens};
+use error::Error;
+use pairing::{DeviceFingerprints, DevicePermissions, PairingSession, PeerFingerprint, TrustedDevice};
+use parallel::StreamJoins;
+use events::TransferUpdate;
+use filters::{FileRules, Filter};
+use hashing::{HashCache, HashedFiles};
+use history::{History, HistoryFilter, HistoryPage, Statistics, StatsRange};
+use hotspot::{Hotspot, JoinPayload};
+use known::KnownDevices;
+use links::Links;
+use messages::ChatMessage;
+use outbox::ScheduledSend;
+use portmap::{MappingState, PortMapping};
+use progress::{LiveProgress, LiveTransfers, ProgressTracker};
+use queue::{Direction, QueueEntry, TransferQueue};
+use quota::DailyUsage;
+use relay::HeldFile;
+use routing::{RelayStats, RoutingTable, SeenDiscoveries};
+use settings::{AcceptDecision, AcceptPolicy, HeartbeatPolicy, NotificationSettings, RetryPolicy, Settings};
+use sharing::{RemoteEntry, SharedItem};
+use signing::ReplayCache;
+use sync::{ConflictPolicy, Resolution, SyncMode, SyncPair, SyncStatus};
+use throttle::Throttle;
+use transport::SecureChannel;
 
 // Device information structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Device {
     id: String,
     name: String,
+    // The address it's listed and reached under, the first of `addresses`
     ip: String,
+    // Every address it announced, best first, tried in turn on connect
+    #[serde(default)]
+    addresses: Vec<String>,
     port: u16,
     status: String,
     device_type: String,
     last_seen: String,
+    public_key: String,
+    #[serde(default)]
+    signing_key: String,
+    verified: bool,
+    // Whether the device advertised shared files when it was discovered
+    #[serde(default)]
+    shares: bool,
+    // Whether it will hold files for devices that are offline
+    #[serde(default)]
+    relay: bool,
+    // Whether it bridges network segments, relaying between them
+    #[serde(default)]
+    gateway: bool,
+    // Added by address rather than discovered, and kept across restarts
+    #[serde(default)]
+    manual: bool,
+    // Unix seconds when it was last discovered or answered a heartbeat
+    #[serde(default)]
+    last_heard: i64,
+    // What the device announced about itself, empty for devices too old to
+    // announce it or added by address
+    #[serde(default)]
+    os: String,
+    #[serde(default)]
+    app_version: String,
+    #[serde(default)]
+    protocol_version: u32,
+    #[serde(default)]
+    fingerprint: String,
+    #[serde(default)]
+    capabilities: Vec<String>,
 }
 
 // File transfer info
@@ -43,26 +166,779 @@ struct FileTransfer {
     from_device: String,
     to_device: String,
     encrypted: bool,
+    // Set when the file was sent as part of a batch
+    #[serde(default)]
+    batch_id: Option<String>,
+    // Recent throughput and time remaining at that rate, and when bytes
+    // started moving
+    #[serde(default)]
+    speed_bps: u64,
+    #[serde(default)]
+    eta_seconds: Option<u64>,
+    #[serde(default)]
+    started_at: Option<String>,
+    // Bytes on the wire per byte of file, when compression or delta
+    // encoding was used
+    #[serde(default)]
+    compression_ratio: Option<f64>,
+    // Hash of the whole file, as announced by the sender
+    #[serde(default)]
+    file_hash: Option<String>,
+    // Set once the transfer has completed or failed
+    #[serde(default)]
+    finished_at: Option<String>,
+    // Where a received file was saved
+    #[serde(default)]
+    saved_path: Option<String>,
+    // Why a failed transfer failed
+    #[serde(default)]
+    error: Option<error::ErrorReport>,
+}
+
+// Files sent together over one connection; per-file progress lives in the
+// FileTransfer records listed here
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BatchTransfer {
+    id: String,
+    transfer_ids: Vec<String>,
+    file_count: u64,
+    total_size: u64,
+    from_device: String,
+    to_device: String,
+}
+
+// The same files sent to several devices at once, as one batch per device
+#[derive(Debug, Clone, Serialize)]
+struct Broadcast {
+    id: String,
+    file_count: u64,
+    total_size: u64,
+    targets: Vec<BroadcastTarget>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BroadcastTarget {
+    device_id: String,
+    device_name: String,
+    batch_id: String,
+    // Filled in when broadcasts are fetched, from the batch's transfers
+    status: String,
+}
+
+// The mDNS daemon while discovery runs, and the name we're registered
+// under, to take back when it stops
+struct Mdns {
+    daemon: ServiceDaemon,
+    fullname: String,
 }
 
 // App state
 struct AppState {
     devices: Arc<Mutex<HashMap<String, Device>>>,
     transfers: Arc<Mutex<Vec<FileTransfer>>>,
-    mdns_daemon: Arc<Mutex<Option<ServiceDaemon>>>,
+    live_progress: LiveTransfers,
+    // Hashes of files we've sent, for sending them again unchanged
+    hashed_files: HashedFiles,
+    // Who each received file came from, for delta transfers
+    received_from: delta::ReceivedFrom,
+    batches: Arc<Mutex<HashMap<String, BatchTransfer>>>,
+    broadcasts: Arc<Mutex<Vec<Broadcast>>>,
+    outbox: Arc<Mutex<Vec<ScheduledSend>>>,
+    // Folders kept in step with folders on other devices
+    sync_pairs: Arc<Mutex<Vec<SyncPair>>>,
+    mdns_daemon: Arc<Mutex<Option<Mdns>>>,
+    // UDP broadcast discovery running alongside mDNS
+    beacons: Arc<Mutex<Option<beacon::Beacons>>>,
+    // Every device that has identified itself, in sight or not
+    known_devices: Arc<Mutex<KnownDevices>>,
     device_id: String,
-    device_name: String,
-    server_port: u16,
-    encryption_key: [u8; 32],
+    // What we're called, which the user can change while we run
+    device_name: Arc<Mutex<String>>,
+    // Where the file server listens, once it does
+    server: Arc<Mutex<ServerStatus>>,
+    identity_key: StaticSecret,
+    signing_key: SigningKey,
+    trusted_devices: Arc<Mutex<HashMap<String, TrustedDevice>>>,
+    pending_pairings: Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>,
+    verified_keys: Arc<Mutex<HashSet<String>>>,
+    pending_approvals: Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>,
+    pending_unlocks: Arc<Mutex<HashMap<String, mpsc::Sender<UnlockAttempt>>>>,
+    settings: Arc<Mutex<Settings>>,
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    cancel_tokens: CancelTokens,
+    queue: Arc<TransferQueue>,
+    throttle: Arc<Throttle>,
+    stream_joins: StreamJoins,
+    daily_usage: Arc<Mutex<DailyUsage>>,
+    history: Arc<Mutex<History>>,
+    shares: Arc<Mutex<Vec<SharedItem>>>,
+    messages: Arc<Mutex<Vec<ChatMessage>>>,
+    // Files we asked peers for, by request id, with the identity of the
+    // peer that will send them
+    pending_pulls: Arc<Mutex<HashMap<String, PublicKey>>>,
+    // Files pulled for a sync, which go to the synced folder
+    sync_pulls: sync::Pulls,
+    // Folders under Downloads that paired devices' syncs made
+    synced_folders: sync::SyncedFolders,
+    // Received files held encrypted until the user releases them
+    quarantined: quarantine::Quarantine,
+    // The guest session running, if any
+    guest_session: guest::GuestSlot,
+    // Files other devices left with us to pass on
+    held_files: Arc<Mutex<Vec<HeldFile>>>,
+    // Files we left with a relay, by transfer id, with the relay's key
+    relayed: Arc<Mutex<HashMap<String, String>>>,
+    // How to reach devices we can't see, by device id
+    routes: Arc<Mutex<RoutingTable>>,
+    seen_discoveries: Arc<Mutex<SeenDiscoveries>>,
+    // Measured quality of the links to devices we see, by device id
+    links: Arc<Mutex<Links>>,
+    relay_stats: Arc<Mutex<RelayStats>>,
+    // The router's forwarding of our file server port, if any
+    port_mapping: Arc<Mutex<MappingState>>,
+    // The hotspot we're hosting for devices to join
+    hotspot: Arc<Mutex<Option<Hotspot>>>,
+    // Discovery over Bluetooth, and the devices it found that we share no
+    // network with yet
+    bluetooth: Arc<Mutex<Option<ble::Bluetooth>>>,
+    nearby: ble::Nearby,
+    // Links to files for browsers to download, by id
+    browser_shares: browser::BrowserShares,
+    // Links to files for anyone with a browser to download, by id
+    download_links: download_links::DownloadLinks,
+    // The read-only WebDAV export of the shares, and who may read it
+    webdav: Arc<Mutex<Option<webdav::DavServer>>>,
+    dav_credentials: Arc<Mutex<Vec<webdav::DavCredential>>>,
+    // The page browsers can upload files to us from, while it's open
+    upload_page: upload::UploadSlot,
+    // Budgets for the networking tasks, and the signal to stop them
+    tasks: tasks::Tasks,
+    // Files opened with us or named on the command line, waiting for the
+    // user to pick where they go
+    pending_outgoing: launch::PendingSends,
+}
+
+// Shared handles needed by connection threads
+#[derive(Clone)]
+struct PeerContext {
+    app: AppHandle,
+    devices: Arc<Mutex<HashMap<String, Device>>>,
+    transfers: Arc<Mutex<Vec<FileTransfer>>>,
+    live_progress: LiveTransfers,
+    hashed_files: HashedFiles,
+    // Who each received file came from, for delta transfers
+    received_from: delta::ReceivedFrom,
+    batches: Arc<Mutex<HashMap<String, BatchTransfer>>>,
+    trusted_devices: Arc<Mutex<HashMap<String, TrustedDevice>>>,
+    verified_keys: Arc<Mutex<HashSet<String>>>,
+    known_devices: Arc<Mutex<KnownDevices>>,
+    pending_pairings: Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>,
+    pending_approvals: Arc<Mutex<HashMap<String, mpsc::Sender<bool>>>>,
+    pending_unlocks: Arc<Mutex<HashMap<String, mpsc::Sender<UnlockAttempt>>>>,
+    settings: Arc<Mutex<Settings>>,
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    cancel_tokens: CancelTokens,
+    queue: Arc<TransferQueue>,
+    throttle: Arc<Throttle>,
+    stream_joins: StreamJoins,
+    daily_usage: Arc<Mutex<DailyUsage>>,
+    history: Arc<Mutex<History>>,
+    shares: Arc<Mutex<Vec<SharedItem>>>,
+    messages: Arc<Mutex<Vec<ChatMessage>>>,
+    // Files we asked peers for, by request id, with the identity of the
+    // peer that will send them
+    pending_pulls: Arc<Mutex<HashMap<String, PublicKey>>>,
+    // Files pulled for a sync, which go to the synced folder
+    sync_pulls: sync::Pulls,
+    // Folders under Downloads that paired devices' syncs made
+    synced_folders: sync::SyncedFolders,
+    quarantined: quarantine::Quarantine,
+    // Files other devices left with us to pass on
+    held_files: Arc<Mutex<Vec<HeldFile>>>,
+    // Files we left with a relay, by transfer id, with the relay's key
+    relayed: Arc<Mutex<HashMap<String, String>>>,
+    routes: Arc<Mutex<RoutingTable>>,
+    seen_discoveries: Arc<Mutex<SeenDiscoveries>>,
+    links: Arc<Mutex<Links>>,
+    relay_stats: Arc<Mutex<RelayStats>>,
+    hotspot: Arc<Mutex<Option<Hotspot>>>,
+    tasks: tasks::Tasks,
+    identity_key: StaticSecret,
+    signing_key: SigningKey,
+    device_id: String,
+    device_name: Arc<Mutex<String>>,
+    // Set on connections to a guest session, which go by its identity
+    guest: Option<Arc<guest::GuestSession>>,
+}
+
+// Incoming transfer awaiting the user's decision
+#[derive(Debug, Clone, Serialize)]
+struct TransferRequest {
+    transfer_id: String,
+    filename: String,
+    size: u64,
+    from_device: String,
+}
+
+// A peer asking for one of our shared files, awaiting the user's decision
+#[derive(Debug, Clone, Serialize)]
+struct FileRequest {
+    request_id: String,
+    path: String,
+    from_device: String,
+}
+
+// Password entered for a protected transfer, with a channel for the verdict
+type UnlockAttempt = (String, mpsc::Sender<bool>);
+
+impl AppState {
+    fn peer_context(&self, app: AppHandle) -> PeerContext {
+        PeerContext {
+            app,
+            devices: self.devices.clone(),
+            transfers: self.transfers.clone(),
+            live_progress: self.live_progress.clone(),
+            hashed_files: self.hashed_files.clone(),
+            received_from: self.received_from.clone(),
+            batches: self.batches.clone(),
+            trusted_devices: self.trusted_devices.clone(),
+            verified_keys: self.verified_keys.clone(),
+            known_devices: self.known_devices.clone(),
+            pending_pairings: self.pending_pairings.clone(),
+            pending_approvals: self.pending_approvals.clone(),
+            pending_unlocks: self.pending_unlocks.clone(),
+            settings: self.settings.clone(),
+            replay_cache: self.replay_cache.clone(),
+            cancel_tokens: self.cancel_tokens.clone(),
+            queue: self.queue.clone(),
+            throttle: self.throttle.clone(),
+            stream_joins: self.stream_joins.clone(),
+            daily_usage: self.daily_usage.clone(),
+            history: self.history.clone(),
+            shares: self.shares.clone(),
+            messages: self.messages.clone(),
+            pending_pulls: self.pending_pulls.clone(),
+            sync_pulls: self.sync_pulls.clone(),
+            synced_folders: self.synced_folders.clone(),
+            quarantined: self.quarantined.clone(),
+            held_files: self.held_files.clone(),
+            relayed: self.relayed.clone(),
+            routes: self.routes.clone(),
+            seen_discoveries: self.seen_discoveries.clone(),
+            links: self.links.clone(),
+            relay_stats: self.relay_stats.clone(),
+            hotspot: self.hotspot.clone(),
+            tasks: self.tasks.clone(),
+            identity_key: self.identity_key.clone(),
+            signing_key: self.signing_key.clone(),
+            device_id: self.device_id.clone(),
+            device_name: self.device_name.clone(),
+            guest: None,
+        }
+    }
+
+    // The context for a guest session, under its throwaway identity
+    fn guest_context(&self, app: AppHandle, session: Arc<guest::GuestSession>) -> PeerContext {
+        PeerContext {
+            identity_key: session.identity_key.clone(),
+            signing_key: session.signing_key.clone(),
+            device_id: session.device_id.clone(),
+            guest: Some(session),
+            ..self.peer_context(app)
+        }
+    }
+
+    fn device_name(&self) -> String {
+        self.device_name.lock().clone()
+    }
+
+    // The port peers reach us on, while the file server is listening
+    fn server_port(&self) -> Result<u16, Error> {
+        let server = self.server.lock();
+        if !server.running {
+            return Err(Error::Internal("The file server isn't listening".to_string()));
+        }
+        Ok(server.port)
+    }
+}
+
+impl PeerContext {
+    fn device_name(&self) -> String {
+        self.device_name.lock().clone()
+    }
+}
+
+// Context string binding derived file keys to this protocol
+const FILE_KEY_INFO: &[u8] = b"FileSharePro v1 file key";
+
+// Packet types carried in PacketHeader
+const PACKET_FILE_TRANSFER: &str = "FILE_TRANSFER";
+const PACKET_PAIR_REQUEST: &str = "PAIR_REQUEST";
+const PACKET_PAIR_RESPONSE: &str = "PAIR_RESPONSE";
+const PACKET_TRANSFER_ACCEPT: &str = "TRANSFER_ACCEPT";
+const PACKET_TRANSFER_REJECT: &str = "TRANSFER_REJECT";
+const PACKET_STREAM_JOIN: &str = "STREAM_JOIN";
+const PACKET_TRANSFER_RECEIPT: &str = "TRANSFER_RECEIPT";
+const PACKET_FILE_REQUEST: &str = "FILE_REQUEST";
+const PACKET_LIST_FILES: &str = "LIST_FILES";
+const PACKET_FILE_LIST: &str = "FILE_LIST";
+const PACKET_MESSAGE: &str = "MESSAGE";
+const PACKET_MESSAGE_RECEIVED: &str = "MESSAGE_RECEIVED";
+const PACKET_HAS_FILE: &str = "HAS_FILE";
+const PACKET_FILES_PRESENT: &str = "FILES_PRESENT";
+const PACKET_HOLD_FOR: &str = "HOLD_FOR";
+const PACKET_HELD_DELIVERY: &str = "HELD_DELIVERY";
+const PACKET_DELIVERY_RECEIPT: &str = "DELIVERY_RECEIPT";
+const PACKET_RECEIPT_RECEIVED: &str = "RECEIPT_RECEIVED";
+const PACKET_FORWARD: &str = "FORWARD";
+const PACKET_FORWARD_READY: &str = "FORWARD_READY";
+const PACKET_ROUTE_ADVERTISEMENT: &str = "ROUTE_ADVERTISEMENT";
+const PACKET_ROUTE_DISCOVERY: &str = "ROUTE_DISCOVERY";
+const PACKET_ROUTE_REPLY: &str = "ROUTE_REPLY";
+const PACKET_PING: &str = "PING";
+const PACKET_PONG: &str = "PONG";
+const PACKET_ROUTE_ERROR: &str = "ROUTE_ERROR";
+const PACKET_IDENTIFY: &str = "IDENTIFY";
+const PACKET_IDENTITY: &str = "IDENTITY";
+const PACKET_SYNC_DELETE: &str = "SYNC_DELETE";
+const PACKET_SYNC_DELETED: &str = "SYNC_DELETED";
+const PACKET_SYNC_LIST: &str = "SYNC_LIST";
+const PACKET_SYNC_FILES: &str = "SYNC_FILES";
+const PACKET_SYNC_FETCH: &str = "SYNC_FETCH";
+
+// Outcomes reported in a TRANSFER_RECEIPT once the receiver has checked
+// and saved the whole file
+const RECEIPT_VERIFIED: &str = "verified";
+const RECEIPT_HASH_MISMATCH: &str = "hash-mismatch";
+const RECEIPT_SAVE_FAILED: &str = "save-failed";
+// Further outcomes for files left with a relay: held until the recipient
+// is online, then passed on to a recipient that doesn't send receipts,
+// refused by it, or never collected
+const RECEIPT_HELD: &str = "held";
+const RECEIPT_DELIVERED: &str = "delivered";
+const RECEIPT_REJECTED: &str = "rejected";
+const RECEIPT_EXPIRED: &str = "expired";
+
+// Files are streamed as independently encrypted and hashed chunks, so
+// neither side holds more than one chunk in memory
+const CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CHUNK_RETRIES: u32 = 3;
+
+// Files smaller than this are always sent, rather than first asking
+// whether the receiver already has them
+const MIN_PRESENCE_CHECK_SIZE: u64 = CHUNK_SIZE as u64;
+// Most hashes answered in one HAS_FILE, since each may mean hashing a file
+const MAX_PRESENCE_CHECKS: usize = 1000;
+
+// Per-chunk replies from the receiver
+const CHUNK_ACK: u8 = 1;
+const CHUNK_NACK: u8 = 0;
+const CHUNK_ABORT: u8 = 2;
+
+// How we appear on our own end of a transfer record
+const THIS_DEVICE: &str = "This Device";
+
+// Longest name a user can give this device, in bytes
+const MAX_DEVICE_NAME: usize = 40;
+
+// Icons a device can announce itself with
+const DEVICE_ICONS: &[&str] = &["desktop", "laptop", "phone", "tablet", "mobile"];
+
+// How long an incoming transfer waits for the user before being rejected
+const APPROVAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+// How long a connecting peer has to get through the handshake and send its
+// header, and how long it may then go without sending anything
+const HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+
+// How long the file server or discovery waits before being started again
+// after failing, doubling up to MAX_RESTART_DELAY while it keeps failing
+const RESTART_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RESTART_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+// How often we check discovery is still running
+const DISCOVERY_CHECK: std::time::Duration = std::time::Duration::from_secs(10);
+// Failed accepts in a row after which a listener is bound again
+const MAX_ACCEPT_ERRORS: u32 = 10;
+
+// How long quitting waits for transfers under way to finish
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Header sent at the start of every connection, after the key exchange
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PacketHeader {
+    packet_type: String,
+    source: String,
+    #[serde(default)]
+    filename: String,
+    #[serde(default)]
+    file_size: u64,
+    // Ephemeral public key the file body was sealed with, for the destination
+    #[serde(default)]
+    file_key: String,
+    // Set for password-protected transfers: Argon2 salt and the content key,
+    // wrapped first with the password key and then with the file key
+    #[serde(default)]
+    password_salt: String,
+    #[serde(default)]
+    wrapped_key: String,
+    // BLAKE3 of the whole plaintext and of each CHUNK_SIZE chunk
+    #[serde(default)]
+    file_hash: String,
+    #[serde(default)]
+    chunk_hashes: Vec<String>,
+    // Set by the receiver when accepting: the first chunk it still needs
+    #[serde(default)]
+    resume_chunk: u64,
+    // Offered by the sender, and echoed back by the receiver to enable it
+    #[serde(default)]
+    compression: String,
+    // Sender supports delta encoding; the receiver answers with signatures
+    // of the copy it already has, if any
+    #[serde(default)]
+    delta: bool,
+    #[serde(default)]
+    delta_block_size: u64,
+    #[serde(default)]
+    delta_signatures: Vec<(u32, String)>,
+    // Streams offered by the sender, then the number the receiver agreed
+    // to along with the token extra streams join with. A STREAM_JOIN
+    // carries the token and its stream number, counting from 1.
+    #[serde(default)]
+    parallel_streams: u64,
+    #[serde(default)]
+    stream_token: String,
+    #[serde(default)]
+    stream_index: u64,
+    // Why the receiver refused, sent with TRANSFER_REJECT
+    #[serde(default)]
+    reason: String,
+    // Set in TRANSFER_ACCEPT by receivers that send a TRANSFER_RECEIPT
+    // after the last chunk, which carries one of the RECEIPT_ outcomes
+    #[serde(default)]
+    receipt: bool,
+    #[serde(default)]
+    result: String,
+    // A FILE_REQUEST names a shared path and the port the requester
+    // listens on; the file it asked for is then sent carrying the same
+    // request id
+    #[serde(default)]
+    request_id: String,
+    #[serde(default)]
+    request_path: String,
+    #[serde(default)]
+    reply_port: u16,
+    // A LIST_FILES asks for the listing of `request_path`, or of every
+    // share when it's empty; the FILE_LIST answer carries the entries
+    #[serde(default)]
+    entries: Vec<RemoteEntry>,
+    // Body of a MESSAGE
+    #[serde(default)]
+    text: String,
+    // Content hashes a HAS_FILE asks about; the FILES_PRESENT answer lists
+    // those the receiver already holds
+    #[serde(default)]
+    file_hashes: Vec<String>,
+    // Identity key of the device a HOLD_FOR is meant for. The sender's id
+    // for the file travels in `request_id`, and comes back in the
+    // DELIVERY_RECEIPT along with the outcome in `result` and `reason`.
+    #[serde(default)]
+    hold_for: String,
+    // Device id of whoever sent a FORWARD, the device it asks to be
+    // connected to, and how many more relays the connection may pass
+    // through. Names are only for display, since two devices can share one.
+    #[serde(default)]
+    source_id: String,
+    #[serde(default)]
+    forward_to: String,
+    #[serde(default)]
+    hop_limit: u32,
+    // Device ids a ROUTE_ADVERTISEMENT says its sender can reach, with the
+    // hops it takes; the answer carries the receiver's own
+    #[serde(default)]
+    routes: Vec<(String, u32)>,
+    // Cost of each advertised route, in the same order
+    #[serde(default)]
+    route_costs: Vec<f64>,
+    // A ROUTE_DISCOVERY looks for the device in `forward_to` under the id
+    // in `request_id`, on behalf of `origin_id`, which is `hops` away. The
+    // ROUTE_REPLY gives how many hops the destination is from its sender.
+    // `cost` is the cost of the path so far, or in a reply of the path on.
+    #[serde(default)]
+    origin_id: String,
+    #[serde(default)]
+    hops: u32,
+    #[serde(default)]
+    cost: f64,
+    // Names of the relays a forwarded connection went through, in order.
+    // Each relay adds itself in front as FORWARD_READY or ROUTE_ERROR
+    // passes back, so on an error the last one is where it failed.
+    #[serde(default)]
+    path: Vec<String>,
+    // Relays still to come on a route the sender pinned, by device id. A
+    // relay given these goes on to the first instead of choosing its own
+    // way, and with none left goes straight to the destination.
+    #[serde(default)]
+    via: Option<Vec<String>>,
+    // Path relative to Downloads, with '/' separators, for files sent as
+    // part of a folder
+    #[serde(default)]
+    relative_path: String,
+    // For files sent by a sync, the pair's folder under Downloads, which
+    // the receiver notes as the sender's to sync if the file makes it
+    #[serde(default)]
+    sync_folder: String,
+    // Position of this file in a batch sent over one connection, and the
+    // batch's total size
+    #[serde(default)]
+    batch_id: String,
+    #[serde(default)]
+    batch_index: u64,
+    #[serde(default)]
+    batch_count: u64,
+    #[serde(default)]
+    batch_size: u64,
+    #[serde(default)]
+    destination: String,
+    // Kind of device the sender is, shown as its icon, sent with IDENTITY
+    // along with its current name in `source`
+    #[serde(default)]
+    device_type: String,
+    // Token from a hotspot QR code, sent with a PAIR_REQUEST so the device
+    // that showed the code pairs without the codes being compared
+    #[serde(default)]
+    pairing_token: String,
+    // Unix seconds, Ed25519 public key and signature over the header
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    nonce: String,
+    #[serde(default)]
+    signing_key: String,
+    #[serde(default)]
+    signature: String,
+}
+
+impl PacketHeader {
+    // Name the file is saved and shown under
+    fn display_name(&self) -> &str {
+        if self.relative_path.is_empty() {
+            &self.filename
+        } else {
+            &self.relative_path
+        }
+    }
+}
+
+// Resolve a sender-supplied name under Downloads, refusing anything that
+// would escape it
+fn download_path_for(name: &str) -> Option<std::path::PathBuf> {
+    let mut path = dirs::download_dir()
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    let mut depth = 0;
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            part if part.contains(':') => return None,
+            part => {
+                path.push(part);
+                depth += 1;
+            }
+        }
+    }
+    (depth > 0).then_some(path)
+}
+
+// Load this installation's long-term identity key, creating it on first run
+fn load_or_create_identity() -> StaticSecret {
+    let path = app_data_dir().join("identity.key");
+    if let Ok(bytes) = std::fs::read(&path) {
+        if let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return StaticSecret::from(bytes);
+        }
+    }
+    
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let _ = std::fs::create_dir_all(app_data_dir());
+    if let Err(e) = std::fs::write(&path, secret.to_bytes()) {
+        eprintln!("Failed to persist identity key: {}", e);
+    }
+    secret
+}
+
+// Load this installation's device id, creating it on first run. Unlike the
+// hostname it never changes, so it's what other devices route to.
+fn load_or_create_device_id() -> String {
+    let path = app_data_dir().join("device_id");
+    if let Ok(id) = std::fs::read_to_string(&path) {
+        if Uuid::parse_str(id.trim()).is_ok() {
+            return id.trim().to_string();
+        }
+    }
+    
+    let id = Uuid::new_v4().to_string();
+    let _ = std::fs::create_dir_all(app_data_dir());
+    if let Err(e) = std::fs::write(&path, &id) {
+        eprintln!("Failed to persist device id: {}", e);
+    }
+    id
+}
+
+// Directory for persistent app data (identity, trusted devices)
+fn app_data_dir() -> std::path::PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| std::env::current_dir().unwrap())
+        .join("file-share-pro")
+}
+
+// Encode a public key for storage and display
+fn encode_public_key(key: &PublicKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+}
+
+// Decode a public key received from a peer or discovery record
+fn decode_public_key(encoded: &str) -> Option<PublicKey> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice()).ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+// Derive a file key sealed to the recipient's long-term identity key.
+// Returns the key and the ephemeral public key the recipient needs to
+// reproduce it; intermediate hops never learn the key.
+fn seal_file_key(recipient: &PublicKey) -> std::io::Result<([u8; 32], PublicKey)> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(recipient);
+    let key = derive_file_key(shared.as_bytes(), &ephemeral, recipient)?;
+    Ok((key, ephemeral))
+}
+
+// Recover a file key sealed to our identity key
+fn open_file_key(identity: &StaticSecret, ephemeral: &PublicKey) -> std::io::Result<[u8; 32]> {
+    let shared = identity.diffie_hellman(ephemeral);
+    if !shared.was_contributory() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid file key",
+        ));
+    }
+    derive_file_key(shared.as_bytes(), ephemeral, &PublicKey::from(identity))
+}
+
+fn derive_file_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> std::io::Result<[u8; 32]> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut key = [0u8; 32];
+    hkdf.expand(FILE_KEY_INFO, &mut key)
+        .map_err(|e| std::io::Error::other(format!("Key derivation error: {:?}", e)))?;
+    Ok(key)
+}
+
+// Derive a key from a transfer password with Argon2id
+fn derive_password_key(password: &str, salt: &[u8]) -> std::io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| std::io::Error::other(format!("Password key derivation error: {}", e)))?;
+    Ok(key)
+}
+
+// Wrap a content key so it needs both the recipient's identity and the password
+fn wrap_content_key(content_key: &[u8; 32], file_key: &[u8; 32], password_key: &[u8; 32]) -> std::io::Result<Vec<u8>> {
+    let inner = encrypt_data(content_key, password_key).map_err(std::io::Error::other)?;
+    encrypt_data(&inner, file_key).map_err(std::io::Error::other)
+}
+
+// Undo `wrap_content_key`; fails on a wrong password
+fn unwrap_content_key(wrapped: &[u8], file_key: &[u8; 32], password_key: &[u8; 32]) -> Result<[u8; 32], String> {
+    let inner = decrypt_data(wrapped, file_key)?;
+    let content_key = decrypt_data(&inner, password_key)?;
+    <[u8; 32]>::try_from(content_key.as_slice()).map_err(|_| "Invalid content key".to_string())
+}
+
+// Prompt the user for a transfer's password until it unwraps the content key
+fn prompt_for_password(
+    transfer_id: &str,
+    header: &PacketHeader,
+    file_key: &[u8; 32],
+    ctx: &PeerContext,
+) -> std::io::Result<Option<[u8; 32]>> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let invalid = |_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed password header");
+    let salt = engine.decode(&header.password_salt).map_err(invalid)?;
+    let wrapped = engine.decode(&header.wrapped_key).map_err(invalid)?;
+    
+    let (tx, rx) = mpsc::channel::<UnlockAttempt>();
+    ctx.pending_unlocks.lock().insert(transfer_id.to_string(), tx);
+    let _ = ctx.app.emit("transfer://password-required", TransferRequest {
+        transfer_id: transfer_id.to_string(),
+        filename: header.filename.clone(),
+        size: header.file_size,
+        from_device: header.source.clone(),
+    });
+    
+    let mut content_key = None;
+    for _ in 0..MAX_PASSWORD_ATTEMPTS {
+        let Ok((password, verdict)) = rx.recv_timeout(APPROVAL_TIMEOUT) else {
+            break;
+        };
+        let unwrapped = derive_password_key(&password, &salt)
+            .ok()
+            .and_then(|password_key| unwrap_content_key(&wrapped, file_key, &password_key).ok());
+        let _ = verdict.send(unwrapped.is_some());
+        if unwrapped.is_some() {
+            content_key = unwrapped;
+            break;
+        }
+    }
+    
+    ctx.pending_unlocks.lock().remove(transfer_id);
+    Ok(content_key)
+}
+
+// Sign and send a header over the channel, padded so its size
+// doesn't reveal the filename or packet type
+fn write_header(channel: &mut SecureChannel, header: &PacketHeader, signing_key: &SigningKey) -> std::io::Result<()> {
+    let mut header = header.clone();
+    signing::sign_header(&mut header, signing_key)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    let encoded = codec::encode_header(&header, channel.binary_headers())?;
+    channel.send_padded(&encoded)
+}
+
+// Pass on a header another device signed, as it signed it
+fn forward_header(channel: &mut SecureChannel, header: &PacketHeader) -> std::io::Result<()> {
+    let encoded = codec::encode_header(header, channel.binary_headers())?;
+    channel.send_padded(&encoded)
+}
+
+// Receive a header from the channel
+fn read_header(channel: &mut SecureChannel) -> std::io::Result<PacketHeader> {
+    let encoded = channel.recv_padded()?;
+    codec::decode_header(&encoded, channel.binary_headers())
 }
 
-// Generate encryption key (shared across all devices for simplicity)
-// In production, use proper key exchange protocol
-fn generate_encryption_key() -> [u8; 32] {
-    // For demo purposes, using a fixed key so all instances can communicate
-    // In production, implement proper key exchange (Diffie-Hellman, etc.)
-    let fixed_key = b"FileShareProSecureKey12345678!!8"; // Exactly 32 bytes
-    *fixed_key
+// Read up to CHUNK_SIZE bytes; a short chunk means end of file. The
+// buffer is filled in place, in as few reads as the OS allows.
+fn read_chunk(file: &mut std::fs::File, buffer: &mut Vec<u8>) -> std::io::Result<usize> {
+    buffer.resize(CHUNK_SIZE, 0);
+    let mut filled = 0;
+    while filled < CHUNK_SIZE {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buffer.truncate(filled);
+    Ok(filled)
 }
 
 // Encrypt data
@@ -85,6 +961,23 @@ fn encrypt_data(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
     Ok(result)
 }
 
+// Encrypt a chunk of a file body under a nonce made from its index, so
+// chunks can be sealed in any order and on any thread. Every transfer has
+// a body key of its own, which keeps the nonces unique. Opened with
+// `decrypt_data` like anything else.
+fn encrypt_chunk(data: &[u8], key: &[u8; 32], index: u64) -> std::io::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..].copy_from_slice(&index.to_be_bytes());
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), data)
+        .map_err(|e| std::io::Error::other(format!("Encryption error: {:?}", e)))?;
+    
+    let mut result = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
+    result.extend_from_slice(&nonce_bytes);
+    result.extend_from_slice(&ciphertext);
+    Ok(result)
+}
+
 // Decrypt data
 fn decrypt_data(encrypted_data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
     if encrypted_data.len() < 12 {
@@ -102,347 +995,5212 @@ fn decrypt_data(encrypted_data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String
         .map_err(|e| format!("Decryption error: {:?}", e))
 }
 
-// Initialize mDNS service discovery
+// Turn discovery, over mDNS with UDP beacons to fall back on, on or off,
+// and keep it that way on later launches
+#[tauri::command]
+fn set_discovery_enabled(enabled: bool, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    {
+        let mut settings = state.settings.lock();
+        settings.discovery_enabled = enabled;
+        settings::save_settings(&settings)?;
+    }
+    let running = state.mdns_daemon.lock().is_some();
+    match (enabled, running) {
+        (true, false) => announce(app, &state),
+        (false, true) => shut_down_discovery(&state),
+        _ => Ok(()),
+    }
+}
+
 #[tauri::command]
-async fn start_discovery(state: State<'_, AppState>) -> Result<String, String> {
-    let mdns = ServiceDaemon::new().map_err(|e| e.to_string())?;
+fn get_discovery_enabled(state: State<'_, AppState>) -> Result<bool, Error> {
+    Ok(state.settings.lock().discovery_enabled)
+}
+
+// Keep discovery running while it's enabled: start it at launch, and again
+// whenever the daemon has died or couldn't be started, waiting longer
+// between attempts while they keep failing
+fn run_discovery(app: AppHandle) {
+    let mut delay = RESTART_DELAY;
+    loop {
+        let state = app.state::<AppState>();
+        if state.tasks.is_shut_down() {
+            return;
+        }
+        let enabled = state.settings.lock().discovery_enabled;
+        if enabled && !discovery_alive(&state) {
+            let started = shut_down_discovery(&state).and_then(|_| announce(app.clone(), &state));
+            match started {
+                Ok(()) => delay = RESTART_DELAY,
+                Err(e) => {
+                    eprintln!("Failed to start discovery: {}", e);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RESTART_DELAY);
+                    continue;
+                }
+            }
+            // Turned off while we were starting it
+            if !state.settings.lock().discovery_enabled {
+                let _ = shut_down_discovery(&state);
+            }
+        }
+        thread::sleep(DISCOVERY_CHECK);
+    }
+}
+
+// Whether the mDNS daemon is there and still answering
+fn discovery_alive(state: &AppState) -> bool {
+    let daemon = state.mdns_daemon.lock();
+    let Some(mdns) = daemon.as_ref() else {
+        return false;
+    };
+    mdns.daemon
+        .status()
+        .ok()
+        .and_then(|status| status.recv_timeout(std::time::Duration::from_secs(1)).ok())
+        == Some(DaemonStatus::Running)
+}
+
+// Register ourselves over mDNS, browse for others and listen for beacons
+fn announce(app: AppHandle, state: &AppState) -> Result<(), Error> {
+    let mdns = ServiceDaemon::new()?;
     
     let service_type = "_fileshare._tcp.local.";
-    let local_ip = local_ip_address::local_ip()
-        .map_err(|e| e.to_string())?
-        .to_string();
+    let interfaces = net::local_interfaces();
+    
+    // Every interface's address, Wi-Fi, Ethernet and VPN alike, since
+    // which of them a peer can reach is only known from its side. They go
+    // in a TXT record too, as mDNS only passes on those of the interface a
+    // peer heard us on.
+    let gateway = state.settings.lock().gateway_mode;
+    let own_addresses = net::own_addresses(&interfaces);
+    if own_addresses.is_empty() {
+        return Err(Error::PeerOffline("No network address to announce".to_string()));
+    }
+    let host_addresses = own_addresses.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",");
+    let txt_addresses = net::txt_addresses(&own_addresses);
+    
+    // Advertise our identity so peers can encrypt files end-to-end to us,
+    let public_key = encode_public_key(&PublicKey::from(&state.identity_key));
+    let signing_key = signing::encode_verifying_key(&state.signing_key.verifying_key());
+    // whether there is anything to browse and whether we relay
+    let sharing = if state.shares.lock().is_empty() { "0" } else { "1" };
+    let relay_enabled = state.settings.lock().relay_enabled;
+    let relaying = if relay_enabled { "1" } else { "0" };
+    // and what we are and can do
+    let fingerprint = pairing::fingerprint(&PublicKey::from(&state.identity_key));
+    let protocol_version = protocol::PROTOCOL_VERSION.to_string();
+    let mut capabilities = protocol::CAPABILITIES;
+    if relay_enabled {
+        capabilities |= protocol::RELAY;
+    }
+    let capabilities = protocol::names(capabilities).join(",");
+    // and which network group we're in, if any
+    let group_tag = group::tag().unwrap_or_default();
+    // The name goes in a TXT record too, since instance names are cut
+    // short and host names can't hold every character a user types
+    let device_name = state.device_name();
+    let device_type = own_device_type(&state.settings.lock());
+    let properties = [
+        ("id", state.device_id.as_str()),
+        ("name", device_name.as_str()),
+        ("pk", public_key.as_str()),
+        ("sk", signing_key.as_str()),
+        ("shares", sharing),
+        ("relay", relaying),
+        ("gw", if gateway { "1" } else { "0" }),
+        ("type", device_type.as_str()),
+        ("os", std::env::consts::OS),
+        ("ver", env!("CARGO_PKG_VERSION")),
+        ("proto", protocol_version.as_str()),
+        ("fp", fingerprint.as_str()),
+        ("caps", capabilities.as_str()),
+        ("addrs", txt_addresses.as_str()),
+        ("grp", group_tag.as_str()),
+    ];
     
-    let service_name = format!("{}.{}", state.device_name, service_type);
+    // The instance name carries the group tag, so groups never share names
+    let service_name = format!("{}.{}", host_label(&device_name), service_type);
+    let port = state.server_port()?;
     let service_info = ServiceInfo::new(
         service_type,
-        &state.device_name,
+        &group::instance_name(&device_name),
         &service_name,
-        &local_ip,
-        state.server_port,
-        None,
-    ).map_err(|e| e.to_string())?;
+        host_addresses.as_str(),
+        port,
+        &properties[..],
+    )?;
     
-    mdns.register(service_info)
-        .map_err(|e| e.to_string())?;
+    let fullname = service_info.get_fullname().to_string();
+    mdns.register(service_info)?;
     
-    let receiver = mdns.browse(service_type)
-        .map_err(|e| e.to_string())?;
+    let receiver = mdns.browse(service_type)?;
     
-    let mut daemon = state.mdns_daemon.lock().unwrap();
-    *daemon = Some(mdns);
+    let mut daemon = state.mdns_daemon.lock();
+    *daemon = Some(Mdns { daemon: mdns, fullname });
     
-    let devices = state.devices.clone();
-    let own_name = state.device_name.clone();
+    // Beacons carry the same properties, for networks that drop multicast
+    let announcement = beacon::Announcement {
+        name: device_name.clone(),
+        port,
+        properties: properties.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        timestamp: 0,
+    };
+    let mdns_found = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let ctx = state.peer_context(app);
+    if let Some(previous) = state.beacons.lock().take() {
+        previous.stop();
+    }
+    match beacon::start(announcement, mdns_found.clone(), &ctx) {
+        Ok(beacons) => *state.beacons.lock() = Some(beacons),
+        Err(e) => eprintln!("Beacon discovery unavailable: {}", e),
+    }
     
-    thread::spawn(move || {
-        while let Ok(event) = receiver.recv() {
+    // Events are handled on the async runtime until the daemon is shut
+    // down or we are
+    let own_id = state.device_id.clone();
+    tauri::async_runtime::spawn(async move {
+        // Device ids by service name, to know which device a removal means
+        let mut service_ids: HashMap<String, String> = HashMap::new();
+        while let Some(Ok(event)) = ctx.tasks.until_shutdown(receiver.recv_async()).await {
             match event {
                 ServiceEvent::ServiceResolved(info) => {
                     let hostname = info.get_hostname().to_string();
                     
                     // Don't add ourselves to the device list
-                    if hostname.starts_with(&own_name) {
+                    if info.get_property_val_str("id") == Some(own_id.as_str()) {
                         continue;
                     }
+                    // Nor devices from other network groups
+                    let instance = info.get_fullname().trim_end_matches(service_type).trim_end_matches('.');
+                    if !group::is_our_instance(instance) {
+                        continue;
+                    }
+                    mdns_found.store(true, std::sync::atomic::Ordering::SeqCst);
                     
-                    let device = Device {
-                        id: Uuid::new_v4().to_string(),
-                        name: hostname.clone(),
-                        ip: info.get_addresses().iter().next()
-                            .map(|addr| addr.to_string())
-                            .unwrap_or_default(),
-                        port: info.get_port(),
-                        status: "Available".to_string(),
-                        device_type: "desktop".to_string(),
-                        last_seen: chrono::Local::now().format("%H:%M:%S").to_string(),
-                    };
-                    
-                    let mut devices = devices.lock().unwrap();
-                    devices.insert(device.id.clone(), device);
+                    let announced = info.get_addresses().iter().copied().collect();
+                    let property = |key: &str| info.get_property_val_str(key).map(str::to_string);
+                    let device = add_announced(&hostname, announced, info.get_port(), &property, &ctx);
+                    service_ids.insert(info.get_fullname().to_string(), device.id);
                 }
                 ServiceEvent::ServiceRemoved(_, fullname) => {
-                    let mut devices = devices.lock().unwrap();
-                    devices.retain(|_, d| d.name != fullname);
+                    // Routes to or through a device that has left are dead
+                    let mut devices = ctx.devices.lock();
+                    let mut lost: Vec<Device> = devices.values().filter(|d| d.name == fullname && !d.manual).cloned().collect();
+                    devices.retain(|_, d| d.name != fullname || d.manual);
+                    if let Some(id) = service_ids.remove(&fullname).filter(|id| devices.get(id).is_none_or(|d| !d.manual)) {
+                        lost.extend(devices.remove(&id));
+                        routing::forget_device(&mut ctx.routes.lock(), &id);
+                    }
+                    drop(devices);
+                    for device in &lost {
+                        events::emit_device_lost(&ctx.app, device);
+                    }
                 }
                 _ => {}
             }
         }
     });
     
-    Ok("Discovery started with encryption enabled 🔒".to_string())
+    Ok(())
 }
 
-// Get discovered devices
-#[tauri::command]
-fn get_devices(state: State<'_, AppState>) -> Result<Vec<Device>, String> {
-    let devices = state.devices.lock().unwrap();
-    Ok(devices.values().cloned().collect())
+// Announce ourselves afresh, if discovery is running, once the networks
+// we're on have changed
+fn restart_discovery(app: AppHandle, state: &AppState) -> Result<(), Error> {
+    if state.mdns_daemon.lock().is_none() {
+        return Ok(());
+    }
+    shut_down_discovery(state)?;
+    announce(app, state)
 }
 
-// Start file receiver server
-#[tauri::command]
-async fn start_file_server(state: State<'_, AppState>) -> Result<u16, String> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", state.server_port))
-        .map_err(|e| e.to_string())?;
+// Put a device that announced itself, over mDNS or a beacon, in the device
+// table, from the addresses it was heard on and the properties it
+// announced. Gives back the device as added.
+fn add_announced(
+    name: &str,
+    announced: Vec<std::net::IpAddr>,
+    port: u16,
+    property: &dyn Fn(&str) -> Option<String>,
+    ctx: &PeerContext,
+) -> Device {
+    let text = |key: &str| property(key).unwrap_or_default();
+    // Devices from before names were announced go by their host name
+    let name = property("name").filter(|name| !name.is_empty()).unwrap_or_else(|| name.to_string());
+    let public_key = text("pk");
+    // Paired devices were verified by comparing codes
+    let verified = !public_key.is_empty()
+        && (ctx.trusted_devices.lock().contains_key(&public_key)
+            || ctx.verified_keys.lock().contains(&public_key));
+    
+    // Interfaces come and go, so ours are looked at afresh
+    let interfaces = net::local_interfaces();
+    let addrs = text("addrs");
+    let announced: Vec<std::net::IpAddr> = announced.into_iter().chain(net::parse_txt_addresses(&addrs)).collect();
     
-    let port = listener.local_addr()
-        .map_err(|e| e.to_string())?
-        .port();
+    // Keyed by the id the device advertises, so seeing it again replaces
+    // the old entry. Devices that don't advertise one keep the id they
+    // were first given here.
+    let advertised_id = property("id").filter(|id| Uuid::parse_str(id).is_ok());
+    let identified = advertised_id.is_some() && !public_key.is_empty();
+    // Any other entries it already has, made before it was heard with its
+    // id or while it had several, are folded into that one.
+    let (id, merged) = {
+        let devices = ctx.devices.lock();
+        let earlier = same_device(&devices, &public_key, &name, &announced, &interfaces);
+        let id = advertised_id
+            .or_else(|| earlier.first().cloned())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let merged: Vec<String> = earlier.into_iter().filter(|earlier| *earlier != id).collect();
+        (id, merged)
+    };
     
-    let transfers = state.transfers.clone();
-    let encryption_key = state.encryption_key;
+    // The fingerprint is worked out from the key where there is one,
+    // rather than taken on trust
+    let fingerprint = decode_public_key(&public_key)
+        .map(|key| pairing::fingerprint(&key))
+        .unwrap_or_else(|| text("fp"));
     
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let transfers = transfers.clone();
-                    thread::spawn(move || {
-                        if let Err(e) = handle_incoming_file(stream, transfers, encryption_key) {
-                            eprintln!("Error handling file: {}", e);
-                        }
-                    });
-                }
-                Err(e) => eprintln!("Connection error: {}", e),
-            }
+    let mut device = Device {
+        id,
+        name,
+        ip: String::new(),
+        addresses: Vec::new(),
+        port,
+        status: "Available".to_string(),
+        device_type: property("type").unwrap_or_else(|| "desktop".to_string()),
+        last_seen: chrono::Local::now().format("%H:%M:%S").to_string(),
+        public_key,
+        signing_key: text("sk"),
+        verified,
+        shares: text("shares") == "1",
+        relay: text("relay") == "1",
+        gateway: text("gw") == "1",
+        manual: false,
+        last_heard: chrono::Utc::now().timestamp(),
+        os: text("os"),
+        app_version: text("ver"),
+        protocol_version: text("proto").parse().unwrap_or(0),
+        fingerprint,
+        capabilities: text("caps")
+            .split(',')
+            .filter(|capability| !capability.is_empty())
+            .map(str::to_string)
+            .collect(),
+    };
+    
+    let mut devices = ctx.devices.lock();
+    let folded: Vec<Device> = merged.iter().filter_map(|id| devices.remove(id)).collect();
+    let previous: Vec<String> = devices
+        .get(&device.id)
+        .into_iter()
+        .chain(&folded)
+        .flat_map(net::device_addresses)
+        .collect();
+    device.addresses = net::merge_addresses(announced.into_iter(), &previous, &interfaces);
+    device.ip = device.addresses.first().cloned().unwrap_or_default();
+    // A device added by hand stays that way once discovery finds it too
+    device.manual = devices.get(&device.id).is_some_and(|d| d.manual);
+    let before = devices.insert(device.id.clone(), device.clone());
+    drop(devices);
+    if !folded.is_empty() {
+        let mut routes = ctx.routes.lock();
+        for earlier in &folded {
+            routing::forget_device(&mut routes, &earlier.id);
         }
-    });
+    }
+    for earlier in &folded {
+        events::emit_device_lost(&ctx.app, earlier);
+    }
+    events::emit_device(&ctx.app, before.as_ref(), &device);
     
-    Ok(port)
+    // Only a device with a lasting id is worth remembering
+    if identified {
+        known::remember(&mut ctx.known_devices.lock(), &device);
+    }
+    device
 }
 
-// Handle incoming encrypted file transfer
-fn handle_incoming_file(
-    mut stream: TcpStream,
-    transfers: Arc<Mutex<Vec<FileTransfer>>>,
-    encryption_key: [u8; 32],
-) -> std::io::Result<()> {
-    // Read filename length
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
-    let filename_len = u32::from_be_bytes(len_buf) as usize;
-    
-    // Read filename
-    let mut filename_buf = vec![0u8; filename_len];
-    stream.read_exact(&mut filename_buf)?;
-    let filename = String::from_utf8_lossy(&filename_buf).to_string();
-    
-    // Read file size
-    let mut size_buf = [0u8; 8];
-    stream.read_exact(&mut size_buf)?;
-    let file_size = u64::from_be_bytes(size_buf);
-    
-    // Create transfer record
-    let transfer_id = Uuid::new_v4().to_string();
-    let transfer = FileTransfer {
-        id: transfer_id.clone(),
-        filename: filename.clone(),
-        size: file_size,
-        progress: 0,
-        status: "Receiving 🔒".to_string(),
-        from_device: "Remote".to_string(),
-        to_device: "This Device".to_string(),
-        encrypted: true,
+// Entries already in the device table for a device announcing itself: those
+// with its key, or for a device without one, those with its name heard on
+// one of the same addresses. Devices added by hand are left alone.
+fn same_device(
+    devices: &HashMap<String, Device>,
+    public_key: &str,
+    name: &str,
+    announced: &[std::net::IpAddr],
+    interfaces: &[if_addrs::Interface],
+) -> Vec<String> {
+    let announced = net::order_addresses(announced.iter().copied(), interfaces);
+    devices
+        .values()
+        .filter(|d| !d.manual)
+        .filter(|d| {
+            if public_key.is_empty() {
+                d.public_key.is_empty() && d.name == name && net::device_addresses(d).iter().any(|a| announced.contains(a))
+            } else {
+                d.public_key == public_key
+            }
+        })
+        .map(|d| d.id.clone())
+        .collect()
+}
+
+// What kind of device we announce ourselves as, which other devices
+// show as our icon
+fn own_device_type(settings: &Settings) -> String {
+    if !settings.device_icon.is_empty() {
+        return settings.device_icon.clone();
+    }
+    match std::env::consts::OS {
+        "android" | "ios" => "mobile",
+        _ => "desktop",
+    }
+    .to_string()
+}
+
+// Our name as an mDNS host label: letters, digits and hyphens only
+fn host_label(name: &str) -> String {
+    let label: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() { "reality".to_string() } else { label.to_string() }
+}
+
+// Keep a paired device's name up to date once it has renamed itself. Only
+// for a name it gave in an IDENTITY over a connection to its key, never
+// one it announced, which anyone could claim.
+fn rename_trusted(public_key: &str, name: &str, ctx: &PeerContext) {
+    let mut trusted = ctx.trusted_devices.lock();
+    if let Some(paired) = trusted.get_mut(public_key).filter(|d| d.name != name) {
+        paired.name = name.to_string();
+        if let Err(e) = pairing::save_trusted_devices(&trusted) {
+            eprintln!("Failed to save trusted devices: {}", e);
+        }
+    }
+}
+
+// Add a device mDNS can't find, by asking the one at `ip`:`port` who it is
+#[tauri::command]
+async fn add_device_manually(name: String, ip: String, port: u16, app: AppHandle, state: State<'_, AppState>) -> Result<Device, Error> {
+    // IPv6 link-local addresses need the interface, as in `fe80::1%3`
+    let ip = ip.trim().trim_start_matches('[').trim_end_matches(']').to_string();
+    let addr = net::socket_addr(&ip, port).ok_or_else(|| Error::InvalidInput(format!("Invalid address: {}", ip)))?;
+    let ip = net::peer_address(&addr);
+    let request = PacketHeader {
+        packet_type: PACKET_IDENTIFY.to_string(),
+        source: state.device_name(),
+        source_id: state.device_id.clone(),
+        ..Default::default()
     };
+    let (identity_key, signing_key) = (state.identity_key.clone(), state.signing_key.clone());
+    let endpoint = net::endpoint(&ip, port);
+    let (response, peer_identity, protocol_version) = state.tasks.run(tasks::Budget::Request, move || {
+        let mut channel = SecureChannel::connect(&addr.to_string(), &identity_key)
+            .map_err(|e| Error::PeerOffline(format!("Could not reach {}: {}", endpoint, e)))?;
+        write_header(&mut channel, &request, &signing_key)?;
+        let response = read_header(&mut channel)?;
+        signing::verify_header(&response, None)?;
+        Ok((response, *channel.peer_identity(), channel.protocol_version()))
+    }).await?;
+    if response.packet_type != PACKET_IDENTITY || Uuid::parse_str(&response.source_id).is_err() {
+        return Err(Error::Unsupported("Device did not identify itself".to_string()));
+    }
+    if response.source_id == state.device_id {
+        return Err(Error::InvalidInput("That's this device".to_string()));
+    }
     
+    let public_key = encode_public_key(&peer_identity);
+    let verified = state.trusted_devices.lock().contains_key(&public_key)
+        || state.verified_keys.lock().contains(&public_key);
     {
-        let mut transfers = transfers.lock().unwrap();
-        transfers.push(transfer.clone());
+        let mut trusted = state.trusted_devices.lock();
+        if let Some(paired) = trusted.get_mut(&public_key).filter(|d| d.device_id.is_empty()) {
+            paired.device_id = response.source_id.clone();
+            let _ = pairing::save_trusted_devices(&trusted);
+        }
     }
+    let name = name.trim();
+    let device = Device {
+        id: response.source_id.clone(),
+        name: if name.is_empty() { response.source.clone() } else { name.to_string() },
+        addresses: vec![ip.clone()],
+        ip,
+        port,
+        status: "Available".to_string(),
+        device_type: Some(response.device_type.clone())
+            .filter(|kind| !kind.is_empty())
+            .unwrap_or_else(|| "desktop".to_string()),
+        last_seen: chrono::Local::now().format("%H:%M:%S").to_string(),
+        public_key,
+        signing_key: response.signing_key.clone(),
+        verified,
+        shares: false,
+        relay: false,
+        gateway: false,
+        manual: true,
+        last_heard: chrono::Utc::now().timestamp(),
+        os: String::new(),
+        app_version: String::new(),
+        protocol_version,
+        fingerprint: pairing::fingerprint(&peer_identity),
+        capabilities: Vec::new(),
+    };
     
-    let download_path = dirs::download_dir()
-        .unwrap_or_else(|| std::env::current_dir().unwrap())
-        .join(&filename);
-    
-    // Receive encrypted file
-    let mut encrypted_data = Vec::new();
-    let mut buffer = [0u8; 8192];
-    let mut received = 0u64;
-    
-    while received < file_size {
-        let bytes_to_read = std::cmp::min(buffer.len() as u64, file_size - received) as usize;
-        let n = stream.read(&mut buffer[..bytes_to_read])?;
-        if n == 0 {
-            break;
+    let mut devices = state.devices.lock();
+    let before = devices.insert(device.id.clone(), device.clone());
+    let manual: Vec<Device> = devices.values().filter(|d| d.manual).cloned().collect();
+    manual::save_manual_devices(&manual)?;
+    drop(devices);
+    events::emit_device(&app, before.as_ref(), &device);
+    known::remember(&mut state.known_devices.lock(), &device);
+    Ok(device)
+}
+
+// Forget a device that was added by address
+#[tauri::command]
+fn remove_manual_device(id: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut devices = state.devices.lock();
+    if let Some(device) = devices.remove(&id) {
+        if !device.manual {
+            devices.insert(id, device);
+        } else {
+            routing::forget_device(&mut state.routes.lock(), &id);
+            events::emit_device_lost(&app, &device);
+        }
+    }
+    let manual: Vec<Device> = devices.values().filter(|d| d.manual).cloned().collect();
+    manual::save_manual_devices(&manual).map_err(Error::from)
+}
+
+// Get discovered devices, followed by the known ones out of sight
+#[tauri::command]
+fn get_devices(state: State<'_, AppState>) -> Result<Vec<Device>, Error> {
+    let devices = state.devices.lock();
+    let offline = known::offline(&state.known_devices.lock(), &devices);
+    Ok(devices.values().cloned().chain(offline).collect())
+}
+
+// Keep the file server up from launch until we quit. When a listener fails
+// the others are closed too and all are bound again, waiting longer between
+// attempts while they keep failing.
+async fn run_file_server(app: AppHandle) {
+    let state = app.state::<AppState>();
+    let mut delay = RESTART_DELAY;
+    loop {
+        let started = std::time::Instant::now();
+        let (first, count) = {
+            let settings = state.settings.lock();
+            (settings.server_port, settings.server_port_count)
+        };
+        // Listen on IPv6 and IPv4 alike
+        let bound = net::bind_port_range(first, count)
+            .and_then(|listeners| Ok((listeners[0].local_addr()?.port(), listeners)));
+        match bound {
+            Ok((port, listeners)) => {
+                let moved = {
+                    let mut server = state.server.lock();
+                    let moved = server.port != port;
+                    *server = ServerStatus { running: true, port, error: None };
+                    moved
+                };
+                println!("📡 File server listening on port {}", port);
+                // Peers only find us at the port we announce
+                if moved {
+                    if let Err(e) = restart_discovery(app.clone(), &state) {
+                        eprintln!("Failed to announce the new port: {}", e);
+                    }
+                }
+                start_port_mapping(&state, port);
+                let stop = state.tasks.child_token();
+                let served: Vec<_> = listeners
+                    .into_iter()
+                    .map(|listener| {
+                        let ctx = state.peer_context(app.clone());
+                        let stop = stop.clone();
+                        tauri::async_runtime::spawn(async move {
+                            let _stop_others = stop.clone().drop_guard();
+                            serve_connections(listener, ctx, &stop).await
+                        })
+                    })
+                    .collect();
+                let mut error = None;
+                for served in served {
+                    match served.await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error = Some(Error::from(e)),
+                        Err(e) => error = Some(Error::Internal(e.to_string())),
+                    }
+                }
+                if let Some(e) = &error {
+                    eprintln!("File server failed: {}", e);
+                }
+                let mut server = state.server.lock();
+                server.running = false;
+                server.error = error.map(|e| e.report());
+            }
+            Err(e) => {
+                eprintln!("Failed to start the file server: {}", e);
+                let mut server = state.server.lock();
+                server.running = false;
+                server.error = Some(Error::from(e).report());
+            }
         }
-        encrypted_data.extend_from_slice(&buffer[..n]);
-        received += n as u64;
         
-        // Update progress
-        let mut transfers = transfers.lock().unwrap();
-        if let Some(t) = transfers.iter_mut().find(|t| t.id == transfer_id) {
-            t.progress = received;
+        // One that ran a good while before failing is back straight away
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+        if state.tasks.until_shutdown(tokio::time::sleep(delay)).await.is_none() {
+            return;
         }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
     }
-    
-    // Decrypt file
-    match decrypt_data(&encrypted_data, &encryption_key) {
-        Ok(decrypted_data) => {
-            std::fs::write(&download_path, decrypted_data)?;
-            
-            // Update status
-            let mut transfers = transfers.lock().unwrap();
-            if let Some(t) = transfers.iter_mut().find(|t| t.id == transfer_id) {
-                t.status = "Completed ✅ (Decrypted)".to_string();
+}
+
+// Devices on other networks reach us through a port on the router, which
+// is moved along when the server comes back on another
+fn start_port_mapping(state: &AppState, port: u16) {
+    let mut mapping = state.port_mapping.lock();
+    if mapping.port == port {
+        return;
+    }
+    mapping.port = port;
+    if !mapping.running {
+        mapping.running = true;
+        let (remap, requests) = mpsc::channel();
+        mapping.remap = Some(remap);
+        let mapping = state.port_mapping.clone();
+        thread::spawn(move || run_port_mapping(mapping, requests));
+    } else if let Some(remap) = &mapping.remap {
+        let _ = remap.send(());
+    }
+}
+
+// Keep the router forwarding the server's port to us, renewing the mapping
+// before it runs out or as soon as `remap` asks
+fn run_port_mapping(state: Arc<Mutex<MappingState>>, remap: mpsc::Receiver<()>) {
+    loop {
+        // A mapping to a port we've stopped listening on is given back
+        let (port, stale) = {
+            let mut state = state.lock();
+            let port = state.port;
+            let stale = state.mapping.take_if(|mapping| mapping.internal_port != port);
+            (port, stale)
+        };
+        if let Some(stale) = stale {
+            if let Err(e) = portmap::unmap(&stale) {
+                eprintln!("Failed to remove port mapping: {}", e);
             }
         }
-        Err(e) => {
-            eprintln!("Decryption failed: {}", e);
-            let mut transfers = transfers.lock().unwrap();
-            if let Some(t) = transfers.iter_mut().find(|t| t.id == transfer_id) {
-                t.status = "Failed ❌ (Decryption Error)".to_string();
+        let wait = match portmap::map_port(port) {
+            Ok(mapping) => {
+                println!("🌍 Reachable from outside at {} via {}", mapping.external_address, mapping.method);
+                let wait = mapping.renew_after();
+                let mut state = state.lock();
+                state.mapping = Some(mapping);
+                state.error = None;
+                wait
+            }
+            Err(e) => {
+                let mut state = state.lock();
+                state.mapping = None;
+                state.error = Some(e);
+                portmap::RETRY_INTERVAL
             }
+        };
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = remap.recv_timeout(wait) {
+            thread::sleep(wait);
         }
     }
-    
-    Ok(())
 }
 
-// Send encrypted file to device
+// Whether the file server is listening, and on which port, or on which it
+// last did and why it stopped
+#[derive(Debug, Clone, Default, Serialize)]
+struct ServerStatus {
+    running: bool,
+    port: u16,
+    error: Option<error::ErrorReport>,
+}
+
 #[tauri::command]
-async fn send_file(
-    file_path: String,
-    target_ip: String,
-    target_port: u16,
-    state: State<'_, AppState>,
-) -> Result<String, String> {
-    let transfers = state.transfers.clone();
-    let encryption_key = state.encryption_key;
+fn get_server_status(state: State<'_, AppState>) -> Result<ServerStatus, Error> {
+    Ok(state.server.lock().clone())
+}
+
+// Ports for the file server to try, `count` of them from `first` on before
+// any free one, from the next time it's started
+#[tauri::command]
+fn set_server_ports(first: u16, count: u16, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.server_port = first;
+    settings.server_port_count = count;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// How devices can reach our file server: the addresses it has on the
+// networks we're on, and the one the router forwards, if it does
+#[derive(Debug, Clone, Serialize)]
+struct ReachabilityInfo {
+    port: u16,
+    local_addresses: Vec<String>,
+    mapping: Option<PortMapping>,
+    // Why no mapping could be made
+    mapping_error: Option<String>,
+}
+
+#[tauri::command]
+fn get_reachability_info(state: State<'_, AppState>) -> Result<ReachabilityInfo, Error> {
+    let local_addresses = net::own_addresses(&net::local_interfaces())
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect();
+    let port = state.server.lock().port;
+    let mapping = state.port_mapping.lock();
+    Ok(ReachabilityInfo {
+        port,
+        local_addresses,
+        mapping: mapping.mapping.clone(),
+        mapping_error: mapping.error.clone(),
+    })
+}
+
+// Accept connections made to a listener until `stop` is cancelled or we
+// shut down, handling each on the blocking pool. Room for a handshake is
+// waited for before a connection is accepted, so beyond MAX_HANDSHAKES
+// peers queue in the backlog, and a connection from an address that has
+// MAX_PER_PEER open already is dropped. Gives up on a listener that fails
+// MAX_ACCEPT_ERRORS times in a row.
+async fn serve_connections(listener: TcpListener, ctx: PeerContext, stop: &tokio_util::sync::CancellationToken) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let mut errors = 0;
+    while let Some(permit) = ctx.tasks.acquire(tasks::Budget::Handshake).await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stop.cancelled() => return Ok(()),
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => {
+                errors = 0;
+                accepted
+            }
+            Err(e) => {
+                errors += 1;
+                if errors >= MAX_ACCEPT_ERRORS {
+                    return Err(e);
+                }
+                eprintln!("Connection error: {}", e);
+                continue;
+            }
+        };
+        let Some(admission) = ctx.tasks.admit(addr.ip(), permit) else {
+            continue;
+        };
+        // Handlers do blocking reads, so the socket goes back to blocking
+        let stream = stream.into_std().and_then(|stream| {
+            stream.set_nonblocking(false)?;
+            Ok(stream)
+        });
+        match stream {
+            Ok(stream) => {
+                let ctx = ctx.clone();
+                tauri::async_runtime::spawn_blocking(move || {
+                    if let Err(e) = handle_incoming_packet(stream, ctx, Some(admission)) {
+                        eprintln!("Error handling packet: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Connection error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// Handle an incoming connection: key exchange, then dispatch on packet type.
+// A connection the server accepted comes with its admission, moved on from
+// the handshake budget once its header is in; one that reached us through
+// a relay or the rendezvous server runs within what carried it.
+fn handle_incoming_packet(stream: TcpStream, ctx: PeerContext, admission: Option<tasks::Admission>) -> std::io::Result<()> {
+    // Authenticate and encrypt the connection before anything else crosses
+    // the wire. The handshake and header have to be in within
+    // HEADER_TIMEOUT, and from then on the peer may go quiet for at most
+    // IDLE_TIMEOUT, except when we relay for it: a relayed connection sits
+    // idle while the device at the far end decides.
+    let deadline = std::time::Instant::now() + HEADER_TIMEOUT;
+    let mut channel = SecureChannel::accept(stream, &ctx.identity_key, deadline)?;
+    let header = read_header(&mut channel)?;
+    channel.clear_deadline()?;
+    if header.packet_type != PACKET_FORWARD {
+        channel.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    }
+    let _admission = match admission {
+        Some(mut admission) => {
+            let budget = if header.packet_type == PACKET_FORWARD { tasks::Budget::Relay } else { tasks::Budget::Connection };
+            if !admission.promote(budget) {
+                return Ok(());
+            }
+            Some(admission)
+        }
+        None => None,
+    };
+    
+    // Every packet must be signed, by the key recorded when the device was
+    // paired or let into the guest session if it was, and must not be a
+    // replay of one we've already seen
+    let sender_key = encode_public_key(channel.peer_identity());
+    let paired = match &ctx.guest {
+        Some(session) => session.admitted().filter(|device| device.public_key == sender_key),
+        None => ctx.trusted_devices.lock().get(&sender_key).cloned(),
+    };
+    if let Err(reason) = validate_header(&header, paired.as_ref(), &ctx) {
+        eprintln!("Rejected packet from {}: {}", header.source, reason);
+        if header.packet_type == PACKET_FILE_TRANSFER {
+            reject_incoming_file(&header, &reason, &ctx);
+            write_rejection(&mut channel, &reason, &ctx)?;
+        }
+        return Ok(());
+    }
+    
+    // A guest session answers the one device the user let in, and only for
+    // what guests may do, trusting it by what the session holds of it.
+    // Elsewhere a guest counts as any other device.
+    let paired = match &ctx.guest {
+        Some(session) => {
+            let admitted = guest::allows(&header.packet_type)
+                && guest::admit(session, channel.peer_identity(), &header.source, &header.signing_key, &ctx);
+            if !admitted {
+                return Ok(());
+            }
+            session.admitted()
+        }
+        None => paired,
+    };
+    
+    // What we don't know yet of a paired device is taken from its packets,
+    // which the handshake showed came from it: the signing key of a device
+    // trusted by its friend code, and its name unless the user gave one,
+    // from its first, and the id of a device paired before ids were
+    // recorded, or trusted by its code, from the first that carries one
+    let unknown = |device: &TrustedDevice| device.signing_key.is_empty() || device.device_id.is_empty();
+    if ctx.guest.is_none() && paired.as_ref().is_some_and(unknown) {
+        let mut trusted = ctx.trusted_devices.lock();
+        if let Some(device) = trusted.get_mut(&sender_key) {
+            let mut learned = false;
+            if device.signing_key.is_empty() {
+                device.signing_key = header.signing_key.clone();
+                if device.name == friends::UNNAMED && !header.source.trim().is_empty() {
+                    device.name = header.source.clone();
+                }
+                learned = true;
+            }
+            if device.device_id.is_empty() && Uuid::parse_str(&header.source_id).is_ok() {
+                device.device_id = header.source_id.clone();
+                learned = true;
+            }
+            if learned {
+                let _ = pairing::save_trusted_devices(&trusted);
+            }
+        }
+    }
+    
+    // A paired device may be kept from some of what pairing allows. Files
+    // we asked it for still come through.
+    let requested = !header.request_id.is_empty()
+        && (ctx.pending_pulls.lock().contains_key(&header.request_id)
+            || ctx.sync_pulls.lock().contains_key(&header.request_id));
+    let refused = paired.as_ref()
+        .filter(|_| !(header.packet_type == PACKET_FILE_TRANSFER && requested))
+        .and_then(|device| device.permissions.refuses(&header.packet_type));
+    if let Some(reason) = refused {
+        if header.packet_type == PACKET_FILE_TRANSFER {
+            reject_incoming_file(&header, reason, &ctx);
+        }
+        return write_rejection(&mut channel, reason, &ctx);
+    }
+    
+    match header.packet_type.as_str() {
+        PACKET_FILE_TRANSFER => {
+            // Consult the accept policy before anything else happens
+            let trusted = paired.is_some();
+            let mut decision = {
+                let settings = ctx.settings.lock();
+                if settings.receiving_paused {
+                    AcceptDecision::Reject
+                } else {
+                    settings.accept_policy.decide(trusted)
+                }
+            };
+            let mut header = header;
+            
+            // A file we asked for is taken without asking again, as long
+            // as it comes from the peer we asked
+            if !header.request_id.is_empty() {
+                let requested_from = ctx.pending_pulls.lock().remove(&header.request_id);
+                if requested_from.is_some_and(|peer| peer.as_bytes() == channel.peer_identity().as_bytes()) {
+                    decision = AcceptDecision::Accept;
+                }
+            }
+            
+            // The rest of a batch follows on the same connection
+            loop {
+                let more = header.batch_index + 1 < header.batch_count;
+                if !handle_incoming_file(&mut channel, &header, decision, &ctx)? || !more {
+                    return Ok(());
+                }
+                
+                // Approving the first file approves the whole batch
+                let next = read_header(&mut channel)?;
+                let same_batch = next.packet_type == PACKET_FILE_TRANSFER
+                    && next.batch_id == header.batch_id
+                    && next.signing_key == header.signing_key;
+                if !same_batch {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unexpected packet in batch"));
+                }
+                validate_header(&next, paired.as_ref(), &ctx)
+                    .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+                decision = AcceptDecision::Accept;
+                header = next;
+            }
+        }
+        PACKET_PAIR_REQUEST => {
+            write_response(&mut channel, PACKET_PAIR_RESPONSE, &ctx)?;
+            
+            let (pairing, decision) = pairing::begin_pairing(&channel, &header.source, &ctx);
+            // Whoever scanned our hotspot code was shown our keys in person.
+            // The token is spent by the first device to bring it, so a code
+            // seen over someone's shoulder pairs nobody else.
+            let scanned = !header.pairing_token.is_empty()
+                && ctx.hotspot.lock()
+                    .as_mut()
+                    .filter(|h| h.token == header.pairing_token)
+                    .map(|h| std::mem::take(&mut h.token))
+                    .is_some();
+            if scanned {
+                pairing::accept(&pairing, &ctx);
+            } else {
+                let _ = ctx.app.emit("pairing://request", &pairing);
+            }
+            pairing::finish_pairing(channel, header.signing_key, pairing, decision, ctx)
+        }
+        PACKET_FILE_REQUEST => {
+            let trusted = paired.is_some();
+            serve_file_request(channel, header, trusted, ctx)
+        }
+        PACKET_LIST_FILES => {
+            // Browsing doesn't prompt, but devices the policy refuses
+            // outright see nothing
+            let trusted = paired.is_some();
+            if ctx.settings.lock().accept_policy.decide(trusted) == AcceptDecision::Reject {
+                return write_rejection(&mut channel, "Unknown device", &ctx);
+            }
+            let listing = sharing::list(&ctx.shares.lock(), &header.request_path);
+            let Some(entries) = listing else {
+                return write_rejection(&mut channel, "Not shared", &ctx);
+            };
+            let response = PacketHeader {
+                packet_type: PACKET_FILE_LIST.to_string(),
+                source: ctx.device_name(),
+                entries,
+                ..Default::default()
+            };
+            write_header(&mut channel, &response, &ctx.signing_key)
+        }
+        PACKET_MESSAGE => {
+            let trusted = paired.is_some();
+            if ctx.settings.lock().accept_policy.decide(trusted) == AcceptDecision::Reject {
+                return write_rejection(&mut channel, "Unknown device", &ctx);
+            }
+            if header.text.len() > messages::MAX_MESSAGE_LEN {
+                return write_rejection(&mut channel, "Message too long", &ctx);
+            }
+            
+            let message = ChatMessage {
+                id: Uuid::new_v4().to_string(),
+                peer: sender_key,
+                peer_name: header.source,
+                from_me: false,
+                text: header.text,
+                sent_at: chrono::Local::now().to_rfc3339(),
+            };
+            messages::record(&mut ctx.messages.lock(), message.clone());
+            let _ = ctx.app.emit("message://received", &message);
+            write_response(&mut channel, PACKET_MESSAGE_RECEIVED, &ctx)
+        }
+        PACKET_HAS_FILE => {
+            // It reveals what we've received, so only paired devices are
+            // told
+            if paired.is_none() {
+                return write_rejection(&mut channel, "Unknown device", &ctx);
+            }
+            let asked: HashSet<&String> = header.file_hashes.iter().take(MAX_PRESENCE_CHECKS).collect();
+            let response = PacketHeader {
+                packet_type: PACKET_FILES_PRESENT.to_string(),
+                source: ctx.device_name(),
+                file_hashes: asked.into_iter().filter(|hash| holds_file(hash, &ctx)).cloned().collect(),
+                ..Default::default()
+            };
+            write_header(&mut channel, &response, &ctx.signing_key)
+        }
+        PACKET_SYNC_LIST => {
+            let trusted = paired.is_some();
+            sync::handle_list(&mut channel, &header, trusted, &ctx)
+        }
+        PACKET_SYNC_FETCH => {
+            let trusted = paired.is_some();
+            sync::handle_fetch(channel, header, trusted, ctx)
+        }
+        PACKET_SYNC_DELETE => {
+            let trusted = paired.is_some();
+            sync::handle_delete(&mut channel, &header, trusted, &ctx)
+        }
+        PACKET_HOLD_FOR => {
+            let trusted = paired.is_some();
+            hold_file(channel, header, trusted, sender_key, ctx)
+        }
+        PACKET_HELD_DELIVERY => receive_held(channel, &header, ctx),
+        PACKET_DELIVERY_RECEIPT => {
+            // Only the relay we left the file with can say how it went
+            let transfer_id = header.request_id.as_str();
+            let relayed_by = ctx.relayed.lock().get(transfer_id).cloned();
+            if relayed_by.as_deref() == Some(sender_key.as_str()) {
+                ctx.relayed.lock().remove(transfer_id);
+                match header.result.as_str() {
+                    RECEIPT_VERIFIED => complete_transfer(&ctx, transfer_id, "Completed ✅ (Delivered via relay & verified)"),
+                    RECEIPT_DELIVERED => complete_transfer(&ctx, transfer_id, "Completed ✅ (Delivered via relay)"),
+                    RECEIPT_REJECTED if header.reason.is_empty() => {
+                        fail_transfer(&ctx, transfer_id, "Rejected by recipient 🚫", Error::Rejected("Declined".to_string()))
+                    }
+                    RECEIPT_REJECTED => fail_transfer(
+                        &ctx,
+                        transfer_id,
+                        &format!("Rejected by recipient 🚫 ({})", header.reason),
+                        Error::Rejected(header.reason.clone()),
+                    ),
+                    RECEIPT_EXPIRED => fail_transfer(
+                        &ctx,
+                        transfer_id,
+                        "Failed ❌ (Never collected from relay)",
+                        Error::PeerOffline("Never collected from relay".to_string()),
+                    ),
+                    other => fail_receipt(&ctx, transfer_id, other),
+                }
+            }
+            write_response(&mut channel, PACKET_RECEIPT_RECEIVED, &ctx)
+        }
+        PACKET_FORWARD => {
+            let trusted = paired.is_some();
+            routing::relay_packet(channel, header, trusted, ctx)
+        }
+        PACKET_ROUTE_ADVERTISEMENT => {
+            let trusted = paired.is_some();
+            routing::answer_advertisement(channel, header, trusted, &sender_key, ctx)
+        }
+        PACKET_ROUTE_DISCOVERY => {
+            let trusted = paired.is_some();
+            routing::answer_discovery(channel, header, trusted, &sender_key, ctx)
+        }
+        PACKET_PING => {
+            let trusted = paired.is_some();
+            links::answer_ping(channel, trusted, ctx)
+        }
+        PACKET_IDENTIFY => {
+            // Nothing the handshake hasn't shown already, beyond our id
+            // and what the user calls us
+            let response = PacketHeader {
+                packet_type: PACKET_IDENTITY.to_string(),
+                source: ctx.device_name(),
+                source_id: ctx.device_id.clone(),
+                device_type: own_device_type(&ctx.settings.lock()),
+                ..Default::default()
+            };
+            write_header(&mut channel, &response, &ctx.signing_key)
+        }
+        PACKET_STREAM_JOIN => {
+            parallel::deliver(&ctx.stream_joins, &header.stream_token, header.stream_index, &header.signing_key, channel)
+                .map_err(|reason| std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason))
+        }
+        other => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown packet type: {}", other),
+        )),
+    }
+}
+
+// List an incoming file we turned away before it got anywhere
+fn reject_incoming_file(header: &PacketHeader, reason: &str, ctx: &PeerContext) {
+    let transfer = FileTransfer {
+        id: Uuid::new_v4().to_string(),
+        filename: header.filename.clone(),
+        size: header.file_size,
+        progress: 0,
+        status: format!("Rejected 🚫 ({})", reason),
+        from_device: header.source.clone(),
+        to_device: THIS_DEVICE.to_string(),
+        encrypted: true,
+        batch_id: None,
+        speed_bps: 0,
+        eta_seconds: None,
+        started_at: None,
+        compression_ratio: None,
+        file_hash: Some(header.file_hash.clone()),
+        finished_at: None,
+        saved_path: None,
+        error: None,
+    };
+    let (id, status) = (transfer.id.clone(), transfer.status.clone());
+    ctx.transfers.lock().push(transfer);
+    fail_transfer(ctx, &id, &status, Error::Rejected(reason.to_string()));
+}
+
+// Whether a file with this content was received before and is still
+// where it was saved, unchanged. Only hashes already worked out are
+// trusted, so a question never has us read a file.
+fn holds_file(file_hash: &str, ctx: &PeerContext) -> bool {
+    let copies = ctx.history.lock().received_copies(file_hash).unwrap_or_default();
+    copies.iter().any(|path| {
+        hashing::cached(&ctx.hashed_files, std::path::Path::new(path)).is_some_and(|hashes| hashes.file_hash == file_hash)
+    })
+}
+
+// Keep a file a paired device can't deliver itself, to pass on once its
+// recipient is online
+fn hold_file(
+    mut channel: SecureChannel,
+    header: PacketHeader,
+    trusted: bool,
+    sender_key: String,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    let (enabled, quota) = {
+        let settings = ctx.settings.lock();
+        (settings.relay_enabled, settings.relay_quota)
+    };
+    if !enabled {
+        return write_rejection(&mut channel, "Not a relay", &ctx);
+    }
+    if !trusted {
+        return write_rejection(&mut channel, "Unknown device", &ctx);
+    }
+    if decode_public_key(&header.hold_for).is_none() {
+        return write_rejection(&mut channel, "Missing recipient", &ctx);
+    }
+    let total_chunks = header.chunk_hashes.len() as u64;
+    let needed = header.file_size + total_chunks * relay::FRAME_OVERHEAD;
+    if let Err(reason) = relay::check_space(needed, quota) {
+        return write_rejection(&mut channel, &reason, &ctx);
+    }
+    
+    let recipient_name = ctx.trusted_devices.lock()
+        .get(&header.hold_for)
+        .map_or_else(|| "Unknown device".to_string(), |d| d.name.clone());
+    let held = HeldFile {
+        id: Uuid::new_v4().to_string(),
+        hold_id: header.request_id.clone(),
+        sender: sender_key,
+        sender_name: header.source.clone(),
+        recipient: header.hold_for.clone(),
+        recipient_name,
+        filename: header.display_name().to_string(),
+        size: header.file_size,
+        received_at: chrono::Utc::now().timestamp(),
+        outcome: None,
+        reason: String::new(),
+        busy: false,
+    };
+    
+    // Compression stays as the sender applied it, for the recipient to undo
+    let compressed = header.compression == compression::ZSTD;
+    let accept = PacketHeader {
+        packet_type: PACKET_TRANSFER_ACCEPT.to_string(),
+        source: ctx.device_name(),
+        compression: if compressed { compression::ZSTD.to_string() } else { String::new() },
+        receipt: true,
+        ..Default::default()
+    };
+    write_header(&mut channel, &accept, &ctx.signing_key)?;
+    
+    // We can't open the chunks to check them, so they're stored as they
+    // come and the recipient checks them on delivery
+    let stored = relay::create_spool(&held.id).and_then(|mut spool| {
+        for _ in 0..total_chunks {
+            let frame = channel.recv()?;
+            relay::write_frame(&mut spool, &frame)?;
+            channel.send(&[CHUNK_ACK])?;
+        }
+        Ok(())
+    });
+    if let Err(e) = stored {
+        relay::remove(&held.id);
+        return Err(e);
+    }
+    
+    // The sender's header is kept as it signed it, for the recipient to
+    // check on delivery
+    relay::save_header(&held.id, &header)?;
+    relay::save_held(&held)?;
+    ctx.held_files.lock().push(held.clone());
+    let _ = ctx.app.emit("relay://held", &held);
+    println!("📦 Holding {} for {}", held.filename, held.recipient_name);
+    write_receipt(&mut channel, RECEIPT_HELD, &ctx)
+}
+
+// Take a file a relay held for us. The sender's header follows the
+// relay's as the sender signed it, and it's the sender, not the relay,
+// that has to be paired for the file to count as from a paired device.
+fn receive_held(mut channel: SecureChannel, relay: &PacketHeader, ctx: PeerContext) -> std::io::Result<()> {
+    let held = read_header(&mut channel)?;
+    let sender = ctx.trusted_devices.lock()
+        .values()
+        .find(|device| !device.signing_key.is_empty() && device.signing_key == held.signing_key)
+        .cloned();
+    let ours = encode_public_key(&PublicKey::from(&ctx.identity_key));
+    let checked = signing::verify_header(&held, sender.as_ref()).and_then(|_| {
+        if held.packet_type == PACKET_HOLD_FOR && held.hold_for == ours {
+            Ok(())
+        } else {
+            Err("Not held for this device".to_string())
+        }
+    });
+    let refused = checked.err().or_else(|| {
+        sender.as_ref()
+            .and_then(|device| device.permissions.refuses(PACKET_FILE_TRANSFER))
+            .map(str::to_string)
+    });
+    if let Some(reason) = refused {
+        eprintln!("Rejected held file from {} via {}: {}", held.source, relay.source, reason);
+        reject_incoming_file(&held, &reason, &ctx);
+        return write_rejection(&mut channel, &reason, &ctx);
+    }
+    
+    // Taken as a plain transfer, without resuming from the sender's side,
+    // delta encoding or extra streams
+    let header = PacketHeader {
+        packet_type: PACKET_FILE_TRANSFER.to_string(),
+        source: format!("{} (via {})", held.source, relay.source),
+        hold_for: String::new(),
+        request_id: String::new(),
+        sync_folder: String::new(),
+        delta: false,
+        parallel_streams: 0,
+        batch_id: String::new(),
+        batch_index: 0,
+        batch_count: 1,
+        batch_size: held.file_size,
+        ..held
+    };
+    let decision = {
+        let settings = ctx.settings.lock();
+        if settings.receiving_paused {
+            AcceptDecision::Reject
+        } else {
+            settings.accept_policy.decide(sender.is_some())
+        }
+    };
+    handle_incoming_file(&mut channel, &header, decision, &ctx).map(|_| ())
+}
+
+// Pass a held file on to its recipient. Gives the outcome to report to the
+// sender, or an error if delivery should be tried again later.
+fn deliver_held(held: &HeldFile, device: &Device, ctx: &PeerContext) -> std::io::Result<(String, String)> {
+    let header = relay::load_header(&held.id)?;
+    
+    // Whoever is at the device's address has to be the device the file is
+    // sealed to
+    let mut channel = net::connect_device(device, &ctx.identity_key)?;
+    if encode_public_key(channel.peer_identity()) != held.recipient {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Recipient identity mismatch"));
+    }
+    // Our header only says we're passing a file on; the sender's follows
+    // untouched, for the recipient to check against the sender's key
+    let annotation = PacketHeader {
+        packet_type: PACKET_HELD_DELIVERY.to_string(),
+        source: ctx.device_name(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &annotation, &ctx.signing_key)?;
+    forward_header(&mut channel, &header)?;
+    
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type != PACKET_TRANSFER_ACCEPT {
+        return Ok((RECEIPT_REJECTED.to_string(), response.reason));
+    }
+    
+    let mut spool = relay::open_spool(&held.id)?;
+    for index in 0..header.chunk_hashes.len() as u64 {
+        let frame = relay::read_frame(&mut spool)?;
+        if index < response.resume_chunk {
+            continue;
+        }
+        // A resend is the same frame again; one damaged in the spool keeps
+        // failing until the recipient gives up on it
+        loop {
+            channel.send(&frame)?;
+            match channel.recv()?.first().copied().unwrap_or(CHUNK_ABORT) {
+                CHUNK_ACK => break,
+                CHUNK_NACK => continue,
+                _ => return Ok((RECEIPT_HASH_MISMATCH.to_string(), String::new())),
+            }
+        }
+    }
+    
+    if !response.receipt {
+        return Ok((RECEIPT_DELIVERED.to_string(), String::new()));
+    }
+    let receipt = read_header(&mut channel)?;
+    if receipt.packet_type != PACKET_TRANSFER_RECEIPT {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Expected a transfer receipt"));
+    }
+    Ok((receipt.result, String::new()))
+}
+
+// Tell the sender of a held file how its delivery went
+fn send_delivery_receipt(held: &HeldFile, device: &Device, ctx: &PeerContext) -> std::io::Result<()> {
+    let mut channel = net::connect_device(device, &ctx.identity_key)?;
+    if encode_public_key(channel.peer_identity()) != held.sender {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Sender identity mismatch"));
+    }
+    let receipt = PacketHeader {
+        packet_type: PACKET_DELIVERY_RECEIPT.to_string(),
+        source: ctx.device_name(),
+        request_id: held.hold_id.clone(),
+        result: held.outcome.clone().unwrap_or_default(),
+        reason: held.reason.clone(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &receipt, &ctx.signing_key)?;
+    
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type != PACKET_RECEIPT_RECEIVED {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Receipt not acknowledged"));
+    }
+    Ok(())
+}
+
+// Answer a peer asking for one of our shared files. If we agree, the file
+// is sent back to it as an ordinary transfer.
+fn serve_file_request(mut channel: SecureChannel, header: PacketHeader, trusted: bool, ctx: PeerContext) -> std::io::Result<()> {
+    let shared = sharing::resolve(&ctx.shares.lock(), &header.request_path);
+    let Some(path) = shared else {
+        return write_rejection(&mut channel, "Not shared", &ctx);
+    };
+    
+    // The accept policy decides who is served without asking
+    let decision = ctx.settings.lock().accept_policy.decide(trusted);
+    let approved = match decision {
+        AcceptDecision::Accept => true,
+        AcceptDecision::Reject => false,
+        AcceptDecision::Ask => {
+            let (tx, rx) = mpsc::channel();
+            ctx.pending_approvals.lock().insert(header.request_id.clone(), tx);
+            let _ = ctx.app.emit("file-request://incoming", FileRequest {
+                request_id: header.request_id.clone(),
+                path: header.request_path.clone(),
+                from_device: header.source.clone(),
+            });
+            notify::file_request(&ctx.app, &header.request_path, &header.source);
+            let approved = rx.recv_timeout(APPROVAL_TIMEOUT).unwrap_or(false);
+            ctx.pending_approvals.lock().remove(&header.request_id);
+            approved
+        }
+    };
+    if !approved {
+        return write_rejection(&mut channel, "Declined", &ctx);
+    }
+    write_response(&mut channel, PACKET_TRANSFER_ACCEPT, &ctx)?;
+    
+    let destination = reply_destination(&channel, header.reply_port)?;
+    drop(channel);
+    send_reply(path, header.request_id, destination, ctx);
+    Ok(())
+}
+
+// Send a file a peer asked for back to it, as a send of our own rather
+// than on the connection it asked over, which is done with
+fn send_reply(path: std::path::PathBuf, request_id: String, destination: Destination, ctx: PeerContext) {
+    ctx.tasks.clone().spawn(tasks::Budget::Send, move || {
+        let result = queue_outgoing(path.to_string_lossy().into_owned(), None, None, &destination, &ctx).and_then(|mut file| {
+            file.request_id = Some(request_id);
+            send_file_internal(vec![file], destination, ctx)
+        });
+        if let Err(e) = result {
+            eprintln!("Error sending requested file: {}", e);
+        }
+    });
+}
+
+// Check a header's signature and reject replays
+fn validate_header(header: &PacketHeader, paired: Option<&TrustedDevice>, ctx: &PeerContext) -> Result<(), String> {
+    let replay_window = ctx.settings.lock().replay_window_secs;
+    signing::verify_header(header, paired)
+        .and_then(|_| ctx.replay_cache.lock().check_and_record(header, replay_window))
+}
+
+// Update the status of a transfer record
+fn set_transfer_status(ctx: &PeerContext, transfer_id: &str, status: &str) {
+    let update = {
+        let mut transfers = ctx.transfers.lock();
+        transfers.iter_mut().find(|t| t.id == transfer_id).map(|t| {
+            t.status = status.to_string();
+            TransferUpdate::from(&*t)
+        })
+    };
+    if let Some(update) = update {
+        events::emit_update(&ctx.app, update);
+    }
+}
+
+// Give a transfer its final status and announce the outcome under `event`
+fn finish_transfer(ctx: &PeerContext, transfer_id: &str, status: &str, event: &str, error: Option<Error>) {
+    let keep = ctx.settings.lock().max_finished_transfers;
+    let live = ctx.live_progress.write().remove(transfer_id);
+    let (transfer, pruned) = {
+        let mut transfers = ctx.transfers.lock();
+        let transfer = transfers.iter_mut().find(|t| t.id == transfer_id).map(|t| {
+            if let Some(live) = &live {
+                apply_live_progress(t, live);
+            }
+            t.status = status.to_string();
+            t.speed_bps = 0;
+            t.eta_seconds = None;
+            t.finished_at = Some(chrono::Local::now().to_rfc3339());
+            t.error = error.as_ref().map(Error::report);
+            t.clone()
+        });
+        (transfer, prune_transfers(&mut transfers, keep))
+    };
+    events::emit_removed(&ctx.app, &pruned);
+    if let Some(transfer) = transfer {
+        let outcome = if event == events::COMPLETED { "completed" } else { "failed" };
+        let peer = transfer_peer(&transfer, ctx);
+        if let Some(session) = &ctx.guest {
+            guest::keep_off_record(session, transfer_id, &ctx.app);
+        } else if let Err(e) = ctx.history.lock().record(&transfer, outcome, &peer) {
+            eprintln!("Failed to save transfer history: {}", e);
+        }
+        events::emit_record(&ctx.app, event, &transfer);
+        notify::finished(&ctx.app, event, &transfer);
+        tray::refresh(&ctx.app);
+    }
+}
+
+// The device on the other end of a transfer, by name. Files we send are
+// recorded by the address they went to.
+fn transfer_peer(transfer: &FileTransfer, ctx: &PeerContext) -> String {
+    if transfer.from_device != THIS_DEVICE {
+        return transfer.from_device.clone();
+    }
+    ctx.devices.lock()
+        .values()
+        .find(|d| d.ip == transfer.to_device || d.addresses.contains(&transfer.to_device))
+        .map_or_else(|| transfer.to_device.clone(), |d| d.name.clone())
+}
+
+// Whether a finished transfer failed, and so stays listed until dismissed
+fn transfer_failed(transfer: &FileTransfer) -> bool {
+    transfer.finished_at.is_some() && transfer.error.is_some()
+}
+
+// Drop the oldest completed records past the `keep` most recent, giving
+// back their ids. They're still in the history.
+fn prune_transfers(transfers: &mut Vec<FileTransfer>, keep: usize) -> Vec<String> {
+    let completed = |t: &FileTransfer| t.finished_at.is_some() && !transfer_failed(t);
+    let mut excess = transfers.iter().filter(|t| completed(t)).count().saturating_sub(keep);
+    let mut pruned = Vec::new();
+    transfers.retain(|t| {
+        if excess > 0 && completed(t) {
+            excess -= 1;
+            pruned.push(t.id.clone());
+            return false;
+        }
+        true
+    });
+    pruned
+}
+
+fn complete_transfer(ctx: &PeerContext, transfer_id: &str, status: &str) {
+    finish_transfer(ctx, transfer_id, status, events::COMPLETED, None);
+}
+
+fn fail_transfer(ctx: &PeerContext, transfer_id: &str, status: &str, error: Error) {
+    finish_transfer(ctx, transfer_id, status, events::FAILED, Some(error));
+}
+
+// Mark a sent file failed because of the receipt the recipient sent back
+fn fail_receipt(ctx: &PeerContext, transfer_id: &str, receipt: &str) {
+    match receipt {
+        RECEIPT_HASH_MISMATCH => fail_transfer(
+            ctx,
+            transfer_id,
+            "Failed ❌ (Corrupted on arrival)",
+            Error::Corrupted("Corrupted on arrival".to_string()),
+        ),
+        RECEIPT_SAVE_FAILED => fail_transfer(
+            ctx,
+            transfer_id,
+            "Failed ❌ (Recipient could not save file)",
+            Error::Internal("Recipient could not save file".to_string()),
+        ),
+        other => fail_transfer(
+            ctx,
+            transfer_id,
+            &format!("Failed ❌ (Recipient reported {})", other),
+            Error::Internal(format!("Recipient reported {}", other)),
+        ),
+    }
+}
+
+// Note when a transfer's bytes start moving and begin measuring its speed
+fn start_progress(ctx: &PeerContext, transfer_id: &str, progress: u64) -> ProgressTracker {
+    let (update, size) = {
+        let mut transfers = ctx.transfers.lock();
+        let update = transfers.iter_mut().find(|t| t.id == transfer_id).map(|t| {
+            t.progress = progress;
+            if t.started_at.is_none() {
+                t.started_at = Some(chrono::Local::now().to_rfc3339());
+            }
+            TransferUpdate::from(&*t)
+        });
+        (update, transfers.iter().find(|t| t.id == transfer_id).map_or(0, |t| t.size))
+    };
+    if let Some(update) = update {
+        events::emit_update(&ctx.app, update);
+    }
+    ProgressTracker::start(&ctx.live_progress, transfer_id, size, progress)
+}
+
+fn apply_live_progress(transfer: &mut FileTransfer, live: &LiveProgress) {
+    (transfer.progress, transfer.speed_bps, transfer.eta_seconds) = live.get();
+}
+
+// Record how much compression and delta encoding saved on the wire
+fn set_compression_ratio(ctx: &PeerContext, transfer_id: &str, packed: u64, raw: u64) {
+    let mut transfers = ctx.transfers.lock();
+    if let Some(t) = transfers.iter_mut().find(|t| t.id == transfer_id) {
+        t.compression_ratio = (raw > 0).then(|| packed as f64 / raw as f64);
+    }
+}
+
+// Refuse a transfer, telling the sender why
+fn write_rejection(channel: &mut SecureChannel, reason: &str, ctx: &PeerContext) -> std::io::Result<()> {
+    let response = PacketHeader {
+        packet_type: PACKET_TRANSFER_REJECT.to_string(),
+        source: ctx.device_name(),
+        reason: reason.to_string(),
+        ..Default::default()
+    };
+    write_header(channel, &response, &ctx.signing_key)
+}
+
+// Tell the sender how the file fared once it was fully received
+fn write_receipt(channel: &mut SecureChannel, result: &str, ctx: &PeerContext) -> std::io::Result<()> {
+    let receipt = PacketHeader {
+        packet_type: PACKET_TRANSFER_RECEIPT.to_string(),
+        source: ctx.device_name(),
+        result: result.to_string(),
+        ..Default::default()
+    };
+    write_header(channel, &receipt, &ctx.signing_key)
+}
+
+// Byte counts of a file moving over one or more streams
+struct StreamProgress {
+    bytes: u64,
+    tracker: ProgressTracker,
+    // Plaintext bytes, and what they took on the wire
+    raw_bytes: u64,
+    packed_bytes: u64,
+}
+
+impl StreamProgress {
+    fn new(bytes: u64, tracker: ProgressTracker) -> Self {
+        StreamProgress { bytes, tracker, raw_bytes: 0, packed_bytes: 0 }
+    }
+
+    // Count a finished chunk towards the transfer's progress
+    fn add(&mut self, raw: usize, packed: usize) {
+        self.bytes += raw as u64;
+        self.raw_bytes += raw as u64;
+        self.packed_bytes += packed as u64;
+        self.tracker.record(self.bytes);
+    }
+}
+
+// Send a bare signed response frame
+fn write_response(channel: &mut SecureChannel, packet_type: &str, ctx: &PeerContext) -> std::io::Result<()> {
+    let response = PacketHeader {
+        packet_type: packet_type.to_string(),
+        source: ctx.device_name(),
+        ..Default::default()
+    };
+    write_header(channel, &response, &ctx.signing_key)
+}
+
+// Handle incoming encrypted file transfer; returns false when the rest of
+// a batch should not follow
+fn handle_incoming_file(
+    channel: &mut SecureChannel,
+    header: &PacketHeader,
+    decision: AcceptDecision,
+    ctx: &PeerContext,
+) -> std::io::Result<bool> {
+    let filename = header.display_name().to_string();
+    let file_size = header.file_size;
+    
+    // Create transfer record
+    let transfer_id = Uuid::new_v4().to_string();
+    let transfer = FileTransfer {
+        id: transfer_id.clone(),
+        filename: filename.clone(),
+        size: file_size,
+        progress: 0,
+        status: "Awaiting approval ⏳".to_string(),
+        from_device: header.source.clone(),
+        to_device: THIS_DEVICE.to_string(),
+        encrypted: true,
+        batch_id: (!header.batch_id.is_empty()).then(|| header.batch_id.clone()),
+        speed_bps: 0,
+        eta_seconds: None,
+        started_at: None,
+        compression_ratio: None,
+        file_hash: Some(header.file_hash.clone()),
+        finished_at: None,
+        saved_path: None,
+        error: None,
+    };
+    
+    ctx.transfers.lock().push(transfer.clone());
+    events::emit_record(&ctx.app, events::STARTED, &transfer);
+    
+    // Group files of the same batch together for the frontend
+    if !header.batch_id.is_empty() {
+        let mut batches = ctx.batches.lock();
+        let batch = batches.entry(header.batch_id.clone()).or_insert_with(|| BatchTransfer {
+            id: header.batch_id.clone(),
+            transfer_ids: Vec::new(),
+            file_count: header.batch_count,
+            total_size: header.batch_size,
+            from_device: header.source.clone(),
+            to_device: THIS_DEVICE.to_string(),
+        });
+        batch.transfer_ids.push(transfer_id.clone());
+    }
+    
+    let token = cancel::register(&ctx.cancel_tokens, &transfer_id);
+    token.attach(channel.try_clone_stream()?);
+    let result = receive_file(channel, header, decision, &transfer_id, &token, ctx);
+    cancel::unregister(&ctx.cancel_tokens, &transfer_id);
+    ctx.throttle.remove_transfer(&transfer_id);
+    
+    // A cancelled download leaves nothing behind to resume
+    let result = if token.is_cancelled() {
+        if let Some(manifest) = resume::load_manifest(&header.file_hash) {
+            resume::discard(&manifest);
+        }
+        fail_transfer(ctx, &transfer_id, "Cancelled ⛔", Error::Cancelled);
+        Ok(false)
+    } else {
+        result
+    };
+    sync::pull_finished(&ctx.sync_pulls, &header.request_id, &transfer_id);
+    result
+}
+
+// Approve, unlock and receive the body of an incoming file
+fn receive_file(
+    channel: &mut SecureChannel,
+    header: &PacketHeader,
+    decision: AcceptDecision,
+    transfer_id: &str,
+    token: &CancelToken,
+    ctx: &PeerContext,
+) -> std::io::Result<bool> {
+    let filename = header.display_name().to_string();
+    let file_size = header.file_size;
+    
+    // A file pulled for a sync goes where the sync asked for it
+    let pulled_to = sync::pulled_path(&ctx.sync_pulls, &header.request_id, transfer_id, channel.peer_identity());
+    let pulled = pulled_to.is_some();
+    let Some(download_path) = pulled_to.or_else(|| download_path_for(&filename)) else {
+        fail_transfer(ctx, transfer_id, "Rejected 🚫 (Unsafe file path)", Error::InvalidInput("Unsafe file path".to_string()));
+        return write_rejection(channel, "Unsafe file path", ctx).map(|_| false);
+    };
+    
+    // Held files are received into the quarantine instead, and only reach
+    // the download path once the user releases them
+    let quarantined = !pulled && ctx.settings.lock().quarantine_incoming;
+    let receive_path = if quarantined {
+        quarantine::receive_path(&header.file_hash)
+    } else {
+        Some(download_path.clone())
+    };
+    let Some(receive_path) = receive_path else {
+        fail_transfer(ctx, transfer_id, "Rejected 🚫 (Malformed file hash)", Error::InvalidInput("Malformed file hash".to_string()));
+        return write_rejection(channel, "Malformed file hash", ctx).map(|_| false);
+    };
+    
+    // A sender reconnecting after a dropped connection picks up where the
+    // last attempt stopped, without asking the user a second time, as long
+    // as it's going to the same place
+    let manifest = resume::load_manifest(&header.file_hash)
+        .filter(|m| m.signing_key == header.signing_key && m.file_size == file_size)
+        .filter(|m| m.part_path == resume::part_path(&receive_path));
+    
+    // Refuse files that wouldn't fit or that break the user's limits
+    // before bothering anyone about them
+    let needed = file_size.saturating_sub(manifest.as_ref().map_or(0, |m| m.offset));
+    let limits = {
+        let settings = ctx.settings.lock();
+        let usage = ctx.daily_usage.lock();
+        quota::check_incoming(&receive_path, file_size, needed, &usage, &settings)
+    };
+    if let Err(e) = limits {
+        let reason = e.to_string();
+        fail_transfer(ctx, transfer_id, &format!("Rejected 🚫 ({})", reason), e);
+        return write_rejection(channel, &reason, ctx).map(|_| false);
+    }
+    
+    let accepted = match decision {
+        AcceptDecision::Accept => true,
+        AcceptDecision::Ask if manifest.is_some() => true,
+        AcceptDecision::Reject => {
+            let reason = if ctx.settings.lock().receiving_paused {
+                "Not receiving files right now"
+            } else {
+                "Unknown device"
+            };
+            fail_transfer(ctx, transfer_id, &format!("Rejected 🚫 ({})", reason), Error::Rejected(reason.to_string()));
+            return write_rejection(channel, reason, ctx).map(|_| false);
+        }
+        AcceptDecision::Ask => {
+            // Ask the user before anything touches the disk
+            let (tx, rx) = mpsc::channel();
+            ctx.pending_approvals.lock().insert(transfer_id.to_string(), tx);
+            let _ = ctx.app.emit("transfer://request", TransferRequest {
+                transfer_id: transfer_id.to_string(),
+                filename: filename.clone(),
+                size: file_size,
+                from_device: header.source.clone(),
+            });
+            notify::request(&ctx.app, &filename, &header.source);
+            let accepted = rx.recv_timeout(APPROVAL_TIMEOUT).unwrap_or(false);
+            ctx.pending_approvals.lock().remove(transfer_id);
+            accepted
+        }
+    };
+    
+    if !accepted {
+        fail_transfer(ctx, transfer_id, "Rejected 🚫", Error::Rejected("Declined".to_string()));
+        return write_rejection(channel, "Declined", ctx).map(|_| false);
+    }
+    
+    // File body is sealed to our identity key, not the hop's session key
+    let file_key = decode_public_key(&header.file_key)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing file key"))
+        .and_then(|ephemeral| open_file_key(&ctx.identity_key, &ephemeral))?;
+    
+    // Password-protected transfers stay paused until the user unlocks them
+    let body_key = if header.password_salt.is_empty() {
+        file_key
+    } else {
+        set_transfer_status(ctx, transfer_id, "Locked 🔑 (Password required)");
+        match prompt_for_password(transfer_id, header, &file_key, ctx)? {
+            Some(content_key) => content_key,
+            None => {
+                fail_transfer(ctx, transfer_id, "Failed ❌ (Wrong password)", Error::WrongPassword);
+                return write_rejection(channel, "Wrong password", ctx).map(|_| false);
+            }
+        }
+    };
+    
+    // Wait for a free incoming slot before anything touches the disk
+    set_transfer_status(ctx, transfer_id, "Queued ⏳");
+    let Some(_slot) = ctx.queue.acquire(transfer_id, Direction::Incoming, &filename, || token.is_cancelled()) else {
+        return Ok(false);
+    };
+    
+    // A sync's first file into a new folder makes it the sender's to sync
+    if !pulled && !header.sync_folder.is_empty() {
+        sync::claim_folder(&header.sync_folder, &download_path, channel.peer_identity(), ctx);
+    }
+    
+    // Files from a folder recreate its directory tree
+    if let Some(parent) = receive_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    
+    // Chunks are written straight to a partial file, which only takes the
+    // real name once the whole-file hash checks out
+    let resuming = manifest.is_some();
+    let resumed = manifest.as_ref()
+        .and_then(|m| resume::reopen(m, &header.chunk_hashes).ok());
+    let mut manifest = resume::PartialManifest {
+        transfer_id: manifest.map(|m| m.transfer_id).unwrap_or_else(|| transfer_id.to_string()),
+        signing_key: header.signing_key.clone(),
+        file_hash: header.file_hash.clone(),
+        file_size,
+        part_path: resume::part_path(&receive_path),
+        verified_chunks: 0,
+        offset: 0,
+    };
+    let partial_file = match resumed {
+        Some(resumed) => {
+            manifest.verified_chunks = resumed.verified_chunks;
+            manifest.offset = resumed.offset;
+            resumed.file
+        }
+        None => std::fs::File::create(&manifest.part_path)?,
+    };
+    resume::save_manifest(&manifest)?;
+    
+    // If we already have a file by this name, and it's the sender's to
+    // know about, the sender only needs to send what changed. It is if it
+    // came from the sender, if the sender is resuming its own transfer, or
+    // if it's in a folder we sync with the sender.
+    let linked = resuming
+        || pulled
+        || ctx.received_from.lock().came_from(&download_path, &header.signing_key);
+    let mut delta_block_size = 0;
+    let mut delta_signatures = Vec::new();
+    if header.delta && linked && download_path.is_file() {
+        let mut file = std::fs::File::open(&download_path)?;
+        delta_block_size = delta::block_size_for(file.metadata()?.len());
+        delta_signatures = delta::signatures(&mut file, delta_block_size)?;
+    }
+    
+    // Spread what's left over several connections if the sender offered to
+    let first_chunk = manifest.verified_chunks;
+    let total_chunks = header.chunk_hashes.len() as u64;
+    let allowed_streams = ctx.settings.lock().parallel_streams;
+    let streams = parallel::negotiate(header.parallel_streams, allowed_streams, total_chunks.saturating_sub(first_chunk));
+    let stream_token = if streams > 1 { Uuid::new_v4().to_string() } else { String::new() };
+    let joins = (streams > 1).then(|| {
+        parallel::expect_joins(&ctx.stream_joins, &stream_token, *channel.peer_identity(), &header.signing_key)
+    });
+    
+    // Tell the sender which chunk to start from, and agree to compression
+    // if it was offered
+    let compressed = header.compression == compression::ZSTD;
+    let accept = PacketHeader {
+        packet_type: PACKET_TRANSFER_ACCEPT.to_string(),
+        source: ctx.device_name(),
+        resume_chunk: first_chunk,
+        compression: if compressed { compression::ZSTD.to_string() } else { String::new() },
+        delta_block_size,
+        delta_signatures,
+        parallel_streams: streams as u64,
+        stream_token: stream_token.clone(),
+        receipt: true,
+        ..Default::default()
+    };
+    write_header(channel, &accept, &ctx.signing_key)?;
+    let mut extra_streams = match joins {
+        Some(joins) => parallel::await_joins(&ctx.stream_joins, &stream_token, joins, streams - 1)?,
+        None => Vec::new(),
+    };
+    if first_chunk > 0 {
+        println!("↩️ Resuming {} from chunk {}", filename, first_chunk);
+    }
+    if streams > 1 {
+        println!("🔀 Receiving {} over {} streams", filename, streams);
+    }
+    set_transfer_status(ctx, transfer_id, "Receiving 🔒");
+    let tracker = start_progress(ctx, transfer_id, manifest.offset);
+    let progress = Mutex::new(StreamProgress::new(manifest.offset, tracker));
+    let download = Mutex::new(resume::PartialDownload::new(partial_file, manifest, total_chunks));
+    
+    // Receive, decrypt and verify each chunk, asking for a resend on
+    // mismatch. Every stream carries its own share of the chunks.
+    let channels = std::iter::once(&mut *channel).chain(extra_streams.iter_mut()).collect();
+    let finished = parallel::run_streams(channels, |stream, channel| {
+        let mut old_file = if delta_block_size > 0 {
+            Some(std::fs::File::open(&download_path)?)
+        } else {
+            None
+        };
+        for index in parallel::stripe(first_chunk, total_chunks, streams, stream) {
+            if token.is_cancelled() {
+                return Ok(false);
+            }
+            let expected_hash = &header.chunk_hashes[index as usize];
+            let mut attempts = 0;
+            loop {
+                let frame = channel.recv()?;
+                ctx.throttle.consume(transfer_id, frame.len());
+                let payload = decrypt_data(&frame, &body_key).ok();
+                let payload = if compressed {
+                    payload.and_then(|p| compression::unpack(&p, 2 * CHUNK_SIZE).map(Cow::into_owned))
+                } else {
+                    payload
+                };
+                let chunk = match old_file.as_mut() {
+                    Some(old) => payload.and_then(|p| delta::decode(&p, old, delta_block_size, CHUNK_SIZE)),
+                    None => payload,
+                }
+                .filter(|chunk| blake3::hash(chunk).to_hex().as_str() == expected_hash);
+                
+                if let Some(chunk) = chunk {
+                    channel.send(&[CHUNK_ACK])?;
+                    download.lock().write_chunk(index, &chunk)?;
+                    progress.lock().add(chunk.len(), frame.len());
+                    break;
+                }
+                
+                attempts += 1;
+                if attempts > MAX_CHUNK_RETRIES {
+                    channel.send(&[CHUNK_ABORT])?;
+                    resume::discard(&download.lock().manifest);
+                    fail_transfer(
+                        ctx,
+                        transfer_id,
+                        &format!("Corrupted ⚠️ (Chunk {})", index),
+                        Error::Corrupted(format!("Chunk {} failed verification", index)),
+                    );
+                    return Ok(false);
+                }
+                eprintln!("Chunk {} of {} failed verification, requesting resend", index, filename);
+                channel.send(&[CHUNK_NACK])?;
+            }
+        }
+        Ok(true)
+    })?;
+    if !finished {
+        return Ok(true);
+    }
+    
+    // Verify the reassembled file before giving it its real name
+    let (manifest, file_hash) = download.into_inner().close()?;
+    let file_hash = match file_hash {
+        Some(file_hash) => file_hash,
+        None => blake3::Hasher::new().update_mmap_rayon(&manifest.part_path)?.finalize(),
+    };
+    if file_hash.to_hex().as_str() != header.file_hash {
+        resume::discard(&manifest);
+        fail_transfer(ctx, transfer_id, "Corrupted ⚠️ (Hash mismatch)", Error::Corrupted("Hash mismatch".to_string()));
+        write_receipt(channel, RECEIPT_HASH_MISMATCH, ctx)?;
+        return Ok(true);
+    }
+    
+    // Programs and scripts are saved under a name that won't run them until
+    // the user confirms them. Files a sync pulled aren't screened.
+    let (versions_kept, screen_incoming, risky_extensions) = {
+        let settings = ctx.settings.lock();
+        (settings.versions_kept, settings.screen_incoming, settings.risky_extensions.clone())
+    };
+    let risk = if screen_incoming && !pulled {
+        screening::screen(&manifest.part_path, &filename, &risky_extensions)
+    } else {
+        Ok(None)
+    };
+    let flagged = risk.as_ref().ok().cloned().flatten();
+    let saved_to = match &flagged {
+        Some(_) => screening::unconfirmed_path(&download_path),
+        None => download_path.clone(),
+    };
+    
+    // The partial file and manifest stay behind if this fails, so sending
+    // the file again picks up from there. A file it replaces is kept as a
+    // version first, or once it's confirmed.
+    let committed = risk.and_then(|risk| {
+        if quarantined {
+            let held = quarantine::QuarantinedFile {
+                transfer_id: transfer_id.to_string(),
+                filename: filename.clone(),
+                size: file_size,
+                file_hash: header.file_hash.clone(),
+                from_device: header.source.clone(),
+                received_at: chrono::Local::now().to_rfc3339(),
+                destination: download_path.to_string_lossy().into_owned(),
+                risk,
+                sealed_key: String::new(),
+            };
+            quarantine::hold(&ctx.quarantined, &manifest.part_path, held, &ctx.identity_key)
+        } else if flagged.is_some() {
+            resume::commit(&manifest.part_path, &saved_to)
+        } else {
+            versions::save(&download_path, versions_kept)
+                .and_then(|_| resume::commit(&manifest.part_path, &download_path))
+        }
+    });
+    if let Err(e) = committed {
+        eprintln!("Could not save {}: {}", filename, e);
+        fail_transfer(ctx, transfer_id, "Failed ❌ (Could not save file)", Error::from(e));
+        write_receipt(channel, RECEIPT_SAVE_FAILED, ctx)?;
+        return Ok(true);
+    }
+    resume::finish(&header.file_hash);
+    ctx.daily_usage.lock().record(file_size);
+    if !quarantined && flagged.is_none() {
+        let hashes = hashing::FileHashes { file_hash: header.file_hash.clone(), chunk_hashes: header.chunk_hashes.clone() };
+        hashing::remember(&ctx.hashed_files, &download_path, hashes);
+        delta::remember_origin(&ctx.received_from, &download_path, &header.signing_key);
+    }
+    if !quarantined {
+        if let Some(t) = ctx.transfers.lock().iter_mut().find(|t| t.id == transfer_id) {
+            t.saved_path = Some(saved_to.to_string_lossy().into_owned());
+        }
+    }
+    if compressed || delta_block_size > 0 {
+        let progress = progress.into_inner();
+        set_compression_ratio(ctx, transfer_id, progress.packed_bytes, progress.raw_bytes);
+    }
+    if quarantined {
+        complete_transfer(ctx, transfer_id, "Quarantined 🔒 (Awaiting release)");
+    } else if let Some(reason) = flagged {
+        complete_transfer(ctx, transfer_id, &format!("Needs confirmation ⚠️ ({})", reason));
+    } else {
+        complete_transfer(ctx, transfer_id, "Completed ✅ (Verified)");
+    }
+    write_receipt(channel, RECEIPT_VERIFIED, ctx)?;
+    
+    Ok(true)
+}
+
+// How the send commands are asked to send files
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct SendOptions {
+    password: Option<String>,
+    // Offer compression for files that look compressible
+    compression: bool,
+}
+
+// Send encrypted file to device, through the relays in `via` if given
+#[tauri::command]
+async fn send_file(
+    file_path: String,
+    target_ip: String,
+    target_port: u16,
+    options: Option<SendOptions>,
+    via: Option<Vec<String>>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, Error> {
+    let options = options.unwrap_or_default();
+    let mut destination = state.destination(target_ip, target_port, options.password, options.compression);
+    let ctx = state.peer_context(app);
+    
+    // Going through relays the user picked, rather than straight there
+    if let Some(via) = via.filter(|via| !via.is_empty()) {
+        let target_id = state.devices.lock()
+            .values()
+            .find(|d| d.ip == destination.ip && d.port == destination.port)
+            .map(|d| d.id.clone())
+            .ok_or_else(|| Error::InvalidInput("Pinned routes need a discovered device".to_string()))?;
+        let topology = {
+            let links = state.links.lock().clone();
+            let devices = state.devices.lock();
+            let trusted = state.trusted_devices.lock();
+            let routes = state.routes.lock();
+            topology::build(&state.device_id, &state.device_name(), &routes, &devices, &links, &trusted)
+        };
+        topology::check_route(&topology, &via, &target_id)?;
+        destination.forward_to = Some(target_id);
+        destination.via = Some(via);
+    }
+    
+    state.tasks.spawn(tasks::Budget::Send, move || {
+        let result = queue_outgoing(file_path, None, None, &destination, &ctx)
+            .and_then(|file| send_file_internal(vec![file], destination, ctx));
+        if let Err(e) = result {
+            eprintln!("Error sending file: {}", e);
+        }
+    });
+    
+    Ok("Encrypted transfer started 🔒".to_string())
+}
+
+// Send several files as one batch over a single connection
+#[tauri::command]
+async fn send_files(
+    paths: Vec<String>,
+    target_ip: String,
+    target_port: u16,
+    options: Option<SendOptions>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<BatchTransfer, Error> {
+    let entries = paths.into_iter().map(|path| (path, None)).collect();
+    let options = options.unwrap_or_default();
+    let destination = state.destination(target_ip, target_port, options.password, options.compression);
+    start_batch(entries, destination, state.peer_context(app))
+}
+
+// Send a folder as one batch, recreating its directory tree on the receiver,
+// leaving out whatever `rules` say to
+#[tauri::command]
+async fn send_folder(
+    folder_path: String,
+    target_ip: String,
+    target_port: u16,
+    options: Option<SendOptions>,
+    rules: Option<FileRules>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<BatchTransfer, Error> {
+    let root = std::path::Path::new(&folder_path);
+    let folder_name = root.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::InvalidInput("Invalid folder path".to_string()))?;
+    
+    let filter = Filter::new(&rules.unwrap_or_default());
+    let mut entries = Vec::new();
+    collect_folder(root, folder_name, "", &filter, &mut entries)?;
+    let options = options.unwrap_or_default();
+    let destination = state.destination(target_ip, target_port, options.password, options.compression);
+    start_batch(entries, destination, state.peer_context(app))
+}
+
+// Recursively list the files under a folder with their relative paths,
+// along with their paths within the folder for `filter` to judge.
+// Symlinks are skipped so a link cycle can't make the walk endless.
+fn collect_folder(
+    dir: &std::path::Path,
+    relative: &str,
+    within: &str,
+    filter: &Filter,
+    entries: &mut Vec<(String, Option<String>)>,
+) -> std::io::Result<()> {
+    let mut children = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+    
+    for entry in children {
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let within = if within.is_empty() { name.clone() } else { format!("{}/{}", within, name) };
+        if filter.ignores(&within, file_type.is_dir()) {
+            continue;
+        }
+        let relative = format!("{}/{}", relative, name);
+        if file_type.is_dir() && name == versions::VERSIONS_DIR {
+            continue;
+        }
+        if file_type.is_dir() {
+            collect_folder(&entry.path(), &relative, &within, filter, entries)?;
+        } else if file_type.is_file() && !filter.too_large(entry.metadata()?.len()) {
+            entries.push((entry.path().to_string_lossy().into_owned(), Some(relative)));
+        }
+    }
+    
+    Ok(())
+}
+
+// Register a batch for a list of (path, relative path) entries and send it
+// in the background. Sizes are totalled up front so progress covers the
+// whole batch.
+fn start_batch(
+    entries: Vec<(String, Option<String>)>,
+    destination: Destination,
+    ctx: PeerContext,
+) -> Result<BatchTransfer, Error> {
+    if entries.is_empty() {
+        return Err(Error::InvalidInput("No files to send".to_string()));
+    }
+    
+    let mut total_size = 0;
+    for (path, _) in &entries {
+        total_size += std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    }
+    
+    let batch = BatchTransfer {
+        id: Uuid::new_v4().to_string(),
+        transfer_ids: Vec::new(),
+        file_count: entries.len() as u64,
+        total_size,
+        from_device: THIS_DEVICE.to_string(),
+        to_device: destination.ip.clone(),
+    };
+    ctx.batches.lock().insert(batch.id.clone(), batch.clone());
+    let batch_id = batch.id.clone();
+    
+    ctx.tasks.clone().spawn(tasks::Budget::Send, move || {
+        // Hash everything up front so each file's record shows as queued
+        let mut files = Vec::new();
+        for (path, relative_path) in entries {
+            match queue_outgoing(path, relative_path, Some(&batch_id), &destination, &ctx) {
+                Ok(file) => files.push(file),
+                Err(e) => eprintln!("Skipping file in batch: {}", e),
+            }
+        }
+        
+        // Number the files that made it, so the receiver knows when the
+        // batch is over
+        let count = files.len() as u64;
+        for (index, file) in files.iter_mut().enumerate() {
+            file.batch = Some(BatchInfo { id: batch_id.clone(), index: index as u64, count, size: total_size });
+        }
+        
+        if let Err(e) = send_file_internal(files, destination, ctx) {
+            eprintln!("Error sending batch: {}", e);
+        }
+    });
+    
+    Ok(batch)
+}
+
+// Where and how outgoing files are sent, shared by every file of a batch
+struct Destination {
+    ip: String,
+    port: u16,
+    // Every address of the device at `ip`, tried in turn, `ip` first
+    addresses: Vec<String>,
+    // Identity key the files are sealed to, when the device was discovered
+    recipient_key: Option<PublicKey>,
+    password: Option<String>,
+    // Offer zstd compression for files that look compressible
+    compression: bool,
+    // Set when leaving the files with a relay: the identity key of the
+    // device they're for
+    hold_for: Option<String>,
+    // Set when the device is out of sight: its device id, with `ip` and
+    // `port` being the next hop that first forwarded connections to it
+    forward_to: Option<String>,
+    // Next hop of the path the latest connection took
+    next_hop: Mutex<Option<String>>,
+    // Relays the user pinned, by device id, in the order to go through
+    // them; set along with `forward_to`
+    via: Option<Vec<String>>,
+    // Set when the device is out of sight: reach it through the
+    // rendezvous server, sealed to `recipient_key`
+    internet: bool,
+    // Set when syncing: the files have to end up at their relative paths,
+    // so they're sent even if the receiver already has them elsewhere
+    exact_paths: bool,
+    // Set when syncing: the pair's folder under the receiver's Downloads
+    sync_folder: Option<String>,
+}
+
+impl AppState {
+    fn destination(&self, ip: String, port: u16, password: Option<String>, compression: bool) -> Destination {
+        // Seal files to the destination's advertised identity key
+        let recipient_key = self.devices.lock()
+            .values()
+            .find(|d| d.ip == ip && d.port == port)
+            .and_then(|d| decode_public_key(&d.public_key));
+        Destination {
+            addresses: self.addresses_for(&ip, port),
+            ip,
+            port,
+            recipient_key,
+            password,
+            compression,
+            hold_for: None,
+            forward_to: None,
+            next_hop: Mutex::new(None),
+            via: None,
+            internet: false,
+            exact_paths: false,
+            sync_folder: None,
+        }
+    }
+    
+    // Every address of the device listed under `ip`, to fall back on if
+    // that one doesn't answer
+    fn addresses_for(&self, ip: &str, port: u16) -> Vec<String> {
+        self.devices.lock()
+            .values()
+            .find(|d| d.ip == ip && d.port == port)
+            .map_or_else(|| vec![ip.to_string()], net::device_addresses)
+    }
+    
+    // Identity key and name of a discovered device, by id, or of a paired
+    // device, by key
+    fn resolve_target(&self, target: &str) -> Result<(String, String), String> {
+        let discovered = self.devices.lock()
+            .get(target)
+            .filter(|d| !d.public_key.is_empty())
+            .map(|d| (d.public_key.clone(), d.name.clone()));
+        match discovered {
+            Some(device) => Ok(device),
+            None => self.trusted_devices.lock()
+                .get(target)
+                .map(|d| (d.public_key.clone(), d.name.clone()))
+                .ok_or_else(|| format!("Unknown device: {}", target)),
+        }
+    }
+}
+
+// Position of a file within its batch
+struct BatchInfo {
+    id: String,
+    index: u64,
+    count: u64,
+    size: u64,
+}
+
+// A file being sent, hashed once up front and reused across reconnects
+struct OutgoingFile {
+    transfer_id: String,
+    path: String,
+    filename: String,
+    size: u64,
+    file_hash: String,
+    chunk_hashes: Vec<String>,
+    // Set for files sent from a folder
+    relative_path: Option<String>,
+    batch: Option<BatchInfo>,
+    // Set when sending a file a peer asked us for
+    request_id: Option<String>,
+}
+
+// Add a file's transfer record and hash it, ready to be sent. The record
+// shows while the file is hashed.
+fn queue_outgoing(
+    file_path: String,
+    relative_path: Option<String>,
+    batch_id: Option<&str>,
+    destination: &Destination,
+    ctx: &PeerContext,
+) -> std::io::Result<OutgoingFile> {
+    let filename = std::path::Path::new(&file_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown")
+        .to_string();
+    
+    let size = std::fs::metadata(&file_path)?.len();
+    let name = relative_path.as_deref().unwrap_or(&filename);
+    let transfer_id = add_outgoing_record(name, size, None, batch_id, destination, ctx);
+    
+    // Hash the plaintext so the receiver can verify each chunk and the whole
+    let hashes = match hashing::hash_file(&ctx.hashed_files, std::path::Path::new(&file_path)) {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            let reported = std::io::Error::new(e.kind(), e.to_string());
+            fail_transfer(ctx, &transfer_id, "Failed ❌ (Could not read file)", Error::from(e));
+            return Err(reported);
+        }
+    };
+    let hashing::FileHashes { file_hash, chunk_hashes } = hashes;
+    if let Some(t) = ctx.transfers.lock().iter_mut().find(|t| t.id == transfer_id) {
+        t.file_hash = Some(file_hash.clone());
+    }
+    set_transfer_status(ctx, &transfer_id, "Queued ⏳");
+    Ok(OutgoingFile { transfer_id, path: file_path, filename, size, file_hash, chunk_hashes, relative_path, batch: None, request_id: None })
+}
+
+// Create the transfer record for a file about to be sent, which is still
+// being hashed if its hash isn't given
+fn add_outgoing_record(
+    name: &str,
+    size: u64,
+    file_hash: Option<&str>,
+    batch_id: Option<&str>,
+    destination: &Destination,
+    ctx: &PeerContext,
+) -> String {
+    let transfer_id = Uuid::new_v4().to_string();
+    let transfer = FileTransfer {
+        id: transfer_id.clone(),
+        filename: name.to_string(),
+        size,
+        progress: 0,
+        status: if file_hash.is_some() { "Queued ⏳" } else { "Hashing 🔍" }.to_string(),
+        from_device: THIS_DEVICE.to_string(),
+        to_device: destination.ip.clone(),
+        encrypted: true,
+        batch_id: batch_id.map(str::to_string),
+        speed_bps: 0,
+        eta_seconds: None,
+        started_at: None,
+        compression_ratio: None,
+        file_hash: file_hash.map(str::to_string),
+        finished_at: None,
+        saved_path: None,
+        error: None,
+    };
+    ctx.transfers.lock().push(transfer.clone());
+    events::emit_record(&ctx.app, events::STARTED, &transfer);
+    if let Some(batch_id) = batch_id {
+        if let Some(batch) = ctx.batches.lock().get_mut(batch_id) {
+            batch.transfer_ids.push(transfer_id.clone());
+        }
+    }
+    transfer_id
+}
+
+impl OutgoingFile {
+    // The same file queued for another destination, reusing its hashes
+    fn copy_to(&self, batch_id: Option<&str>, destination: &Destination, ctx: &PeerContext) -> OutgoingFile {
+        let name = self.relative_path.as_deref().unwrap_or(&self.filename);
+        OutgoingFile {
+            transfer_id: add_outgoing_record(name, self.size, Some(&self.file_hash), batch_id, destination, ctx),
+            path: self.path.clone(),
+            filename: self.filename.clone(),
+            size: self.size,
+            file_hash: self.file_hash.clone(),
+            chunk_hashes: self.chunk_hashes.clone(),
+            relative_path: self.relative_path.clone(),
+            batch: None,
+            request_id: None,
+        }
+    }
+}
+
+// Send files in order over one connection, reconnecting after a dropped
+// connection; the receiver tells us which chunk to continue from
+fn send_file_internal(
+    files: Vec<OutgoingFile>,
+    destination: Destination,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    // Leave out whatever the receiver already has. A relay can't say what
+    // the device it holds for has.
+    let present = if destination.hold_for.is_none() && !destination.exact_paths {
+        files_already_present(&files, &destination, &ctx)
+    } else {
+        HashSet::new()
+    };
+    let mut files: Vec<OutgoingFile> = files.into_iter()
+        .filter(|file| {
+            if !present.contains(&file.file_hash) {
+                return true;
+            }
+            if let Some(t) = ctx.transfers.lock().iter_mut().find(|t| t.id == file.transfer_id) {
+                t.progress = t.size;
+            }
+            complete_transfer(&ctx, &file.transfer_id, "Already present ✅");
+            false
+        })
+        .collect();
+    
+    // Renumber what's left of a batch, so the receiver knows where it ends
+    if !present.is_empty() {
+        let count = files.len() as u64;
+        let size = files.iter().map(|f| f.size).sum();
+        for (index, file) in files.iter_mut().enumerate() {
+            if let Some(batch) = &mut file.batch {
+                batch.index = index as u64;
+                batch.count = count;
+                batch.size = size;
+            }
+        }
+    }
+    
+    let tokens: Vec<_> = files.iter()
+        .map(|file| cancel::register(&ctx.cancel_tokens, &file.transfer_id))
+        .collect();
+    
+    // A batch takes a single outgoing slot, since it uses one connection
+    let (job_id, label) = match files.first() {
+        Some(file) => match &file.batch {
+            Some(batch) => (batch.id.clone(), format!("{} files", batch.count)),
+            None => (file.transfer_id.clone(), file.filename.clone()),
+        },
+        None => return Ok(()),
+    };
+    let slot = ctx.queue.acquire(&job_id, Direction::Outgoing, &label, || tokens.iter().all(|t| t.is_cancelled()));
+    if slot.is_none() {
+        for file in &files {
+            fail_transfer(&ctx, &file.transfer_id, "Cancelled ⛔", Error::Cancelled);
+            cancel::unregister(&ctx.cancel_tokens, &file.transfer_id);
+            ctx.throttle.remove_transfer(&file.transfer_id);
+        }
+        return Ok(());
+    }
+    
+    let retry = ctx.settings.lock().retry;
+    let mut next = 0;
+    let mut attempt = 0;
+    let mut switches = 0;
+    let mut result = Ok(());
+    while next < files.len() {
+        let started_at = next;
+        let outcome = send_remaining(&files, &mut next, &tokens, &destination, &ctx);
+        
+        // Each file gets the full set of retries
+        if next > started_at {
+            attempt = 0;
+            switches = 0;
+        }
+        
+        // A cancelled file is skipped, and the rest of the batch continues
+        // on a fresh connection
+        if next < files.len() && tokens[next].is_cancelled() {
+            fail_transfer(&ctx, &files[next].transfer_id, "Cancelled ⛔", Error::Cancelled);
+            next += 1;
+            continue;
+        }
+        
+        match outcome {
+            Ok(true) => {}
+            Ok(false) => {
+                // Refusing one file of a batch refuses the rest
+                for file in &files[next + 1..] {
+                    fail_transfer(&ctx, &file.transfer_id, "Rejected by recipient 🚫", Error::Rejected("Declined".to_string()));
+                }
+                break;
+            }
+            Err(e) => {
+                if switches < routing::MAX_PATHS && switch_path(&destination, &ctx) {
+                    // The relay we went through died; resume from the last
+                    // acknowledged chunk over the next path straight away
+                    switches += 1;
+                    eprintln!("Transfer of {} lost its route ({}), switching path", files[next].filename, e);
+                    set_transfer_status(&ctx, &files[next].transfer_id, "Switching route 🔀");
+                } else if attempt < retry.max_attempts {
+                    // Back off, then reconnect and resume from the last
+                    // acknowledged chunk
+                    attempt += 1;
+                    let delay = retry.delay(attempt);
+                    eprintln!("Transfer of {} interrupted ({}), retrying in {:?}", files[next].filename, e, delay);
+                    set_transfer_status(
+                        &ctx,
+                        &files[next].transfer_id,
+                        &format!("Retrying 🔄 ({}/{})", attempt, retry.max_attempts),
+                    );
+                    
+                    let deadline = std::time::Instant::now() + delay;
+                    while std::time::Instant::now() < deadline && !tokens[next].is_cancelled() {
+                        thread::sleep(std::time::Duration::from_millis(100));
+                    }
+                } else {
+                    let status = match routing::failed_relay(&e) {
+                        Some(relay) => format!("Failed at relay: {} ❌", relay),
+                        None => "Failed ❌ (Connection lost)".to_string(),
+                    };
+                    for file in &files[next..] {
+                        fail_transfer(&ctx, &file.transfer_id, &status, Error::PeerOffline(e.to_string()));
+                    }
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+    }
+    
+    for file in &files {
+        cancel::unregister(&ctx.cancel_tokens, &file.transfer_id);
+        ctx.throttle.remove_transfer(&file.transfer_id);
+    }
+    result
+}
+
+// Ask the receiver which of these files it already holds, by content hash.
+// Receivers that don't understand the question are taken to hold none.
+fn files_already_present(files: &[OutgoingFile], destination: &Destination, ctx: &PeerContext) -> HashSet<String> {
+    let file_hashes: Vec<String> = files.iter()
+        .filter(|file| file.size >= MIN_PRESENCE_CHECK_SIZE)
+        .map(|file| file.file_hash.clone())
+        .collect();
+    if file_hashes.is_empty() {
+        return HashSet::new();
+    }
+    
+    let ask = || -> std::io::Result<Vec<String>> {
+        let mut channel = connect_destination(destination, ctx)?;
+        let query = PacketHeader {
+            packet_type: PACKET_HAS_FILE.to_string(),
+            source: ctx.device_name(),
+            file_hashes,
+            ..Default::default()
+        };
+        write_header(&mut channel, &query, &ctx.signing_key)?;
+        
+        let response = read_header(&mut channel)?;
+        signing::verify_header(&response, None)
+            .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+        if response.packet_type != PACKET_FILES_PRESENT {
+            return Ok(Vec::new());
+        }
+        Ok(response.file_hashes)
+    };
+    ask().unwrap_or_default().into_iter().collect()
+}
+
+// Give up on the path the last connection to a routed device took,
+// returning whether there's another to try
+fn switch_path(destination: &Destination, ctx: &PeerContext) -> bool {
+    let next_hop = destination.next_hop.lock().take();
+    match (&destination.forward_to, next_hop) {
+        (Some(device_id), Some(next_hop)) => routing::fail_over(device_id, &next_hop, ctx),
+        _ => false,
+    }
+}
+
+// Connect to where files are going. Routed devices get the best path that
+// works at the time, unless the user pinned one.
+fn connect_destination(destination: &Destination, ctx: &PeerContext) -> std::io::Result<SecureChannel> {
+    if destination.internet {
+        let recipient = destination.recipient_key
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Destination has no identity key"))?;
+        let server = ctx.settings.lock().rendezvous()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "Internet transfers are off"))?;
+        return wan::connect(&server, &recipient, ctx);
+    }
+    match &destination.forward_to {
+        Some(device_id) if destination.via.is_some() => {
+            let recipient = destination.recipient_key
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Destination has no identity key"))?;
+            routing::connect_via(device_id, destination.via.as_deref().unwrap_or_default(), &recipient, ctx)
+        }
+        Some(device_id) => {
+            let recipient = destination.recipient_key
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Destination has no identity key"))?;
+            let (channel, next_hop) = routing::connect_towards(device_id, &recipient, ctx)?;
+            *destination.next_hop.lock() = next_hop;
+            Ok(channel)
+        }
+        None => net::connect_any(&destination.addresses, destination.port, &ctx.identity_key),
+    }
+}
+
+// Open a connection and send files from `next` onwards, advancing it as
+// each one finishes. Returns false if the recipient refused a file.
+fn send_remaining(
+    files: &[OutgoingFile],
+    next: &mut usize,
+    tokens: &[Arc<CancelToken>],
+    destination: &Destination,
+    ctx: &PeerContext,
+) -> std::io::Result<bool> {
+    // Fresh Noise handshake for every connection
+    let mut channel = connect_destination(destination, ctx)?;
+    
+    while *next < files.len() {
+        let token = &tokens[*next];
+        token.attach(channel.try_clone_stream()?);
+        if !send_one(&mut channel, &files[*next], destination, token, ctx)? {
+            return Ok(false);
+        }
+        if token.is_cancelled() {
+            return Ok(true);
+        }
+        *next += 1;
+    }
+    
+    Ok(true)
+}
+
+// Send a single file's header and body over an open channel
+fn send_one(
+    channel: &mut SecureChannel,
+    file: &OutgoingFile,
+    destination: &Destination,
+    token: &CancelToken,
+    ctx: &PeerContext,
+) -> std::io::Result<bool> {
+    let transfer_id = file.transfer_id.as_str();
+    
+    // Encrypt file end-to-end; fall back to the connected peer's identity
+    // when the destination wasn't discovered via mDNS
+    let recipient_key = destination.recipient_key.unwrap_or(*channel.peer_identity());
+    let (file_key, file_ephemeral) = seal_file_key(&recipient_key)?;
+    
+    // With a password, the body uses a random content key that the
+    // recipient can only unwrap once the password is entered
+    let engine = base64::engine::general_purpose::STANDARD;
+    let (body_key, password_salt, wrapped_key) = match destination.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => {
+            let mut content_key = [0u8; 32];
+            OsRng.fill_bytes(&mut content_key);
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let password_key = derive_password_key(password, &salt)?;
+            let wrapped = wrap_content_key(&content_key, &file_key, &password_key)?;
+            (content_key, engine.encode(salt), engine.encode(wrapped))
+        }
+        None => (file_key, String::new(), String::new()),
+    };
+    
+    // Send header with filename, size, hashes and position in the batch
+    let (batch_id, batch_index, batch_count, batch_size) = match &file.batch {
+        Some(batch) => (batch.id.clone(), batch.index, batch.count, batch.size),
+        None => (String::new(), 0, 1, file.size),
+    };
+    let packet_type = if destination.hold_for.is_some() { PACKET_HOLD_FOR } else { PACKET_FILE_TRANSFER };
+    let header = PacketHeader {
+        packet_type: packet_type.to_string(),
+        source: ctx.device_name(),
+        hold_for: destination.hold_for.clone().unwrap_or_default(),
+        filename: file.filename.clone(),
+        file_size: file.size,
+        file_key: encode_public_key(&file_ephemeral),
+        password_salt,
+        wrapped_key,
+        file_hash: file.file_hash.clone(),
+        chunk_hashes: file.chunk_hashes.clone(),
+        destination: destination.ip.clone(),
+        relative_path: file.relative_path.clone().unwrap_or_default(),
+        sync_folder: destination.sync_folder.clone().unwrap_or_default(),
+        // Only what the recipient said it can do is offered
+        compression: if destination.compression
+            && channel.peer_supports(protocol::COMPRESSION)
+            && compression::worth_compressing(&file.filename)
+        {
+            compression::ZSTD.to_string()
+        } else {
+            String::new()
+        },
+        delta: channel.peer_supports(protocol::DELTA),
+        request_id: match &destination.hold_for {
+            Some(_) => transfer_id.to_string(),
+            None => file.request_id.clone().unwrap_or_default(),
+        },
+        parallel_streams: if channel.peer_supports(protocol::PARALLEL) {
+            ctx.settings.lock().parallel_streams as u64
+        } else {
+            1
+        },
+        batch_id,
+        batch_index,
+        batch_count,
+        batch_size,
+        ..Default::default()
+    };
+    write_header(channel, &header, &ctx.signing_key)?;
+    
+    // Wait for the recipient to approve before streaming the body
+    set_transfer_status(ctx, transfer_id, "Waiting for approval ⏳");
+    let response = read_header(channel)?;
+    if response.packet_type != PACKET_TRANSFER_ACCEPT {
+        let (status, reason) = if response.reason.is_empty() {
+            ("Rejected by recipient 🚫".to_string(), "Declined".to_string())
+        } else {
+            (format!("Rejected by recipient 🚫 ({})", response.reason), response.reason)
+        };
+        fail_transfer(ctx, transfer_id, &status, Error::Rejected(reason));
+        return Ok(false);
+    }
+    set_transfer_status(ctx, transfer_id, "Encrypting & Sending 🔒");
+    
+    // Skip whatever the receiver already has from an earlier attempt
+    let total_chunks = file.chunk_hashes.len() as u64;
+    let first_chunk = response.resume_chunk.min(total_chunks);
+    let sent = (first_chunk * CHUNK_SIZE as u64).min(file.size);
+    let tracker = start_progress(ctx, transfer_id, sent);
+    let progress = Mutex::new(StreamProgress::new(sent, tracker));
+    
+    // Chunks are delta-encoded against the receiver's copy if it has one,
+    // then compressed if the receiver agreed, then encrypted
+    let delta = (response.delta_block_size > 0)
+        .then(|| delta::SignatureIndex::new(response.delta_block_size, &response.delta_signatures));
+    let compressed = response.compression == compression::ZSTD && !header.compression.is_empty();
+    
+    // Open the extra streams the receiver agreed to, if any; a receiver
+    // that doesn't support them leaves us on this one
+    let streams = response.parallel_streams.min(header.parallel_streams).clamp(1, parallel::MAX_STREAMS as u64) as usize;
+    let mut extra_streams = Vec::with_capacity(streams - 1);
+    for stream_index in 1..streams as u64 {
+        let mut extra = connect_destination(destination, ctx)?;
+        let join = PacketHeader {
+            packet_type: PACKET_STREAM_JOIN.to_string(),
+            source: ctx.device_name(),
+            stream_token: response.stream_token.clone(),
+            stream_index,
+            ..Default::default()
+        };
+        write_header(&mut extra, &join, &ctx.signing_key)?;
+        extra_streams.push(extra);
+    }
+    
+    let seal = |index: u64, chunk: &[u8]| {
+        let encoded = match &delta {
+            Some(signatures) => Cow::Owned(delta::encode(chunk, signatures)),
+            None => Cow::Borrowed(chunk),
+        };
+        let payload = if compressed {
+            Cow::Owned(compression::pack(&encoded))
+        } else {
+            encoded
+        };
+        Ok(pipeline::SealedChunk {
+            index,
+            raw_len: chunk.len(),
+            packed_len: payload.len(),
+            sealed: encrypt_chunk(&payload, &body_key, index)?,
+        })
+    };
+    
+    // Send each chunk encrypted on its own, sealed ahead on other cores
+    // while the last is sent; resend when the receiver reports a failed
+    // verification. Every stream carries its own share of the chunks.
+    let channels = std::iter::once(&mut *channel).chain(extra_streams.iter_mut()).collect();
+    let finished = parallel::run_streams(channels, |stream, channel| {
+        let reader = std::fs::File::open(&file.path)?;
+        let chunks = parallel::stripe(first_chunk, total_chunks, streams, stream);
+        pipeline::seal_ahead(reader, chunks, &seal, |chunk| {
+            if token.is_cancelled() {
+                return Ok(false);
+            }
+            loop {
+                ctx.throttle.consume(transfer_id, chunk.sealed.len());
+                channel.send(&chunk.sealed)?;
+                
+                let reply = channel.recv()?;
+                match reply.first().copied().unwrap_or(CHUNK_ABORT) {
+                    CHUNK_ACK => break,
+                    CHUNK_NACK => eprintln!("Resending chunk {} of {}", chunk.index, file.filename),
+                    _ => {
+                        fail_transfer(
+                            ctx,
+                            transfer_id,
+                            &format!("Failed ❌ (Chunk {} corrupted)", chunk.index),
+                            Error::Corrupted(format!("Chunk {} corrupted", chunk.index)),
+                        );
+                        return Ok(false);
+                    }
+                }
+            }
+            progress.lock().add(chunk.raw_len, chunk.packed_len);
+            Ok(true)
+        })
+    })?;
+    if !finished {
+        return Ok(true);
+    }
+    
+    if compressed || delta.is_some() {
+        let progress = progress.into_inner();
+        set_compression_ratio(ctx, transfer_id, progress.packed_bytes, progress.raw_bytes);
+    }
+    
+    // Only call it done once the receiver has checked and saved the file;
+    // older receivers don't say, so all we know is that it was sent
+    if !response.receipt {
+        complete_transfer(ctx, transfer_id, "Completed ✅ (Encrypted)");
+        return Ok(true);
+    }
+    set_transfer_status(ctx, transfer_id, "Verifying 🔍");
+    let receipt = read_header(channel)?;
+    if receipt.packet_type != PACKET_TRANSFER_RECEIPT {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Expected a transfer receipt"));
+    }
+    match receipt.result.as_str() {
+        RECEIPT_VERIFIED => complete_transfer(ctx, transfer_id, "Completed ✅ (Delivered & verified)"),
+        RECEIPT_HELD => {
+            // The relay reports back once it has passed the file on
+            let relay_key = encode_public_key(channel.peer_identity());
+            ctx.relayed.lock().insert(transfer_id.to_string(), relay_key);
+            set_transfer_status(ctx, transfer_id, "Held by relay 📦");
+        }
+        other => fail_receipt(ctx, transfer_id, other),
+    }
+    
+    Ok(true)
+}
+
+// Send files to several discovered devices at once. Each file is read and
+// hashed once; every device then gets its own connection and its own
+// encryption pass, since files are sealed to the recipient's key.
+#[tauri::command]
+async fn send_file_to_many(
+    paths: Vec<String>,
+    targets: Vec<String>,
+    compression: Option<bool>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Broadcast, Error> {
+    if paths.is_empty() || targets.is_empty() {
+        return Err(Error::InvalidInput("Nothing to send".to_string()));
+    }
+    let devices: Vec<Device> = {
+        let known = state.devices.lock();
+        targets.iter()
+            .map(|id| known.get(id).cloned().ok_or_else(|| format!("Unknown device: {}", id)))
+            .collect::<Result<_, _>>()?
+    };
+    
+    let mut total_size = 0;
+    for path in &paths {
+        total_size += std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    }
+    
+    // One batch per device, so each shows up like an ordinary batch
+    let mut sends = Vec::new();
+    let mut broadcast_targets = Vec::new();
+    for device in devices {
+        let destination = state.destination(device.ip.clone(), device.port, None, compression.unwrap_or(false));
+        let batch = BatchTransfer {
+            id: Uuid::new_v4().to_string(),
+            transfer_ids: Vec::new(),
+            file_count: paths.len() as u64,
+            total_size,
+            from_device: THIS_DEVICE.to_string(),
+            to_device: destination.ip.clone(),
+        };
+        state.batches.lock().insert(batch.id.clone(), batch.clone());
+        broadcast_targets.push(BroadcastTarget {
+            device_id: device.id,
+            device_name: device.name,
+            batch_id: batch.id.clone(),
+            status: "Queued ⏳".to_string(),
+        });
+        sends.push((batch.id, destination));
+    }
+    let broadcast = Broadcast {
+        id: Uuid::new_v4().to_string(),
+        file_count: paths.len() as u64,
+        total_size,
+        targets: broadcast_targets,
+    };
+    state.broadcasts.lock().push(broadcast.clone());
+    
+    let ctx = state.peer_context(app);
+    state.tasks.spawn(tasks::Budget::Send, move || {
+        // Hash for the first device, then copy the results for the rest
+        let (first_batch, first_destination) = &sends[0];
+        let mut originals = Vec::new();
+        for path in paths {
+            match queue_outgoing(path, None, Some(first_batch), first_destination, &ctx) {
+                Ok(file) => originals.push(file),
+                Err(e) => eprintln!("Skipping file in broadcast: {}", e),
+            }
+        }
+        
+        for (i, (batch_id, destination)) in sends.into_iter().enumerate() {
+            let mut files: Vec<OutgoingFile> = if i == 0 {
+                std::mem::take(&mut originals)
+            } else {
+                originals.iter().map(|file| file.copy_to(Some(&batch_id), &destination, &ctx)).collect()
+            };
+            let count = files.len() as u64;
+            for (index, file) in files.iter_mut().enumerate() {
+                file.batch = Some(BatchInfo { id: batch_id.clone(), index: index as u64, count, size: total_size });
+            }
+            
+            let ctx = ctx.clone();
+            ctx.tasks.clone().spawn(tasks::Budget::Send, move || {
+                if let Err(e) = send_file_internal(files, destination, ctx) {
+                    eprintln!("Error sending to {}: {}", batch_id, e);
+                }
+            });
+        }
+    });
+    
+    Ok(broadcast)
+}
+
+// Get broadcasts, with where each device's batch is up to
+#[tauri::command]
+fn get_broadcasts(state: State<'_, AppState>) -> Result<Vec<Broadcast>, Error> {
+    let mut broadcasts = state.broadcasts.lock().clone();
+    let transfers = state.transfers.lock();
+    for target in broadcasts.iter_mut().flat_map(|b| b.targets.iter_mut()) {
+        // The first file that hasn't completed says where the batch is
+        let mut statuses = transfers.iter()
+            .filter(|t| t.batch_id.as_deref() == Some(target.batch_id.as_str()))
+            .map(|t| t.status.as_str())
+            .peekable();
+        if statuses.peek().is_some() {
+            target.status = statuses
+                .find(|s| !s.starts_with("Completed"))
+                .unwrap_or("Completed ✅")
+                .to_string();
+        }
+    }
+    Ok(broadcasts)
+}
+
+// Send a file later: at `at` (Unix seconds), or as soon as the device is
+// online if no time is given. Either way the device has to be online for
+// the send to start. `target` is a discovered device's id or a paired
+// device's key, so paired devices can be picked while they're offline.
+#[tauri::command]
+fn schedule_send(
+    file_path: String,
+    target: String,
+    at: Option<i64>,
+    compression: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<ScheduledSend, Error> {
+    if !std::path::Path::new(&file_path).is_file() {
+        return Err(Error::NotFound(format!("Not a file: {}", file_path)));
+    }
+    let (key, name) = state.resolve_target(&target)?;
+    
+    let entry = ScheduledSend {
+        id: Uuid::new_v4().to_string(),
+        path: file_path,
+        target: key,
+        target_name: name,
+        send_at: at,
+        compression: compression.unwrap_or(false),
+        created_at: chrono::Local::now().to_rfc3339(),
+        attempts: 0,
+        sending: false,
+    };
+    let mut outbox = state.outbox.lock();
+    outbox.push(entry.clone());
+    outbox::save_outbox(&outbox)?;
+    Ok(entry)
+}
+
+// Sends still waiting in the outbox
+#[tauri::command]
+fn get_scheduled_sends(state: State<'_, AppState>) -> Result<Vec<ScheduledSend>, Error> {
+    Ok(state.outbox.lock().clone())
+}
+
+// Drop a send from the outbox, before it starts or before it's retried
+#[tauri::command]
+fn cancel_scheduled_send(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut outbox = state.outbox.lock();
+    let before = outbox.len();
+    outbox.retain(|entry| entry.id != id);
+    if outbox.len() == before {
+        return Err(Error::NotFound("Scheduled send not found".to_string()));
+    }
+    outbox::save_outbox(&outbox).map_err(Error::from)
+}
+
+// Keep `local_path` in step with a folder on a paired device: what's
+// added, changed or deleted in it is mirrored to `remote_folder` under the
+// device's Downloads, by default the local folder's name, and with
+// `two_way` the other way as well. `target` is as for `schedule_send`.
+// The device only lets the pair list, fetch from or delete in a remote
+// folder its first sync made, or one the device has a pair of its own
+// for with us, so `remote_folder` shouldn't be there already.
+#[tauri::command]
+fn add_sync_pair(
+    local_path: String,
+    target: String,
+    remote_folder: Option<String>,
+    two_way: Option<bool>,
+    conflict_policy: Option<ConflictPolicy>,
+    state: State<'_, AppState>,
+) -> Result<SyncStatus, Error> {
+    let root = std::path::Path::new(&local_path);
+    if !root.is_dir() {
+        return Err(Error::NotFound(format!("Not a folder: {}", local_path)));
+    }
+    let (key, name) = state.resolve_target(&target)?;
+    if !state.trusted_devices.lock().contains_key(&key) {
+        return Err(Error::InvalidInput("Only paired devices can be synced with".to_string()));
+    }
+    let remote_folder = match remote_folder {
+        Some(folder) => folder,
+        None => root.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| Error::InvalidInput("Invalid folder path".to_string()))?
+            .to_string(),
+    };
+    if download_path_for(&remote_folder).is_none() {
+        return Err(Error::InvalidInput("Invalid remote folder".to_string()));
+    }
+    
+    let mode = if two_way.unwrap_or(false) { SyncMode::TwoWay } else { SyncMode::OneWay };
+    let pair = SyncPair::new(local_path, key, name, remote_folder, mode, conflict_policy.unwrap_or_default());
+    let status = pair.status();
+    let mut pairs = state.sync_pairs.lock();
+    pairs.push(pair);
+    sync::save_pairs(&pairs)?;
+    Ok(status)
+}
+
+// Stop syncing a folder; what was already synced stays on both devices
+#[tauri::command]
+fn remove_sync_pair(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut pairs = state.sync_pairs.lock();
+    let before = pairs.len();
+    pairs.retain(|pair| pair.id != id);
+    if pairs.len() == before {
+        return Err(Error::NotFound("Sync pair not found".to_string()));
+    }
+    sync::save_pairs(&pairs).map_err(Error::from)
+}
+
+// Every sync pair and how it's doing
+#[tauri::command]
+fn get_sync_status(state: State<'_, AppState>) -> Result<Vec<SyncStatus>, Error> {
+    Ok(state.sync_pairs.lock().iter().map(SyncPair::status).collect())
+}
+
+// Change which files of a pair's folder are synced. Files the rules now
+// leave out stay as they are on both devices.
+#[tauri::command]
+fn update_sync_rules(id: String, rules: FileRules, state: State<'_, AppState>) -> Result<SyncStatus, Error> {
+    let mut pairs = state.sync_pairs.lock();
+    let pair = pairs.iter_mut()
+        .find(|pair| pair.id == id)
+        .ok_or_else(|| Error::NotFound("Sync pair not found".to_string()))?;
+    pair.rules = rules;
+    let status = pair.status();
+    sync::save_pairs(&pairs)?;
+    Ok(status)
+}
+
+// Change how a two-way pair settles files changed on both ends
+#[tauri::command]
+fn set_sync_conflict_policy(id: String, policy: ConflictPolicy, state: State<'_, AppState>) -> Result<SyncStatus, Error> {
+    let mut pairs = state.sync_pairs.lock();
+    let pair = pairs.iter_mut()
+        .find(|pair| pair.id == id)
+        .ok_or_else(|| Error::NotFound("Sync pair not found".to_string()))?;
+    pair.conflict_policy = policy;
+    let status = pair.status();
+    sync::save_pairs(&pairs)?;
+    Ok(status)
+}
+
+// Settle a conflict the user was asked about, and sync the pair with it
+#[tauri::command]
+fn resolve_sync_conflict(
+    id: String,
+    path: String,
+    resolution: Resolution,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), Error> {
+    {
+        let mut pairs = state.sync_pairs.lock();
+        let conflict = pairs.iter_mut()
+            .find(|pair| pair.id == id)
+            .and_then(|pair| pair.conflicts.iter_mut().find(|conflict| conflict.path == path))
+            .ok_or_else(|| Error::NotFound("Sync conflict not found".to_string()))?;
+        conflict.resolution = Some(resolution);
+        sync::save_pairs(&pairs)?;
+    }
+    // Busy pairs pick it up on their next rescan
+    sync::start(&app, &id);
+    Ok(())
+}
+
+// Leave a file with a relay for a device that's offline; the relay sends it
+// on once the device is back. `target` is as for `schedule_send`, and
+// `relay` a discovered device with relaying turned on.
+#[tauri::command]
+async fn send_via_relay(
+    file_path: String,
+    target: String,
+    relay: String,
+    compression: Option<bool>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, Error> {
+    let (target_key, _) = state.resolve_target(&target)?;
+    let recipient_key = decode_public_key(&target_key).ok_or_else(|| Error::Unsupported("Device has no identity key".to_string()))?;
+    let relay = state.devices.lock().get(&relay).cloned().ok_or_else(|| Error::NotFound("Unknown relay".to_string()))?;
+    
+    // Sealed to the recipient, so the relay never sees the contents
+    let destination = Destination {
+        addresses: net::device_addresses(&relay),
+        ip: relay.ip,
+        port: relay.port,
+        recipient_key: Some(recipient_key),
+        password: None,
+        compression: compression.unwrap_or(false),
+        hold_for: Some(target_key),
+        forward_to: None,
+        next_hop: Mutex::new(None),
+        via: None,
+        internet: false,
+        exact_paths: false,
+        sync_folder: None,
+    };
+    let ctx = state.peer_context(app);
+    state.tasks.spawn(tasks::Budget::Send, move || {
+        let result = queue_outgoing(file_path, None, None, &destination, &ctx)
+            .and_then(|file| send_file_internal(vec![file], destination, ctx));
+        if let Err(e) = result {
+            eprintln!("Error leaving file with relay: {}", e);
+        }
+    });
+    
+    Ok("Handing file to relay 📦".to_string())
+}
+
+// Hold files for paired devices that are offline, using up to `quota` bytes
+// of disk. Takes effect for discovery the next time it starts.
+#[tauri::command]
+fn set_relay(enabled: bool, quota: u64, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.relay_enabled = enabled;
+    settings.relay_quota = quota;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Files we're holding for other devices
+#[tauri::command]
+fn get_held_files(state: State<'_, AppState>) -> Result<Vec<HeldFile>, Error> {
+    Ok(state.held_files.lock().clone())
+}
+
+// Deliver held files whose recipient is online, and report back to
+// senders once they are
+fn run_relay(app: AppHandle) {
+    loop {
+        thread::sleep(relay::RETRY_INTERVAL);
+        let state = app.state::<AppState>();
+        let now = chrono::Utc::now().timestamp();
+        let online: HashMap<String, Device> = state.devices.lock()
+            .values()
+            .filter(|d| !d.public_key.is_empty())
+            .map(|d| (d.public_key.clone(), d.clone()))
+            .collect();
+        
+        let mut work = Vec::new();
+        for held in state.held_files.lock().iter_mut().filter(|h| !h.busy) {
+            if held.outcome.is_none() && held.is_expired(now) {
+                held.outcome = Some(RECEIPT_EXPIRED.to_string());
+                relay::discard_body(&held.id);
+                let _ = relay::save_held(held);
+            }
+            let peer = if held.outcome.is_none() { &held.recipient } else { &held.sender };
+            if let Some(device) = online.get(peer) {
+                held.busy = true;
+                work.push((held.clone(), device.clone()));
+            }
+        }
+        
+        for (held, device) in work {
+            let ctx = state.peer_context(app.clone());
+            state.tasks.spawn(tasks::Budget::Send, move || {
+                if held.outcome.is_none() {
+                    let delivered = deliver_held(&held, &device, &ctx);
+                    let mut all = ctx.held_files.lock();
+                    let Some(entry) = all.iter_mut().find(|h| h.id == held.id) else {
+                        return;
+                    };
+                    entry.busy = false;
+                    match delivered {
+                        Ok((outcome, reason)) => {
+                            entry.outcome = Some(outcome);
+                            entry.reason = reason;
+                            relay::discard_body(&entry.id);
+                            let _ = relay::save_held(entry);
+                            let _ = ctx.app.emit("relay://delivered", &*entry);
+                            drop(all);
+                            if let Err(e) = ctx.history.lock().record_relayed(&held.sender_name, held.size) {
+                                eprintln!("Failed to save relay statistics: {}", e);
+                            }
+                        }
+                        Err(e) => eprintln!("Could not deliver held file {}: {}", held.filename, e),
+                    }
+                } else {
+                    let sent = send_delivery_receipt(&held, &device, &ctx);
+                    let mut all = ctx.held_files.lock();
+                    match sent {
+                        Ok(()) => {
+                            relay::remove(&held.id);
+                            all.retain(|h| h.id != held.id);
+                        }
+                        Err(e) => {
+                            eprintln!("Could not send delivery receipt for {}: {}", held.filename, e);
+                            if let Some(entry) = all.iter_mut().find(|h| h.id == held.id) {
+                                entry.busy = false;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Send a file to a device, discovered or paired, going through another
+// device when it can't be reached directly
+#[tauri::command]
+async fn send_to_device(
+    file_path: String,
+    target: String,
+    password: Option<String>,
+    compression: Option<bool>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, Error> {
+    let (target_key, target_name) = state.resolve_target(&target)?;
+    let discovered = state.devices.lock()
+        .values()
+        .find(|d| d.public_key == target_key)
+        .map(|d| d.id.clone());
+    let target_id = discovered
+        .or_else(|| state.trusted_devices.lock().get(&target_key).map(|d| d.device_id.clone()))
+        .filter(|id| !id.is_empty());
+    let internet = state.trusted_devices.lock().contains_key(&target_key)
+        && state.settings.lock().rendezvous().is_some();
+    
+    let ctx = state.peer_context(app);
+    let path = target_id.as_ref().and_then(|id| routing::choose_paths(id, &ctx).into_iter().next());
+    let (destination, status) = match path {
+        Some(routing::Path::Direct(device)) => (
+            state.destination(device.ip, device.port, password, compression.unwrap_or(false)),
+            "Encrypted transfer started 🔒".to_string(),
+        ),
+        Some(routing::Path::Via(route, next_hop)) => {
+            let destination = Destination {
+                addresses: net::device_addresses(&next_hop),
+                ip: next_hop.ip,
+                port: next_hop.port,
+                recipient_key: decode_public_key(&target_key),
+                password,
+                compression: compression.unwrap_or(false),
+                hold_for: None,
+                forward_to: target_id,
+                next_hop: Mutex::new(None),
+                via: None,
+                internet: false,
+                exact_paths: false,
+                sync_folder: None,
+            };
+            let status = format!("Encrypted transfer started via {} ({} hops) 🔁", next_hop.name, route.hops);
+            (destination, status)
+        }
+        // Paired devices can still be reached over the internet
+        None if internet => {
+            let destination = Destination {
+                addresses: Vec::new(),
+                ip: target_name,
+                port: 0,
+                recipient_key: decode_public_key(&target_key),
+                password,
+                compression: compression.unwrap_or(false),
+                hold_for: None,
+                forward_to: None,
+                next_hop: Mutex::new(None),
+                via: None,
+                internet: true,
+                exact_paths: false,
+                sync_folder: None,
+            };
+            (destination, "Encrypted transfer started over the internet 🌍".to_string())
+        }
+        None => return Err(Error::PeerOffline(format!("No route to {}", target_name))),
+    };
+    
+    state.tasks.spawn(tasks::Budget::Send, move || {
+        let result = queue_outgoing(file_path, None, None, &destination, &ctx)
+            .and_then(|file| send_file_internal(vec![file], destination, ctx));
+        if let Err(e) = result {
+            eprintln!("Error sending file: {}", e);
+        }
+    });
+    
+    Ok(status)
+}
+
+// Bridge the network segments we're on. Takes effect the next time
+// discovery starts, since that's when we announce ourselves.
+#[tauri::command]
+fn set_gateway_mode(enabled: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.gateway_mode = enabled;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Join the network group with this passphrase, or leave groups with an
+// empty one. Devices from before are dropped from the list, and discovery
+// starts over in the new group.
+#[tauri::command]
+fn set_network_group(passphrase: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    group::join(&passphrase)?;
+    {
+        let mut settings = state.settings.lock();
+        settings.network_group = passphrase;
+        settings::save_settings(&settings)?;
+    }
+    let lost: Vec<Device> = {
+        let mut devices = state.devices.lock();
+        let lost = devices.values().filter(|d| !d.manual).cloned().collect();
+        devices.retain(|_, d| d.manual);
+        lost
+    };
+    for device in &lost {
+        events::emit_device_lost(&app, device);
+    }
+    restart_discovery(app, &state)
+}
+
+// Rename this device. Discovery starts over under the new name, and
+// devices that already know us pick it up from their next heartbeat.
+#[tauri::command]
+fn set_device_name(name: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::InvalidInput("Device name can't be empty".to_string()));
+    }
+    // The name is part of our mDNS instance name, which has to fit in a
+    // DNS label along with the group tag
+    if name.len() > MAX_DEVICE_NAME {
+        return Err(Error::InvalidInput(format!("Device name can be at most {} bytes", MAX_DEVICE_NAME)));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(Error::InvalidInput("Device name can't contain control characters".to_string()));
+    }
+    {
+        let mut settings = state.settings.lock();
+        settings.device_name = name.clone();
+        settings::save_settings(&settings)?;
+    }
+    *state.device_name.lock() = name;
+    restart_discovery(app, &state)
+}
+
+// Choose the icon other devices show for us, one of DEVICE_ICONS
+#[tauri::command]
+fn set_device_icon(kind: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    if !DEVICE_ICONS.contains(&kind.as_str()) {
+        return Err(Error::InvalidInput(format!("Unknown device icon: {}", kind)));
+    }
+    {
+        let mut settings = state.settings.lock();
+        settings.device_icon = kind;
+        settings::save_settings(&settings)?;
+    }
+    restart_discovery(app, &state)
+}
+
+// Send to paired devices outside the LAN through the rendezvous server
+// at `server` (host:port), and be reachable through it
+#[tauri::command]
+fn set_internet_transfers(enabled: bool, server: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let server = server.trim().to_string();
+    let has_port = server.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if enabled && !has_port {
+        return Err(Error::InvalidInput("Rendezvous server must be given as host:port".to_string()));
+    }
+    let mut settings = state.settings.lock();
+    settings.internet_transfers = enabled;
+    settings.rendezvous_server = server;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Forward connections between other devices, within the given limits
+#[tauri::command]
+fn set_relay_policy(
+    allow_relaying: bool,
+    trusted_only: bool,
+    max_transfers: usize,
+    bandwidth_limit: u64,
+    state: State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.allow_relaying = allow_relaying;
+    settings.relay_trusted_only = trusted_only;
+    settings.max_relayed_transfers = max_transfers;
+    settings.relay_bandwidth_limit = bandwidth_limit;
+    state.throttle.set_transfer_limit(routing::RELAY_THROTTLE_KEY, Some(bandwidth_limit));
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Traffic forwarded for other devices since startup
+#[tauri::command]
+fn get_relay_stats(state: State<'_, AppState>) -> Result<RelayStats, Error> {
+    Ok(state.relay_stats.lock().clone())
+}
+
+// Devices reachable only through others, and who to go through
+#[tauri::command]
+fn get_routes(state: State<'_, AppState>) -> Result<Vec<routing::RouteInfo>, Error> {
+    let devices = state.devices.lock();
+    let trusted = state.trusted_devices.lock();
+    let routes = state.routes.lock();
+    Ok(routes.routes().map(|route| routing::describe(route, &devices, &trusted)).collect())
+}
+
+// Every device we know of, the links between them and the paths we'd
+// take, for drawing the mesh
+#[tauri::command]
+fn get_network_topology(state: State<'_, AppState>) -> Result<topology::Topology, Error> {
+    let links = state.links.lock().clone();
+    let devices = state.devices.lock();
+    let trusted = state.trusted_devices.lock();
+    let routes = state.routes.lock();
+    Ok(topology::build(&state.device_id, &state.device_name(), &routes, &devices, &links, &trusted))
+}
+
+// Our addresses before and after a change of network
+#[derive(Debug, Clone, Serialize)]
+struct NetworkChange {
+    addresses: Vec<String>,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+// Watch our addresses, and when they change, as when moving from Wi-Fi to
+// Ethernet, announce ourselves again under the new ones and have the
+// router forward our port on the network we're on now. The file server
+// listens on every address at once, so it goes on without rebinding.
+fn run_network_watch(app: AppHandle) {
+    let mut current: HashSet<std::net::IpAddr> = net::own_addresses(&net::local_interfaces()).into_iter().collect();
+    loop {
+        thread::sleep(net::NETWORK_POLL);
+        let own_addresses = net::own_addresses(&net::local_interfaces());
+        let addresses: HashSet<std::net::IpAddr> = own_addresses.iter().copied().collect();
+        if addresses == current {
+            continue;
+        }
+        let change = NetworkChange {
+            addresses: own_addresses.iter().map(|ip| ip.to_string()).collect(),
+            added: addresses.difference(&current).map(|ip| ip.to_string()).collect(),
+            removed: current.difference(&addresses).map(|ip| ip.to_string()).collect(),
+        };
+        current = addresses;
+        println!("🔀 Network changed, now on {}", change.addresses.join(", "));
+        
+        // With no address at all there's nothing to announce; we do so
+        // once we're on a network again
+        let state = app.state::<AppState>();
+        if !current.is_empty() {
+            if let Err(e) = restart_discovery(app.clone(), &state) {
+                eprintln!("Failed to announce on the new network: {}", e);
+            }
+            if let Some(remap) = &state.port_mapping.lock().remap {
+                let _ = remap.send(());
+            }
+        }
+        let _ = app.emit("network://changed", &change);
+    }
+}
+
+// Ping every device we know of, and update whether each is available,
+// stale or offline
+fn run_heartbeats(app: AppHandle) {
+    loop {
+        let state = app.state::<AppState>();
+        let policy = state.settings.lock().heartbeat;
+        thread::sleep(policy.interval());
+        let ctx = state.peer_context(app.clone());
+        
+        let now = chrono::Utc::now().timestamp();
+        let (targets, changes) = {
+            let mut devices = state.devices.lock();
+            let changes = heartbeat::update_statuses(&mut devices, &policy, now);
+            let offline = known::offline(&state.known_devices.lock(), &devices);
+            let targets: Vec<Device> = devices.values()
+                .cloned()
+                .chain(offline)
+                .filter(|d| !d.public_key.is_empty() && !d.ip.is_empty())
+                .collect();
+            (targets, changes)
+        };
+        for device in &changes.changed {
+            let _ = app.emit(events::DEVICE_UPDATED, device);
+        }
+        for device in changes.gone {
+            println!("💤 {} stopped answering", device.name);
+            routing::forget_device(&mut state.routes.lock(), &device.id);
+            events::emit_device_lost(&app, &device);
+        }
+        
+        for device in targets {
+            let ctx = ctx.clone();
+            ctx.tasks.clone().spawn(tasks::Budget::Request, move || {
+                if let Ok(identity) = heartbeat::ping(&device, &ctx) {
+                    let now = chrono::Utc::now().timestamp();
+                    let device = heartbeat::identified(device, &identity);
+                    rename_trusted(&device.public_key, &device.name, &ctx);
+                    let (before, heard) = heartbeat::heard_from(&mut ctx.devices.lock(), &device, now);
+                    events::emit_device(&ctx.app, before.as_ref(), &heard);
+                    known::remember(&mut ctx.known_devices.lock(), &device);
+                }
+            });
+        }
+    }
+}
+
+// How often devices are pinged and when they count as stale or offline
+#[tauri::command]
+fn set_heartbeat_policy(policy: HeartbeatPolicy, state: State<'_, AppState>) -> Result<(), Error> {
+    if policy.interval_secs == 0 {
+        return Err(Error::InvalidInput("Heartbeat interval must be at least a second".to_string()));
+    }
+    if policy.stale_after_secs > policy.offline_after_secs {
+        return Err(Error::InvalidInput("Devices have to go stale before they go offline".to_string()));
+    }
+    let mut settings = state.settings.lock();
+    settings.heartbeat = policy;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Stay registered with the rendezvous server while internet transfers are
+// on, taking the sessions paired devices open to us through it
+fn run_internet(app: AppHandle) {
+    loop {
+        let state = app.state::<AppState>();
+        let server = state.settings.lock().rendezvous();
+        if let Some(server) = server {
+            let ctx = state.peer_context(app.clone());
+            let settings = state.settings.clone();
+            let wanted = || settings.lock().rendezvous().as_ref() == Some(&server);
+            if let Err(e) = wan::serve(&server, &wanted, &ctx) {
+                eprintln!("Rendezvous server {}: {}", server, e);
+            }
+        }
+        thread::sleep(wan::RECONNECT_INTERVAL);
+    }
+}
+
+// Measure the link to every device we see directly and exchange routes
+// with it, dropping the ones that have gone stale
+fn run_routing(app: AppHandle) {
+    // Neighbours whose exchange hasn't finished yet, skipped until it has
+    let in_flight: Arc<Mutex<HashSet<String>>> = Arc::default();
+    loop {
+        thread::sleep(routing::ROUTE_INTERVAL);
+        let state = app.state::<AppState>();
+        let neighbours: Vec<Device> = {
+            let devices = state.devices.lock();
+            let links = state.links.lock().clone();
+            let now = chrono::Utc::now().timestamp();
+            routing::expire_routes(&mut state.routes.lock(), &devices, &links, now);
+            devices.values().filter(|d| !d.public_key.is_empty()).cloned().collect()
+        };
+        
+        // One task per neighbour, so one that doesn't answer holds up none
+        // of the others
+        for neighbour in neighbours {
+            if !in_flight.lock().insert(neighbour.id.clone()) {
+                continue;
+            }
+            let ctx = state.peer_context(app.clone());
+            let in_flight = in_flight.clone();
+            state.tasks.spawn(tasks::Budget::Request, move || {
+                links::probe_link(&neighbour, &ctx);
+                if let Err(e) = routing::exchange_routes(&neighbour, &ctx) {
+                    eprintln!("Route exchange with {} failed: {}", neighbour.name, e);
+                }
+                in_flight.lock().remove(&neighbour.id);
+            });
+        }
+    }
+}
+
+// Pass running transfers' progress on to the frontend, every
+// PROGRESS_INTERVAL for all that moved, however fast their chunks go
+fn run_progress_reporter(app: AppHandle) {
+    let mut reporter = progress::Reporter::default();
+    loop {
+        thread::sleep(events::PROGRESS_INTERVAL);
+        let state = app.state::<AppState>();
+        let moved = reporter.tick(&state.live_progress);
+        if moved.is_empty() {
+            continue;
+        }
+        let updates: Vec<TransferUpdate> = {
+            let mut transfers = state.transfers.lock();
+            moved.iter()
+                .filter_map(|(transfer_id, live)| {
+                    // Finished in the meantime, with its outcome sent already
+                    let t = transfers.iter_mut().find(|t| t.id == *transfer_id && t.finished_at.is_none())?;
+                    apply_live_progress(t, live);
+                    Some(TransferUpdate::from(&*t))
+                })
+                .collect()
+        };
+        events::emit_updates(&app, &updates);
+    }
+}
+
+// Start outbox entries that are due and whose device is online
+fn run_outbox(app: AppHandle) {
+    loop {
+        thread::sleep(outbox::CHECK_INTERVAL);
+        let state = app.state::<AppState>();
+        let now = chrono::Utc::now().timestamp();
+        
+        let due = {
+            let devices = state.devices.lock();
+            let mut outbox = state.outbox.lock();
+            let mut due = Vec::new();
+            for entry in outbox.iter_mut().filter(|entry| entry.is_due(now)) {
+                if let Some(device) = devices.values().find(|d| d.public_key == entry.target) {
+                    entry.sending = true;
+                    due.push((entry.clone(), device.clone()));
+                }
+            }
+            due
+        };
+        
+        for (entry, device) in due {
+            let _ = app.emit("outbox://sending", &entry);
+            let destination = state.destination(device.ip, device.port, None, entry.compression);
+            let ctx = state.peer_context(app.clone());
+            state.tasks.spawn(tasks::Budget::Send, move || {
+                let sent = queue_outgoing(entry.path.clone(), None, None, &destination, &ctx).and_then(|file| {
+                    let transfer_id = file.transfer_id.clone();
+                    send_file_internal(vec![file], destination, ctx.clone()).map(|_| transfer_id)
+                });
+                // Done with once the file arrived, or the user stopped it
+                let done = match sent {
+                    Ok(transfer_id) => ctx.transfers.lock()
+                        .iter()
+                        .find(|t| t.id == transfer_id)
+                        .is_some_and(|t| {
+                            let cancelled = t.error.as_ref().is_some_and(|e| e.code == Error::Cancelled.code());
+                            t.finished_at.is_some() && (t.error.is_none() || cancelled)
+                        }),
+                    Err(e) => {
+                        eprintln!("Error sending scheduled file: {}", e);
+                        false
+                    }
+                };
+                settle_scheduled_send(&ctx.app, &entry.id, done);
+            });
+        }
+    }
+}
+
+// Take a scheduled send out of the outbox once it's done with, or put it
+// off to be tried again
+fn settle_scheduled_send(app: &AppHandle, id: &str, done: bool) {
+    let state = app.state::<AppState>();
+    let mut outbox = state.outbox.lock();
+    if done {
+        outbox.retain(|entry| entry.id != id);
+    } else if let Some(entry) = outbox.iter_mut().find(|entry| entry.id == id) {
+        entry.retry_later(chrono::Utc::now().timestamp());
+        let _ = app.emit("outbox://retrying", &*entry);
+    }
+    if let Err(e) = outbox::save_outbox(&outbox) {
+        eprintln!("Failed to save outbox: {}", e);
+    }
+}
+
+// Get batches, for grouping transfers in the history
+#[tauri::command]
+fn get_batches(state: State<'_, AppState>) -> Result<Vec<BatchTransfer>, Error> {
+    let batches = state.batches.lock();
+    Ok(batches.values().cloned().collect())
+}
+
+// Get transfer history
+#[tauri::command]
+fn get_transfers(state: State<'_, AppState>) -> Result<Vec<FileTransfer>, Error> {
+    let mut transfers = state.transfers.lock().clone();
+    let live = state.live_progress.read();
+    for transfer in &mut transfers {
+        if let Some(live) = live.get(&transfer.id) {
+            apply_live_progress(transfer, live);
+        }
+    }
+    Ok(transfers)
+}
+
+// Finished transfers from the saved history, newest first, a page at a time
+#[tauri::command]
+fn get_transfer_history(
+    filter: Option<HistoryFilter>,
+    page: Option<u32>,
+    state: State<'_, AppState>,
+) -> Result<HistoryPage, Error> {
+    let history = state.history.lock();
+    history.page(&filter.unwrap_or_default(), page.unwrap_or(0)).map_err(Error::from)
+}
+
+// Forget every finished transfer, both saved and in memory
+#[tauri::command]
+fn clear_history(app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    state.history.lock().clear()?;
+    remove_transfers(&app, &state, |t| t.finished_at.is_some());
+    Ok(())
+}
+
+// Take a finished transfer off the list, which is how a failure is
+// dismissed. It stays in the history.
+#[tauri::command]
+fn remove_transfer(transfer_id: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    let finished = state.transfers.lock()
+        .iter()
+        .find(|t| t.id == transfer_id)
+        .map(|t| t.finished_at.is_some())
+        .ok_or_else(|| Error::NotFound("Unknown transfer".to_string()))?;
+    if !finished {
+        return Err(Error::InvalidInput("Transfer hasn't finished; cancel it first".to_string()));
+    }
+    remove_transfers(&app, &state, |t| t.id == transfer_id);
+    Ok(())
+}
+
+// Take every completed transfer off the list, leaving failures and those
+// still running
+#[tauri::command]
+fn clear_completed_transfers(app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    remove_transfers(&app, &state, |t| t.finished_at.is_some() && !transfer_failed(t));
+    Ok(())
+}
+
+// Earlier versions kept of a file another device overwrote or a sync
+// deleted, newest first
+#[tauri::command]
+fn list_file_versions(path: String) -> Result<Vec<versions::FileVersion>, Error> {
+    Ok(versions::list(std::path::Path::new(&path))?)
+}
+
+// Put a kept version back in place of a file; what's there now is kept as
+// a version in turn
+#[tauri::command]
+fn restore_version(path: String, id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let versions_kept = state.settings.lock().versions_kept;
+    Ok(versions::restore(std::path::Path::new(&path), &id, versions_kept)?)
+}
+
+// How many earlier versions are kept of each overwritten file
+#[tauri::command]
+fn set_versions_kept(count: usize, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.versions_kept = count;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Give a received program or script its real name, once the user has
+// confirmed they want it
+#[tauri::command]
+fn confirm_risky_file(transfer_id: String, app: AppHandle, state: State<'_, AppState>) -> Result<String, Error> {
+    let unconfirmed = received_file_path(&transfer_id, &state)?;
+    let confirmed = screening::confirmed_path(&unconfirmed)
+        .ok_or_else(|| Error::InvalidInput("File doesn't need confirming".to_string()))?;
+    let versions_kept = state.settings.lock().versions_kept;
+    versions::save(&confirmed, versions_kept)?;
+    resume::commit(&unconfirmed, &confirmed)?;
+    
+    let saved = confirmed.to_string_lossy().into_owned();
+    resaved(&app, &state, &transfer_id, "Completed ✅ (Confirmed)", &saved);
+    Ok(saved)
+}
+
+// Whether incoming programs and scripts need confirming, and which
+// extensions are flagged by name; no extensions given keeps the list
+#[tauri::command]
+fn set_screening(enabled: bool, risky_extensions: Option<Vec<String>>, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.screen_incoming = enabled;
+    if let Some(extensions) = risky_extensions {
+        settings.risky_extensions = extensions
+            .iter()
+            .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect();
+    }
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Whether incoming files are held in the quarantine until released
+#[tauri::command]
+fn set_quarantine_incoming(enabled: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.quarantine_incoming = enabled;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Received files waiting in the quarantine
+#[tauri::command]
+fn get_quarantined_files(state: State<'_, AppState>) -> Vec<quarantine::QuarantinedFile> {
+    quarantine::list(&state.quarantined)
+}
+
+// Let a held file out to where it would have been saved. Decrypting it
+// takes as long as the file is big, so it's done off the main thread.
+#[tauri::command]
+async fn release_from_quarantine(transfer_id: String, app: AppHandle, state: State<'_, AppState>) -> Result<String, Error> {
+    let versions_kept = state.settings.lock().versions_kept;
+    let (quarantined, identity_key, id) = (state.quarantined.clone(), state.identity_key.clone(), transfer_id.clone());
+    let saved = state.tasks.run(tasks::Budget::Request, move || {
+        Ok(quarantine::release(&quarantined, &id, &identity_key, versions_kept)?)
+    }).await?;
+    let saved = saved.to_string_lossy().into_owned();
+
+    resaved(&app, &state, &transfer_id, "Completed ✅ (Released)", &saved);
+    Ok(saved)
+}
+
+// Note where a received file ended up, and tell the UI
+fn resaved(app: &AppHandle, state: &AppState, transfer_id: &str, status: &str, saved: &str) {
+    if let Err(e) = state.history.lock().resaved(transfer_id, status, saved) {
+        eprintln!("Failed to save transfer history: {}", e);
+    }
+    let updated = state.transfers.lock().iter_mut().find(|t| t.id == transfer_id).map(|t| {
+        t.status = status.to_string();
+        t.saved_path = Some(saved.to_string());
+        t.clone()
+    });
+    if let Some(transfer) = updated {
+        events::emit_record(app, events::COMPLETED, &transfer);
+    }
+}
+
+// Delete a held file without letting it out
+#[tauri::command]
+fn discard_quarantined(transfer_id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    Ok(quarantine::discard(&state.quarantined, &transfer_id)?)
+}
+
+// How many completed transfers stay listed
+#[tauri::command]
+fn set_max_finished_transfers(count: usize, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    {
+        let mut settings = state.settings.lock();
+        settings.max_finished_transfers = count;
+        settings::save_settings(&settings)?;
+    }
+    let pruned = prune_transfers(&mut state.transfers.lock(), count);
+    events::emit_removed(&app, &pruned);
+    tray::refresh(&app);
+    Ok(())
+}
+
+fn remove_transfers(app: &AppHandle, state: &AppState, remove: impl Fn(&FileTransfer) -> bool) {
+    let mut removed = Vec::new();
+    state.transfers.lock().retain(|t| {
+        if remove(t) {
+            removed.push(t.id.clone());
+            return false;
+        }
+        true
+    });
+    events::emit_removed(app, &removed);
+    tray::refresh(app);
+}
+
+// Check what finding and reaching other devices depends on, measuring the
+// link to `throughput_to` too if given
+#[tauri::command]
+async fn run_diagnostics(
+    throughput_to: Option<String>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<diagnostics::DiagnosticsReport, Error> {
+    state.tasks.run(tasks::Budget::Request, move || {
+        Ok(diagnostics::run(&app, throughput_to.as_deref()))
+    }).await
+}
+
+// The BLAKE3 hash of a file, as a transfer of it would carry. It's
+// remembered, so sending the file next doesn't hash it again.
+#[tauri::command]
+async fn get_file_hash(path: String, state: State<'_, AppState>) -> Result<String, Error> {
+    let hashed_files = state.hashed_files.clone();
+    state.tasks.run(tasks::Budget::Request, move || {
+        Ok(hashing::hash_file(&hashed_files, std::path::Path::new(&path))?.file_hash)
+    }).await
+}
+
+// Usage totals over a range of days, overall, per peer and per day
+#[tauri::command]
+fn get_statistics(range: Option<StatsRange>, state: State<'_, AppState>) -> Result<Statistics, Error> {
+    let history = state.history.lock();
+    history.statistics(&range.unwrap_or_default()).map_err(Error::from)
+}
+
+// Where a finished incoming transfer was saved, from memory or the history
+fn received_file_path(transfer_id: &str, state: &AppState) -> Result<std::path::PathBuf, Error> {
+    let in_memory = state.transfers.lock()
+        .iter()
+        .find(|t| t.id == transfer_id)
+        .map(|t| t.saved_path.clone());
+    let saved_path = match in_memory {
+        Some(saved_path) => saved_path,
+        None => state.history.lock()
+            .saved_path(transfer_id)?,
+    };
+    
+    let path = std::path::PathBuf::from(saved_path.ok_or_else(|| Error::NotFound("Transfer has no saved file".to_string()))?);
+    if !path.exists() {
+        return Err(Error::NotFound("File has been moved or deleted".to_string()));
+    }
+    Ok(path)
+}
+
+// Open a received file with the platform's default application
+#[tauri::command]
+fn open_received_file(transfer_id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let path = received_file_path(&transfer_id, &state)?;
+    open::that_detached(path).map_err(Error::from)
+}
+
+// Show a received file in the platform's file manager, selected where the
+// file manager supports it
+#[tauri::command]
+fn show_in_folder(transfer_id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let path = received_file_path(&transfer_id, &state)?;
+    
+    #[cfg(target_os = "windows")]
+    let result = std::process::Command::new("explorer")
+        .arg(format!("/select,{}", path.display()))
+        .spawn()
+        .map(|_| ());
+    #[cfg(target_os = "macos")]
+    let result = std::process::Command::new("open")
+        .arg("-R")
+        .arg(&path)
+        .spawn()
+        .map(|_| ());
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let result = open::that_detached(path.parent().unwrap_or(&path));
+    
+    result.map_err(Error::from)
+}
+
+fn shut_down_discovery(state: &AppState) -> Result<(), Error> {
+    let mut daemon = state.mdns_daemon.lock();
+    if let Some(mdns) = daemon.take() {
+        // Say goodbye first, so peers drop us now rather than when our
+        // records run out
+        if let Ok(unregistered) = mdns.daemon.unregister(&mdns.fullname) {
+            let _ = unregistered.recv_timeout(std::time::Duration::from_secs(1));
+        }
+        mdns.daemon.shutdown()?;
+    }
+    if let Some(beacons) = state.beacons.lock().take() {
+        beacons.stop();
+    }
+    Ok(())
+}
+
+// Start pairing with a discovered device; returns the code to compare
+#[tauri::command]
+async fn pair_device(
+    device_id: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<PairingSession, Error> {
+    let device = state.devices.lock()
+        .get(&device_id)
+        .cloned()
+        .ok_or_else(|| Error::NotFound("Unknown device".to_string()))?;
+    
+    let ctx = state.peer_context(app);
+    state.tasks.run(tasks::Budget::Request, move || {
+        let mut channel = net::connect_device(&device, &ctx.identity_key)?;
+        write_response(&mut channel, PACKET_PAIR_REQUEST, &ctx)?;
+        
+        let response = read_header(&mut channel)?;
+        if response.packet_type != PACKET_PAIR_RESPONSE {
+            return Err(Error::Unsupported("Unexpected pairing response".to_string()));
+        }
+        signing::verify_header(&response, None)?;
+        
+        let (pairing, decision) = pairing::begin_pairing(&channel, &response.source, &ctx);
+        let result = pairing.clone();
+        
+        ctx.tasks.clone().spawn(tasks::Budget::Request, move || {
+            if let Err(e) = pairing::finish_pairing(channel, response.signing_key, pairing, decision, ctx) {
+                eprintln!("Pairing failed: {}", e);
+            }
+        });
+        Ok(result)
+    }).await
+}
+
+// Accept or reject a pending pairing after comparing codes
+#[tauri::command]
+fn confirm_pairing(pairing_id: String, accept: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let pending = state.pending_pairings.lock();
+    let sender = pending.get(&pairing_id).ok_or_else(|| Error::NotFound("No pending pairing".to_string()))?;
+    sender.send(accept).map_err(Error::from)
+}
+
+// Host a hotspot for devices with no network in common with us, giving
+// back the QR code they scan to join it and pair
+#[tauri::command]
+async fn start_hotspot(app: AppHandle, state: State<'_, AppState>) -> Result<Hotspot, Error> {
+    let port = state.server_port()?;
+    let (ssid, password, token) = hotspot::credentials();
+    // Where we can't start one, the user turns on a hotspot with these
+    let started = match hotspot::start(&ssid, &password) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Couldn't start a hotspot: {}", e);
+            false
+        }
+    };
+    
+    let payload = JoinPayload {
+        ssid: ssid.clone(),
+        password: password.clone(),
+        token: token.clone(),
+        name: state.device_name(),
+        id: state.device_id.clone(),
+        public_key: encode_public_key(&PublicKey::from(&state.identity_key)),
+        signing_key: signing::encode_verifying_key(&state.signing_key.verifying_key()),
+        port,
+        addresses: net::own_addresses(&net::local_interfaces()).iter().map(|ip| ip.to_string()).collect(),
+    }
+    .encode();
+    let hotspot = Hotspot {
+        ssid,
+        password,
+        started,
+        qr_svg: hotspot::qr_svg(&payload)?,
+        payload,
+        token,
+    };
+    *state.hotspot.lock() = Some(hotspot.clone());
+    
+    // Announce ourselves on the hotspot's network too
+    restart_discovery(app, &state)?;
+    Ok(hotspot)
+}
+
+// Start a guest session lasting `duration_secs`, giving back where the
+// guest connects to and the fingerprint it should see
+#[tauri::command]
+fn start_guest_session(duration_secs: u64, app: AppHandle) -> Result<guest::GuestSessionInfo, Error> {
+    guest::start(&app, std::time::Duration::from_secs(duration_secs))
+}
+
+// The guest session running, if any
+#[tauri::command]
+fn get_guest_session(state: State<'_, AppState>) -> Option<guest::GuestSessionInfo> {
+    state.guest_session.lock().as_ref().map(|session| session.info())
+}
+
+// Let in, or turn away, the device asking to join the guest session
+#[tauri::command]
+fn approve_guest(session_id: String, accept: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let pending = state.pending_approvals.lock();
+    let sender = pending.get(&session_id).ok_or_else(|| Error::NotFound("No guest waiting".to_string()))?;
+    sender.send(accept).map_err(Error::from)
+}
+
+// End the guest session now, erasing its pairing and history
+#[tauri::command]
+fn end_guest_session(app: AppHandle) -> Result<(), Error> {
+    guest::end(&app, None)
+}
+
+// Stop hosting the hotspot; its code no longer pairs
+#[tauri::command]
+fn stop_hotspot(state: State<'_, AppState>) -> Result<(), Error> {
+    let hotspot = state.hotspot.lock().take().ok_or_else(|| Error::NotFound("No hotspot running".to_string()))?;
+    if hotspot.started {
+        hotspot::stop()?;
+    }
+    Ok(())
+}
+
+// Serve a file to the first browser that opens the link given back, for
+// devices without the app
+#[tauri::command]
+fn share_to_browser(file_path: String, app: AppHandle, state: State<'_, AppState>) -> Result<browser::BrowserLink, Error> {
+    browser::share(std::path::Path::new(&file_path), app, state.browser_shares.clone()).map_err(Error::from)
+}
+
+// Stop serving a browser link, including any download under way
+#[tauri::command]
+fn cancel_browser_share(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let shares = state.browser_shares.lock();
+    shares.get(&id).ok_or_else(|| Error::NotFound("Browser link not found".to_string()))?.stop();
+    Ok(())
+}
+
+// Links to files for browsers that are still being served
+#[tauri::command]
+fn get_browser_shares(state: State<'_, AppState>) -> Result<Vec<browser::BrowserLink>, Error> {
+    Ok(state.browser_shares.lock().values().map(|share| share.link.clone()).collect())
+}
+
+// Serve a file at a link anyone on the network can download it from,
+// optionally behind a password, until it has been downloaded `max_downloads`
+// times (once by default) or `expires_in_secs` have passed
+#[tauri::command]
+fn share_via_link(
+    file_path: String,
+    password: Option<String>,
+    expires_in_secs: Option<u64>,
+    max_downloads: Option<u32>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<download_links::DownloadLink, Error> {
+    download_links::share(
+        std::path::Path::new(&file_path),
+        password,
+        expires_in_secs.map(std::time::Duration::from_secs),
+        max_downloads,
+        app,
+        state.download_links.clone(),
+    )
+    .map_err(Error::from)
+}
+
+// Take a download link down, letting downloads under way finish
+#[tauri::command]
+fn cancel_download_link(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let links = state.download_links.lock();
+    links.get(&id).ok_or_else(|| Error::NotFound("Download link not found".to_string()))?.stop();
+    Ok(())
+}
+
+// Download links that still work
+#[tauri::command]
+fn get_download_links(state: State<'_, AppState>) -> Result<Vec<download_links::DownloadLink>, Error> {
+    Ok(state.download_links.lock().values().map(|link| link.link()).collect())
+}
+
+// Open a page that any device with a browser can upload files to us from,
+// giving back its link and QR code. Each file is asked about like any
+// other incoming transfer.
+#[tauri::command]
+fn start_upload_page(app: AppHandle, state: State<'_, AppState>) -> Result<upload::UploadPage, Error> {
+    upload::open(state.peer_context(app), state.upload_page.clone()).map_err(Error::from)
+}
+
+#[tauri::command]
+fn stop_upload_page(state: State<'_, AppState>) -> Result<(), Error> {
+    let page = state.upload_page.lock().take().ok_or_else(|| Error::NotFound("No upload page open".to_string()))?;
+    page.stop();
+    Ok(())
+}
+
+#[tauri::command]
+fn get_upload_page(state: State<'_, AppState>) -> Result<Option<upload::UploadPage>, Error> {
+    Ok(state.upload_page.lock().as_ref().map(|open| open.page.clone()))
+}
+
+fn webdav_status(state: &AppState) -> webdav::DavStatus {
+    match &*state.webdav.lock() {
+        Some(server) => webdav::DavStatus {
+            running: true,
+            port: server.port,
+            urls: net::http_urls(server.port, "/"),
+        },
+        None => webdav::DavStatus {
+            running: false,
+            port: state.settings.lock().webdav_port,
+            urls: Vec::new(),
+        },
+    }
+}
+
+// Export the shares read-only over WebDAV, on `port` or the one used last,
+// and keep doing so on later launches
+#[tauri::command]
+fn start_webdav(port: Option<u16>, state: State<'_, AppState>) -> Result<webdav::DavStatus, Error> {
+    let port = port.unwrap_or_else(|| state.settings.lock().webdav_port);
+    {
+        let mut running = state.webdav.lock();
+        if let Some(server) = running.take() {
+            server.stop();
+        }
+        *running = Some(webdav::start(port, state.shares.clone(), state.dav_credentials.clone())?);
+    }
+    {
+        let mut settings = state.settings.lock();
+        settings.webdav_enabled = true;
+        settings.webdav_port = port;
+        settings::save_settings(&settings)?;
+    }
+    Ok(webdav_status(&state))
+}
+
+#[tauri::command]
+fn stop_webdav(state: State<'_, AppState>) -> Result<(), Error> {
+    if let Some(server) = state.webdav.lock().take() {
+        server.stop();
+    }
+    let mut settings = state.settings.lock();
+    settings.webdav_enabled = false;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+#[tauri::command]
+fn get_webdav_status(state: State<'_, AppState>) -> Result<webdav::DavStatus, Error> {
+    Ok(webdav_status(&state))
+}
+
+// Make a user name and password for a program or device to read the
+// WebDAV export with. The password is only ever given back here.
+#[tauri::command]
+fn add_webdav_credential(label: String, state: State<'_, AppState>) -> Result<webdav::NewDavCredential, Error> {
+    let mut credentials = state.dav_credentials.lock();
+    let (credential, password) = webdav::new_credential(&label, &credentials)?;
+    let account = webdav::DavAccount::from(&credential);
+    credentials.push(credential);
+    webdav::save_credentials(&credentials)?;
+    Ok(webdav::NewDavCredential { account, password })
+}
+
+// Revoke a WebDAV credential; whatever used it can no longer read the export
+#[tauri::command]
+fn remove_webdav_credential(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut credentials = state.dav_credentials.lock();
+    credentials.retain(|credential| credential.id != id);
+    webdav::save_credentials(&credentials).map_err(Error::from)
+}
+
+#[tauri::command]
+fn get_webdav_credentials(state: State<'_, AppState>) -> Result<Vec<webdav::DavAccount>, Error> {
+    Ok(state.dav_credentials.lock().iter().map(webdav::DavAccount::from).collect())
+}
+
+// Join the hotspot in a scanned QR code and pair with the device hosting it
+#[tauri::command]
+async fn join_hotspot(payload: String, app: AppHandle, state: State<'_, AppState>) -> Result<String, Error> {
+    let payload = JoinPayload::decode(&payload)?;
+    // Where we can't join by ourselves, the user has until the network
+    // shows up to join it by hand
+    let joining = payload.clone();
+    let addresses = state.tasks.run(tasks::Budget::Request, move || {
+        if let Err(e) = hotspot::join(&joining) {
+            eprintln!("Couldn't join {}: {}", joining.ssid, e);
+        }
+        hotspot::wait_for_network(&joining).map_err(Error::from)
+    }).await?;
+    restart_discovery(app.clone(), &state)?;
     
-    thread::spawn(move || {
-        if let Err(e) = send_file_internal(file_path, target_ip, target_port, transfers, encryption_key) {
-            eprintln!("Error sending file: {}", e);
+    let ctx = state.peer_context(app);
+    let announced = addresses.iter().filter_map(|address| address.parse().ok()).collect();
+    let property = |key: &str| match key {
+        "id" => Some(payload.id.clone()),
+        "pk" => Some(payload.public_key.clone()),
+        "sk" => Some(payload.signing_key.clone()),
+        _ => None,
+    };
+    add_announced(&payload.name, announced, payload.port, &property, &ctx);
+    
+    state.tasks.run(tasks::Budget::Request, move || {
+        // The keys in the code are the ones we must reach
+        let mut channel = net::connect_any(&addresses, payload.port, &ctx.identity_key)?;
+        if encode_public_key(channel.peer_identity()) != payload.public_key {
+            return Err(Error::PermissionDenied("Hotspot host's identity doesn't match its code".to_string()));
         }
-    });
+        let request = PacketHeader {
+            packet_type: PACKET_PAIR_REQUEST.to_string(),
+            source: ctx.device_name(),
+            pairing_token: payload.token.clone(),
+            ..Default::default()
+        };
+        write_header(&mut channel, &request, &ctx.signing_key)?;
+        let response = read_header(&mut channel)?;
+        if response.packet_type != PACKET_PAIR_RESPONSE || response.signing_key != payload.signing_key {
+            return Err(Error::Unsupported("Unexpected pairing response".to_string()));
+        }
+        signing::verify_header(&response, None)?;
+        
+        let (pairing, decision) = pairing::begin_pairing(&channel, &payload.name, &ctx);
+        pairing::accept(&pairing, &ctx);
+        ctx.tasks.clone().spawn(tasks::Budget::Request, move || {
+            if let Err(e) = pairing::finish_pairing(channel, response.signing_key, pairing, decision, ctx) {
+                eprintln!("Pairing failed: {}", e);
+            }
+        });
+        
+        Ok(format!("Joined {} and pairing with {} 📶", payload.ssid, payload.name))
+    }).await
+}
+
+// Find nearby devices over Bluetooth, and let them find us, handing them
+// over to the network once we share one
+#[tauri::command]
+fn start_bluetooth(app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut bluetooth = state.bluetooth.lock();
+    if bluetooth.is_some() {
+        return Ok(());
+    }
+    let handoff = ble::Handoff {
+        id: state.device_id.clone(),
+        name: state.device_name(),
+        public_key: encode_public_key(&PublicKey::from(&state.identity_key)),
+        signing_key: signing::encode_verifying_key(&state.signing_key.verifying_key()),
+        fingerprint: pairing::fingerprint(&PublicKey::from(&state.identity_key)),
+        port: state.server_port()?,
+        addresses: net::own_addresses(&net::local_interfaces())
+            .iter()
+            .take(ble::MAX_HANDOFF_ADDRESSES)
+            .map(|ip| ip.to_string())
+            .collect(),
+    };
+    *bluetooth = Some(ble::start(handoff, state.peer_context(app), state.nearby.clone())?);
+    Ok(())
+}
+
+#[tauri::command]
+fn stop_bluetooth(state: State<'_, AppState>) -> Result<(), Error> {
+    if let Some(bluetooth) = state.bluetooth.lock().take() {
+        bluetooth.stop();
+    }
+    Ok(())
+}
+
+// Devices found over Bluetooth that aren't on a network we're on
+#[tauri::command]
+fn get_nearby_devices(state: State<'_, AppState>) -> Result<Vec<ble::NearbyDevice>, Error> {
+    Ok(state.nearby.lock().values().cloned().collect())
+}
+
+// List devices we have paired with
+#[tauri::command]
+fn get_trusted_devices(state: State<'_, AppState>) -> Result<Vec<TrustedDevice>, Error> {
+    let trusted = state.trusted_devices.lock();
+    Ok(trusted.values().cloned().collect())
+}
+
+// Fingerprints of our identity key and every known peer's key
+#[tauri::command]
+fn get_device_fingerprint(state: State<'_, AppState>) -> Result<DeviceFingerprints, Error> {
+    let devices = state.devices.lock();
+    let peers = devices.values()
+        .filter_map(|d| {
+            let key = decode_public_key(&d.public_key)?;
+            Some(PeerFingerprint {
+                device_id: d.id.clone(),
+                name: d.name.clone(),
+                fingerprint: pairing::fingerprint(&key),
+                verified: d.verified,
+            })
+        })
+        .collect();
     
-    Ok("Encrypted transfer started 🔒".to_string())
+    Ok(DeviceFingerprints {
+        own: pairing::fingerprint(&PublicKey::from(&state.identity_key)),
+        peers,
+    })
 }
 
-fn send_file_internal(
-    file_path: String,
+// Mark a peer as verified after comparing fingerprints out of band
+#[tauri::command]
+fn verify_device(device_id: String, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut devices = state.devices.lock();
+    let device = devices.get_mut(&device_id).ok_or_else(|| Error::NotFound("Unknown device".to_string()))?;
+    if device.public_key.is_empty() {
+        return Err(Error::Unsupported("Device has not advertised a public key".to_string()));
+    }
+    
+    device.verified = true;
+    state.verified_keys.lock().insert(device.public_key.clone());
+    let _ = app.emit(events::DEVICE_UPDATED, &*device);
+    Ok(())
+}
+
+// Where to send a file a peer asked for: to the address the request came
+// from, at the port it gave, sealed to the identity that asked
+fn reply_destination(channel: &SecureChannel, reply_port: u16) -> std::io::Result<Destination> {
+    let ip = net::peer_address(&channel.try_clone_stream()?.peer_addr()?);
+    Ok(Destination {
+        addresses: vec![ip.clone()],
+        ip,
+        port: reply_port,
+        recipient_key: Some(*channel.peer_identity()),
+        password: None,
+        compression: true,
+        hold_for: None,
+        forward_to: None,
+        next_hop: Mutex::new(None),
+        via: None,
+        internet: false,
+        exact_paths: false,
+        sync_folder: None,
+    })
+}
+
+// Ask a peer for a file it shares, by share id or `<id>/<path>` within a
+// shared folder. Once the peer agrees the file arrives like any other
+// transfer; the request id is returned.
+#[tauri::command]
+async fn request_file(
     target_ip: String,
     target_port: u16,
-    transfers: Arc<Mutex<Vec<FileTransfer>>>,
-    encryption_key: [u8; 32],
-) -> std::io::Result<()> {
-    let mut stream = TcpStream::connect(format!("{}:{}", target_ip, target_port))?;
-    
-    // Read file
-    let file_data = std::fs::read(&file_path)?;
-    
-    let filename = std::path::Path::new(&file_path)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown");
+    remote_path: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, Error> {
+    let addresses = state.addresses_for(&target_ip, target_port);
+    let reply_port = state.server_port()?;
+    let ctx = state.peer_context(app);
+    state.tasks.run(tasks::Budget::Request, move || {
+        let mut channel = net::connect_any(&addresses, target_port, &ctx.identity_key)?;
+        
+        let request_id = Uuid::new_v4().to_string();
+        let request = PacketHeader {
+            packet_type: PACKET_FILE_REQUEST.to_string(),
+            source: ctx.device_name(),
+            request_id: request_id.clone(),
+            request_path: remote_path,
+            reply_port,
+            ..Default::default()
+        };
+        ctx.pending_pulls.lock().insert(request_id.clone(), *channel.peer_identity());
+        
+        let response = write_header(&mut channel, &request, &ctx.signing_key)
+            .and_then(|_| read_header(&mut channel))
+            .map_err(Error::from)
+            .and_then(|response| signing::verify_header(&response, None).map(|_| response).map_err(Error::Rejected));
+        match response {
+            Ok(response) if response.packet_type == PACKET_TRANSFER_ACCEPT => Ok(request_id),
+            other => {
+                ctx.pending_pulls.lock().remove(&request_id);
+                match other {
+                    Ok(response) if !response.reason.is_empty() => Err(Error::Rejected(response.reason)),
+                    Ok(_) => Err(Error::Rejected("Request refused".to_string())),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }).await
+}
+
+// Browse what a peer shares: its shared files and folders when `path` is
+// empty, otherwise the contents of that shared folder
+#[tauri::command]
+async fn list_remote_files(
+    target_ip: String,
+    target_port: u16,
+    path: Option<String>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Vec<RemoteEntry>, Error> {
+    let addresses = state.addresses_for(&target_ip, target_port);
+    let ctx = state.peer_context(app);
+    state.tasks.run(tasks::Budget::Request, move || {
+        let mut channel = net::connect_any(&addresses, target_port, &ctx.identity_key)?;
+        let request = PacketHeader {
+            packet_type: PACKET_LIST_FILES.to_string(),
+            source: ctx.device_name(),
+            request_path: path.unwrap_or_default(),
+            ..Default::default()
+        };
+        write_header(&mut channel, &request, &ctx.signing_key)?;
+        
+        let response = read_header(&mut channel)?;
+        signing::verify_header(&response, None)?;
+        if response.packet_type != PACKET_FILE_LIST {
+            return Err(Error::Rejected(if response.reason.is_empty() { "Listing refused".to_string() } else { response.reason }));
+        }
+        Ok(response.entries)
+    }).await
+}
+
+// Send a text message to a device
+#[tauri::command]
+async fn send_message(
+    target_ip: String,
+    target_port: u16,
+    text: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<ChatMessage, Error> {
+    if text.len() > messages::MAX_MESSAGE_LEN {
+        return Err(Error::InvalidInput("Message too long".to_string()));
+    }
+    let addresses = state.addresses_for(&target_ip, target_port);
+    let ctx = state.peer_context(app);
+    let packet = PacketHeader {
+        packet_type: PACKET_MESSAGE.to_string(),
+        source: ctx.device_name(),
+        text: text.clone(),
+        ..Default::default()
+    };
+    let (response, peer_identity) = state.tasks.run(tasks::Budget::Request, move || {
+        let mut channel = net::connect_any(&addresses, target_port, &ctx.identity_key)?;
+        write_header(&mut channel, &packet, &ctx.signing_key)?;
+        
+        let response = read_header(&mut channel)?;
+        signing::verify_header(&response, None)?;
+        Ok((response, *channel.peer_identity()))
+    }).await?;
+    if response.packet_type != PACKET_MESSAGE_RECEIVED {
+        return Err(Error::Rejected(if response.reason.is_empty() { "Message refused".to_string() } else { response.reason }));
+    }
     
-    // Encrypt file
-    let encrypted_data = encrypt_data(&file_data, &encryption_key)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let message = ChatMessage {
+        id: Uuid::new_v4().to_string(),
+        peer: encode_public_key(&peer_identity),
+        peer_name: response.source,
+        from_me: true,
+        text,
+        sent_at: chrono::Local::now().to_rfc3339(),
+    };
+    messages::record(&mut state.messages.lock(), message.clone());
+    Ok(message)
+}
+
+// Messages exchanged with a device, identified by its public key, oldest first
+#[tauri::command]
+fn get_messages(peer: String, state: State<'_, AppState>) -> Result<Vec<ChatMessage>, Error> {
+    let messages = state.messages.lock();
+    Ok(messages.iter().filter(|m| m.peer == peer).cloned().collect())
+}
+
+// Offer a file or folder to peers, who can then request it
+#[tauri::command]
+fn share_path(path: String, state: State<'_, AppState>) -> Result<SharedItem, Error> {
+    let item = SharedItem::new(&path)?;
+    let mut shares = state.shares.lock();
+    shares.push(item.clone());
+    sharing::save_shares(&shares)?;
+    Ok(item)
+}
+
+// Stop offering a shared file or folder
+#[tauri::command]
+fn unshare_path(id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut shares = state.shares.lock();
+    shares.retain(|s| s.id != id);
+    sharing::save_shares(&shares).map_err(Error::from)
+}
+
+// Get the files and folders we share
+#[tauri::command]
+fn get_shares(state: State<'_, AppState>) -> Result<Vec<SharedItem>, Error> {
+    Ok(state.shares.lock().clone())
+}
+
+// Accept or reject an incoming transfer announced via `transfer://request`,
+// or a file request announced via `file-request://incoming`
+#[tauri::command]
+fn respond_to_transfer(transfer_id: String, accept: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let pending = state.pending_approvals.lock();
+    let sender = pending.get(&transfer_id).ok_or_else(|| Error::NotFound("No pending transfer".to_string()))?;
+    sender.send(accept).map_err(Error::from)
+}
+
+// Trust a discovered device without going through code comparison
+#[tauri::command]
+fn add_trusted_device(device_id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let device = state.devices.lock()
+        .get(&device_id)
+        .cloned()
+        .ok_or_else(|| Error::NotFound("Unknown device".to_string()))?;
+    if decode_public_key(&device.public_key).is_none() {
+        return Err(Error::Unsupported("Device has not advertised a public key".to_string()));
+    }
     
-    let encrypted_size = encrypted_data.len() as u64;
+    let mut trusted = state.trusted_devices.lock();
+    // Trusting it again keeps what the user held back from it
+    let permissions = trusted.get(&device.public_key)
+        .map(|trusted| trusted.permissions)
+        .unwrap_or_default();
+    trusted.insert(device.public_key.clone(), TrustedDevice {
+        public_key: device.public_key,
+        device_id: device.id,
+        signing_key: device.signing_key,
+        name: device.name,
+        paired_at: chrono::Local::now().to_rfc3339(),
+        permissions,
+    });
+    pairing::save_trusted_devices(&trusted).map_err(Error::from)
+}
+
+// Choose what a paired device may do with us, found by its device id or
+// public key
+#[tauri::command]
+fn set_device_permissions(device_id: String, perms: DevicePermissions, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut trusted = state.trusted_devices.lock();
+    let device = trusted.values_mut()
+        .find(|d| (!d.device_id.is_empty() && d.device_id == device_id) || d.public_key == device_id)
+        .ok_or_else(|| Error::NotFound("Device is not trusted".to_string()))?;
+    device.permissions = perms;
+    pairing::save_trusted_devices(&trusted).map_err(Error::from)
+}
+
+// Our friend code, for others to trust us by before they've seen us
+#[tauri::command]
+fn get_friend_code(state: State<'_, AppState>) -> String {
+    friends::friend_code(&PublicKey::from(&state.identity_key))
+}
+
+// Trust the device a friend code belongs to, out of sight for now. It's
+// recognised as paired once it turns up.
+#[tauri::command]
+fn add_friend(code: String, name: Option<String>, state: State<'_, AppState>) -> Result<TrustedDevice, Error> {
+    let key = friends::parse_friend_code(&code)
+        .ok_or_else(|| Error::InvalidInput("Not a valid friend code".to_string()))?;
+    let public_key = encode_public_key(&key);
+    if public_key == encode_public_key(&PublicKey::from(&state.identity_key)) {
+        return Err(Error::InvalidInput("That's this device's own code".to_string()));
+    }
     
-    // Create transfer record
-    let transfer_id = Uuid::new_v4().to_string();
-    let transfer = FileTransfer {
-        id: transfer_id.clone(),
-        filename: filename.to_string(),
-        size: encrypted_size,
-        progress: 0,
-        status: "Encrypting & Sending 🔒".to_string(),
-        from_device: "This Device".to_string(),
-        to_device: target_ip.clone(),
-        encrypted: true,
+    let mut trusted = state.trusted_devices.lock();
+    if let Some(device) = trusted.get(&public_key) {
+        return Ok(device.clone());
+    }
+    // Its id and signing key are learned once it connects; what it
+    // announces, even if it's in sight, could be anyone's claim
+    let device = TrustedDevice {
+        public_key: public_key.clone(),
+        device_id: String::new(),
+        signing_key: String::new(),
+        name: name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| friends::UNNAMED.to_string()),
+        paired_at: chrono::Local::now().to_rfc3339(),
+        permissions: DevicePermissions::default(),
     };
-    
+    trusted.insert(public_key, device.clone());
+    pairing::save_trusted_devices(&trusted)?;
+    Ok(device)
+}
+
+// Remove a device from the allowlist by its public key
+#[tauri::command]
+fn remove_trusted_device(public_key: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut trusted = state.trusted_devices.lock();
+    if trusted.remove(&public_key).is_none() {
+        return Err(Error::NotFound("Device is not trusted".to_string()));
+    }
+    pairing::save_trusted_devices(&trusted).map_err(Error::from)
+}
+
+// Change how incoming transfers are accepted
+#[tauri::command]
+fn set_accept_policy(policy: AcceptPolicy, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.accept_policy = policy;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Limit how many transfers run at once in each direction
+#[tauri::command]
+fn set_concurrency_limits(max_outgoing: usize, max_incoming: usize, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.max_concurrent_outgoing = max_outgoing.max(1);
+    settings.max_concurrent_incoming = max_incoming.max(1);
+    state.queue.set_limits(settings.max_concurrent_outgoing, settings.max_concurrent_incoming);
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Keep running in the tray when the window is closed, or quit
+#[tauri::command]
+fn set_run_in_background(enabled: bool, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.run_in_background = enabled;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Turn away incoming files until unpaused
+#[tauri::command]
+fn set_receiving_paused(paused: bool, app: AppHandle, state: State<'_, AppState>) -> Result<(), Error> {
     {
-        let mut transfers = transfers.lock().unwrap();
-        transfers.push(transfer.clone());
+        let mut settings = state.settings.lock();
+        settings.receiving_paused = paused;
+        settings::save_settings(&settings)?;
+    }
+    tray::refresh(&app);
+    Ok(())
+}
+
+// Which transfer events are announced as notifications
+#[tauri::command]
+fn set_notifications(notifications: NotificationSettings, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.notifications = notifications;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// How often and how patiently failed transfers are retried
+#[tauri::command]
+fn set_retry_policy(policy: RetryPolicy, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.retry = policy;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Cap the combined rate of all transfers, in bytes per second (0 for no limit)
+#[tauri::command]
+fn set_bandwidth_limit(bytes_per_sec: u64, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.bandwidth_limit = bytes_per_sec;
+    state.throttle.set_global_limit(bytes_per_sec);
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Give one transfer, or every file of a batch, its own rate limit in place
+// of the global one. None puts it back on the global limit.
+#[tauri::command]
+fn set_transfer_bandwidth_limit(
+    transfer_id: String,
+    bytes_per_sec: Option<u64>,
+    state: State<'_, AppState>,
+) -> Result<(), Error> {
+    let batch = state.batches.lock().get(&transfer_id).cloned();
+    let transfer_ids = match batch {
+        Some(batch) => batch.transfer_ids,
+        None => vec![transfer_id],
+    };
+    for transfer_id in &transfer_ids {
+        state.throttle.set_transfer_limit(transfer_id, bytes_per_sec);
+    }
+    Ok(())
+}
+
+// Running and waiting transfers, in the order they will run
+#[tauri::command]
+fn get_queue(state: State<'_, AppState>) -> Result<Vec<QueueEntry>, Error> {
+    Ok(state.queue.entries())
+}
+
+// Move a waiting transfer (or batch) to a new position in the queue
+#[tauri::command]
+fn reorder_queue(id: String, position: usize, state: State<'_, AppState>) -> Result<(), Error> {
+    state.queue.reorder(&id, position)
+}
+
+// How many connections a large file may be spread over, 1 to disable
+#[tauri::command]
+fn set_parallel_streams(streams: usize, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.parallel_streams = streams.clamp(1, parallel::MAX_STREAMS);
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Limit the size of any one incoming file and the bytes received per day,
+// 0 for no limit
+#[tauri::command]
+fn set_receive_limits(max_file_size: u64, daily_quota: u64, state: State<'_, AppState>) -> Result<(), Error> {
+    let mut settings = state.settings.lock();
+    settings.max_file_size = max_file_size;
+    settings.daily_quota = daily_quota;
+    settings::save_settings(&settings).map_err(Error::from)
+}
+
+// Supply the password for a transfer announced via `transfer://password-required`.
+// Waiting for the transfer to try it is done off the main thread.
+#[tauri::command]
+async fn unlock_transfer(transfer_id: String, password: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let (tx, rx) = mpsc::channel();
+    {
+        let pending = state.pending_unlocks.lock();
+        let sender = pending.get(&transfer_id).ok_or_else(|| Error::NotFound("No locked transfer".to_string()))?;
+        sender.send((password, tx))?;
     }
     
-    // Send filename length
-    let filename_bytes = filename.as_bytes();
-    stream.write_all(&(filename_bytes.len() as u32).to_be_bytes())?;
-    
-    // Send filename
-    stream.write_all(filename_bytes)?;
-    
-    // Send encrypted file size
-    stream.write_all(&encrypted_size.to_be_bytes())?;
+    state.tasks.run(tasks::Budget::Request, move || {
+        match rx.recv_timeout(std::time::Duration::from_secs(30)) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::WrongPassword),
+            Err(_) => Err(Error::NotFound("Transfer is no longer waiting for a password".to_string())),
+        }
+    }).await
+}
+
+// Abort a running transfer in either direction. Cancelling a batch id
+// cancels every file in it that hasn't finished.
+#[tauri::command]
+fn cancel_transfer(transfer_id: String, state: State<'_, AppState>) -> Result<(), Error> {
+    let batch = state.batches.lock().get(&transfer_id).cloned();
+    let transfer_ids = match batch {
+        Some(batch) => batch.transfer_ids,
+        None => vec![transfer_id],
+    };
     
-    // Send encrypted content
-    let mut sent = 0u64;
-    let chunk_size = 8192;
+    let tokens: Vec<_> = {
+        let tokens = state.cancel_tokens.lock();
+        transfer_ids.iter().filter_map(|id| tokens.get(id).cloned()).collect()
+    };
+    if tokens.is_empty() {
+        return Err(Error::NotFound("No running transfer".to_string()));
+    }
+    for token in tokens {
+        token.cancel();
+    }
     
-    for chunk in encrypted_data.chunks(chunk_size) {
-        stream.write_all(chunk)?;
-        sent += chunk.len() as u64;
-        
-        // Update progress
-        let mut transfers = transfers.lock().unwrap();
-        if let Some(t) = transfers.iter_mut().find(|t| t.id == transfer_id) {
-            t.progress = sent;
-            if sent >= encrypted_size {
-                t.status = "Completed ✅ (Encrypted)".to_string();
-            }
+    // Wake up anything still waiting on the user
+    for transfer_id in &transfer_ids {
+        if let Some(approval) = state.pending_approvals.lock().remove(transfer_id) {
+            let _ = approval.send(false);
         }
+        state.pending_unlocks.lock().remove(transfer_id);
     }
     
     Ok(())
 }
 
-// Get transfer history
-#[tauri::command]
-fn get_transfers(state: State<'_, AppState>) -> Result<Vec<FileTransfer>, String> {
-    let transfers = state.transfers.lock().unwrap();
-    Ok(transfers.clone())
+// The app was launched again while running. The new process exits; here
+// the window is brought forward, and any files it was started with are
+// handed to the UI to pick where to send them.
+fn relaunched(app: &AppHandle, args: Vec<String>, cwd: String) {
+    tray::show_window(app);
+    if let Some(send) = launch::parse(&args, std::path::Path::new(&cwd)) {
+        launch::add(app, &app.state::<AppState>().pending_outgoing, send);
+    }
 }
 
-// Stop discovery
+// Sends waiting for the user to pick where they go, handed over once; the
+// UI hears of later ones as they come
 #[tauri::command]
-fn stop_discovery(state: State<'_, AppState>) -> Result<(), String> {
-    let mut daemon = state.mdns_daemon.lock().unwrap();
-    if let Some(mdns) = daemon.take() {
-        mdns.shutdown().map_err(|e| e.to_string())?;
+fn get_pending_sends(state: State<'_, AppState>) -> Result<Vec<launch::PendingSend>, Error> {
+    Ok(std::mem::take(&mut *state.pending_outgoing.lock()))
+}
+
+// Wind everything down as the app quits. Nothing new starts once the tasks
+// are stopped; we leave mDNS and the other ways in, give transfers under way
+// a moment to finish and record the rest as interrupted. Partial downloads
+// keep their manifests, so they resume once the sender tries again.
+fn shut_down(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    state.tasks.shut_down();
+    
+    if let Err(e) = shut_down_discovery(&state) {
+        eprintln!("Failed to stop discovery: {}", e);
+    }
+    if let Some(page) = state.upload_page.lock().take() {
+        page.stop();
+    }
+    // Only stopped, so they're back on the next launch
+    if let Some(server) = state.webdav.lock().take() {
+        server.stop();
+    }
+    for share in state.browser_shares.lock().values() {
+        share.stop();
+    }
+    if let Some(bluetooth) = state.bluetooth.lock().take() {
+        bluetooth.stop();
+    }
+    if let Some(hotspot) = state.hotspot.lock().take() {
+        if hotspot.started {
+            if let Err(e) = hotspot::stop() {
+                eprintln!("Failed to stop hotspot: {}", e);
+            }
+        }
+    }
+    
+    // Nobody is left to answer, so refuse whatever is still asking
+    for (_, approval) in state.pending_approvals.lock().drain() {
+        let _ = approval.send(false);
+    }
+    for (_, pairing) in state.pending_pairings.lock().drain() {
+        let _ = pairing.send(false);
+    }
+    state.pending_unlocks.lock().clear();
+    
+    if !tauri::async_runtime::block_on(state.tasks.wait_idle(SHUTDOWN_GRACE)) {
+        eprintln!("Quitting with transfers still under way");
+    }
+    let ctx = state.peer_context(app.clone());
+    let unfinished: Vec<String> = state.transfers.lock()
+        .iter()
+        .filter(|t| t.finished_at.is_none())
+        .map(|t| t.id.clone())
+        .collect();
+    for transfer_id in unfinished {
+        fail_transfer(&ctx, &transfer_id, "Interrupted ⛔", Error::Cancelled);
+    }
+    
+    // Everything else is saved as it changes; devices are only saved when
+    // something beyond the time they were last seen does
+    if let Err(e) = known::save_known_devices(&state.known_devices.lock()) {
+        eprintln!("Failed to save known devices: {}", e);
+    }
+    
+    // Leave the router as we found it
+    let mapping = state.port_mapping.lock().mapping.take();
+    if let Some(mapping) = mapping {
+        if let Err(e) = portmap::unmap(&mapping) {
+            eprintln!("Failed to remove port mapping: {}", e);
+        }
     }
-    Ok(())
 }
 
 fn main() {
-    let device_id = Uuid::new_v4().to_string();
+    let device_id = load_or_create_device_id();
     let hostname = hostname::get()
         .ok()
         .and_then(|h| h.into_string().ok())
         .unwrap_or_else(|| "Unknown".to_string());
     
-    // Use fixed key so all devices can communicate
-    let encryption_key = generate_encryption_key();
+    let identity_key = load_or_create_identity();
+    let signing_key = signing::load_or_create_signing_key();
+    
+    let settings = settings::load_settings();
+    if let Err(e) = group::join(&settings.network_group) {
+        eprintln!("Failed to join network group: {}", e);
+    }
+    let (max_outgoing, max_incoming) = (settings.max_concurrent_outgoing, settings.max_concurrent_incoming);
+    let bandwidth_limit = settings.bandwidth_limit;
+    // Named after the machine until the user names us something else
+    let device_name = Some(settings.device_name.clone()).filter(|name| !name.is_empty()).unwrap_or(hostname);
+    let relay_bandwidth_limit = settings.relay_bandwidth_limit;
+    
+    // Files we were started with wait for the UI to ask for them
+    let args: Vec<String> = std::env::args().collect();
+    let cwd = std::env::current_dir().unwrap_or_default();
     
     println!("🔐 Encryption enabled - ChaCha20-Poly1305");
-    println!("🔑 Using shared encryption key");
+    println!("🔑 Noise_XX transport with per-device identity keys");
+    
+    // Devices added by address are there from the start, until they fail
+    // to answer heartbeats
+    let now = chrono::Utc::now().timestamp();
+    let devices = manual::load_manual_devices()
+        .into_iter()
+        .map(|device| (device.id.clone(), Device { last_heard: now, ..device }))
+        .collect();
     
     let app_state = AppState {
-        devices: Arc::new(Mutex::new(HashMap::new())),
+        devices: Arc::new(Mutex::new(devices)),
         transfers: Arc::new(Mutex::new(Vec::new())),
+        live_progress: Arc::new(parking_lot::RwLock::new(HashMap::new())),
+        hashed_files: Arc::new(Mutex::new(HashCache::default())),
+        received_from: Arc::new(Mutex::new(delta::load_origins())),
+        batches: Arc::new(Mutex::new(HashMap::new())),
+        broadcasts: Arc::new(Mutex::new(Vec::new())),
+        outbox: Arc::new(Mutex::new(outbox::load_outbox())),
+        sync_pairs: Arc::new(Mutex::new(sync::load_pairs())),
         mdns_daemon: Arc::new(Mutex::new(None)),
+        beacons: Arc::new(Mutex::new(None)),
+        port_mapping: Arc::new(Mutex::new(MappingState::default())),
+        hotspot: Arc::new(Mutex::new(None)),
+        bluetooth: Arc::new(Mutex::new(None)),
+        nearby: Arc::new(Mutex::new(HashMap::new())),
+        browser_shares: Arc::new(Mutex::new(HashMap::new())),
+        download_links: Arc::new(Mutex::new(HashMap::new())),
+        webdav: Arc::new(Mutex::new(None)),
+        dav_credentials: Arc::new(Mutex::new(webdav::load_credentials())),
+        upload_page: Arc::new(Mutex::new(None)),
+        tasks: tasks::Tasks::new(),
+        pending_outgoing: Arc::new(Mutex::new(launch::parse(&args, &cwd).into_iter().collect())),
+        known_devices: Arc::new(Mutex::new(known::load_known_devices())),
         device_id,
-        device_name: hostname,
-        server_port: 8888,
-        encryption_key,
+        device_name: Arc::new(Mutex::new(device_name)),
+        server: Arc::new(Mutex::new(ServerStatus::default())),
+        identity_key,
+        signing_key,
+        trusted_devices: Arc::new(Mutex::new(pairing::load_trusted_devices())),
+        pending_pairings: Arc::new(Mutex::new(HashMap::new())),
+        verified_keys: Arc::new(Mutex::new(HashSet::new())),
+        pending_approvals: Arc::new(Mutex::new(HashMap::new())),
+        pending_unlocks: Arc::new(Mutex::new(HashMap::new())),
+        settings: Arc::new(Mutex::new(settings)),
+        replay_cache: Arc::new(Mutex::new(ReplayCache::default())),
+        cancel_tokens: Arc::new(Mutex::new(HashMap::new())),
+        queue: Arc::new(TransferQueue::new(max_outgoing, max_incoming)),
+        throttle: Arc::new(Throttle::new(bandwidth_limit)),
+        stream_joins: Arc::new(Mutex::new(HashMap::new())),
+        daily_usage: Arc::new(Mutex::new(DailyUsage::load())),
+        history: Arc::new(Mutex::new(History::open())),
+        shares: Arc::new(Mutex::new(sharing::load_shares())),
+        messages: Arc::new(Mutex::new(messages::load_messages())),
+        pending_pulls: Arc::new(Mutex::new(HashMap::new())),
+        sync_pulls: Arc::new(Mutex::new(HashMap::new())),
+        synced_folders: Arc::new(Mutex::new(sync::load_synced_folders())),
+        quarantined: Arc::new(Mutex::new(quarantine::load_quarantine())),
+        guest_session: Arc::new(Mutex::new(None)),
+        held_files: Arc::new(Mutex::new(relay::load_held())),
+        relayed: Arc::new(Mutex::new(HashMap::new())),
+        routes: Arc::new(Mutex::new(RoutingTable::new())),
+        seen_discoveries: Arc::new(Mutex::new(SeenDiscoveries::new())),
+        links: Arc::new(Mutex::new(Links::new())),
+        relay_stats: Arc::new(Mutex::new(RelayStats::default())),
     };
 
+    app_state.throttle.set_transfer_limit(routing::RELAY_THROTTLE_KEY, Some(relay_bandwidth_limit));
+
     tauri::Builder::default()
+        // Only one of us may run, or there would be two of the same device
+        // on the network; launching again brings back the one running
+        .plugin(tauri_plugin_single_instance::init(|app, args, cwd| {
+            relaunched(app, args, cwd);
+        }))
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_notification::init())
         .manage(app_state)
+        // In the background a closed window is only hidden
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::CloseRequested { api, .. } = event {
+                let app = window.app_handle();
+                if app.state::<AppState>().settings.lock().run_in_background {
+                    api.prevent_close();
+                    let _ = window.hide();
+                }
+            }
+        })
+        .setup(|app| {
+            if let Err(e) = tray::create(app.handle()) {
+                eprintln!("Failed to create the tray icon: {}", e);
+            }
+            let handle = app.handle().clone();
+            tasks::supervise("progress", move || run_progress_reporter(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("outbox", move || run_outbox(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("sync", move || sync::run(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("relay", move || run_relay(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("routing", move || run_routing(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("heartbeats", move || run_heartbeats(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("internet", move || run_internet(handle.clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("network watch", move || run_network_watch(handle.clone()));
+            // Receive and be found from the start, for as long as we run
+            tauri::async_runtime::spawn(run_file_server(app.handle().clone()));
+            let handle = app.handle().clone();
+            tasks::supervise("discovery", move || run_discovery(handle.clone()));
+            let state = app.state::<AppState>();
+            let webdav_port = {
+                let settings = state.settings.lock();
+                settings.webdav_enabled.then_some(settings.webdav_port)
+            };
+            if let Some(port) = webdav_port {
+                match webdav::start(port, state.shares.clone(), state.dav_credentials.clone()) {
+                    Ok(server) => *state.webdav.lock() = Some(server),
+                    Err(e) => eprintln!("Failed to start the WebDAV export: {}", e),
+                }
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
-            start_discovery,
+            set_discovery_enabled,
+            get_discovery_enabled,
+            get_server_status,
+            get_pending_sends,
+            set_run_in_background,
+            set_receiving_paused,
+            set_notifications,
+            set_server_ports,
             get_devices,
-            start_file_server,
+            add_device_manually,
+            remove_manual_device,
             send_file,
+            send_files,
+            send_folder,
             get_transfers,
-            stop_discovery,
+            get_transfer_history,
+            clear_history,
+            remove_transfer,
+            clear_completed_transfers,
+            set_max_finished_transfers,
+            list_file_versions,
+            restore_version,
+            set_versions_kept,
+            set_quarantine_incoming,
+            get_quarantined_files,
+            release_from_quarantine,
+            discard_quarantined,
+            confirm_risky_file,
+            set_screening,
+            run_diagnostics,
+            get_statistics,
+            get_file_hash,
+            open_received_file,
+            show_in_folder,
+            get_batches,
+            send_file_to_many,
+            get_broadcasts,
+            schedule_send,
+            get_scheduled_sends,
+            cancel_scheduled_send,
+            add_sync_pair,
+            remove_sync_pair,
+            get_sync_status,
+            update_sync_rules,
+            set_sync_conflict_policy,
+            resolve_sync_conflict,
+            send_via_relay,
+            set_relay,
+            get_held_files,
+            send_to_device,
+            get_routes,
+            get_network_topology,
+            set_relay_policy,
+            set_gateway_mode,
+            set_internet_transfers,
+            set_network_group,
+            set_device_name,
+            set_device_icon,
+            get_relay_stats,
+            get_reachability_info,
+            pair_device,
+            confirm_pairing,
+            start_hotspot,
+            stop_hotspot,
+            join_hotspot,
+            share_to_browser,
+            cancel_browser_share,
+            get_browser_shares,
+            share_via_link,
+            cancel_download_link,
+            get_download_links,
+            start_upload_page,
+            stop_upload_page,
+            get_upload_page,
+            start_webdav,
+            stop_webdav,
+            get_webdav_status,
+            add_webdav_credential,
+            remove_webdav_credential,
+            get_webdav_credentials,
+            start_bluetooth,
+            stop_bluetooth,
+            get_nearby_devices,
+            get_trusted_devices,
+            set_device_permissions,
+            get_friend_code,
+            add_friend,
+            start_guest_session,
+            get_guest_session,
+            approve_guest,
+            end_guest_session,
+            get_device_fingerprint,
+            verify_device,
+            respond_to_transfer,
+            add_trusted_device,
+            remove_trusted_device,
+            set_accept_policy,
+            unlock_transfer,
+            cancel_transfer,
+            set_concurrency_limits,
+            set_bandwidth_limit,
+            set_retry_policy,
+            set_heartbeat_policy,
+            set_parallel_streams,
+            set_receive_limits,
+            request_file,
+            share_path,
+            unshare_path,
+            get_shares,
+            list_remote_files,
+            send_message,
+            get_messages,
+            set_transfer_bandwidth_limit,
+            get_queue,
+            reorder_queue,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| match event {
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            RunEvent::Opened { urls } => {
+                if let Some(send) = launch::opened(urls.iter().filter_map(|url| url.to_file_path().ok())) {
+                    launch::add(app, &app.state::<AppState>().pending_outgoing, send);
+                }
+            }
+            RunEvent::Exit => shut_down(app),
+            _ => {}
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn downloads() -> std::path::PathBuf {
+        dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap())
+    }
+
+    // Names of files and folders, leaving out "." and ".."
+    fn part() -> impl Strategy<Value = String> {
+        "[A-Za-z0-9 _-][A-Za-z0-9 ._-]{0,15}"
+    }
+
+    proptest! {
+        #[test]
+        fn names_stay_under_downloads(parts in prop::collection::vec(part(), 1..5), backslashes in any::<bool>()) {
+            let name = parts.join(if backslashes { "\\" } else { "/" });
+            let path = download_path_for(&name).unwrap();
+            prop_assert!(path.starts_with(downloads()));
+            prop_assert_eq!(path.strip_prefix(downloads()).unwrap().components().count(), parts.len());
+        }
+
+        #[test]
+        fn separators_and_dots_alone_are_nothing(parts in prop::collection::vec(prop_oneof![Just(""), Just(".")], 0..6)) {
+            prop_assert_eq!(download_path_for(&parts.join("/")), None);
+        }
+
+        #[test]
+        fn parent_folders_are_refused(parts in prop::collection::vec(part(), 0..4), at in any::<prop::sample::Index>()) {
+            let mut parts = parts;
+            parts.insert(at.index(parts.len() + 1), "..".to_string());
+            prop_assert_eq!(download_path_for(&parts.join("/")), None);
+        }
+
+        #[test]
+        fn drive_and_stream_names_are_refused(before in part(), after in part()) {
+            prop_assert_eq!(download_path_for(&format!("{}:{}", before, after)), None);
+            prop_assert_eq!(download_path_for(&format!("folder/{}:{}", before, after)), None);
+        }
+    }
 }
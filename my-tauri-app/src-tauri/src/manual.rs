@@ -0,0 +1,27 @@
+// Devices added by address, for networks where mDNS doesn't get through
+//
+// Such a device is asked who it is over an ordinary handshake, which gives
+// its identity key, and an IDENTIFY packet, whose signed answer gives its
+// device id and signing key. It then sits among the discovered devices and
+// is saved to disk, so it's still there after a restart.
+
+use std::path::PathBuf;
+
+use crate::{app_data_dir, Device};
+
+fn manual_devices_path() -> PathBuf {
+    app_data_dir().join("manual_devices.json")
+}
+
+pub fn load_manual_devices() -> Vec<Device> {
+    std::fs::read(manual_devices_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_manual_devices(devices: &[Device]) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(devices)?;
+    std::fs::write(manual_devices_path(), json)
+}
@@ -0,0 +1,59 @@
+// Text messages between devices
+//
+// Messages travel as MESSAGE packets over the same Noise channel as files,
+// one connection per message. History is kept per peer identity and
+// persisted, trimmed to the most recent MAX_HISTORY messages.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::app_data_dir;
+
+// Longest message accepted, in bytes
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    // Encoded identity key of the other device, and its name
+    pub peer: String,
+    pub peer_name: String,
+    pub from_me: bool,
+    pub text: String,
+    pub sent_at: String,
+}
+
+fn messages_path() -> PathBuf {
+    app_data_dir().join("messages.json")
+}
+
+pub fn load_messages() -> Vec<ChatMessage> {
+    std::fs::read(messages_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+// Add a message to the history and save it
+pub fn record(history: &mut Vec<ChatMessage>, message: ChatMessage) {
+    history.push(message);
+    if history.len() > MAX_HISTORY {
+        history.drain(..history.len() - MAX_HISTORY);
+    }
+    save(history);
+}
+
+// Drop every message with a peer and save what's left
+pub fn forget_peer(history: &mut Vec<ChatMessage>, peer: &str) {
+    history.retain(|message| message.peer != peer);
+    save(history);
+}
+
+fn save(history: &[ChatMessage]) {
+    if let Ok(json) = serde_json::to_vec_pretty(history) {
+        let _ = std::fs::create_dir_all(app_data_dir());
+        let _ = std::fs::write(messages_path(), json);
+    }
+}
@@ -0,0 +1,285 @@
+// Addresses, on networks that may be IPv4, IPv6 or both
+//
+// A device announces every address it has, and we keep all of them, best
+// first: one on a subnet we share, then others of a family we can use,
+// then IPv6 link-local ones. Link-local addresses only mean something on
+// a given interface, so they're kept once per interface of ours that has
+// one, with its index as the zone (`fe80::1%3`).
+//
+// Ranking only guesses: a VPN or a second network can make an address on
+// a subnet we share unreachable all the same. So connecting races every
+// address, each starting a moment after the one ranked above it, and takes
+// whichever connects first.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use if_addrs::{IfAddr, Interface};
+use x25519_dalek::StaticSecret;
+
+use crate::transport::SecureChannel;
+use crate::Device;
+
+// Head start each address gets over the one ranked below it
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+// How long any one address has to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often our addresses are looked at for a change of network
+pub const NETWORK_POLL: Duration = Duration::from_secs(3);
+
+// Most bytes in one TXT record value
+const MAX_TXT_VALUE: usize = 255;
+
+// Our network interfaces, loopback aside
+pub fn local_interfaces() -> Vec<Interface> {
+    if_addrs::get_if_addrs()
+        .map(|interfaces| interfaces.into_iter().filter(|i| !i.is_loopback()).collect())
+        .unwrap_or_default()
+}
+
+fn is_link_local_v6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+// Whether `ip` is on the same subnet as one of our interfaces
+fn on_our_subnet(ip: &IpAddr, interfaces: &[Interface]) -> bool {
+    interfaces.iter().any(|interface| match (&interface.addr, ip) {
+        (IfAddr::V4(own), IpAddr::V4(ip)) => {
+            let mask = u32::from(own.netmask);
+            u32::from(own.ip) & mask == u32::from(*ip) & mask
+        }
+        (IfAddr::V6(own), IpAddr::V6(ip)) if !is_link_local_v6(ip) => {
+            let mask = u128::from(own.netmask);
+            u128::from(own.ip) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    })
+}
+
+// Whether any of `addresses` is on a network we're on
+pub fn shares_subnet(addresses: &[IpAddr], interfaces: &[Interface]) -> bool {
+    addresses.iter().any(|ip| on_our_subnet(ip, interfaces))
+}
+
+// A device's announced addresses as we'd try them, best first
+pub fn order_addresses(announced: impl Iterator<Item = IpAddr>, interfaces: &[Interface]) -> Vec<String> {
+    let have_v4 = interfaces.iter().any(|i| i.ip().is_ipv4());
+    let have_v6 = interfaces.iter().any(|i| matches!(i.ip(), IpAddr::V6(ip) if !is_link_local_v6(&ip)));
+    let zones: Vec<u32> = interfaces
+        .iter()
+        .filter(|i| matches!(i.ip(), IpAddr::V6(ip) if is_link_local_v6(&ip)))
+        .filter_map(|i| i.index)
+        .collect();
+
+    let mut ranked: Vec<(u8, String)> = Vec::new();
+    for ip in announced {
+        let ip = ip.to_canonical();
+        match ip {
+            IpAddr::V6(v6) if is_link_local_v6(&v6) => {
+                ranked.extend(zones.iter().map(|zone| (4, format!("{}%{}", v6, zone))));
+            }
+            _ => {
+                let rank = match (on_our_subnet(&ip, interfaces), ip.is_ipv4()) {
+                    (true, true) => 0,
+                    (true, false) => 1,
+                    (false, true) if have_v4 => 2,
+                    (false, false) if have_v6 => 3,
+                    _ => 5,
+                };
+                ranked.push((rank, ip.to_string()));
+            }
+        }
+    }
+    ranked.sort();
+    ranked.dedup();
+    ranked.into_iter().map(|(_, address)| address).collect()
+}
+
+// A socket address for an address as kept on a device, zone included
+pub fn socket_addr(address: &str, port: u16) -> Option<SocketAddr> {
+    endpoint(address, port).parse().ok()
+}
+
+// `address`:`port` in the form connecting takes, IPv6 in brackets
+pub fn endpoint(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+// The address a connection came from, as we'd keep it on a device. IPv4
+// peers reaching a dual-stack socket show up as mapped IPv6 addresses.
+pub fn peer_address(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(v6) if is_link_local_v6(v6.ip()) && v6.scope_id() != 0 => {
+            format!("{}%{}", v6.ip(), v6.scope_id())
+        }
+        _ => addr.ip().to_canonical().to_string(),
+    }
+}
+
+// Addresses to try for a device, best first. Devices saved before they
+// carried several addresses only have the one they're listed under.
+pub fn device_addresses(device: &Device) -> Vec<String> {
+    if device.addresses.is_empty() {
+        vec![device.ip.clone()]
+    } else {
+        device.addresses.clone()
+    }
+}
+
+fn no_address() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "No usable address")
+}
+
+// Every address of ours, as announced: IPv4 first, then IPv6, link-local
+// ones without a zone since it means nothing to anyone else
+pub fn own_addresses(interfaces: &[Interface]) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = interfaces.iter().map(|interface| interface.ip()).collect();
+    addresses.sort_by_key(|ip| ip.is_ipv6());
+    addresses.dedup();
+    addresses
+}
+
+// A device's addresses once it's announced itself again: the ones just
+// announced, along with those it had before that are still on a network
+// we're on, since an announcement heard on one interface may leave out
+// the others
+pub fn merge_addresses(announced: impl Iterator<Item = IpAddr>, previous: &[String], interfaces: &[Interface]) -> Vec<String> {
+    let kept = previous
+        .iter()
+        .filter_map(|address| address.split('%').next()?.parse::<IpAddr>().ok())
+        .filter(|ip| on_our_subnet(ip, interfaces) || matches!(ip, IpAddr::V6(v6) if is_link_local_v6(v6)));
+    order_addresses(announced.chain(kept), interfaces)
+}
+
+// Links to `path` on a server of ours, one for each of our IPv4 addresses,
+// which browsers on other devices take more readily than IPv6 ones
+pub fn http_urls(port: u16, path: &str) -> Vec<String> {
+    own_addresses(&local_interfaces())
+        .into_iter()
+        .filter(IpAddr::is_ipv4)
+        .map(|ip| format!("http://{}:{}{}", ip, port, path))
+        .collect()
+}
+
+// Our addresses for the `addrs` TXT record, as many as fit in one value
+pub fn txt_addresses(addresses: &[IpAddr]) -> String {
+    let mut value = String::new();
+    for address in addresses.iter().map(IpAddr::to_string) {
+        let len = value.len() + address.len() + usize::from(!value.is_empty());
+        if len > MAX_TXT_VALUE {
+            break;
+        }
+        if !value.is_empty() {
+            value.push(',');
+        }
+        value.push_str(&address);
+    }
+    value
+}
+
+// Addresses listed in a device's `addrs` TXT record
+pub fn parse_txt_addresses(value: &str) -> impl Iterator<Item = IpAddr> + '_ {
+    value.split(',').filter_map(|address| address.trim().parse().ok())
+}
+
+// Open a plain connection to whichever of `addresses` answers first
+pub fn tcp_connect(addresses: &[String], port: u16) -> std::io::Result<TcpStream> {
+    race_connect(addresses, port, CONNECT_TIMEOUT)
+}
+
+// Each address is tried on its own thread, starting CONNECT_STAGGER after
+// the one ranked above it, so better addresses get the first chance but a
+// dead one holds nothing up for long. Addresses whose turn comes after
+// another has connected aren't tried at all.
+fn race_connect(addresses: &[String], port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let targets: Vec<SocketAddr> = addresses.iter().filter_map(|address| socket_addr(address, port)).collect();
+    if targets.is_empty() {
+        return Err(no_address());
+    }
+    
+    let connected = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    for (rank, target) in targets.into_iter().enumerate() {
+        let (tx, connected) = (tx.clone(), connected.clone());
+        std::thread::spawn(move || {
+            std::thread::sleep(CONNECT_STAGGER * rank as u32);
+            if !connected.load(Ordering::SeqCst) {
+                let _ = tx.send(TcpStream::connect_timeout(&target, timeout));
+            }
+        });
+    }
+    drop(tx);
+    
+    // A slower attempt that connects anyway is dropped, closing it
+    let mut last_error = no_address();
+    for attempt in rx {
+        match attempt {
+            Ok(stream) => {
+                connected.store(true, Ordering::SeqCst);
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Connect to the first of `addresses` that answers and complete the
+// handshake over it
+pub fn connect_any(addresses: &[String], port: u16, identity: &StaticSecret) -> std::io::Result<SecureChannel> {
+    SecureChannel::connect_over(tcp_connect(addresses, port)?, identity)
+}
+
+// Connect to a device at whichever of its addresses answers
+pub fn connect_device(device: &Device, identity: &StaticSecret) -> std::io::Result<SecureChannel> {
+    connect_any(&device_addresses(device), device.port, identity)
+}
+
+// Connect to a device, giving up if it doesn't answer within `timeout`,
+// handshake included
+pub fn connect_device_timeout(device: &Device, identity: &StaticSecret, timeout: Duration) -> std::io::Result<SecureChannel> {
+    let stream = race_connect(&device_addresses(device), device.port, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let channel = SecureChannel::connect_over(stream, identity)?;
+    channel.set_read_timeout(None)?;
+    Ok(channel)
+}
+
+// Listeners on the first free port of the `count` from `first` on, or on
+// any free port once those are all taken. With a count of 0 any free port
+// will do from the start.
+pub fn bind_port_range(first: u16, count: u16) -> std::io::Result<Vec<TcpListener>> {
+    for port in (first..=u16::MAX).take(usize::from(count)) {
+        match bind_dual_stack(port) {
+            Ok(listeners) => return Ok(listeners),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => eprintln!("Couldn't listen on port {}: {}", port, e),
+        }
+    }
+    bind_dual_stack(0)
+}
+
+// Listeners for both address families on `port`. Where an IPv6 socket
+// takes IPv4 connections too, it's the only one; where it doesn't, an
+// IPv4 listener on the same port sits beside it. Machines without IPv6
+// get just the IPv4 one.
+pub fn bind_dual_stack(port: u16) -> std::io::Result<Vec<TcpListener>> {
+    let v6 = match TcpListener::bind(("::", port)) {
+        Ok(listener) => listener,
+        Err(_) => return Ok(vec![TcpListener::bind(("0.0.0.0", port))?]),
+    };
+    let port = v6.local_addr()?.port();
+    let mut listeners = vec![v6];
+    if let Ok(v4) = TcpListener::bind(("0.0.0.0", port)) {
+        listeners.push(v4);
+    }
+    Ok(listeners)
+}
@@ -0,0 +1,73 @@
+// Notifications from the operating system
+//
+// Incoming transfers waiting to be accepted, and transfers that complete
+// or fail, are announced as native notifications, each kind unless turned
+// off in the settings. Nothing is shown while the window has focus, since
+// it's all there already.
+//
+// A received file's notification carries the OPEN_FILE action with the
+// transfer's id; where the platform shows actions the UI opens the file
+// when it's picked. Elsewhere the file is a click away in the tray's recent
+// transfers.
+
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::settings::NotificationSettings;
+use crate::{events, AppState, FileTransfer};
+
+pub const OPEN_FILE: &str = "open-file";
+
+// A device wants to send us a file
+pub fn request(app: &AppHandle, filename: &str, from_device: &str) {
+    if !enabled(app, |n| n.requests) {
+        return;
+    }
+    show(app, "Incoming file", format!("{} wants to send {}", from_device, filename), None);
+}
+
+// A device asks for a file we share
+pub fn file_request(app: &AppHandle, path: &str, from_device: &str) {
+    if !enabled(app, |n| n.requests) {
+        return;
+    }
+    show(app, "File requested", format!("{} asks for {}", from_device, path), None);
+}
+
+// A transfer finished, announced under `event` as it is to the UI
+pub fn finished(app: &AppHandle, event: &str, transfer: &FileTransfer) {
+    if event == events::COMPLETED {
+        if !enabled(app, |n| n.completed) {
+            return;
+        }
+        match &transfer.saved_path {
+            Some(_) => show(app, "File received", format!("{} from {}", transfer.filename, transfer.from_device), Some(&transfer.id)),
+            None => show(app, "File sent", format!("{} to {}", transfer.filename, transfer.to_device), None),
+        }
+    } else {
+        if !enabled(app, |n| n.failed) {
+            return;
+        }
+        let reason = transfer.error.as_ref().map_or(transfer.status.as_str(), |error| error.message.as_str());
+        show(app, "Transfer failed", format!("{}: {}", transfer.filename, reason), None);
+    }
+}
+
+// Whether to show a notification of a kind now. Transfers cut short by
+// quitting aren't worth one.
+fn enabled(app: &AppHandle, kind: impl Fn(&NotificationSettings) -> bool) -> bool {
+    let state = app.state::<AppState>();
+    let looking = app.get_webview_window("main").is_some_and(|window| window.is_focused().unwrap_or(false));
+    !looking && !state.tasks.is_shut_down() && kind(&state.settings.lock().notifications)
+}
+
+// Show a notification, offering to open the file `received` saved
+fn show(app: &AppHandle, title: &str, body: String, received: Option<&str>) {
+    let mut notification = app.notification().builder().title(title).body(body);
+    if let Some(transfer_id) = received {
+        notification = notification.action_type_id(OPEN_FILE).extra("transfer_id", transfer_id);
+    }
+    if let Err(e) = notification.show() {
+        eprintln!("Failed to show notification: {}", e);
+    }
+}
@@ -0,0 +1,70 @@
+// Sends waiting for a set time or for their device to come online
+//
+// The target is kept as the device's identity key, which stays the same
+// across restarts and address changes. The outbox is saved to disk and
+// checked every CHECK_INTERVAL. An entry goes out once its time has come
+// and its device is online, as an ordinary transfer, and only leaves the
+// outbox once that transfer has delivered the file or the user cancelled
+// it. A send that fails stays, due again after RETRY_DELAY, doubling with
+// each failure up to MAX_RETRY_DELAY.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::app_data_dir;
+
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledSend {
+    pub id: String,
+    pub path: String,
+    // Identity key and name of the device to send to
+    pub target: String,
+    pub target_name: String,
+    // Unix seconds; with no time it goes as soon as the device is online
+    pub send_at: Option<i64>,
+    pub compression: bool,
+    pub created_at: String,
+    // Sends that failed so far
+    #[serde(default)]
+    pub attempts: u32,
+    // Going out now; one cut short by quitting goes again on the next run
+    #[serde(default, skip_deserializing)]
+    pub sending: bool,
+}
+
+impl ScheduledSend {
+    pub fn is_due(&self, now: i64) -> bool {
+        !self.sending && self.send_at.is_none_or(|at| at <= now)
+    }
+
+    // Put off a send that failed until it's tried again
+    pub fn retry_later(&mut self, now: i64) {
+        let delay = RETRY_DELAY.saturating_mul(1 << self.attempts.min(16)).min(MAX_RETRY_DELAY);
+        self.attempts += 1;
+        self.sending = false;
+        self.send_at = Some(now + delay.as_secs() as i64);
+    }
+}
+
+fn outbox_path() -> PathBuf {
+    app_data_dir().join("outbox.json")
+}
+
+pub fn load_outbox() -> Vec<ScheduledSend> {
+    std::fs::read(outbox_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_outbox(outbox: &[ScheduledSend]) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(outbox)?;
+    std::fs::write(outbox_path(), json)
+}
@@ -0,0 +1,278 @@
+// Device pairing with short authentication strings (SAS)
+//
+// Both sides derive a six-digit code from the Noise handshake hash. A man
+// in the middle ends up with a different handshake on each leg, so the
+// codes only match when the users are really talking to each other.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::Duration;
+use tauri::Emitter;
+use uuid::Uuid;
+use x25519_dalek::PublicKey;
+
+use crate::transport::SecureChannel;
+use crate::{
+    app_data_dir, encode_public_key, PeerContext, PACKET_FILE_REQUEST, PACKET_FILE_TRANSFER, PACKET_FORWARD,
+    PACKET_HAS_FILE, PACKET_HOLD_FOR, PACKET_LIST_FILES, PACKET_MESSAGE, PACKET_ROUTE_DISCOVERY, PACKET_SYNC_DELETE,
+    PACKET_SYNC_FETCH, PACKET_SYNC_LIST,
+};
+
+// How long either user has to compare and confirm the code
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A device we have paired with, keyed by its encoded identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedDevice {
+    pub public_key: String,
+    // Stable id the device advertises, once we've seen it
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub signing_key: String,
+    pub name: String,
+    pub paired_at: String,
+    #[serde(default)]
+    pub permissions: DevicePermissions,
+}
+
+// What a paired device may do with us; all of it unless the user holds it
+// back. Listing and fetching a synced folder read from us, as browsing
+// does; deleting from one changes our files, as sending does.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DevicePermissions {
+    pub send_files: bool,
+    pub browse_shares: bool,
+    pub relay: bool,
+    pub messages: bool,
+}
+
+impl Default for DevicePermissions {
+    fn default() -> Self {
+        DevicePermissions {
+            send_files: true,
+            browse_shares: true,
+            relay: true,
+            messages: true,
+        }
+    }
+}
+
+impl DevicePermissions {
+    // Why a packet of this type is refused, if the device isn't allowed
+    // what it asks for
+    pub fn refuses(&self, packet_type: &str) -> Option<&'static str> {
+        let (allowed, reason) = match packet_type {
+            PACKET_FILE_TRANSFER | PACKET_SYNC_DELETE => (self.send_files, "Not allowed to send files"),
+            PACKET_LIST_FILES | PACKET_FILE_REQUEST | PACKET_HAS_FILE | PACKET_SYNC_LIST | PACKET_SYNC_FETCH => {
+                (self.browse_shares, "Not allowed to browse shared files")
+            }
+            PACKET_FORWARD | PACKET_HOLD_FOR | PACKET_ROUTE_DISCOVERY => (self.relay, "Not allowed to relay"),
+            PACKET_MESSAGE => (self.messages, "Not allowed to send messages"),
+            _ => (true, ""),
+        };
+        (!allowed).then_some(reason)
+    }
+}
+
+// Pairing in progress, shown to the user while codes are compared
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingSession {
+    pub pairing_id: String,
+    pub device_name: String,
+    pub code: String,
+}
+
+// Our fingerprint plus those of discovered peers
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceFingerprints {
+    pub own: String,
+    pub peers: Vec<PeerFingerprint>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerFingerprint {
+    pub device_id: String,
+    pub name: String,
+    pub fingerprint: String,
+    pub verified: bool,
+}
+
+// Outcome emitted once both sides have answered
+#[derive(Debug, Clone, Serialize)]
+struct PairingResult {
+    pairing_id: String,
+    device_name: String,
+    paired: bool,
+}
+
+fn trusted_store_path() -> std::path::PathBuf {
+    app_data_dir().join("trusted_devices.json")
+}
+
+// Load the trusted-device store, starting empty if missing or unreadable
+pub fn load_trusted_devices() -> HashMap<String, TrustedDevice> {
+    std::fs::read(trusted_store_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+// Persist the trusted-device store
+pub fn save_trusted_devices(devices: &HashMap<String, TrustedDevice>) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(devices)?;
+    std::fs::write(trusted_store_path(), json)
+}
+
+// Human-readable fingerprint of an identity key: the first 128 bits of its
+// SHA-256 digest as eight groups of four hex digits
+pub fn fingerprint(key: &PublicKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Derive the six-digit code both users compare
+fn short_auth_string(handshake_hash: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"FileSharePro SAS")
+        .chain_update(handshake_hash)
+        .finalize();
+    let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", value / 1000, value % 1000)
+}
+
+// Register a pending pairing and compute its code. The returned receiver
+// yields the local user's decision via `confirm_pairing`.
+pub fn begin_pairing(
+    channel: &SecureChannel,
+    peer_name: &str,
+    ctx: &PeerContext,
+) -> (PairingSession, mpsc::Receiver<bool>) {
+    let pairing = PairingSession {
+        pairing_id: Uuid::new_v4().to_string(),
+        device_name: peer_name.to_string(),
+        code: short_auth_string(channel.handshake_hash()),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    ctx.pending_pairings.lock().insert(pairing.pairing_id.clone(), tx);
+
+    (pairing, rx)
+}
+
+// Accept a pending pairing without asking, when the peer was checked some
+// other way
+pub fn accept(pairing: &PairingSession, ctx: &PeerContext) {
+    if let Some(decision) = ctx.pending_pairings.lock().get(&pairing.pairing_id) {
+        let _ = decision.send(true);
+    }
+}
+
+// Wait for the local decision, exchange it with the peer, and store the
+// peer's identity key if both sides accepted.
+pub fn finish_pairing(
+    mut channel: SecureChannel,
+    peer_signing_key: String,
+    pairing: PairingSession,
+    decision: mpsc::Receiver<bool>,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    let accepted = decision.recv_timeout(PAIRING_TIMEOUT).unwrap_or(false);
+    ctx.pending_pairings.lock().remove(&pairing.pairing_id);
+
+    channel.set_read_timeout(Some(PAIRING_TIMEOUT))?;
+    channel.send(&[accepted as u8])?;
+    let peer_answer = channel.recv()?;
+
+    let paired = accepted && peer_answer == [1];
+    if paired {
+        let public_key = encode_public_key(channel.peer_identity());
+        let device_id = ctx.devices.lock()
+            .values()
+            .find(|d| d.public_key == public_key)
+            .map(|d| d.id.clone())
+            .unwrap_or_default();
+        let mut trusted = ctx.trusted_devices.lock();
+        // Pairing again keeps what the user held back from the device
+        let permissions = trusted.get(&public_key)
+            .map(|device| device.permissions)
+            .unwrap_or_default();
+        trusted.insert(public_key.clone(), TrustedDevice {
+            public_key,
+            device_id,
+            signing_key: peer_signing_key,
+            name: pairing.device_name.clone(),
+            paired_at: chrono::Local::now().to_rfc3339(),
+            permissions,
+        });
+        save_trusted_devices(&trusted)?;
+    }
+
+    let _ = ctx.app.emit("pairing://completed", PairingResult {
+        pairing_id: pairing.pairing_id,
+        device_name: pairing.device_name,
+        paired,
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn each_permission_covers_its_packets(
+            send_files in any::<bool>(),
+            browse_shares in any::<bool>(),
+            relay in any::<bool>(),
+            messages in any::<bool>(),
+        ) {
+            let permissions = DevicePermissions { send_files, browse_shares, relay, messages };
+            let covered = [
+                (PACKET_FILE_TRANSFER, send_files),
+                (PACKET_SYNC_DELETE, send_files),
+                (PACKET_LIST_FILES, browse_shares),
+                (PACKET_FILE_REQUEST, browse_shares),
+                (PACKET_HAS_FILE, browse_shares),
+                (PACKET_SYNC_LIST, browse_shares),
+                (PACKET_SYNC_FETCH, browse_shares),
+                (PACKET_FORWARD, relay),
+                (PACKET_HOLD_FOR, relay),
+                (PACKET_ROUTE_DISCOVERY, relay),
+                (PACKET_MESSAGE, messages),
+            ];
+            for (packet_type, allowed) in covered {
+                prop_assert_eq!(permissions.refuses(packet_type).is_none(), allowed, "{}", packet_type);
+            }
+        }
+
+        // What no permission covers, such as pairing, is never refused
+        #[test]
+        fn other_packets_are_let_through(packet_type in "[A-Z_]{1,20}") {
+            let covered = [
+                PACKET_FILE_TRANSFER, PACKET_SYNC_DELETE, PACKET_LIST_FILES, PACKET_FILE_REQUEST, PACKET_HAS_FILE,
+                PACKET_SYNC_LIST, PACKET_SYNC_FETCH, PACKET_FORWARD, PACKET_HOLD_FOR, PACKET_ROUTE_DISCOVERY,
+                PACKET_MESSAGE,
+            ];
+            prop_assume!(!covered.contains(&packet_type.as_str()));
+            let nothing = DevicePermissions { send_files: false, browse_shares: false, relay: false, messages: false };
+            prop_assert_eq!(nothing.refuses(&packet_type), None);
+        }
+
+        #[test]
+        fn default_refuses_nothing(packet_type in "[A-Z_]{1,20}") {
+            prop_assert_eq!(DevicePermissions::default().refuses(&packet_type), None);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Parallel streams for large files
+//
+// One TCP stream rarely fills a fast LAN link. The sender offers a number
+// of streams in its file header; the receiver answers with how many it
+// will take and a token, and the sender opens that many extra connections
+// which join the transfer by presenting the token. Chunks are striped
+// across the streams, each carrying every Nth chunk, and written at their
+// offsets. A peer that doesn't know about streams never answers the offer,
+// so the transfer stays on one connection.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use x25519_dalek::PublicKey;
+
+use crate::transport::SecureChannel;
+
+// Upper bound on streams per file, whatever the settings say
+pub const MAX_STREAMS: usize = 16;
+
+// Files with fewer chunks left than this always use a single stream
+const MIN_PARALLEL_CHUNKS: u64 = 8;
+
+// How long the receiver waits for the extra streams to connect
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A transfer waiting for its extra streams, and who may supply them
+pub struct PendingJoin {
+    peer: PublicKey,
+    signing_key: String,
+    sender: mpsc::Sender<(u64, SecureChannel)>,
+}
+
+pub type StreamJoins = Arc<Mutex<HashMap<String, PendingJoin>>>;
+
+// Number of streams the receiver agrees to, given the sender's offer
+pub fn negotiate(offered: u64, allowed: usize, remaining_chunks: u64) -> usize {
+    if remaining_chunks < MIN_PARALLEL_CHUNKS {
+        return 1;
+    }
+    (offered.min(remaining_chunks) as usize).min(allowed).clamp(1, MAX_STREAMS)
+}
+
+// Chunks carried by one stream, starting from the first chunk still needed
+pub fn stripe(first: u64, total: u64, streams: usize, stream: usize) -> impl Iterator<Item = u64> {
+    (first + stream as u64..total).step_by(streams)
+}
+
+// Start accepting extra streams for a transfer. Only connections from the
+// same peer, signed with the same key, may join.
+pub fn expect_joins(
+    joins: &StreamJoins,
+    token: &str,
+    peer: PublicKey,
+    signing_key: &str,
+) -> mpsc::Receiver<(u64, SecureChannel)> {
+    let (sender, receiver) = mpsc::channel();
+    joins.lock().insert(token.to_string(), PendingJoin {
+        peer,
+        signing_key: signing_key.to_string(),
+        sender,
+    });
+    receiver
+}
+
+// Hand a joining connection to the transfer it names
+pub fn deliver(
+    joins: &StreamJoins,
+    token: &str,
+    index: u64,
+    signing_key: &str,
+    channel: SecureChannel,
+) -> Result<(), String> {
+    let joins = joins.lock();
+    let pending = joins.get(token).ok_or("No transfer is waiting for this stream")?;
+    if pending.peer.as_bytes() != channel.peer_identity().as_bytes() || pending.signing_key != signing_key {
+        return Err("Stream joined from a different device".to_string());
+    }
+    pending.sender.send((index, channel)).map_err(|e| e.to_string())
+}
+
+// Wait for `count` extra streams, numbered from 1, and return them in order
+pub fn await_joins(
+    joins: &StreamJoins,
+    token: &str,
+    receiver: mpsc::Receiver<(u64, SecureChannel)>,
+    count: usize,
+) -> io::Result<Vec<SecureChannel>> {
+    let deadline = Instant::now() + JOIN_TIMEOUT;
+    let mut slots: Vec<Option<SecureChannel>> = (0..count).map(|_| None).collect();
+    let mut joined = 0;
+    while joined < count {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let Ok((index, channel)) = receiver.recv_timeout(wait) else {
+            break;
+        };
+        if let Some(slot) = slots.get_mut((index as usize).wrapping_sub(1)).filter(|s| s.is_none()) {
+            *slot = Some(channel);
+            joined += 1;
+        }
+    }
+    joins.lock().remove(token);
+
+    if joined < count {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Parallel streams did not connect"));
+    }
+    Ok(slots.into_iter().flatten().collect())
+}
+
+// Run one worker per stream, each on its own thread. A worker returns
+// false to stop the whole transfer; the other streams are then shut down,
+// and the errors that causes are ignored.
+pub fn run_streams<F>(channels: Vec<&mut SecureChannel>, worker: F) -> io::Result<bool>
+where
+    F: Fn(usize, &mut SecureChannel) -> io::Result<bool> + Sync,
+{
+    // With a single stream there is nobody to wake up
+    let sockets = if channels.len() > 1 {
+        channels.iter().map(|c| c.try_clone_stream()).collect::<io::Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+
+    let results: Vec<io::Result<bool>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = channels.into_iter()
+            .enumerate()
+            .map(|(stream, channel)| {
+                let (worker, sockets) = (&worker, &sockets);
+                scope.spawn(move || {
+                    let result = worker(stream, channel);
+                    if !matches!(result, Ok(true)) {
+                        for socket in sockets {
+                            let _ = socket.shutdown(Shutdown::Both);
+                        }
+                    }
+                    result
+                })
+            })
+            .collect();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(io::Error::other("Stream worker panicked"))))
+            .collect()
+    });
+
+    if results.iter().any(|r| matches!(r, Ok(false))) {
+        return Ok(false);
+    }
+    results.into_iter().find(Result::is_err).unwrap_or(Ok(true))
+}
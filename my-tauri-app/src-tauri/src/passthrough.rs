@@ -0,0 +1,125 @@
+// Moving relayed bytes from one socket to another
+//
+// A relay only ever holds one PASSTHROUGH_SIZE stretch of a connection at
+// a time: it's taken in from one socket, and nothing more is read until
+// all of it has gone out of the other. A slow receiver so holds up the
+// sender through TCP's own flow control, and a relay's memory stays the
+// same whatever the size of the files going through it.
+//
+// On Linux the stretch sits in a pipe and is moved with splice(2), so it
+// never leaves the kernel. Elsewhere it's copied through a buffer of ours.
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+// Most bytes taken in at a time
+pub const PASSTHROUGH_SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+pub struct Passthrough {
+    read_end: std::os::fd::OwnedFd,
+    write_end: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Passthrough {
+    pub fn new() -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let mut fds = [0; 2];
+        // SAFETY: pipe2 fills in two new descriptors, which are owned from
+        // here on
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let (read_end, write_end) = unsafe {
+            (std::os::fd::OwnedFd::from_raw_fd(fds[0]), std::os::fd::OwnedFd::from_raw_fd(fds[1]))
+        };
+        Ok(Passthrough { read_end, write_end })
+    }
+
+    // Take in what `from` has, up to PASSTHROUGH_SIZE, waiting until it has
+    // something; 0 once it's closed
+    pub async fn fill(&mut self, from: &mut OwnedReadHalf) -> std::io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        let socket = from.as_ref();
+        loop {
+            socket.readable().await?;
+            let moved = socket.try_io(tokio::io::Interest::READABLE, || {
+                splice(socket.as_raw_fd(), self.write_end.as_raw_fd(), PASSTHROUGH_SIZE)
+            });
+            match moved {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                moved => return moved,
+            }
+        }
+    }
+
+    // Send all `len` bytes taken in on to `to`
+    pub async fn drain(&mut self, to: &mut OwnedWriteHalf, len: usize) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let socket = to.as_ref();
+        let mut left = len;
+        while left > 0 {
+            socket.writable().await?;
+            let moved = socket.try_io(tokio::io::Interest::WRITABLE, || {
+                splice(self.read_end.as_raw_fd(), socket.as_raw_fd(), left)
+            });
+            match moved {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(moved) => left -= moved,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: std::os::fd::RawFd, to: std::os::fd::RawFd, len: usize) -> std::io::Result<usize> {
+    // SAFETY: both descriptors stay open for the call, and no offsets are
+    // passed since neither end is a file
+    let moved = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Passthrough {
+    buffer: Vec<u8>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Passthrough {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Passthrough { buffer: vec![0u8; PASSTHROUGH_SIZE] })
+    }
+
+    // Take in what `from` has, up to PASSTHROUGH_SIZE, waiting until it has
+    // something; 0 once it's closed
+    pub async fn fill(&mut self, from: &mut OwnedReadHalf) -> std::io::Result<usize> {
+        use tokio::io::AsyncReadExt;
+
+        from.read(&mut self.buffer).await
+    }
+
+    // Send all `len` bytes taken in on to `to`
+    pub async fn drain(&mut self, to: &mut OwnedWriteHalf, len: usize) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        to.write_all(&self.buffer[..len]).await
+    }
+}
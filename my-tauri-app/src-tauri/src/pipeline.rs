@@ -0,0 +1,108 @@
+// Sealing a file's chunks ahead of sending them
+//
+// Every chunk of a file is read, delta encoded, compressed and encrypted
+// before it's sent, after which its stream waits for the receiver to take
+// it. Done one after another on the stream's own thread, a fast link sits
+// idle while a single core seals, and the cores sit idle while the link
+// carries the chunk.
+//
+// `seal_ahead` splits the work into three stages. A reader reads the
+// stream's chunks in order; the rayon pool seals them a batch at a time,
+// across as many cores as it has; and the stream gets them back in order
+// through a bounded channel, with nothing left to do but send them. One
+// batch waits to be sent while the next is sealed, and no more, so memory
+// stays the same however large the file.
+//
+// Since chunks are sealed out of order and on any thread, each chunk's
+// nonce is its index in the file rather than a count kept by the sender.
+// A transfer's body key is its own, and a chunk is sent on one stream
+// only, so no nonce is used twice under a key.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::sync::mpsc;
+
+use rayon::prelude::*;
+
+use crate::{read_chunk, CHUNK_SIZE};
+
+// Most chunks in a batch, sealed together
+const MAX_BATCH: usize = 8;
+
+// A chunk ready to go on the wire
+pub struct SealedChunk {
+    pub index: u64,
+    // Bytes of the file it carries, and of the payload that was encrypted
+    pub raw_len: usize,
+    pub packed_len: usize,
+    pub sealed: Vec<u8>,
+}
+
+// Read the chunks at `indices` from `file`, seal each with `seal`, and
+// hand them to `send` in order, reading and sealing the next while it
+// sends. `send` gives back false to stop early; so does this when it does.
+pub fn seal_ahead<S, F>(
+    mut file: File,
+    indices: impl Iterator<Item = u64> + Send,
+    seal: &S,
+    mut send: F,
+) -> io::Result<bool>
+where
+    S: Fn(u64, &[u8]) -> io::Result<SealedChunk> + Sync,
+    F: FnMut(SealedChunk) -> io::Result<bool>,
+{
+    let batch_size = rayon::current_num_threads().clamp(1, MAX_BATCH);
+    let (sealed_tx, sealed_rx) = mpsc::sync_channel::<io::Result<SealedChunk>>(batch_size);
+
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut indices = indices;
+            loop {
+                let mut batch = Vec::with_capacity(batch_size);
+                let mut ended = false;
+                for index in indices.by_ref().take(batch_size) {
+                    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                    let read = file
+                        .seek(SeekFrom::Start(index * CHUNK_SIZE as u64))
+                        .and_then(|_| read_chunk(&mut file, &mut chunk));
+                    match read {
+                        Ok(0) => {
+                            ended = true;
+                            break;
+                        }
+                        Ok(_) => batch.push((index, chunk)),
+                        Err(e) => {
+                            let _ = sealed_tx.send(Err(e));
+                            return;
+                        }
+                    }
+                }
+                if batch.is_empty() {
+                    return;
+                }
+
+                let sealed: Vec<io::Result<SealedChunk>> = batch
+                    .par_iter()
+                    .map(|(index, chunk)| seal(*index, chunk))
+                    .collect();
+                // The stream gave up if nobody's receiving
+                for chunk in sealed {
+                    if sealed_tx.send(chunk).is_err() {
+                        return;
+                    }
+                }
+                if ended {
+                    return;
+                }
+            }
+        });
+
+        // Dropping the receiver on the way out stops the reader
+        for chunk in sealed_rx {
+            if !send(chunk?)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    })
+}
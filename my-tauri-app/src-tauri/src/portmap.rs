@@ -0,0 +1,192 @@
+// Port forwarding on home routers, so devices on other networks can reach
+// our file server
+//
+// We ask the router to forward the server's port to us, over UPnP IGD if
+// it answers, NAT-PMP if not. Mappings are leased: they're renewed at half
+// their lifetime while we run, and removed when we quit.
+//
+// NAT-PMP has to be sent to the default gateway, which the standard
+// library can't tell us, so we try the first address of each private IPv4
+// network we're on, which is where home routers almost always sit.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use if_addrs::IfAddr;
+use igd_next::{PortMappingProtocol, SearchOptions};
+use serde::Serialize;
+
+use crate::net;
+
+// How long we ask for each mapping to last
+const LEASE_SECS: u32 = 3600;
+
+// How long a router has to answer
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long to wait before trying again when no router would map the port
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_MAP_TCP: u8 = 2;
+
+const DESCRIPTION: &str = "Reality file sharing";
+
+// A port forwarded to us by the router
+#[derive(Debug, Clone, Serialize)]
+pub struct PortMapping {
+    // "upnp" or "nat-pmp"
+    pub method: String,
+    pub gateway: String,
+    // Where devices outside can reach our file server
+    pub external_address: String,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lease_secs: u32,
+    #[serde(skip)]
+    upnp: Option<igd_next::Gateway>,
+}
+
+impl PortMapping {
+    // When to ask for the mapping again, before it runs out
+    pub fn renew_after(&self) -> Duration {
+        Duration::from_secs(u64::from(self.lease_secs.max(2) / 2))
+    }
+}
+
+// The mapping we hold, or why we hold none
+#[derive(Default)]
+pub struct MappingState {
+    pub mapping: Option<PortMapping>,
+    pub error: Option<String>,
+    // The port to forward, the one the file server is listening on
+    pub port: u16,
+    // Set once something keeps the mapping renewed
+    pub running: bool,
+    // Wakes whatever keeps it renewed to map the port again at once, as
+    // when we've moved to another network and so behind another router
+    pub remap: Option<mpsc::Sender<()>>,
+}
+
+// Our IPv4 address on the network `gateway` is on
+fn local_address_towards(gateway: Ipv4Addr) -> Option<Ipv4Addr> {
+    net::local_interfaces().into_iter().find_map(|interface| match interface.addr {
+        IfAddr::V4(v4) => {
+            let mask = u32::from(v4.netmask);
+            (u32::from(v4.ip) & mask == u32::from(gateway) & mask).then_some(v4.ip)
+        }
+        IfAddr::V6(_) => None,
+    })
+}
+
+fn map_upnp(port: u16) -> Result<PortMapping, String> {
+    let gateway = igd_next::search_gateway(SearchOptions {
+        timeout: Some(GATEWAY_TIMEOUT),
+        ..Default::default()
+    })
+    .map_err(|e| format!("No UPnP gateway: {}", e))?;
+    let IpAddr::V4(gateway_ip) = gateway.addr.ip() else {
+        return Err("UPnP gateway is not on IPv4".to_string());
+    };
+    let local = local_address_towards(gateway_ip).ok_or("Not on the UPnP gateway's network")?;
+    gateway.add_port(PortMappingProtocol::TCP, port, SocketAddr::from((local, port)), LEASE_SECS, DESCRIPTION)
+        .map_err(|e| format!("UPnP mapping refused: {}", e))?;
+    let external_ip = gateway.get_external_ip().map_err(|e| format!("No external address: {}", e))?;
+
+    Ok(PortMapping {
+        method: "upnp".to_string(),
+        gateway: gateway.addr.to_string(),
+        external_address: SocketAddr::new(external_ip, port).to_string(),
+        internal_port: port,
+        external_port: port,
+        lease_secs: LEASE_SECS,
+        upnp: Some(gateway),
+    })
+}
+
+// Routers NAT-PMP may be running on
+fn nat_pmp_gateways() -> Vec<Ipv4Addr> {
+    net::local_interfaces().into_iter().filter_map(|interface| match interface.addr {
+        IfAddr::V4(v4) if v4.ip.is_private() => {
+            let mask = u32::from(v4.netmask);
+            Some(Ipv4Addr::from((u32::from(v4.ip) & mask) | 1))
+        }
+        _ => None,
+    })
+    .collect()
+}
+
+// Send a NAT-PMP request and wait for the answer to it, checking the
+// result code
+fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8], response_len: usize) -> Result<Vec<u8>, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    socket.set_read_timeout(Some(GATEWAY_TIMEOUT)).map_err(|e| e.to_string())?;
+    socket.send_to(request, SocketAddrV4::new(gateway, NAT_PMP_PORT)).map_err(|e| e.to_string())?;
+
+    let mut response = vec![0u8; response_len];
+    let (len, from) = socket.recv_from(&mut response).map_err(|_| format!("No NAT-PMP answer from {}", gateway))?;
+    if from.ip() != IpAddr::V4(gateway) || len < response_len || response[1] != request[1] | 0x80 {
+        return Err("Malformed NAT-PMP answer".to_string());
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(response),
+        code => Err(format!("NAT-PMP request refused ({})", code)),
+    }
+}
+
+// Ask `gateway` to map `port` for `lease` seconds, 0 to remove the mapping
+fn nat_pmp_map(gateway: Ipv4Addr, port: u16, external_port: u16, lease: u32) -> Result<(u16, u32), String> {
+    let mut request = vec![0, NAT_PMP_MAP_TCP, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lease.to_be_bytes());
+    let response = nat_pmp_request(gateway, &request, 16)?;
+    let mapped_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((mapped_port, lifetime))
+}
+
+fn map_nat_pmp(port: u16) -> Result<PortMapping, String> {
+    let mut last_error = "No private IPv4 network".to_string();
+    for gateway in nat_pmp_gateways() {
+        let attempt = nat_pmp_request(gateway, &[0, NAT_PMP_EXTERNAL_ADDRESS], 12).and_then(|response| {
+            let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+            let (external_port, lifetime) = nat_pmp_map(gateway, port, port, LEASE_SECS)?;
+            Ok(PortMapping {
+                method: "nat-pmp".to_string(),
+                gateway: gateway.to_string(),
+                external_address: SocketAddrV4::new(external_ip, external_port).to_string(),
+                internal_port: port,
+                external_port,
+                lease_secs: lifetime,
+                upnp: None,
+            })
+        });
+        match attempt {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Have the router forward `port` to us, by whichever means it supports
+pub fn map_port(port: u16) -> Result<PortMapping, String> {
+    map_upnp(port).or_else(|upnp_error| {
+        map_nat_pmp(port).map_err(|pmp_error| format!("{}; {}", upnp_error, pmp_error))
+    })
+}
+
+// Take a mapping down again
+pub fn unmap(mapping: &PortMapping) -> Result<(), String> {
+    match &mapping.upnp {
+        Some(gateway) => gateway.remove_port(PortMappingProtocol::TCP, mapping.external_port)
+            .map_err(|e| e.to_string()),
+        None => {
+            let gateway: Ipv4Addr = mapping.gateway.parse().map_err(|_| "Malformed gateway address")?;
+            nat_pmp_map(gateway, mapping.internal_port, 0, 0).map(|_| ())
+        }
+    }
+}
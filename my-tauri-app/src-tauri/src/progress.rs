@@ -0,0 +1,161 @@
+// Transfer speed and time remaining
+//
+// Speed is measured over a short sliding window rather than since the
+// start, so it follows throttling and network changes within seconds.
+//
+// While a transfer's bytes move, its count lives in a LiveProgress of its
+// own rather than in the transfer list, so counting a chunk is a single
+// atomic store that takes no lock other transfers need. A `Reporter`,
+// ticking every PROGRESS_INTERVAL, looks over the counts of every running
+// transfer, works out their speeds, and brings the records of those that
+// moved up to date, to be passed on to the frontend together. However fast
+// chunks go by, the UI hears about progress at that pace and no faster.
+// In between, `get_transfers` reads the live counts through the index.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+struct SpeedMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl SpeedMeter {
+    // A meter that started at `progress` bytes at `start`
+    fn since(start: Instant, progress: u64) -> Self {
+        SpeedMeter { samples: VecDeque::from([(start, progress)]) }
+    }
+
+    // Record the current byte count and return bytes per second over the
+    // window
+    fn record(&mut self, progress: u64) -> u64 {
+        let now = Instant::now();
+        self.samples.push_back((now, progress));
+
+        // Keep one sample older than the window so it is always spanned
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) > SPEED_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (oldest_time, oldest_progress) = self.samples[0];
+        let elapsed = now.duration_since(oldest_time).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0;
+        }
+        (progress.saturating_sub(oldest_progress) as f64 / elapsed) as u64
+    }
+}
+
+// Seconds left at the current speed, if there is any speed to go by
+pub fn eta_seconds(remaining: u64, speed_bps: u64) -> Option<u64> {
+    (speed_bps > 0).then(|| remaining.div_ceil(speed_bps))
+}
+
+// Stands in for no estimate in LiveProgress
+const NO_ETA: u64 = u64::MAX;
+
+// Where a running transfer is at. Bytes are counted by the transfer, and
+// speed and time remaining worked out from them by the reporter.
+pub struct LiveProgress {
+    size: u64,
+    started: (Instant, u64),
+    progress: AtomicU64,
+    speed_bps: AtomicU64,
+    eta_seconds: AtomicU64,
+}
+
+impl LiveProgress {
+    fn new(size: u64, progress: u64) -> Self {
+        LiveProgress {
+            size,
+            started: (Instant::now(), progress),
+            progress: AtomicU64::new(progress),
+            speed_bps: AtomicU64::new(0),
+            eta_seconds: AtomicU64::new(NO_ETA),
+        }
+    }
+
+    // Bytes so far, speed and time remaining
+    pub fn get(&self) -> (u64, u64, Option<u64>) {
+        let eta = self.eta_seconds.load(Ordering::Relaxed);
+        (
+            self.progress.load(Ordering::Relaxed),
+            self.speed_bps.load(Ordering::Relaxed),
+            (eta != NO_ETA).then_some(eta),
+        )
+    }
+}
+
+// Running transfers' live progress, by transfer id
+pub type LiveTransfers = Arc<RwLock<HashMap<String, Arc<LiveProgress>>>>;
+
+// Counts one transfer's bytes into its LiveProgress
+pub struct ProgressTracker {
+    live: Arc<LiveProgress>,
+}
+
+impl ProgressTracker {
+    // Start counting a transfer of `size` bytes from `progress`, listing it
+    // in `index`
+    pub fn start(index: &LiveTransfers, transfer_id: &str, size: u64, progress: u64) -> Self {
+        let live = Arc::new(LiveProgress::new(size, progress));
+        index.write().insert(transfer_id.to_string(), live.clone());
+        ProgressTracker { live }
+    }
+
+    pub fn record(&self, progress: u64) {
+        self.live.progress.store(progress, Ordering::Relaxed);
+    }
+}
+
+// A running transfer's speed meter, and what was last reported of it
+struct Reported {
+    meter: SpeedMeter,
+    progress: u64,
+    speed_bps: u64,
+}
+
+// Looks over the running transfers' progress at each tick
+#[derive(Default)]
+pub struct Reporter {
+    transfers: HashMap<String, Reported>,
+}
+
+impl Reporter {
+    // Measure every running transfer's speed and time remaining, giving
+    // back those that changed since the last tick
+    pub fn tick(&mut self, index: &LiveTransfers) -> Vec<(String, Arc<LiveProgress>)> {
+        let running: Vec<(String, Arc<LiveProgress>)> = index
+            .read()
+            .iter()
+            .map(|(transfer_id, live)| (transfer_id.clone(), live.clone()))
+            .collect();
+        self.transfers.retain(|transfer_id, _| running.iter().any(|(id, _)| id == transfer_id));
+
+        running
+            .into_iter()
+            .filter(|(transfer_id, live)| {
+                let reported = self.transfers.entry(transfer_id.clone()).or_insert_with(|| Reported {
+                    meter: SpeedMeter::since(live.started.0, live.started.1),
+                    progress: live.started.1,
+                    speed_bps: 0,
+                });
+                let progress = live.progress.load(Ordering::Relaxed);
+                let speed = reported.meter.record(progress);
+                let eta = eta_seconds(live.size.saturating_sub(progress), speed);
+                live.speed_bps.store(speed, Ordering::Relaxed);
+                live.eta_seconds.store(eta.unwrap_or(NO_ETA), Ordering::Relaxed);
+
+                let changed = progress != reported.progress || speed != reported.speed_bps;
+                reported.progress = progress;
+                reported.speed_bps = speed;
+                changed
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,158 @@
+// Protocol versions and capabilities, agreed on in the handshake
+//
+// Each end puts a hello in its encrypted handshake payload: the newest and
+// oldest protocol versions it speaks, a bitmap of what it can do and the
+// schema of its binary packet headers (see codec.rs). The responder's goes
+// in the second handshake message and the initiator's in the third, so
+// both are authenticated along with the keys. A connection
+// then runs at the highest version both ends speak; when there is none it
+// is dropped with a clear error before a single header is sent, rather
+// than the two ends misreading each other's packets.
+//
+// Devices from before hellos send empty payloads and ignore ours. They
+// count as version 1, with the capabilities every copy of it had.
+
+use std::io::{Error, ErrorKind};
+
+// Newest version we speak, and the oldest we still talk to. Version 4
+// signs the whole header; earlier ones signed only a few of its fields,
+// leaving the rest open to change, so they're no longer talked to.
+pub const PROTOCOL_VERSION: u32 = 4;
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+// Version of devices that send no hello
+const LEGACY_VERSION: u32 = 1;
+
+// What a device can do, one bit each
+pub const COMPRESSION: u64 = 1 << 0;
+pub const FOLDERS: u64 = 1 << 1;
+pub const RESUME: u64 = 1 << 2;
+pub const PARALLEL: u64 = 1 << 3;
+pub const DELTA: u64 = 1 << 4;
+pub const ROUTING: u64 = 1 << 5;
+pub const RELAY: u64 = 1 << 6;
+pub const SYNC: u64 = 1 << 7;
+// Takes a tunnel's last hop to itself inside a channel of its own
+pub const TUNNEL: u64 = 1 << 8;
+
+// Capabilities every copy of this version has. Relaying is a setting, so
+// it's only announced over discovery, along with the rest.
+pub const CAPABILITIES: u64 = COMPRESSION | FOLDERS | RESUME | PARALLEL | DELTA | ROUTING | SYNC | TUNNEL;
+
+// Capabilities of devices that send no hello
+const LEGACY_CAPABILITIES: u64 = COMPRESSION | FOLDERS | RESUME | PARALLEL | DELTA | ROUTING;
+
+// Names capabilities are announced under in TXT records
+const NAMES: &[(u64, &str)] = &[
+    (COMPRESSION, "compression"),
+    (FOLDERS, "folders"),
+    (RESUME, "resume"),
+    (PARALLEL, "parallel"),
+    (DELTA, "delta"),
+    (ROUTING, "routing"),
+    (RELAY, "relay"),
+    (SYNC, "sync"),
+    (TUNNEL, "tunnel"),
+];
+
+// Bytes in an encoded hello, and in one from version 2, which had no
+// header schema; later versions may append to it
+const HELLO_LEN: usize = 18;
+const HELLO_V2_LEN: usize = 16;
+
+// What each end tells the other in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    pub capabilities: u64,
+    // 0 for devices that only send JSON headers
+    pub header_schema: u16,
+}
+
+impl Hello {
+    pub fn ours() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+            header_schema: crate::codec::HEADER_SCHEMA,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(HELLO_LEN);
+        encoded.extend_from_slice(&self.version.to_be_bytes());
+        encoded.extend_from_slice(&self.min_version.to_be_bytes());
+        encoded.extend_from_slice(&self.capabilities.to_be_bytes());
+        encoded.extend_from_slice(&self.header_schema.to_be_bytes());
+        encoded
+    }
+
+    // Read a hello, taking an empty payload for a device from before them
+    // and ignoring anything a newer version added
+    pub fn decode(payload: &[u8]) -> std::io::Result<Self> {
+        if payload.is_empty() {
+            return Ok(Hello {
+                version: LEGACY_VERSION,
+                min_version: LEGACY_VERSION,
+                capabilities: LEGACY_CAPABILITIES,
+                header_schema: 0,
+            });
+        }
+        if payload.len() < HELLO_V2_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed protocol hello"));
+        }
+        let hello = Hello {
+            version: u32::from_be_bytes(payload[0..4].try_into().unwrap()),
+            min_version: u32::from_be_bytes(payload[4..8].try_into().unwrap()),
+            capabilities: u64::from_be_bytes(payload[8..16].try_into().unwrap()),
+            header_schema: payload.get(16..HELLO_LEN)
+                .map_or(0, |schema| u16::from_be_bytes(schema.try_into().unwrap())),
+        };
+        if hello.min_version > hello.version {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed protocol hello"));
+        }
+        Ok(hello)
+    }
+}
+
+// What a connection runs with once both ends have said hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Agreed {
+    pub version: u32,
+    // What the other end can do
+    pub capabilities: u64,
+    // Whether headers go in binary, which takes both ends using the same
+    // header schema
+    pub binary_headers: bool,
+}
+
+// The highest version both ends speak, or why there is none
+pub fn negotiate(ours: &Hello, theirs: &Hello) -> std::io::Result<Agreed> {
+    if theirs.version < ours.min_version {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Peer too old: it speaks protocol version {}, we need at least {}", theirs.version, ours.min_version),
+        ));
+    }
+    if theirs.min_version > ours.version {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Peer too new: it needs protocol version {}, we speak up to {}", theirs.min_version, ours.version),
+        ));
+    }
+    Ok(Agreed {
+        version: ours.version.min(theirs.version),
+        capabilities: theirs.capabilities,
+        binary_headers: ours.header_schema != 0 && ours.header_schema == theirs.header_schema,
+    })
+}
+
+// Capabilities as announced in TXT records
+pub fn names(capabilities: u64) -> Vec<&'static str> {
+    NAMES.iter()
+        .filter(|(bit, _)| capabilities & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
@@ -0,0 +1,207 @@
+// Incoming files held back until the user lets them in
+//
+// With `quarantine_incoming` on, a file that arrives isn't saved where it
+// would be. It's received into the quarantine folder in the app's data,
+// verified as usual, and straight away encrypted there under a key of its
+// own, its plaintext deleted, so nothing opens, previews or indexes it by
+// accident. That key is kept sealed under one derived from our identity
+// key. The identity key is saved in the app's data as it is, so this
+// keeps nothing from anyone who can read that data.
+// `release` decrypts the file to where it would have been saved, checking
+// its hash on the way, and `discard` deletes it unopened.
+//
+// Files a sync pulled are never held, since we asked for them.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::Engine;
+use hkdf::Hkdf;
+use parking_lot::Mutex;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::StaticSecret;
+
+use crate::{app_data_dir, codec, decrypt_data, encrypt_chunk, encrypt_data, read_chunk, resume, versions};
+
+const QUARANTINE_KEY_INFO: &[u8] = b"FileSharePro v1 quarantine key";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedFile {
+    pub transfer_id: String,
+    pub filename: String,
+    pub size: u64,
+    pub file_hash: String,
+    pub from_device: String,
+    pub received_at: String,
+    // Where it's saved once released
+    pub destination: String,
+    // Why it could run code, if screening flagged it
+    pub risk: Option<String>,
+    // The file's own key, sealed under the quarantine key when it's held,
+    // and never shown to the UI
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sealed_key: String,
+}
+
+pub type Quarantine = Arc<Mutex<Vec<QuarantinedFile>>>;
+
+fn quarantine_dir() -> PathBuf {
+    app_data_dir().join("quarantine")
+}
+
+fn index_path() -> PathBuf {
+    quarantine_dir().join("index.json")
+}
+
+fn sealed_path(transfer_id: &str) -> PathBuf {
+    quarantine_dir().join(format!("{}.sealed", transfer_id))
+}
+
+pub fn load_quarantine() -> Vec<QuarantinedFile> {
+    std::fs::read(index_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_quarantine(files: &[QuarantinedFile]) -> io::Result<()> {
+    std::fs::create_dir_all(quarantine_dir())?;
+    let json = serde_json::to_vec_pretty(files)?;
+    std::fs::write(index_path(), json)
+}
+
+// What the UI is shown, without the keys
+pub fn list(quarantine: &Quarantine) -> Vec<QuarantinedFile> {
+    quarantine.lock()
+        .iter()
+        .map(|file| QuarantinedFile { sealed_key: String::new(), ..file.clone() })
+        .collect()
+}
+
+// Where a file to be held is received, out of sight. The name follows the
+// file's hash, so an interrupted transfer resumes into the same place; the
+// hash comes from the sender, so there's no place for anything else.
+pub fn receive_path(file_hash: &str) -> Option<PathBuf> {
+    codec::is_hash(file_hash).then(|| quarantine_dir().join(file_hash))
+}
+
+fn quarantine_key(identity: &StaticSecret) -> io::Result<[u8; 32]> {
+    let hkdf = Hkdf::<Sha256>::new(None, identity.as_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(QUARANTINE_KEY_INFO, &mut key)
+        .map_err(|e| io::Error::other(format!("Key derivation error: {:?}", e)))?;
+    Ok(key)
+}
+
+// Encrypt a received and verified file into the quarantine, deleting the
+// plaintext, and list it there
+pub fn hold(
+    quarantine: &Quarantine,
+    received: &Path,
+    mut file: QuarantinedFile,
+    identity: &StaticSecret,
+) -> io::Result<()> {
+    let mut file_key = [0u8; 32];
+    OsRng.fill_bytes(&mut file_key);
+    let sealed_key = encrypt_data(&file_key, &quarantine_key(identity)?).map_err(io::Error::other)?;
+    file.sealed_key = base64::engine::general_purpose::STANDARD.encode(sealed_key);
+
+    // Each chunk is sealed under its index and stored after its length
+    let sealed = sealed_path(&file.transfer_id);
+    let result = (|| {
+        let mut input = File::open(received)?;
+        let mut output = File::create(&sealed)?;
+        let mut chunk = Vec::new();
+        for index in 0.. {
+            if read_chunk(&mut input, &mut chunk)? == 0 {
+                break;
+            }
+            let encrypted = encrypt_chunk(&chunk, &file_key, index)?;
+            output.write_all(&(encrypted.len() as u32).to_be_bytes())?;
+            output.write_all(&encrypted)?;
+        }
+        output.sync_all()
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&sealed);
+        return Err(e);
+    }
+    std::fs::remove_file(received)?;
+
+    let mut files = quarantine.lock();
+    files.push(file);
+    save_quarantine(&files)
+}
+
+// Decrypt a held file to where it would have been saved, as a received file
+// is: through a partial file, checked against its hash and keeping a
+// version of a file it replaces. Gives back where it went.
+pub fn release(quarantine: &Quarantine, transfer_id: &str, identity: &StaticSecret, versions_kept: usize) -> io::Result<PathBuf> {
+    let file = quarantine.lock()
+        .iter()
+        .find(|file| file.transfer_id == transfer_id)
+        .cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not in quarantine"))?;
+    let sealed_key = base64::engine::general_purpose::STANDARD
+        .decode(&file.sealed_key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Damaged quarantine key"))?;
+    let file_key: [u8; 32] = decrypt_data(&sealed_key, &quarantine_key(identity)?)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Damaged quarantine key"))?;
+
+    let destination = PathBuf::from(&file.destination);
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let part_path = resume::part_path(&destination);
+    let result = (|| {
+        let mut input = File::open(sealed_path(transfer_id))?;
+        let mut output = File::create(&part_path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut length = [0u8; 4];
+        loop {
+            match input.read_exact(&mut length) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let mut encrypted = vec![0u8; u32::from_be_bytes(length) as usize];
+            input.read_exact(&mut encrypted)?;
+            let chunk = decrypt_data(&encrypted, &file_key)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Quarantined file is damaged"))?;
+            hasher.update(&chunk);
+            output.write_all(&chunk)?;
+        }
+        if hasher.finalize().to_hex().as_str() != file.file_hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Quarantined file is damaged"));
+        }
+        output.sync_all()?;
+        versions::save(&destination, versions_kept)?;
+        resume::commit(&part_path, &destination)
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    discard(quarantine, transfer_id)?;
+    Ok(destination)
+}
+
+// Delete a held file without opening it
+pub fn discard(quarantine: &Quarantine, transfer_id: &str) -> io::Result<()> {
+    let mut files = quarantine.lock();
+    let before = files.len();
+    files.retain(|file| file.transfer_id != transfer_id);
+    if files.len() == before {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Not in quarantine"));
+    }
+    let _ = std::fs::remove_file(sealed_path(transfer_id));
+    save_quarantine(&files)
+}
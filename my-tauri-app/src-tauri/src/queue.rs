@@ -0,0 +1,157 @@
+// Transfer queue with concurrency limits
+//
+// Every transfer takes a slot before it starts moving bytes. Outgoing and
+// incoming transfers have separate limits; anything over the limit waits
+// in queue order, which the user can rearrange.
+
+use crate::error::Error;
+use parking_lot::{Condvar, Mutex};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often a waiting transfer re-checks whether it was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+// A queued or running transfer, as shown to the frontend
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub direction: Direction,
+    pub label: String,
+    pub active: bool,
+}
+
+struct QueueState {
+    entries: Vec<QueueEntry>,
+    max_outgoing: usize,
+    max_incoming: usize,
+}
+
+impl QueueState {
+    fn limit(&self, direction: Direction) -> usize {
+        match direction {
+            Direction::Outgoing => self.max_outgoing,
+            Direction::Incoming => self.max_incoming,
+        }
+    }
+
+    // True when `id` is the first waiting entry in its direction and a
+    // slot is free
+    fn can_start(&self, id: &str, direction: Direction) -> bool {
+        let same_direction = || self.entries.iter().filter(|e| e.direction == direction);
+        let active = same_direction().filter(|e| e.active).count();
+        let next = same_direction().find(|e| !e.active);
+        active < self.limit(direction) && next.is_some_and(|e| e.id == id)
+    }
+}
+
+pub struct TransferQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+// Held while a transfer runs; dropping it frees the slot
+pub struct QueueSlot {
+    queue: Arc<TransferQueue>,
+    id: String,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.remove(&self.id);
+    }
+}
+
+impl TransferQueue {
+    pub fn new(max_outgoing: usize, max_incoming: usize) -> Self {
+        TransferQueue {
+            state: Mutex::new(QueueState {
+                entries: Vec::new(),
+                max_outgoing: max_outgoing.max(1),
+                max_incoming: max_incoming.max(1),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn set_limits(&self, max_outgoing: usize, max_incoming: usize) {
+        let mut state = self.state.lock();
+        state.max_outgoing = max_outgoing.max(1);
+        state.max_incoming = max_incoming.max(1);
+        self.changed.notify_all();
+    }
+
+    // Wait for a free slot. Returns None if `cancelled` reports true while
+    // the transfer is still waiting.
+    pub fn acquire(
+        self: &Arc<Self>,
+        id: &str,
+        direction: Direction,
+        label: &str,
+        cancelled: impl Fn() -> bool,
+    ) -> Option<QueueSlot> {
+        let mut state = self.state.lock();
+        state.entries.push(QueueEntry {
+            id: id.to_string(),
+            direction,
+            label: label.to_string(),
+            active: false,
+        });
+
+        while !state.can_start(id, direction) {
+            if cancelled() {
+                state.entries.retain(|e| e.id != id);
+                self.changed.notify_all();
+                return None;
+            }
+            self.changed.wait_for(&mut state, CANCEL_POLL);
+        }
+
+        if let Some(entry) = state.entries.iter_mut().find(|e| e.id == id) {
+            entry.active = true;
+        }
+        Some(QueueSlot { queue: self.clone(), id: id.to_string() })
+    }
+
+    fn remove(&self, id: &str) {
+        self.state.lock().entries.retain(|e| e.id != id);
+        self.changed.notify_all();
+    }
+
+    // Running transfers first, then waiting ones in the order they'll start
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        let state = self.state.lock();
+        let (mut active, waiting): (Vec<_>, Vec<_>) = state.entries.iter().cloned().partition(|e| e.active);
+        active.extend(waiting);
+        active
+    }
+
+    // Move a waiting transfer to `position` among the waiting transfers
+    pub fn reorder(&self, id: &str, position: usize) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        let index = state.entries.iter()
+            .position(|e| e.id == id && !e.active)
+            .ok_or_else(|| Error::NotFound("Transfer is not waiting in the queue".to_string()))?;
+        let entry = state.entries.remove(index);
+
+        // Translate the position among waiting entries into an index
+        let insert_at = state.entries.iter()
+            .enumerate()
+            .filter(|(_, e)| !e.active)
+            .nth(position)
+            .map(|(i, _)| i)
+            .unwrap_or(state.entries.len());
+        state.entries.insert(insert_at, entry);
+
+        self.changed.notify_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+// Limits on what incoming files may take up
+//
+// Before a transfer is accepted, the receiver checks the announced size
+// against the free space where the file would be saved, the largest file
+// the user allows and what is left of today's quota. Files count against
+// the quota once they complete, and the count is kept on disk so
+// restarting the app doesn't reset it.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::app_data_dir;
+use crate::error::Error;
+use crate::settings::Settings;
+
+// Space left free on top of the file itself, so a transfer never fills
+// the disk to the last byte
+const DISK_HEADROOM: u64 = 64 * 1024 * 1024;
+
+// Bytes received on one calendar day
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DailyUsage {
+    date: String,
+    bytes: u64,
+}
+
+fn usage_path() -> std::path::PathBuf {
+    app_data_dir().join("usage.json")
+}
+
+fn today() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+impl DailyUsage {
+    pub fn load() -> Self {
+        std::fs::read(usage_path())
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    // Bytes received so far today
+    pub fn today(&self) -> u64 {
+        if self.date == today() {
+            self.bytes
+        } else {
+            0
+        }
+    }
+
+    // Count a completed file against today's quota
+    pub fn record(&mut self, bytes: u64) {
+        let date = today();
+        if self.date != date {
+            self.date = date;
+            self.bytes = 0;
+        }
+        self.bytes += bytes;
+
+        if let Ok(json) = serde_json::to_vec_pretty(self) {
+            let _ = std::fs::create_dir_all(app_data_dir());
+            let _ = std::fs::write(usage_path(), json);
+        }
+    }
+}
+
+// Check an incoming file against the user's limits and the free space at
+// `path`. `needed` is what is still to be written, which is less than the
+// file size when resuming. The error is sent to the sender as the reason
+// for rejecting.
+pub fn check_incoming(
+    path: &Path,
+    file_size: u64,
+    needed: u64,
+    usage: &DailyUsage,
+    settings: &Settings,
+) -> Result<(), Error> {
+    if settings.max_file_size > 0 && file_size > settings.max_file_size {
+        return Err(Error::QuotaExceeded(format!("File exceeds the {} byte limit", settings.max_file_size)));
+    }
+    if settings.daily_quota > 0 && usage.today() + file_size > settings.daily_quota {
+        return Err(Error::QuotaExceeded("Daily quota reached".to_string()));
+    }
+
+    // Folders in the path may not exist yet, so ask about the nearest one
+    // that does
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+    if let Ok(available) = fs4::available_space(existing) {
+        if available < needed.saturating_add(DISK_HEADROOM) {
+            return Err(Error::DiskFull);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+// Store-and-forward relaying for devices that are offline
+//
+// A paired device can leave a file with us for a device it can't reach, by
+// sending it as HOLD_FOR instead of FILE_TRANSFER. The body arrives sealed
+// to the recipient's identity key, so the spool only ever holds frames we
+// can't read. Once the recipient is online the file goes on to it under a
+// HELD_DELIVERY followed by the sender's own header, untouched, which the
+// recipient checks against the sender's key, and the sender is told how
+// that went with a DELIVERY_RECEIPT as soon as it can be reached.
+//
+// Each held file is three entries in the spool folder: `<id>.json` with
+// what the UI shows, `<id>.header` with the sender's header and
+// `<id>.spool` with its encrypted chunks, each prefixed by its length.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{app_data_dir, PacketHeader};
+
+// How often the relay tries to deliver held files and receipts
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Held files nobody collects are dropped after this long
+pub const HOLD_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Space taken per chunk on top of the data: length prefix, nonce and tag
+pub const FRAME_OVERHEAD: u64 = 4 + 12 + 16;
+
+// Space left free on the disk besides the spool quota
+const DISK_HEADROOM: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeldFile {
+    pub id: String,
+    // The sender's id for the file, echoed in the delivery receipt
+    pub hold_id: String,
+    // Identity keys and names of the sender and the device it's for
+    pub sender: String,
+    pub sender_name: String,
+    pub recipient: String,
+    pub recipient_name: String,
+    pub filename: String,
+    pub size: u64,
+    // Unix seconds
+    pub received_at: i64,
+    // One of the RECEIPT_ outcomes once delivery is over, kept until the
+    // sender has been told
+    #[serde(default)]
+    pub outcome: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+    // Being delivered, or its receipt being sent, right now
+    #[serde(skip)]
+    pub busy: bool,
+}
+
+impl HeldFile {
+    pub fn is_expired(&self, now: i64) -> bool {
+        now - self.received_at > HOLD_EXPIRY.as_secs() as i64
+    }
+}
+
+fn spool_dir() -> PathBuf {
+    app_data_dir().join("relay")
+}
+
+fn entry_path(id: &str, extension: &str) -> PathBuf {
+    spool_dir().join(format!("{}.{}", id, extension))
+}
+
+// Every file held in the spool, delivered or not
+pub fn load_held() -> Vec<HeldFile> {
+    let Ok(entries) = std::fs::read_dir(spool_dir()) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|e| e == "json"))
+        .filter_map(|entry| std::fs::read(entry.path()).ok())
+        .filter_map(|bytes| serde_json::from_slice(&bytes).ok())
+        .collect()
+}
+
+pub fn save_held(held: &HeldFile) -> std::io::Result<()> {
+    std::fs::create_dir_all(spool_dir())?;
+    std::fs::write(entry_path(&held.id, "json"), serde_json::to_vec_pretty(held)?)
+}
+
+pub fn save_header(id: &str, header: &PacketHeader) -> std::io::Result<()> {
+    std::fs::create_dir_all(spool_dir())?;
+    std::fs::write(entry_path(id, "header"), serde_json::to_vec(header)?)
+}
+
+pub fn load_header(id: &str) -> std::io::Result<PacketHeader> {
+    Ok(serde_json::from_slice(&std::fs::read(entry_path(id, "header"))?)?)
+}
+
+pub fn create_spool(id: &str) -> std::io::Result<std::fs::File> {
+    std::fs::create_dir_all(spool_dir())?;
+    std::fs::File::create(entry_path(id, "spool"))
+}
+
+pub fn open_spool(id: &str) -> std::io::Result<std::fs::File> {
+    std::fs::File::open(entry_path(id, "spool"))
+}
+
+pub fn write_frame(spool: &mut impl Write, frame: &[u8]) -> std::io::Result<()> {
+    spool.write_all(&(frame.len() as u32).to_le_bytes())?;
+    spool.write_all(frame)
+}
+
+pub fn read_frame(spool: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    spool.read_exact(&mut len)?;
+    let mut frame = vec![0u8; u32::from_le_bytes(len) as usize];
+    spool.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+// Drop a held file's contents once they're no longer needed, keeping the
+// entry until its receipt is sent
+pub fn discard_body(id: &str) {
+    let _ = std::fs::remove_file(entry_path(id, "spool"));
+    let _ = std::fs::remove_file(entry_path(id, "header"));
+}
+
+pub fn remove(id: &str) {
+    discard_body(id);
+    let _ = std::fs::remove_file(entry_path(id, "json"));
+}
+
+// Bytes the spool takes up on disk
+pub fn spool_usage() -> u64 {
+    std::fs::read_dir(spool_dir())
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+// Check there's room to hold `needed` more bytes, within the quota and on
+// the disk. The error is sent to the sender as the reason for refusing.
+pub fn check_space(needed: u64, quota: u64) -> Result<(), String> {
+    if spool_usage().saturating_add(needed) > quota {
+        return Err("Relay is full".to_string());
+    }
+    let dir = spool_dir();
+    let existing = dir.ancestors().find(|p| p.exists()).unwrap_or(&dir);
+    if let Ok(available) = fs4::available_space(existing) {
+        if available < needed.saturating_add(DISK_HEADROOM) {
+            return Err("Not enough disk space on relay".to_string());
+        }
+    }
+    Ok(())
+}
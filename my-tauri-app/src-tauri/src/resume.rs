@@ -0,0 +1,276 @@
+// Resumable downloads
+//
+// Incoming files are written to a `.part` file next to their final
+// location. A small manifest in the app data directory records how far the
+// download got, so a reconnecting sender can continue from the last
+// verified chunk instead of starting over.
+//
+// Nothing takes the file's real name until all of it is on disk: the
+// partial file is synced, then renamed into place, and the folder it's in
+// synced too so the rename itself survives a crash. The manifest is
+// replaced whole rather than rewritten, so it's never left half written.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{app_data_dir, codec, read_chunk, CHUNK_SIZE};
+
+// Progress of a partial download, keyed by the file's BLAKE3 hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialManifest {
+    pub transfer_id: String,
+    // Signing key of the sender, so only they can resume it
+    pub signing_key: String,
+    pub file_hash: String,
+    pub file_size: u64,
+    pub part_path: PathBuf,
+    // Chunks written and verified so far, and the byte offset they end at
+    pub verified_chunks: u64,
+    pub offset: u64,
+}
+
+// A partial file reopened for appending, with the plaintext already on disk
+pub struct ResumedDownload {
+    pub file: File,
+    pub verified_chunks: u64,
+    pub offset: u64,
+}
+
+// A partial file being filled in, possibly out of order when chunks
+// arrive over several streams. While they come in order from the start,
+// the whole file is hashed as they're written, so it needn't be read back
+// to be checked.
+pub struct PartialDownload {
+    file: File,
+    pub manifest: PartialManifest,
+    written: Vec<bool>,
+    hasher: Option<blake3::Hasher>,
+    hashed_chunks: u64,
+}
+
+impl PartialDownload {
+    pub fn new(file: File, manifest: PartialManifest, total_chunks: u64) -> Self {
+        let written = (0..total_chunks).map(|index| index < manifest.verified_chunks).collect();
+        let hasher = (manifest.verified_chunks == 0).then(blake3::Hasher::new);
+        PartialDownload { file, manifest, written, hasher, hashed_chunks: 0 }
+    }
+
+    // Write a verified chunk at its offset. The manifest only counts chunks
+    // up to the first gap, so a resumed download asks again for anything
+    // after it.
+    pub fn write_chunk(&mut self, index: u64, chunk: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(index * CHUNK_SIZE as u64))?;
+        self.file.write_all(chunk)?;
+        if index == self.hashed_chunks {
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update_rayon(chunk);
+                self.hashed_chunks += 1;
+            }
+        } else {
+            self.hasher = None;
+        }
+        if let Some(written) = self.written.get_mut(index as usize) {
+            *written = true;
+        }
+        while self.written.get(self.manifest.verified_chunks as usize) == Some(&true) {
+            self.manifest.verified_chunks += 1;
+        }
+        self.manifest.offset = (self.manifest.verified_chunks * CHUNK_SIZE as u64).min(self.manifest.file_size);
+        save_manifest(&self.manifest)
+    }
+
+    // Flush everything to disk and close the file, giving back the whole
+    // file's hash too if it was hashed as it was written
+    pub fn close(self) -> std::io::Result<(PartialManifest, Option<blake3::Hash>)> {
+        self.file.sync_all()?;
+        let complete = self.hashed_chunks == self.written.len() as u64;
+        let hash = self.hasher.filter(|_| complete).map(|hasher| hasher.finalize());
+        Ok((self.manifest, hash))
+    }
+}
+
+// The hash comes from the sender, so anything but a hash is refused
+// rather than let name a file outside the folder
+fn manifest_path(file_hash: &str) -> std::io::Result<PathBuf> {
+    if !codec::is_hash(file_hash) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed file hash"));
+    }
+    Ok(app_data_dir().join("partial").join(format!("{}.json", file_hash)))
+}
+
+// Load the manifest for a file, if a download of it was interrupted
+pub fn load_manifest(file_hash: &str) -> Option<PartialManifest> {
+    std::fs::read(manifest_path(file_hash).ok()?)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+}
+
+// Persist a manifest after each verified chunk
+pub fn save_manifest(manifest: &PartialManifest) -> std::io::Result<()> {
+    let path = manifest_path(&manifest.file_hash)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(manifest)?;
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, json)?;
+    std::fs::rename(temp, path)
+}
+
+// Give a complete, synced partial file its real name
+pub fn commit(part_path: &Path, download_path: &Path) -> std::io::Result<()> {
+    std::fs::rename(part_path, download_path)?;
+    match download_path.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+// Folders can only be synced where they can be opened like files
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Forget a partial download, removing the `.part` file as well
+pub fn discard(manifest: &PartialManifest) {
+    let _ = std::fs::remove_file(&manifest.part_path);
+    finish(&manifest.file_hash);
+}
+
+// Drop the manifest once the download has been completed
+pub fn finish(file_hash: &str) {
+    if let Ok(path) = manifest_path(file_hash) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// Reopen a partial file and re-verify the chunks already on disk. The file
+// is truncated after the last chunk that still matches its hash, so a crash
+// between writing a chunk and saving the manifest loses nothing but that
+// chunk.
+pub fn reopen(manifest: &PartialManifest, chunk_hashes: &[String]) -> std::io::Result<ResumedDownload> {
+    let mut existing = File::open(&manifest.part_path)?;
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+    let mut verified_chunks = 0u64;
+    let mut offset = 0u64;
+
+    for expected_hash in chunk_hashes.iter().take(manifest.verified_chunks as usize) {
+        let read = read_chunk(&mut existing, &mut buffer)?;
+        if read == 0 || blake3::hash(&buffer).to_hex().as_str() != expected_hash {
+            break;
+        }
+        verified_chunks += 1;
+        offset += read as u64;
+    }
+
+    let mut file = OpenOptions::new().write(true).open(&manifest.part_path)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::End(0))?;
+
+    Ok(ResumedDownload { file, verified_chunks, offset })
+}
+
+// Path of the partial file for a download
+pub fn part_path(download_path: &Path) -> PathBuf {
+    let mut name = download_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    download_path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // What a sender could put in a header's file hash to lead out of the
+    // folder manifests are kept in
+    fn traversal() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("../trusted_devices".to_string()),
+            Just("/tmp/anything".to_string()),
+            Just("..\\..\\settings".to_string()),
+            "(\\.\\./){1,4}[a-z_]{1,20}",
+            "[0-9a-f]{0,63}",
+        ]
+    }
+
+    fn manifest(file_hash: String) -> PartialManifest {
+        PartialManifest {
+            transfer_id: String::new(),
+            signing_key: String::new(),
+            file_hash,
+            file_size: 0,
+            part_path: PathBuf::new(),
+            verified_chunks: 0,
+            offset: 0,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn manifests_stay_in_their_folder(hash in "[0-9a-f]{64}") {
+            let path = manifest_path(&hash).unwrap();
+            let folder = app_data_dir().join("partial");
+            prop_assert_eq!(path.parent(), Some(folder.as_path()));
+        }
+
+        #[test]
+        fn traversal_hashes_are_refused(hash in traversal()) {
+            prop_assert!(manifest_path(&hash).is_err());
+            prop_assert!(load_manifest(&hash).is_none());
+            prop_assert!(save_manifest(&manifest(hash)).is_err());
+        }
+
+        #[test]
+        fn manifests_are_kept_until_finished(hash in "[0-9a-f]{64}", verified_chunks in 0..1000u64) {
+            let saved = PartialManifest { verified_chunks, offset: verified_chunks * CHUNK_SIZE as u64, ..manifest(hash.clone()) };
+            save_manifest(&saved).unwrap();
+            let loaded = load_manifest(&hash).unwrap();
+            prop_assert_eq!(loaded.verified_chunks, verified_chunks);
+            prop_assert_eq!(loaded.offset, saved.offset);
+            finish(&hash);
+            prop_assert!(load_manifest(&hash).is_none());
+        }
+
+        #[test]
+        fn part_files_sit_beside_their_file(name in "[a-z]{1,12}(\\.[a-z]{1,4})?") {
+            let download_path = PathBuf::from("downloads").join(&name);
+            prop_assert_eq!(part_path(&download_path), PathBuf::from("downloads").join(format!("{}.part", name)));
+        }
+    }
+
+    // Only the chunks that still match their hashes are kept, and the file
+    // is cut off after the last of them
+    #[test]
+    fn reopening_keeps_the_verified_chunks() {
+        let part_path = std::env::temp_dir().join(format!("{}.part", uuid::Uuid::new_v4()));
+        let chunks: Vec<Vec<u8>> = (0..3u8).map(|byte| vec![byte; CHUNK_SIZE]).collect();
+        let mut chunk_hashes: Vec<String> = chunks.iter().map(|chunk| blake3::hash(chunk).to_hex().to_string()).collect();
+        std::fs::write(&part_path, chunks.concat()).unwrap();
+        let partial = PartialManifest { part_path: part_path.clone(), verified_chunks: 3, ..manifest(String::new()) };
+
+        let resumed = reopen(&partial, &chunk_hashes).unwrap();
+        assert_eq!((resumed.verified_chunks, resumed.offset), (3, 3 * CHUNK_SIZE as u64));
+
+        chunk_hashes[1] = blake3::hash(b"something else").to_hex().to_string();
+        let resumed = reopen(&partial, &chunk_hashes).unwrap();
+        assert_eq!((resumed.verified_chunks, resumed.offset), (1, CHUNK_SIZE as u64));
+        assert_eq!(std::fs::metadata(&part_path).unwrap().len(), CHUNK_SIZE as u64);
+
+        // What the manifest doesn't count isn't checked, let alone kept
+        let unsaved = PartialManifest { verified_chunks: 0, ..partial };
+        let resumed = reopen(&unsaved, &chunk_hashes).unwrap();
+        assert_eq!((resumed.verified_chunks, resumed.offset), (0, 0));
+        assert_eq!(std::fs::metadata(&part_path).unwrap().len(), 0);
+
+        std::fs::remove_file(part_path).unwrap();
+    }
+}
@@ -0,0 +1,1007 @@
+// Reaching devices through other devices
+//
+// A device we can't connect to directly may still be reachable through one
+// we can. The sender asks its next hop to FORWARD the connection to the
+// destination; the hop connects onwards, either straight to the
+// destination if it can see it or through its own next hop, answers
+// FORWARD_READY and from then on just passes bytes both ways. The sender
+// then runs a fresh handshake with the destination through the tunnel, so
+// relays only ever see Noise ciphertext and the destination authenticates
+// the real sender. Each link of the tunnel, the last one to the
+// destination included, is itself a channel between the two devices on
+// it, so the destination isn't given away by the bytes on any one link.
+//
+// Devices are routed to by the id they advertise, which stays the same when
+// a device is renamed and tells apart devices that share a name. Names are
+// looked up only to show routes to the user.
+//
+// Routes are learnt distance-vector style. Every ROUTE_INTERVAL each
+// device sends its direct neighbours a ROUTE_ADVERTISEMENT listing what it
+// can reach, in how many hops and at what cost, and gets theirs back in
+// reply. Costs add up the link costs measured along the way, so a fast
+// path of several hops can beat a slow or lossy direct link. A
+// destination is reached through whichever neighbour offers the fewest
+// cost (Bellman-Ford), routes learnt from a neighbour aren't advertised
+// back to it (split horizon), and routes nobody has confirmed within
+// ROUTE_TIMEOUT are dropped. A device in gateway mode sees the devices on
+// each of its network segments directly, so its advertisements carry the
+// devices of one segment over to the other, which mDNS can't.
+//
+// A destination no advertisement mentions can still be found on demand,
+// AODV style. The sender floods a ROUTE_DISCOVERY to its neighbours, who
+// pass it on to theirs until it reaches a device that is, or knows the way
+// to, the destination. That device answers ROUTE_REPLY, and the reply goes
+// back along the chain of connections the request came down, each device
+// on the way caching the route it learns. Devices that have already seen a
+// request turn it away, so the flood doesn't loop.
+//
+// When a relay can't get a forwarded connection any further it answers
+// ROUTE_ERROR, naming the destination. Every device the error passes back
+// through drops its route there, so the sender's next attempt picks
+// another path or discovers a new one instead of trying the dead one.
+// FORWARD_READY and ROUTE_ERROR act as per-hop ACK and NACK: each relay
+// puts its name in front of the path it passes back, so the sender learns
+// which relays a tunnel goes through, or at which one it failed.
+//
+// A sender may also pin the relays to go through. The FORWARD then lists
+// those still to come, and each relay goes on to the next one it names,
+// or with none left straight to the destination, without consulting its
+// routes.
+//
+// Up to MAX_PATHS routes are kept per destination, each through a
+// different neighbour and best first. A connection that can't get through
+// one path tries the next, and a transfer whose relay dies partway drops
+// that path and resumes over the next one.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+use x25519_dalek::PublicKey;
+
+use crate::links::{link_cost, LinkMetrics, Links, DEFAULT_LINK_COST};
+use crate::net;
+use crate::pairing::TrustedDevice;
+use crate::passthrough::Passthrough;
+use crate::protocol;
+use crate::tasks::Budget;
+use crate::transport::{self, SecureChannel};
+use crate::{
+    read_header, signing, write_header, write_rejection, Device, PacketHeader, PeerContext,
+    PACKET_FORWARD, PACKET_FORWARD_READY, PACKET_ROUTE_ADVERTISEMENT, PACKET_ROUTE_DISCOVERY, PACKET_ROUTE_ERROR,
+    PACKET_ROUTE_REPLY,
+};
+
+// Relays a forwarded connection may pass through before it's refused, so a
+// routing loop can't go on forever
+pub const MAX_HOPS: u32 = 8;
+
+// How often routes are exchanged with neighbours
+pub const ROUTE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Routes not advertised again within this long are dropped
+const ROUTE_TIMEOUT: Duration = ROUTE_INTERVAL.saturating_mul(3);
+
+// Entries taken from a single advertisement
+const MAX_ADVERTISED_ROUTES: usize = 1000;
+
+// How long a route found on demand is kept without being used again
+const DISCOVERED_ROUTE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+// Time allowed per remaining hop for a ROUTE_DISCOVERY to be answered
+const DISCOVERY_HOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long a discovery's request id is remembered, to turn away copies
+const SEEN_DISCOVERY_LIFETIME: Duration = Duration::from_secs(60);
+
+// Routes kept per destination
+pub const MAX_PATHS: usize = 3;
+
+// Throttle bucket shared by all relayed traffic
+pub const RELAY_THROTTLE_KEY: &str = "relay";
+
+// Traffic this device has forwarded for others since it started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RelayStats {
+    pub relayed_connections: u64,
+    pub active: usize,
+    pub bytes_forwarded: u64,
+    // Connections turned away by the relay settings
+    pub refused: u64,
+}
+
+// How to reach a device we can't see directly, by device id
+#[derive(Debug, Clone, Serialize)]
+pub struct Route {
+    pub destination: String,
+    // The directly reachable device to hand connections to
+    pub next_hop: String,
+    pub hops: u32,
+    // Sum of the link costs along the path, and how the first link measures
+    pub cost: f64,
+    pub link: LinkMetrics,
+    // Unix seconds when the next hop last advertised it
+    pub updated_at: i64,
+    // Found by a ROUTE_DISCOVERY rather than advertised, so it lasts for
+    // DISCOVERED_ROUTE_LIFETIME
+    #[serde(default)]
+    pub on_demand: bool,
+}
+
+// Routes by destination device id, cheapest first
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    paths: HashMap<String, Vec<Route>>,
+    // The devices each neighbour last advertised at one hop, which it
+    // sees directly, with the cost of its link to them
+    neighbour_links: HashMap<String, HashMap<String, f64>>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn best(&self, destination: &str) -> Option<&Route> {
+        self.paths.get(destination).and_then(|paths| paths.first())
+    }
+
+    pub fn paths(&self, destination: &str) -> &[Route] {
+        self.paths.get(destination).map_or(&[], Vec::as_slice)
+    }
+
+    fn via(&self, destination: &str, next_hop: &str) -> Option<&Route> {
+        self.paths(destination).iter().find(|route| route.next_hop == next_hop)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.paths.values().flatten()
+    }
+
+    pub fn neighbour_links(&self) -> &HashMap<String, HashMap<String, f64>> {
+        &self.neighbour_links
+    }
+
+    // Add a route, replacing the one through the same next hop, and keep
+    // the cheapest MAX_PATHS
+    pub fn insert(&mut self, route: Route) {
+        let paths = self.paths.entry(route.destination.clone()).or_default();
+        paths.retain(|r| r.next_hop != route.next_hop);
+        paths.push(route);
+        paths.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        paths.truncate(MAX_PATHS);
+    }
+
+    pub fn remove_path(&mut self, destination: &str, next_hop: &str) -> bool {
+        let removed = self.paths.get_mut(destination).is_some_and(|paths| {
+            let before = paths.len();
+            paths.retain(|route| route.next_hop != next_hop);
+            paths.len() < before
+        });
+        self.paths.retain(|_, paths| !paths.is_empty());
+        removed
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Route) -> bool) {
+        for paths in self.paths.values_mut() {
+            paths.retain(&mut keep);
+        }
+        self.paths.retain(|_, paths| !paths.is_empty());
+    }
+}
+
+// Where along a path a forwarded connection failed, carried in the io
+// error so each hop can pass it back unchanged
+#[derive(Debug)]
+pub struct HopFailure {
+    // Relays from the one we asked up to the one that failed
+    pub path: Vec<String>,
+    pub reason: String,
+}
+
+impl fmt::Display for HopFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.last() {
+            Some(relay) => write!(f, "Failed at relay {}: {}", relay, self.reason),
+            None => f.write_str(&self.reason),
+        }
+    }
+}
+
+impl std::error::Error for HopFailure {}
+
+// The relay a forwarded connection failed at, if that's why it failed
+pub fn failed_relay(error: &std::io::Error) -> Option<&str> {
+    let failure = error.get_ref()?.downcast_ref::<HopFailure>()?;
+    failure.path.last().map(String::as_str)
+}
+
+// Request ids of route discoveries seen lately, with when they were seen
+pub type SeenDiscoveries = HashMap<String, i64>;
+
+// A route as shown to the user, with names for its devices
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    #[serde(flatten)]
+    pub route: Route,
+    pub destination_name: String,
+    pub next_hop_name: String,
+}
+
+// What we tell the neighbour `to` we can reach: ourselves, and unless we
+// won't relay for it, the devices we see directly and our routes, leaving
+// out those that go through it. Each entry comes with its hops, and
+// separately with its cost, so peers that predate costs still read the
+// hops.
+pub fn advertisement(
+    table: &RoutingTable,
+    devices: &HashMap<String, Device>,
+    links: &Links,
+    own_id: &str,
+    to: &str,
+    relaying: bool,
+) -> (Vec<(String, u32)>, Vec<f64>) {
+    if !relaying {
+        return (vec![(own_id.to_string(), 0)], vec![0.0]);
+    }
+    let mut reachable: HashMap<String, (u32, f64)> = HashMap::new();
+    for id in devices.keys() {
+        reachable.insert(id.clone(), (1, link_cost(links, id)));
+    }
+    for route in table.routes().filter(|route| route.next_hop != to) {
+        let entry = reachable.entry(route.destination.clone()).or_insert((route.hops, route.cost));
+        if route.cost < entry.1 {
+            *entry = (route.hops, route.cost);
+        }
+    }
+    reachable.remove(to);
+    reachable.insert(own_id.to_string(), (0, 0.0));
+    reachable.into_iter().map(|(id, (hops, cost))| ((id, hops), cost)).unzip()
+}
+
+// Routes as neighbour `from` advertised them
+pub struct Advertisement<'a> {
+    pub from: &'a str,
+    pub entries: &'a [(String, u32)],
+    // Cost of each entry's route, in the same order
+    pub costs: &'a [f64],
+}
+
+// Take in the routes neighbour `from` advertised. A route through `from`
+// always replaces our previous one through it, since it knows best what
+// the way on costs now, and is kept if it's among the cheapest few. A
+// device we see directly only gets a route when going round is cheaper
+// than the direct link.
+pub fn apply_advertisement(
+    table: &mut RoutingTable,
+    devices: &HashMap<String, Device>,
+    links: &Links,
+    own_id: &str,
+    advertisement: Advertisement,
+    now: i64,
+) {
+    let Advertisement { from, entries, costs } = advertisement;
+    let link = links.get(from).cloned().unwrap_or_default();
+    let first_hop = link_cost(links, from);
+    let mut seen_by_from = HashMap::new();
+    for (index, (destination, hops)) in entries.iter().enumerate().take(MAX_ADVERTISED_ROUTES) {
+        // Peers that don't send costs are taken to have unmeasured links
+        let advertised = costs.get(index).copied().filter(|c| c.is_finite() && *c >= 0.0);
+        if *hops == 1 {
+            seen_by_from.insert(destination.clone(), advertised.unwrap_or(DEFAULT_LINK_COST));
+        }
+        if destination == own_id || destination == from {
+            continue;
+        }
+        let hops = hops.saturating_add(1);
+        let cost = advertised.unwrap_or(DEFAULT_LINK_COST * (hops - 1) as f64) + first_hop;
+        let direct_cheaper = devices.contains_key(destination) && link_cost(links, destination) <= cost;
+        if hops > MAX_HOPS || direct_cheaper {
+            // Out of reach or not worth it, which for the route through
+            // `from` means it's gone
+            table.remove_path(destination, from);
+            continue;
+        }
+        table.insert(Route {
+            destination: destination.clone(),
+            next_hop: from.to_string(),
+            hops,
+            cost,
+            link: link.clone(),
+            updated_at: now,
+            on_demand: false,
+        });
+    }
+
+    // Whatever `from` no longer mentions, it can no longer reach, though
+    // it may still find it on demand
+    table.retain(|route| {
+        route.next_hop != from || route.on_demand || entries.iter().any(|(d, _)| *d == route.destination)
+    });
+    table.neighbour_links.insert(from.to_string(), seen_by_from);
+}
+
+// Drop routes through neighbours that have gone, or that haven't been
+// advertised lately, along with routes to devices now cheaper to reach
+// directly
+pub fn expire_routes(table: &mut RoutingTable, devices: &HashMap<String, Device>, links: &Links, now: i64) {
+    table.retain(|route| {
+        let lifetime = if route.on_demand { DISCOVERED_ROUTE_LIFETIME } else { ROUTE_TIMEOUT };
+        let direct_cheaper = devices.contains_key(&route.destination) && link_cost(links, &route.destination) <= route.cost;
+        devices.contains_key(&route.next_hop) && !direct_cheaper && now - route.updated_at <= lifetime.as_secs() as i64
+    });
+    table.neighbour_links.retain(|neighbour, _| devices.contains_key(neighbour));
+}
+
+// Remember a route found on demand, unless a cheaper one through the same
+// next hop is known
+fn cache_route(table: &mut RoutingTable, links: &Links, destination: &str, next_hop: &str, hops: u32, cost: f64) {
+    let cost = cost + link_cost(links, next_hop);
+    if table.via(destination, next_hop).is_some_and(|route| route.cost < cost) {
+        return;
+    }
+    table.insert(Route {
+        destination: destination.to_string(),
+        next_hop: next_hop.to_string(),
+        hops,
+        cost,
+        link: links.get(next_hop).cloned().unwrap_or_default(),
+        updated_at: chrono::Utc::now().timestamp(),
+        on_demand: true,
+    });
+}
+
+// Note a discovery's request id, returning false if it was seen already
+fn first_sighting(seen: &mut SeenDiscoveries, request_id: &str, now: i64) -> bool {
+    seen.retain(|_, at| now - *at <= SEEN_DISCOVERY_LIFETIME.as_secs() as i64);
+    seen.insert(request_id.to_string(), now).is_none()
+}
+
+// Find a route to a device no advertisement has told us about, by asking
+// the network, and cache it
+pub fn discover_route(destination: &str, ctx: &PeerContext) -> Option<Route> {
+    let request_id = Uuid::new_v4().to_string();
+    first_sighting(&mut ctx.seen_discoveries.lock(), &request_id, chrono::Utc::now().timestamp());
+    let request = PacketHeader {
+        packet_type: PACKET_ROUTE_DISCOVERY.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        origin_id: ctx.device_id.clone(),
+        forward_to: destination.to_string(),
+        request_id,
+        hop_limit: MAX_HOPS,
+        ..Default::default()
+    };
+    let (next_hop, hops, cost) = flood_discovery(&request, &[], ctx)?;
+    println!("🧭 Found a route to {} through {} ({} hops)", destination, next_hop, hops + 1);
+    let links = ctx.links.lock().clone();
+    cache_route(&mut ctx.routes.lock(), &links, destination, &next_hop, hops + 1, cost);
+    ctx.routes.lock().best(destination).cloned()
+}
+
+// Pass a discovery on to every neighbour but those in `except`, returning
+// the first that knows the way, with the hops and cost from there
+fn flood_discovery(request: &PacketHeader, except: &[&str], ctx: &PeerContext) -> Option<(String, u32, f64)> {
+    let neighbours: Vec<Device> = ctx.devices.lock()
+        .values()
+        .filter(|d| !d.public_key.is_empty() && !except.contains(&d.id.as_str()))
+        .cloned()
+        .collect();
+    if neighbours.is_empty() {
+        return None;
+    }
+
+    let timeout = DISCOVERY_HOP_TIMEOUT * request.hop_limit;
+    let (found, replies) = mpsc::channel();
+    for neighbour in neighbours {
+        let (request, ctx, found) = (request.clone(), ctx.clone(), found.clone());
+        ctx.tasks.clone().spawn(Budget::Request, move || {
+            if let Ok(Some((hops, cost))) = ask_neighbour(&neighbour, &request, timeout, &ctx) {
+                let _ = found.send((neighbour.id, hops, cost));
+            }
+        });
+    }
+    drop(found);
+    replies.recv_timeout(timeout).ok()
+}
+
+// Send a discovery to one neighbour, returning its hops and cost to the
+// destination if it answers with a route
+fn ask_neighbour(
+    neighbour: &Device,
+    request: &PacketHeader,
+    timeout: Duration,
+    ctx: &PeerContext,
+) -> std::io::Result<Option<(u32, f64)>> {
+    let mut channel = net::connect_device(neighbour, &ctx.identity_key)?;
+    if crate::encode_public_key(channel.peer_identity()) != neighbour.public_key {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Neighbour identity mismatch"));
+    }
+    write_header(&mut channel, request, &ctx.signing_key)?;
+
+    channel.set_read_timeout(Some(timeout))?;
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    let cost = if response.cost.is_finite() && response.cost > 0.0 {
+        response.cost
+    } else {
+        DEFAULT_LINK_COST * response.hops as f64
+    };
+    Ok((response.packet_type == PACKET_ROUTE_REPLY).then_some((response.hops, cost)))
+}
+
+// Answer a neighbour's route discovery: with a route if we are, or know
+// the way to, the destination, otherwise by asking our own neighbours
+pub fn answer_discovery(
+    mut channel: SecureChannel,
+    header: PacketHeader,
+    trusted: bool,
+    sender_key: &str,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    if ctx.settings.lock().accept_policy.decide(trusted) == crate::AcceptDecision::Reject {
+        return write_rejection(&mut channel, "Unknown device", &ctx);
+    }
+    if ctx.devices.lock().get(&header.source_id).is_none_or(|d| d.public_key != sender_key) {
+        return write_rejection(&mut channel, "Not a neighbour", &ctx);
+    }
+    // Only the destination itself answers for devices that don't relay
+    if header.forward_to != ctx.device_id && !ctx.settings.lock().relays_for(trusted) {
+        return write_rejection(&mut channel, "Not relaying", &ctx);
+    }
+    let now = chrono::Utc::now().timestamp();
+    if !first_sighting(&mut ctx.seen_discoveries.lock(), &header.request_id, now) {
+        return write_rejection(&mut channel, "Already seen", &ctx);
+    }
+
+    // The way back to whoever is looking is through the neighbour asking
+    let links = ctx.links.lock().clone();
+    let origin_hops = header.hops + 1;
+    let origin_cost = header.cost + link_cost(&links, &header.source_id);
+    if header.origin_id != ctx.device_id && !ctx.devices.lock().contains_key(&header.origin_id) {
+        cache_route(&mut ctx.routes.lock(), &links, &header.origin_id, &header.source_id, origin_hops, header.cost);
+    }
+
+    let destination = header.forward_to.as_str();
+    let known = if destination == ctx.device_id {
+        Some((0, 0.0))
+    } else if let Some(route) = ctx.routes.lock().best(destination) {
+        Some((route.hops, route.cost))
+    } else if ctx.devices.lock().contains_key(destination) {
+        Some((1, link_cost(&links, destination)))
+    } else {
+        None
+    };
+    let found = match known {
+        Some(found) => Some(found),
+        None if header.hop_limit > 1 => {
+            let request = PacketHeader {
+                packet_type: PACKET_ROUTE_DISCOVERY.to_string(),
+                source: ctx.device_name(),
+                source_id: ctx.device_id.clone(),
+                origin_id: header.origin_id.clone(),
+                forward_to: header.forward_to.clone(),
+                request_id: header.request_id.clone(),
+                hop_limit: header.hop_limit - 1,
+                hops: origin_hops,
+                cost: origin_cost,
+                ..Default::default()
+            };
+            flood_discovery(&request, &[&header.source_id, &header.origin_id], &ctx).map(|(next_hop, hops, cost)| {
+                cache_route(&mut ctx.routes.lock(), &links, destination, &next_hop, hops + 1, cost);
+                (hops + 1, cost + link_cost(&links, &next_hop))
+            })
+        }
+        None => None,
+    };
+    let Some((hops, cost)) = found else {
+        return write_rejection(&mut channel, "No route to destination", &ctx);
+    };
+
+    let reply = PacketHeader {
+        packet_type: PACKET_ROUTE_REPLY.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        request_id: header.request_id,
+        hops,
+        cost,
+        ..Default::default()
+    };
+    write_header(&mut channel, &reply, &ctx.signing_key)
+}
+
+// Send our routes to a neighbour and take in the ones it answers with.
+// Neighbours that don't exchange routes just leave us with none of theirs,
+// and one that doesn't answer within ROUTE_INTERVAL is given up on before
+// the next round.
+pub fn exchange_routes(neighbour: &Device, ctx: &PeerContext) -> std::io::Result<()> {
+    let mut channel = net::connect_device_timeout(neighbour, &ctx.identity_key, ROUTE_INTERVAL)?;
+    if crate::encode_public_key(channel.peer_identity()) != neighbour.public_key {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Neighbour identity mismatch"));
+    }
+    channel.set_read_timeout(Some(ROUTE_INTERVAL))?;
+    channel.set_write_timeout(Some(ROUTE_INTERVAL))?;
+    let links = ctx.links.lock().clone();
+    let trusted = ctx.trusted_devices.lock().contains_key(&neighbour.public_key);
+    let relaying = ctx.settings.lock().relays_for(trusted);
+    let (routes, route_costs) = {
+        let devices = ctx.devices.lock();
+        advertisement(&ctx.routes.lock(), &devices, &links, &ctx.device_id, &neighbour.id, relaying)
+    };
+    let request = PacketHeader {
+        packet_type: PACKET_ROUTE_ADVERTISEMENT.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        routes,
+        route_costs,
+        ..Default::default()
+    };
+    write_header(&mut channel, &request, &ctx.signing_key)?;
+
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type == PACKET_ROUTE_ADVERTISEMENT {
+        let devices = ctx.devices.lock();
+        let now = chrono::Utc::now().timestamp();
+        let received = Advertisement { from: &neighbour.id, entries: &response.routes, costs: &response.route_costs };
+        apply_advertisement(&mut ctx.routes.lock(), &devices, &links, &ctx.device_id, received, now);
+    }
+    Ok(())
+}
+
+// Take in a neighbour's routes and answer with ours. Only a device we see
+// directly, under the identity it connected with, counts as a neighbour.
+pub fn answer_advertisement(
+    mut channel: SecureChannel,
+    header: PacketHeader,
+    trusted: bool,
+    sender_key: &str,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    if ctx.settings.lock().accept_policy.decide(trusted) == crate::AcceptDecision::Reject {
+        return write_rejection(&mut channel, "Unknown device", &ctx);
+    }
+    let devices = ctx.devices.lock().clone();
+    if devices.get(&header.source_id).is_none_or(|d| d.public_key != sender_key) {
+        return write_rejection(&mut channel, "Not a neighbour", &ctx);
+    }
+
+    let links = ctx.links.lock().clone();
+    let relaying = ctx.settings.lock().relays_for(trusted);
+    let (routes, route_costs) = {
+        let mut table = ctx.routes.lock();
+        let now = chrono::Utc::now().timestamp();
+        let id = &ctx.device_id;
+        let received = Advertisement { from: &header.source_id, entries: &header.routes, costs: &header.route_costs };
+        apply_advertisement(&mut table, &devices, &links, id, received, now);
+        advertisement(&table, &devices, &links, id, &header.source_id, relaying)
+    };
+    let response = PacketHeader {
+        packet_type: PACKET_ROUTE_ADVERTISEMENT.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        routes,
+        route_costs,
+        ..Default::default()
+    };
+    write_header(&mut channel, &response, &ctx.signing_key)
+}
+
+// Name to show for a device id: the one it's discovered under, or the one
+// it was paired under, or failing both the id itself
+pub fn device_name(id: &str, devices: &HashMap<String, Device>, trusted: &HashMap<String, TrustedDevice>) -> String {
+    devices
+        .get(id)
+        .map(|d| d.name.clone())
+        .or_else(|| trusted.values().find(|d| d.device_id == id).map(|d| d.name.clone()))
+        .unwrap_or_else(|| id.to_string())
+}
+
+pub fn describe(route: &Route, devices: &HashMap<String, Device>, trusted: &HashMap<String, TrustedDevice>) -> RouteInfo {
+    RouteInfo {
+        route: route.clone(),
+        destination_name: device_name(&route.destination, devices, trusted),
+        next_hop_name: device_name(&route.next_hop, devices, trusted),
+    }
+}
+
+// How to reach a device
+pub enum Path {
+    Direct(Device),
+    // Through a route's next hop
+    Via(Route, Device),
+}
+
+// The ways to a device, best first: its routes, since a route to a device
+// we see is only kept while it beats the direct link, then directly, and
+// failing both a route found on demand
+pub fn choose_paths(destination: &str, ctx: &PeerContext) -> Vec<Path> {
+    let routes = ctx.routes.lock().paths(destination).to_vec();
+    let mut paths: Vec<Path> = {
+        let devices = ctx.devices.lock();
+        let via = routes.into_iter()
+            .filter_map(|route| devices.get(&route.next_hop).cloned().map(|next_hop| Path::Via(route, next_hop)));
+        via.chain(devices.get(destination).cloned().map(Path::Direct)).collect()
+    };
+    if paths.is_empty() {
+        if let Some(route) = discover_route(destination, ctx) {
+            if let Some(next_hop) = ctx.devices.lock().get(&route.next_hop).cloned() {
+                paths.push(Path::Via(route, next_hop));
+            }
+        }
+    }
+    paths
+}
+
+// Stop using the path to `destination` through `next_hop`, returning
+// whether another way there is still known
+pub fn fail_over(destination: &str, next_hop: &str, ctx: &PeerContext) -> bool {
+    let mut table = ctx.routes.lock();
+    if table.remove_path(destination, next_hop) {
+        println!("🧭 Dropped the route to {} through {}", destination, next_hop);
+    }
+    !table.paths(destination).is_empty() || ctx.devices.lock().contains_key(destination)
+}
+
+// Drop every route to or through a device that has gone
+pub fn forget_device(table: &mut RoutingTable, id: &str) {
+    table.retain(|route| route.destination != id && route.next_hop != id);
+    table.neighbour_links.remove(id);
+}
+
+// Connect to a device we route to, trying each way there best first and
+// checking it holds `identity`. Gives back the next hop used, if any.
+pub fn connect_towards(
+    destination: &str,
+    identity: &PublicKey,
+    ctx: &PeerContext,
+) -> std::io::Result<(SecureChannel, Option<String>)> {
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No route to destination");
+    for path in choose_paths(destination, ctx) {
+        let (attempt, next_hop) = match path {
+            Path::Direct(device) => {
+                (net::connect_device(&device, &ctx.identity_key), None)
+            }
+            Path::Via(_, next_hop) => {
+                let stream = open_path(&next_hop, destination, MAX_HOPS, ctx).map(|(stream, relays)| {
+                    println!("🔁 Tunnel to {} acknowledged by {}", destination, relays.join(" → "));
+                    stream
+                });
+                (stream.and_then(|stream| SecureChannel::connect_over(stream, &ctx.identity_key)), Some(next_hop.id))
+            }
+        };
+        match attempt {
+            Ok(channel) if channel.peer_identity().as_bytes() == identity.as_bytes() => return Ok((channel, next_hop)),
+            Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Destination identity mismatch")),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Connect to a device along the relays the user pinned, checking it holds
+// `identity`. Nothing else is tried if that way fails.
+pub fn connect_via(
+    destination: &str,
+    via: &[String],
+    identity: &PublicKey,
+    ctx: &PeerContext,
+) -> std::io::Result<SecureChannel> {
+    let (stream, relays) = open_pinned(destination, via, MAX_HOPS, ctx)?;
+    println!("🔁 Pinned tunnel to {} acknowledged by {}", destination, relays.join(" → "));
+    let channel = SecureChannel::connect_over(stream, &ctx.identity_key)?;
+    if channel.peer_identity().as_bytes() != identity.as_bytes() {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Destination identity mismatch"));
+    }
+    Ok(channel)
+}
+
+// Open a forwarded connection through the first of the pinned relays,
+// which must be in sight, handing it the rest
+fn open_pinned(
+    destination: &str,
+    via: &[String],
+    hop_limit: u32,
+    ctx: &PeerContext,
+) -> std::io::Result<(TcpStream, Vec<String>)> {
+    let not_in_sight = || std::io::Error::new(std::io::ErrorKind::NotFound, "Pinned relay not in sight");
+    let (first, rest) = via.split_first().ok_or_else(not_in_sight)?;
+    let relay = ctx.devices.lock().get(first).cloned().ok_or_else(not_in_sight)?;
+    open_forward(&relay, destination, hop_limit, Some(rest), ctx)
+}
+
+// Open a forwarded connection to `destination` through `next_hop`, giving
+// up on that path if it fails
+fn open_path(
+    next_hop: &Device,
+    destination: &str,
+    hop_limit: u32,
+    ctx: &PeerContext,
+) -> std::io::Result<(TcpStream, Vec<String>)> {
+    open_forward(next_hop, destination, hop_limit, None, ctx).inspect_err(|_| {
+        fail_over(destination, &next_hop.id, ctx);
+    })
+}
+
+// Have the hop `hop` open a forwarded connection to `destination`,
+// giving back the socket once it's ready to carry the tunnel, along with
+// the relays that acknowledged it
+fn open_forward(
+    hop: &Device,
+    destination: &str,
+    hop_limit: u32,
+    via: Option<&[String]>,
+    ctx: &PeerContext,
+) -> std::io::Result<(TcpStream, Vec<String>)> {
+    let channel = net::connect_device(hop, &ctx.identity_key)?;
+    forward_over(channel, destination, hop_limit, via, ctx)
+}
+
+// Ask the device at the other end of `channel` to forward to `destination`,
+// or to take the tunnel itself when it's the destination
+fn forward_over(
+    mut channel: SecureChannel,
+    destination: &str,
+    hop_limit: u32,
+    via: Option<&[String]>,
+    ctx: &PeerContext,
+) -> std::io::Result<(TcpStream, Vec<String>)> {
+    let request = PacketHeader {
+        packet_type: PACKET_FORWARD.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        forward_to: destination.to_string(),
+        hop_limit,
+        via: via.map(<[String]>::to_vec),
+        ..Default::default()
+    };
+    write_header(&mut channel, &request, &ctx.signing_key)?;
+
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| std::io::Error::new(std::io::ErrorKind::InvalidData, reason))?;
+    // Relays that predate paths only ever name themselves
+    let path = if response.path.is_empty() { vec![response.source] } else { response.path };
+    if response.packet_type == PACKET_ROUTE_ERROR {
+        let failure = HopFailure { path, reason: response.reason };
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, failure));
+    }
+    if response.packet_type != PACKET_FORWARD_READY {
+        let reason = if response.reason.is_empty() { "Forwarding refused".to_string() } else { response.reason };
+        let failure = HopFailure { path, reason };
+        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, failure));
+    }
+    Ok((transport::tunnel(channel)?, path))
+}
+
+// Tell whoever asked us to forward that `destination` can't be reached
+// this way, and through which relays it got before failing
+fn write_route_error(
+    channel: &mut SecureChannel,
+    destination: &str,
+    failure: HopFailure,
+    ctx: &PeerContext,
+) -> std::io::Result<()> {
+    let response = PacketHeader {
+        packet_type: PACKET_ROUTE_ERROR.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        forward_to: destination.to_string(),
+        reason: failure.reason,
+        path: failure.path,
+        ..Default::default()
+    };
+    write_header(channel, &response, &ctx.signing_key)
+}
+
+// Forward a connection for a peer towards the device it names, if the
+// relay settings allow it. Anything that goes wrong before the tunnel is
+// up is reported back as the reason for refusing; after that, a failure on
+// either side closes both.
+pub fn relay_packet(
+    mut channel: SecureChannel,
+    header: PacketHeader,
+    trusted: bool,
+    ctx: PeerContext,
+) -> std::io::Result<()> {
+    if header.forward_to == ctx.device_id {
+        return accept_tunnel(channel, ctx);
+    }
+    let (relays, max_active) = {
+        let settings = ctx.settings.lock();
+        (settings.relays_for(trusted), settings.max_relayed_transfers)
+    };
+    let refusal = {
+        let mut stats = ctx.relay_stats.lock();
+        let refusal = if !relays {
+            Some("Not relaying")
+        } else if stats.active >= max_active {
+            Some("Relay busy")
+        } else {
+            None
+        };
+        match refusal {
+            Some(_) => stats.refused += 1,
+            None => stats.active += 1,
+        }
+        refusal
+    };
+    if let Some(reason) = refusal {
+        return write_rejection(&mut channel, reason, &ctx);
+    }
+
+    let result = forward_connection(channel, &header, &ctx);
+    ctx.relay_stats.lock().active -= 1;
+    result
+}
+
+// Take the last hop of a tunnel to us, and handle the connection inside
+// like any other
+fn accept_tunnel(mut channel: SecureChannel, ctx: PeerContext) -> std::io::Result<()> {
+    let ready = PacketHeader {
+        packet_type: PACKET_FORWARD_READY.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &ready, &ctx.signing_key)?;
+    crate::handle_incoming_packet(transport::tunnel(channel)?, ctx, None)
+}
+
+fn forward_connection(mut channel: SecureChannel, header: &PacketHeader, ctx: &PeerContext) -> std::io::Result<()> {
+    if header.hop_limit == 0 {
+        return write_rejection(&mut channel, "Too many hops", ctx);
+    }
+
+    let onward = match &header.via {
+        Some(via) => pinned_onward(&header.forward_to, via, header.hop_limit - 1, ctx).map_err(|e| onward_failure(&e)),
+        None => routed_onward(header, ctx),
+    };
+    let (onward, mut relays) = match onward {
+        Ok(found) => found,
+        Err(mut failure) => {
+            failure.path.insert(0, ctx.device_name());
+            return write_route_error(&mut channel, &header.forward_to, failure, ctx);
+        }
+    };
+
+    relays.insert(0, ctx.device_name());
+    let ready = PacketHeader {
+        packet_type: PACKET_FORWARD_READY.to_string(),
+        source: ctx.device_name(),
+        source_id: ctx.device_id.clone(),
+        path: relays,
+        ..Default::default()
+    };
+    write_header(&mut channel, &ready, &ctx.signing_key)?;
+    ctx.relay_stats.lock().relayed_connections += 1;
+    println!("🔁 Relaying a connection from {} ({}) to {}", header.source, header.source_id, header.forward_to);
+    let (sent, received) = pipe(transport::tunnel(channel)?, onward, ctx)?;
+    println!("🔁 Relayed {} bytes out and {} bytes back for {}", sent, received, header.source);
+    if let Err(e) = ctx.history.lock().record_relayed(&header.source, sent + received) {
+        eprintln!("Failed to save relay statistics: {}", e);
+    }
+    Ok(())
+}
+
+// Straight to the destination, or on to our own next hop for it, trying
+// each way there in turn but never back where the connection came from
+fn routed_onward(header: &PacketHeader, ctx: &PeerContext) -> Result<(TcpStream, Vec<String>), HopFailure> {
+    let mut failure = HopFailure { path: Vec::new(), reason: "No route to destination".to_string() };
+    for path in choose_paths(&header.forward_to, ctx) {
+        let attempt = match path {
+            Path::Direct(device) => open_last_hop(&device, ctx).map(|stream| (stream, Vec::new())),
+            Path::Via(_, next_hop) if next_hop.id == header.source_id => continue,
+            Path::Via(_, next_hop) => open_path(&next_hop, &header.forward_to, header.hop_limit - 1, ctx),
+        };
+        match attempt {
+            Ok(found) => return Ok(found),
+            Err(e) => failure = onward_failure(&e),
+        }
+    }
+    Err(failure)
+}
+
+// On along the rest of a pinned route, or with none of it left straight
+// to the destination, which then has to be in sight
+fn pinned_onward(
+    destination: &str,
+    via: &[String],
+    hop_limit: u32,
+    ctx: &PeerContext,
+) -> std::io::Result<(TcpStream, Vec<String>)> {
+    if !via.is_empty() {
+        return open_pinned(destination, via, hop_limit, ctx);
+    }
+    let device = ctx.devices.lock().get(destination).cloned()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Destination not in sight"))?;
+    Ok((open_last_hop(&device, ctx)?, Vec::new()))
+}
+
+// Open the link on to the destination itself, inside a channel of its own
+// like every other hop. Only a device that doesn't say it can take one is
+// given the connection as it is; any other failure is passed back.
+fn open_last_hop(device: &Device, ctx: &PeerContext) -> std::io::Result<TcpStream> {
+    let channel = net::connect_device(device, &ctx.identity_key)?;
+    if !channel.peer_supports(protocol::TUNNEL) {
+        return net::tcp_connect(&net::device_addresses(device), device.port);
+    }
+    forward_over(channel, &device.id, 0, None, ctx).map(|(stream, _)| stream)
+}
+
+// What went wrong further on, as reported by the hops beyond us, or else
+// as seen from here
+fn onward_failure(error: &std::io::Error) -> HopFailure {
+    match error.get_ref().and_then(|inner| inner.downcast_ref::<HopFailure>()) {
+        Some(further) => HopFailure { path: further.path.clone(), reason: further.reason.clone() },
+        None => HopFailure { path: Vec::new(), reason: format!("Next hop unreachable ({})", error) },
+    }
+}
+
+// Copy bytes both ways between two sockets until either side closes, or
+// until we shut down. Both directions run as one task on the async
+// runtime, which the connection's thread waits on. Nothing is held beyond
+// one passthrough each way, whatever the size of the file.
+fn pipe(incoming: TcpStream, outgoing: TcpStream, ctx: &PeerContext) -> std::io::Result<(u64, u64)> {
+    incoming.set_nonblocking(true)?;
+    outgoing.set_nonblocking(true)?;
+    tauri::async_runtime::block_on(async {
+        let (mut incoming_read, mut incoming_write) = tokio::net::TcpStream::from_std(incoming)?.into_split();
+        let (mut outgoing_read, mut outgoing_write) = tokio::net::TcpStream::from_std(outgoing)?.into_split();
+
+        // Once the way back closes, the way there is done with too
+        let closed = ctx.tasks.child_token();
+        let forward = async {
+            let copied = relay_copy(&mut incoming_read, &mut outgoing_write, &closed, ctx).await;
+            let _ = outgoing_write.shutdown().await;
+            copied
+        };
+        let back = async {
+            let copied = relay_copy(&mut outgoing_read, &mut incoming_write, &closed, ctx).await;
+            closed.cancel();
+            copied
+        };
+        let (forward, back) = tokio::join!(forward, back);
+        Ok((forward.unwrap_or(0), back.unwrap_or(0)))
+    })
+}
+
+// Copy from one socket to the other until the first closes or `closed` is
+// cancelled, within the relay bandwidth limit, counting what passes in
+// the relay stats
+async fn relay_copy(
+    from: &mut OwnedReadHalf,
+    to: &mut OwnedWriteHalf,
+    closed: &CancellationToken,
+    ctx: &PeerContext,
+) -> std::io::Result<u64> {
+    let mut passthrough = Passthrough::new()?;
+    let mut copied = 0;
+    loop {
+        let read = tokio::select! {
+            read = passthrough.fill(from) => read?,
+            _ = closed.cancelled() => return Ok(copied),
+        };
+        if read == 0 {
+            return Ok(copied);
+        }
+        let wait = ctx.throttle.reserve(RELAY_THROTTLE_KEY, read);
+        tokio::select! {
+            written = async {
+                tokio::time::sleep(wait).await;
+                passthrough.drain(to, read).await
+            } => written?,
+            _ = closed.cancelled() => return Ok(copied),
+        }
+        copied += read as u64;
+        ctx.relay_stats.lock().bytes_forwarded += read as u64;
+    }
+}
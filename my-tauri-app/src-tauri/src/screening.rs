@@ -0,0 +1,146 @@
+// Warnings for incoming files that could run code
+//
+// A received file is screened once it's verified: by its extension against
+// the user's list, by the first bytes of programs and scripts whatever it's
+// called, and, for a zip archive, by the names of the files inside it. A
+// risky file is saved under its name with `.unconfirmed` added, so opening
+// it by mistake doesn't run it, and only gets its real name once the user
+// confirms it. A file held in the quarantine is listed there with what's
+// risky about it instead, since it waits for the user anyway.
+//
+// Other archive formats aren't looked into, nor are zips too large for
+// their plain format, which only their extension can flag.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// Added to the name of a risky file until it's confirmed
+const UNCONFIRMED_SUFFIX: &str = ".unconfirmed";
+
+const DEFAULT_RISKY_EXTENSIONS: &[&str] = &[
+    // Programs and installers
+    "exe", "msi", "com", "scr", "pif", "dll", "cpl", "app", "dmg", "pkg",
+    "deb", "rpm", "apk", "appimage", "jar", "run",
+    // Scripts
+    "bat", "cmd", "ps1", "psm1", "vbs", "vbe", "js", "jse", "wsf", "hta",
+    "sh", "bash", "zsh", "command", "py", "pl", "rb", "applescript",
+    // Shortcuts and documents that carry macros
+    "lnk", "url", "reg", "docm", "xlsm", "pptm",
+];
+
+// Zip's end of central directory record, which may be followed by a
+// comment of up to 64KiB
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const END_OF_DIRECTORY_LEN: u64 = 22;
+const DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const DIRECTORY_ENTRY_LEN: usize = 46;
+
+pub fn default_risky_extensions() -> Vec<String> {
+    DEFAULT_RISKY_EXTENSIONS.iter().map(|e| e.to_string()).collect()
+}
+
+fn risky_name(name: &str, extensions: &[String]) -> bool {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    extension.is_some_and(|extension| extensions.iter().any(|e| e.eq_ignore_ascii_case(&extension)))
+}
+
+// Why the file at `path`, received as `name`, could run code, if it could
+pub fn screen(path: &Path, name: &str, extensions: &[String]) -> io::Result<Option<String>> {
+    if risky_name(name, extensions) {
+        return Ok(Some("Program or script".to_string()));
+    }
+
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let read = file.read(&mut magic)?;
+    let magic = &magic[..read];
+    // Windows, Linux and macOS programs
+    let program = magic.starts_with(b"MZ")
+        || magic.starts_with(b"\x7fELF")
+        || matches!(magic, [0xfe, 0xed, 0xfa, 0xce | 0xcf] | [0xce | 0xcf, 0xfa, 0xed, 0xfe]);
+    if program {
+        return Ok(Some("Program".to_string()));
+    }
+    if magic.starts_with(b"#!") {
+        return Ok(Some("Script".to_string()));
+    }
+    if magic.starts_with(b"PK\x03\x04") {
+        let inside = zip_names(&mut file)?;
+        if let Some(program) = inside.iter().find(|inside| risky_name(inside, extensions)) {
+            return Ok(Some(format!("Archive containing {}", program)));
+        }
+    }
+    Ok(None)
+}
+
+// The names of the files in a zip archive, from its central directory. An
+// archive that can't be read as one has none.
+fn zip_names(file: &mut File) -> io::Result<Vec<String>> {
+    let len = file.metadata()?.len();
+    if len < END_OF_DIRECTORY_LEN {
+        return Ok(Vec::new());
+    }
+    let tail_len = len.min(END_OF_DIRECTORY_LEN + u16::MAX as u64);
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+
+    let Some(end) = (0..=tail.len() - END_OF_DIRECTORY_LEN as usize)
+        .rev()
+        .find(|&at| u32_at(&tail, at) == Some(END_OF_DIRECTORY))
+    else {
+        return Ok(Vec::new());
+    };
+    let (Some(size), Some(offset)) = (u32_at(&tail, end + 12), u32_at(&tail, end + 16)) else {
+        return Ok(Vec::new());
+    };
+    if offset as u64 + size as u64 > len {
+        return Ok(Vec::new());
+    }
+    let mut directory = vec![0u8; size as usize];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut directory)?;
+
+    let mut names = Vec::new();
+    let mut at = 0;
+    while u32_at(&directory, at) == Some(DIRECTORY_ENTRY) {
+        let (Some(name_len), Some(extra_len), Some(comment_len)) =
+            (u16_at(&directory, at + 28), u16_at(&directory, at + 30), u16_at(&directory, at + 32))
+        else {
+            break;
+        };
+        let name_start = at + DIRECTORY_ENTRY_LEN;
+        let Some(name) = directory.get(name_start..name_start + name_len as usize) else {
+            break;
+        };
+        names.push(String::from_utf8_lossy(name).into_owned());
+        at = name_start + name_len as usize + extra_len as usize + comment_len as usize;
+    }
+    Ok(names)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// Where a risky file waits for the user, beside where it belongs
+pub fn unconfirmed_path(download_path: &Path) -> PathBuf {
+    let mut name = download_path.file_name().unwrap_or_default().to_os_string();
+    name.push(UNCONFIRMED_SUFFIX);
+    download_path.with_file_name(name)
+}
+
+// Where a file waiting for the user belongs, if it's waiting at all
+pub fn confirmed_path(unconfirmed: &Path) -> Option<PathBuf> {
+    let name = unconfirmed.file_name()?.to_str()?;
+    let name = name.strip_suffix(UNCONFIRMED_SUFFIX).filter(|name| !name.is_empty())?;
+    Some(unconfirmed.with_file_name(name))
+}
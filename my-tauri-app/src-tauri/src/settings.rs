@@ -0,0 +1,260 @@
+// Persistent user settings
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::app_data_dir;
+
+// How incoming transfers are handled depending on whether the sender is trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcceptPolicy {
+    // Prompt for every transfer, even from unknown devices
+    AlwaysAsk,
+    // Accept trusted devices silently, prompt for everyone else
+    AutoAcceptFromTrusted,
+    // Refuse unknown devices, prompt for trusted ones
+    RejectUnknown,
+}
+
+// What to do with a specific incoming transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptDecision {
+    Accept,
+    Ask,
+    Reject,
+}
+
+impl AcceptPolicy {
+    pub fn decide(self, sender_trusted: bool) -> AcceptDecision {
+        match (self, sender_trusted) {
+            (AcceptPolicy::AlwaysAsk, _) => AcceptDecision::Ask,
+            (AcceptPolicy::AutoAcceptFromTrusted, true) => AcceptDecision::Accept,
+            (AcceptPolicy::AutoAcceptFromTrusted, false) => AcceptDecision::Ask,
+            (AcceptPolicy::RejectUnknown, true) => AcceptDecision::Ask,
+            (AcceptPolicy::RejectUnknown, false) => AcceptDecision::Reject,
+        }
+    }
+}
+
+// Reconnect attempts after a transfer loses its connection, with the delay
+// doubling after each failure
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay_ms: 1000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before the given attempt, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_millis(self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms))
+    }
+}
+
+// How often devices are pinged, and how long one may go unheard before it
+// shows as stale, then as offline
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatPolicy {
+    pub interval_secs: u64,
+    pub stale_after_secs: u64,
+    pub offline_after_secs: u64,
+}
+
+impl Default for HeartbeatPolicy {
+    fn default() -> Self {
+        HeartbeatPolicy {
+            interval_secs: 15,
+            stale_after_secs: 45,
+            offline_after_secs: 120,
+        }
+    }
+}
+
+impl HeartbeatPolicy {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+}
+
+// Which transfer events are announced with a notification
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    // Incoming files waiting to be accepted
+    pub requests: bool,
+    pub completed: bool,
+    pub failed: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        NotificationSettings {
+            requests: true,
+            completed: true,
+            failed: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub accept_policy: AcceptPolicy,
+    // How far a header's timestamp may drift before it is treated as a replay
+    pub replay_window_secs: i64,
+    // Transfers allowed to run at once; the rest wait in the queue
+    pub max_concurrent_outgoing: usize,
+    pub max_concurrent_incoming: usize,
+    // Combined rate of all transfers in bytes per second, 0 for no limit
+    pub bandwidth_limit: u64,
+    pub retry: RetryPolicy,
+    pub heartbeat: HeartbeatPolicy,
+    // Connections a large file may be spread over, 1 to always use one
+    pub parallel_streams: usize,
+    // Largest incoming file and total bytes received per day, 0 for no limit
+    pub max_file_size: u64,
+    pub daily_quota: u64,
+    // Hold files for paired devices that are offline, in a spool of at
+    // most `relay_quota` bytes
+    pub relay_enabled: bool,
+    pub relay_quota: u64,
+    // Forward connections between other devices, only for paired senders
+    // if `relay_trusted_only`, at most `max_relayed_transfers` at once and
+    // `relay_bandwidth_limit` bytes per second between them, 0 for no limit
+    pub allow_relaying: bool,
+    pub relay_trusted_only: bool,
+    pub max_relayed_transfers: usize,
+    pub relay_bandwidth_limit: u64,
+    // Relay between the network segments we're on, so devices on one can
+    // reach those on another through us
+    pub gateway_mode: bool,
+    // Stay reachable from outside the LAN through the rendezvous server
+    // at `rendezvous_server` (host:port), and send to paired devices we
+    // can't see through it
+    pub internet_transfers: bool,
+    pub rendezvous_server: String,
+    // Passphrase of the network group we're in, empty for none; only
+    // devices with the same one see and connect to us
+    pub network_group: String,
+    // Announce ourselves and look for other devices
+    pub discovery_enabled: bool,
+    // Keep running in the tray when the window is closed
+    pub run_in_background: bool,
+    // Turn away incoming files, other than ones we asked for
+    pub receiving_paused: bool,
+    pub notifications: NotificationSettings,
+    // Completed transfers kept listed, the oldest going first once there
+    // are more; failed ones stay until they're dismissed
+    pub max_finished_transfers: usize,
+    // Earlier versions kept of each file another device overwrites or a
+    // sync deletes, 0 to keep none
+    pub versions_kept: usize,
+    // Hold incoming files, encrypted, until they're released
+    pub quarantine_incoming: bool,
+    // Save incoming programs, scripts and archives of them under a name
+    // that won't run until the user confirms them; `risky_extensions` are
+    // flagged by name, on top of what's recognised by content
+    pub screen_incoming: bool,
+    pub risky_extensions: Vec<String>,
+    // What we show up as on other devices; an empty name means the
+    // machine's hostname
+    pub device_name: String,
+    pub device_icon: String,
+    // Ports the file server tries in turn, `server_port_count` of them from
+    // `server_port` on, before taking any free one; a count of 0 takes any
+    // free one straight away
+    pub server_port: u16,
+    pub server_port_count: u16,
+    // Export the shares read-only over WebDAV on `webdav_port`
+    pub webdav_enabled: bool,
+    pub webdav_port: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            accept_policy: AcceptPolicy::RejectUnknown,
+            replay_window_secs: 300,
+            max_concurrent_outgoing: 3,
+            max_concurrent_incoming: 3,
+            bandwidth_limit: 0,
+            retry: RetryPolicy::default(),
+            heartbeat: HeartbeatPolicy::default(),
+            parallel_streams: 4,
+            max_file_size: 0,
+            daily_quota: 0,
+            relay_enabled: false,
+            relay_quota: 4 * 1024 * 1024 * 1024,
+            allow_relaying: false,
+            relay_trusted_only: true,
+            max_relayed_transfers: 4,
+            relay_bandwidth_limit: 0,
+            gateway_mode: false,
+            internet_transfers: false,
+            rendezvous_server: String::new(),
+            network_group: String::new(),
+            discovery_enabled: true,
+            run_in_background: true,
+            receiving_paused: false,
+            notifications: NotificationSettings::default(),
+            max_finished_transfers: 200,
+            versions_kept: 5,
+            quarantine_incoming: false,
+            screen_incoming: true,
+            risky_extensions: crate::screening::default_risky_extensions(),
+            device_name: String::new(),
+            device_icon: String::new(),
+            server_port: 8888,
+            server_port_count: 10,
+            webdav_enabled: false,
+            webdav_port: crate::webdav::DEFAULT_PORT,
+        }
+    }
+}
+
+impl Settings {
+    // Whether we forward connections for a sender. A gateway relays
+    // whether or not relaying was allowed separately.
+    pub fn relays_for(&self, sender_trusted: bool) -> bool {
+        (self.allow_relaying || self.gateway_mode) && (sender_trusted || !self.relay_trusted_only)
+    }
+
+    // The rendezvous server to use, if internet transfers are on
+    pub fn rendezvous(&self) -> Option<String> {
+        Some(self.rendezvous_server.clone()).filter(|server| self.internet_transfers && !server.is_empty())
+    }
+}
+
+fn settings_path() -> std::path::PathBuf {
+    app_data_dir().join("settings.json")
+}
+
+// Load settings, falling back to defaults if missing or unreadable
+pub fn load_settings() -> Settings {
+    std::fs::read(settings_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+// Persist settings
+pub fn save_settings(settings: &Settings) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(settings)?;
+    std::fs::write(settings_path(), json)
+}
@@ -0,0 +1,183 @@
+// Files and folders this device offers to its peers
+//
+// A peer browses the shares with LIST_FILES and pulls something from them
+// by sending a FILE_REQUEST. Each share
+// has an id; a file inside a shared folder is addressed by the folder's id
+// followed by its path within the folder, e.g. `<id>/photos/cat.jpg`.
+// Nothing outside the shares can be requested.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::app_data_dir;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedItem {
+    pub id: String,
+    pub path: String,
+    pub is_dir: bool,
+}
+
+impl SharedItem {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(SharedItem {
+            id: Uuid::new_v4().to_string(),
+            path: path.to_string(),
+            is_dir: metadata.is_dir(),
+        })
+    }
+}
+
+fn shares_path() -> PathBuf {
+    app_data_dir().join("shares.json")
+}
+
+// Load the shared items, or none if nothing has been shared yet
+pub fn load_shares() -> Vec<SharedItem> {
+    std::fs::read(shares_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_shares(shares: &[SharedItem]) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(shares)?;
+    std::fs::write(shares_path(), json)
+}
+
+// An entry of a remote listing, as shown when browsing a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    pub name: String,
+    // What to pass back to list or request this entry
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Unix seconds
+    pub modified: i64,
+}
+
+impl RemoteEntry {
+    fn new(name: String, path: String, metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs() as i64);
+        RemoteEntry {
+            name,
+            path,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified,
+        }
+    }
+}
+
+// Resolve a requested path to a file on disk, refusing anything that isn't
+// shared or that would lead out of a shared folder
+pub fn resolve(shares: &[SharedItem], requested: &str) -> Option<PathBuf> {
+    locate(shares, requested).filter(|path| path.is_file())
+}
+
+// List a shared folder, or every share when `requested` is empty.
+// Symlinks are left out, since they could lead anywhere.
+pub fn list(shares: &[SharedItem], requested: &str) -> Option<Vec<RemoteEntry>> {
+    let requested = requested.trim_matches(['/', '\\']);
+    if requested.is_empty() {
+        let entries = shares.iter()
+            .filter_map(|share| {
+                let metadata = std::fs::metadata(&share.path).ok()?;
+                let name = std::path::Path::new(&share.path).file_name()?.to_string_lossy().into_owned();
+                Some(RemoteEntry::new(name, share.id.clone(), &metadata))
+            })
+            .collect();
+        return Some(entries);
+    }
+
+    let dir = locate(shares, requested).filter(|path| path.is_dir())?;
+    let mut entries: Vec<RemoteEntry> = std::fs::read_dir(dir).ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = std::fs::symlink_metadata(entry.path()).ok()?;
+            if metadata.file_type().is_symlink() {
+                return None;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = format!("{}/{}", requested, name);
+            Some(RemoteEntry::new(name, path, &metadata))
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(entries)
+}
+
+// Find a shared path on disk, without caring whether it's a file or folder
+pub fn locate(shares: &[SharedItem], requested: &str) -> Option<PathBuf> {
+    let mut parts = requested.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".");
+    let id = parts.next()?;
+    let item = shares.iter().find(|s| s.id == id)?;
+
+    let mut path = PathBuf::from(&item.path);
+    for part in parts {
+        if !item.is_dir || part == ".." || part.contains(':') {
+            return None;
+        }
+        path.push(part);
+    }
+
+    // A symlink inside the folder could still point anywhere
+    let root = std::fs::canonicalize(&item.path).ok()?;
+    let real = std::fs::canonicalize(&path).ok()?;
+    real.starts_with(&root).then_some(real)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // A new folder of its own under the temp folder, resolved
+    fn temp_folder() -> PathBuf {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::canonicalize(path).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn shared_paths_are_found_inside_their_share(name in "[a-z]{1,12}") {
+            let folder = temp_folder();
+            let outside = temp_folder();
+            std::fs::create_dir(folder.join("sub")).unwrap();
+            std::fs::write(folder.join("sub").join(&name), b"shared").unwrap();
+            std::fs::write(outside.join(&name), b"not shared").unwrap();
+            let share = SharedItem::new(folder.to_str().unwrap()).unwrap();
+            let file = SharedItem::new(outside.join(&name).to_str().unwrap()).unwrap();
+            let shares = [share.clone(), file.clone()];
+
+            prop_assert_eq!(locate(&shares, &share.id), Some(folder.clone()));
+            prop_assert_eq!(locate(&shares, &format!("{}/sub/{}", share.id, name)), Some(folder.join("sub").join(&name)));
+            prop_assert_eq!(locate(&shares, &format!("{}\\sub\\{}", share.id, name)), Some(folder.join("sub").join(&name)));
+            prop_assert_eq!(locate(&shares, &file.id), Some(outside.join(&name)));
+            prop_assert_eq!(locate(&shares, &format!("{}/{}", Uuid::new_v4(), name)), None);
+            prop_assert_eq!(locate(&shares, &format!("{}/missing", share.id)), None);
+
+            // Nothing leads out of a share, or into a shared file
+            let escape = format!("{}/sub/../../{}/{}", share.id, outside.file_name().unwrap().to_str().unwrap(), name);
+            prop_assert_eq!(locate(&shares, &escape), None);
+            prop_assert_eq!(locate(&shares, &format!("{}/C:{}", share.id, name)), None);
+            prop_assert_eq!(locate(&shares, &format!("{}/{}", file.id, name)), None);
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(&outside, folder.join("link")).unwrap();
+                prop_assert_eq!(locate(&shares, &format!("{}/link/{}", share.id, name)), None);
+            }
+
+            std::fs::remove_dir_all(folder).unwrap();
+            std::fs::remove_dir_all(outside).unwrap();
+        }
+    }
+}
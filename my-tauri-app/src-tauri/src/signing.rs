@@ -0,0 +1,219 @@
+// Ed25519 signatures over packet headers
+//
+// The session handshake only authenticates the hop we are talking to.
+// Signing the header lets the receiver check that the claimed source
+// really produced it, independently of how it reached us, and that no
+// field of it was changed on the way.
+
+use base64::Engine;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+use crate::pairing::TrustedDevice;
+use crate::{app_data_dir, PacketHeader};
+
+// Load this installation's signing key, creating it on first run
+pub fn load_or_create_signing_key() -> SigningKey {
+    let path = app_data_dir().join("signing.key");
+    if let Ok(bytes) = std::fs::read(&path) {
+        if let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return SigningKey::from_bytes(&bytes);
+        }
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+    let _ = std::fs::create_dir_all(app_data_dir());
+    if let Err(e) = std::fs::write(&path, key.to_bytes()) {
+        eprintln!("Failed to persist signing key: {}", e);
+    }
+    key
+}
+
+// Encode a verifying key for headers, TXT records and the trust store
+pub fn encode_verifying_key(key: &VerifyingKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+}
+
+// What the signature covers: each field of the header but the signature
+// that isn't at its default, by name, in name order, as JSON. Relays pass
+// headers on re-encoded, so it can't be the bytes that were sent, and a
+// peer on another header schema drops the fields it doesn't know, so it
+// can't be our own layout of the header either. This way a header signs
+// the same on every schema that knows every field set in it.
+fn signed_bytes(header: &PacketHeader) -> Result<Vec<u8>, String> {
+    let unencodable = |_| "Unencodable header".to_string();
+    let unset = serde_json::to_value(PacketHeader::default()).map_err(unencodable)?;
+    let serde_json::Value::Object(fields) = serde_json::to_value(header).map_err(unencodable)? else {
+        return Err("Unencodable header".to_string());
+    };
+    let set: BTreeMap<String, serde_json::Value> = fields.into_iter()
+        .filter(|(name, value)| name != "signature" && unset.get(name) != Some(value))
+        .collect();
+    serde_json::to_vec(&set).map_err(unencodable)
+}
+
+// Stamp and sign a header with our key
+pub fn sign_header(header: &mut PacketHeader, key: &SigningKey) -> Result<(), String> {
+    header.timestamp = chrono::Utc::now().timestamp();
+    header.nonce = Uuid::new_v4().to_string();
+    header.signing_key = encode_verifying_key(&key.verifying_key());
+    let signature = key.sign(&signed_bytes(header)?);
+    header.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+    Ok(())
+}
+
+// Check a header's signature. When the sender is a paired device, the
+// signing key must also match the one recorded at pairing time.
+pub fn verify_header(header: &PacketHeader, paired: Option<&TrustedDevice>) -> Result<(), String> {
+    if header.signature.is_empty() || header.signing_key.is_empty() {
+        return Err("Unsigned packet".to_string());
+    }
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let key_bytes = engine.decode(&header.signing_key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+        .ok_or("Malformed signing key")?;
+    let key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| "Malformed signing key")?;
+
+    let signature_bytes = engine.decode(&header.signature)
+        .ok()
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes.as_slice()).ok())
+        .ok_or("Malformed signature")?;
+    let signature = Signature::from_bytes(&signature_bytes);
+
+    key.verify(&signed_bytes(header)?, &signature)
+        .map_err(|_| "Invalid signature".to_string())?;
+
+    if let Some(device) = paired {
+        if !device.signing_key.is_empty() && device.signing_key != header.signing_key {
+            return Err("Signing key does not match paired device".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+// Recently seen (signing key, nonce) pairs, so a captured header cannot be
+// replayed while its timestamp is still inside the acceptance window
+#[derive(Default)]
+pub struct ReplayCache {
+    seen: HashMap<(String, String), i64>,
+}
+
+impl ReplayCache {
+    // Reject stale or already-seen headers, otherwise remember this one
+    pub fn check_and_record(&mut self, header: &PacketHeader, window_secs: i64) -> Result<(), String> {
+        let now = chrono::Utc::now().timestamp();
+        if (now - header.timestamp).abs() > window_secs {
+            return Err("Stale packet".to_string());
+        }
+        if header.nonce.is_empty() {
+            return Err("Missing nonce".to_string());
+        }
+
+        // Anything older than the window would be rejected as stale anyway
+        self.seen.retain(|_, timestamp| now - *timestamp <= window_secs);
+
+        let key = (header.signing_key.clone(), header.nonce.clone());
+        if self.seen.contains_key(&key) {
+            return Err("Replayed packet".to_string());
+        }
+        self.seen.insert(key, header.timestamp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{decode_header, encode_header};
+    use proptest::prelude::*;
+
+    fn signed(filename: String, relative_path: String, file_size: u64) -> PacketHeader {
+        let mut header = PacketHeader {
+            packet_type: "FILE_TRANSFER".to_string(),
+            filename,
+            relative_path,
+            file_size,
+            ..Default::default()
+        };
+        sign_header(&mut header, &SigningKey::generate(&mut OsRng)).unwrap();
+        header
+    }
+
+    proptest! {
+        #[test]
+        fn signatures_hold_however_the_header_was_sent(
+            filename in "\\PC{1,40}",
+            relative_path in "\\PC{0,60}",
+            file_size in any::<u64>(),
+        ) {
+            let header = signed(filename, relative_path, file_size);
+            prop_assert!(verify_header(&header, None).is_ok());
+            for binary in [true, false] {
+                let decoded = decode_header(&encode_header(&header, binary).unwrap(), binary).unwrap();
+                prop_assert!(verify_header(&decoded, None).is_ok());
+            }
+
+            // A peer on an older schema, without fields left at their default
+            let mut json = serde_json::to_value(&header).unwrap();
+            json.as_object_mut().unwrap().remove("sync_folder");
+            let older: PacketHeader = serde_json::from_value(json).unwrap();
+            prop_assert!(verify_header(&older, None).is_ok());
+        }
+
+        #[test]
+        fn changed_fields_fail(
+            filename in "\\PC{1,40}",
+            relative_path in "\\PC{0,60}",
+            file_size in any::<u64>(),
+            sync_folder in "\\PC{1,20}",
+        ) {
+            let header = signed(filename, relative_path, file_size);
+            let resized = PacketHeader { file_size: file_size ^ 1, ..header.clone() };
+            prop_assert!(verify_header(&resized, None).is_err());
+            let moved = PacketHeader { sync_folder, ..header };
+            prop_assert!(verify_header(&moved, None).is_err());
+        }
+
+        #[test]
+        fn headers_are_taken_once(nonces in prop::collection::hash_set("[0-9a-f-]{36}", 1..8), window in 1..600i64) {
+            let mut cache = ReplayCache::default();
+            let now = chrono::Utc::now().timestamp();
+            let stamped = |nonce: &String| PacketHeader {
+                timestamp: now,
+                nonce: nonce.clone(),
+                signing_key: "key".to_string(),
+                ..Default::default()
+            };
+            for nonce in &nonces {
+                prop_assert!(cache.check_and_record(&stamped(nonce), window).is_ok());
+            }
+            for nonce in &nonces {
+                prop_assert_eq!(cache.check_and_record(&stamped(nonce), window), Err("Replayed packet".to_string()));
+            }
+
+            // The same nonce from another key is another header
+            let other = PacketHeader { signing_key: "other".to_string(), ..stamped(nonces.iter().next().unwrap()) };
+            prop_assert!(cache.check_and_record(&other, window).is_ok());
+        }
+
+        #[test]
+        fn stale_and_unnamed_headers_are_refused(age in 2..100_000i64, window in 1..600i64, ahead in any::<bool>()) {
+            let mut cache = ReplayCache::default();
+            let now = chrono::Utc::now().timestamp();
+            let skew = window + age;
+            let stale = PacketHeader {
+                timestamp: if ahead { now + skew } else { now - skew },
+                nonce: "nonce".to_string(),
+                ..Default::default()
+            };
+            prop_assert_eq!(cache.check_and_record(&stale, window), Err("Stale packet".to_string()));
+            let unnamed = PacketHeader { timestamp: now, ..Default::default() };
+            prop_assert_eq!(cache.check_and_record(&unnamed, window), Err("Missing nonce".to_string()));
+        }
+    }
+}
@@ -0,0 +1,1260 @@
+// Keeping a folder in step with a folder on another device
+//
+// A sync pair ties a local folder to a folder under a paired device's
+// Downloads. `run` watches the folder of every pair and, once changes to
+// one have settled for SETTLE_DELAY, works out what changed since it was
+// last synced and mirrors that. Every pair is also rescanned each
+// RESCAN_INTERVAL, which catches what the watcher missed, pairs whose
+// device was offline and, for two-way pairs, changes on the other end.
+//
+// What was last synced, each file's size, modification time on both ends
+// and hash, is saved with its pair as its sync database. Against it, a file
+// on either end is unchanged, changed, new or deleted: a file whose size
+// and time are as they were isn't looked at again, and one that was only
+// touched is hashed and found the same. A one-way pair sends what changed
+// here; a two-way pair also lists the other end, with a SYNC_LIST that
+// names what we last saw there so it only hashes what changed since, and
+// takes what changed there. A file changed on both ends is a conflict,
+// settled by the pair's policy.
+//
+// A pair's rules (see filters.rs) decide which of its files are synced.
+// Both ends leave out what they exclude while walking the folder, so the
+// other end is sent them with each listing. A file they leave out is left
+// alone on both ends, even if it was synced before the rules changed.
+//
+// Files go out as ordinary transfers, to the same relative paths on the
+// other end, and are pulled back with a SYNC_FETCH, which the other end
+// answers by sending the file back. Either way the file is written to a
+// `.part` file and renamed into place once verified, so a synced file is
+// never seen half written, and `.part` files are never synced. A deletion,
+// either way, only goes ahead if the file still has the contents that
+// were synced, so nothing changed since is lost, and what a sync replaces
+// or deletes is kept as a version (see versions.rs).
+//
+// Sync transfers name their pair's folder, and the receiving end notes a
+// folder a paired device's sync made under its Downloads as that device's
+// (see PeerFolders). A device is only answered a SYNC_LIST, a SYNC_FETCH
+// or a SYNC_DELETE for paths inside a folder of its own, or inside the
+// folder of a pair the receiving end has with it. A folder that was there
+// before the sync stays out of its reach, so a pair wants a new folder.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use ::notify::{RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use tauri::{AppHandle, Emitter, Manager};
+use uuid::Uuid;
+use x25519_dalek::PublicKey;
+
+use crate::error::{Error, ErrorReport};
+use crate::filters::{FileRules, Filter};
+use crate::sharing::RemoteEntry;
+use crate::versions;
+use crate::{
+    app_data_dir, connect_destination, download_path_for, encode_public_key, hashing, protocol, queue_outgoing, read_header,
+    reply_destination, send_file_internal, send_reply, signing, tasks, write_header, write_rejection, write_response, AppState,
+    Destination, Device, PacketHeader, PeerContext, SecureChannel, PACKET_SYNC_DELETE, PACKET_SYNC_DELETED,
+    PACKET_SYNC_FETCH, PACKET_SYNC_FILES, PACKET_SYNC_LIST, PACKET_TRANSFER_ACCEPT,
+};
+
+// Quiet time after a change before its folder is synced
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+// How often every folder is rescanned, changed or not
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+// Longest the loop waits for a change before looking at the pairs again
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// How long a pulled file has to start arriving once the other end agreed
+// to send it
+const PULL_START_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A file as it was when it was last synced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedFile {
+    pub size: u64,
+    // Unix milliseconds, here and on the other end
+    pub modified: i64,
+    #[serde(default)]
+    pub remote_modified: i64,
+    pub file_hash: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    // Changes here are mirrored there
+    #[default]
+    OneWay,
+    // Changes on either end are mirrored to the other
+    TwoWay,
+}
+
+// What to do with a file changed on both ends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    // The copy modified last replaces the other
+    NewestWins,
+    // Ours is renamed aside and both copies end up on both ends
+    #[default]
+    KeepBoth,
+    // The file is left alone until the user picks
+    Ask,
+}
+
+// Which copy of a conflicting file to keep
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Local,
+    Remote,
+    Both,
+}
+
+// A file changed on both ends, waiting for the user to pick
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncConflict {
+    pub path: String,
+    pub local_size: u64,
+    pub local_modified: i64,
+    pub remote_size: u64,
+    pub remote_modified: i64,
+    // What the user picked, applied at the next sync
+    #[serde(default)]
+    pub resolution: Option<Resolution>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    #[default]
+    Idle,
+    // The device is offline
+    Waiting,
+    Scanning,
+    Syncing,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPair {
+    pub id: String,
+    pub local_path: String,
+    // Identity key and name of the device synced with
+    pub target: String,
+    pub target_name: String,
+    // Folder under the device's Downloads the files go to
+    pub remote_folder: String,
+    #[serde(default)]
+    pub mode: SyncMode,
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    // Which files are synced
+    #[serde(default)]
+    pub rules: FileRules,
+    pub created_at: String,
+    #[serde(default)]
+    pub last_synced: Option<String>,
+    // Files as they were last synced, by path relative to the folder
+    #[serde(default)]
+    pub synced: HashMap<String, SyncedFile>,
+    #[serde(default)]
+    pub conflicts: Vec<SyncConflict>,
+    // How the pair is doing; not saved
+    #[serde(skip)]
+    pub phase: Phase,
+    #[serde(skip)]
+    pub pending: usize,
+    #[serde(skip)]
+    pub error: Option<ErrorReport>,
+}
+
+// A pair as the UI sees it
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncStatus {
+    pub id: String,
+    pub local_path: String,
+    pub target: String,
+    pub target_name: String,
+    pub remote_folder: String,
+    pub mode: SyncMode,
+    pub conflict_policy: ConflictPolicy,
+    pub rules: FileRules,
+    pub phase: Phase,
+    // Files synced, and changes still to go through
+    pub files: usize,
+    pub pending: usize,
+    pub conflicts: Vec<SyncConflict>,
+    pub last_synced: Option<String>,
+    pub error: Option<ErrorReport>,
+}
+
+impl SyncPair {
+    pub fn new(
+        local_path: String,
+        target: String,
+        target_name: String,
+        remote_folder: String,
+        mode: SyncMode,
+        conflict_policy: ConflictPolicy,
+    ) -> Self {
+        SyncPair {
+            id: Uuid::new_v4().to_string(),
+            local_path,
+            target,
+            target_name,
+            remote_folder: remote_folder.trim_matches(['/', '\\']).to_string(),
+            mode,
+            conflict_policy,
+            rules: FileRules::default(),
+            created_at: chrono::Local::now().to_rfc3339(),
+            last_synced: None,
+            synced: HashMap::new(),
+            conflicts: Vec::new(),
+            phase: Phase::Idle,
+            pending: 0,
+            error: None,
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        SyncStatus {
+            id: self.id.clone(),
+            local_path: self.local_path.clone(),
+            target: self.target.clone(),
+            target_name: self.target_name.clone(),
+            remote_folder: self.remote_folder.clone(),
+            mode: self.mode,
+            conflict_policy: self.conflict_policy,
+            rules: self.rules.clone(),
+            phase: self.phase,
+            files: self.synced.len(),
+            pending: self.pending,
+            conflicts: self.conflicts.clone(),
+            last_synced: self.last_synced.clone(),
+            error: self.error.clone(),
+        }
+    }
+
+    fn busy(&self) -> bool {
+        matches!(self.phase, Phase::Scanning | Phase::Syncing)
+    }
+
+    // Where a file of the folder is on the other device, under Downloads
+    fn remote_path(&self, relative: &str) -> String {
+        format!("{}/{}", self.remote_folder, relative)
+    }
+}
+
+fn pairs_path() -> PathBuf {
+    app_data_dir().join("sync.json")
+}
+
+pub fn load_pairs() -> Vec<SyncPair> {
+    std::fs::read(pairs_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_pairs(pairs: &[SyncPair]) -> io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(pairs)?;
+    std::fs::write(pairs_path(), json)
+}
+
+// The folders under our Downloads that paired devices' syncs made, by the
+// identity key of the device, each named as under Downloads with '/'
+// between its parts. A device is only answered about the folders its
+// syncs made, and each folder belongs to one device at most.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PeerFolders {
+    folders: HashMap<String, BTreeSet<String>>,
+}
+
+pub type SyncedFolders = Arc<Mutex<PeerFolders>>;
+
+impl PeerFolders {
+    fn owns(&self, peer: &str, folder: &str) -> bool {
+        self.folders.get(peer).is_some_and(|folders| folders.contains(folder))
+    }
+
+    // Make `folder` `peer`'s, and no other device's
+    fn claim(&mut self, peer: &str, folder: String) {
+        for folders in self.folders.values_mut() {
+            folders.remove(&folder);
+        }
+        self.folders.retain(|_, folders| !folders.is_empty());
+        self.folders.entry(peer.to_string()).or_default().insert(folder);
+    }
+
+    // Where the folders of `peer` that are still there really are
+    fn roots(&self, peer: &str) -> Vec<PathBuf> {
+        self.folders.get(peer)
+            .into_iter()
+            .flatten()
+            .filter_map(|folder| download_path_for(folder))
+            .filter_map(|path| std::fs::canonicalize(path).ok())
+            .collect()
+    }
+}
+
+// A folder under Downloads as PeerFolders names it, however it was written
+fn folder_name(folder: &str) -> String {
+    folder.split(['/', '\\'])
+        .filter(|part| !matches!(*part, "" | "."))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn synced_folders_path() -> PathBuf {
+    app_data_dir().join("sync_folders.json")
+}
+
+pub fn load_synced_folders() -> PeerFolders {
+    std::fs::read(synced_folders_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_synced_folders(folders: &PeerFolders) -> io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(folders)?;
+    std::fs::write(synced_folders_path(), json)
+}
+
+// Note the folder a paired device's sync is sending `download_path` into
+// as the device's, if the file is what makes the folder. A folder that
+// was here before, or that another device's sync made, is left as it is,
+// so a sync can't reach what it didn't make.
+pub fn claim_folder(folder: &str, download_path: &Path, peer: &PublicKey, ctx: &PeerContext) {
+    let peer = encode_public_key(peer);
+    if !ctx.trusted_devices.lock().contains_key(&peer) {
+        return;
+    }
+    let folder = folder_name(folder);
+    let Some(path) = download_path_for(&folder) else {
+        return;
+    };
+    if download_path == path || !download_path.starts_with(&path) {
+        return;
+    }
+    let mut folders = ctx.synced_folders.lock();
+    if folders.owns(&peer, &folder) || path.exists() {
+        return;
+    }
+    folders.claim(&peer, folder);
+    if let Err(e) = save_synced_folders(&folders) {
+        eprintln!("Failed to save synced folders: {}", e);
+    }
+}
+
+// Watch the pairs' folders and sync each once its changes settle, for as
+// long as the app runs
+pub fn run(app: AppHandle) {
+    let (events_tx, events) = mpsc::channel();
+    let mut watcher = ::notify::recommended_watcher(events_tx)
+        .map_err(|e| eprintln!("Can't watch sync folders, rescanning only: {}", e))
+        .ok();
+    let mut watched: HashSet<PathBuf> = HashSet::new();
+    // Pairs with changes, and when they're due to be synced
+    let mut due: HashMap<String, Instant> = HashMap::new();
+    let mut last_rescan: Option<Instant> = None;
+
+    loop {
+        let state = app.state::<AppState>();
+        let folders: Vec<(String, PathBuf)> = state.sync_pairs.lock()
+            .iter()
+            .map(|pair| (pair.id.clone(), PathBuf::from(&pair.local_path)))
+            .collect();
+
+        // Watch the folders of the pairs there are now, and no others. A
+        // folder that can't be watched yet is tried again next time.
+        if let Some(watcher) = watcher.as_mut() {
+            let wanted: HashSet<PathBuf> = folders.iter().map(|(_, folder)| folder.clone()).collect();
+            for folder in watched.iter().filter(|folder| !wanted.contains(*folder)) {
+                let _ = watcher.unwatch(folder);
+            }
+            watched.retain(|folder| wanted.contains(folder));
+            for folder in wanted {
+                if !watched.contains(&folder) && watcher.watch(&folder, RecursiveMode::Recursive).is_ok() {
+                    watched.insert(folder);
+                }
+            }
+        }
+
+        let first = if watcher.is_some() {
+            events.recv_timeout(POLL_INTERVAL).ok()
+        } else {
+            std::thread::sleep(POLL_INTERVAL);
+            None
+        };
+        for event in first.into_iter().chain(events.try_iter()) {
+            // Reading a file, as hashing it does, isn't a change
+            let Ok(event) = event else { continue };
+            if event.kind.is_access() {
+                continue;
+            }
+            for (id, folder) in &folders {
+                if event.paths.iter().any(|path| path.starts_with(folder)) {
+                    due.insert(id.clone(), Instant::now() + SETTLE_DELAY);
+                }
+            }
+        }
+
+        if last_rescan.is_none_or(|at| at.elapsed() >= RESCAN_INTERVAL) {
+            for (id, _) in &folders {
+                due.entry(id.clone()).or_insert_with(Instant::now);
+            }
+            last_rescan = Some(Instant::now());
+        }
+
+        let now = Instant::now();
+        let ready: Vec<String> = due.iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ready {
+            if start(&app, &id) {
+                due.remove(&id);
+            }
+        }
+    }
+}
+
+// Sync a pair in the background if its device is online. False when it's
+// still busy with the last sync, to be tried again later.
+pub fn start(app: &AppHandle, id: &str) -> bool {
+    let state = app.state::<AppState>();
+    let device = {
+        let mut pairs = state.sync_pairs.lock();
+        let Some(pair) = pairs.iter_mut().find(|pair| pair.id == id) else {
+            return true;
+        };
+        if pair.busy() {
+            return false;
+        }
+        let device = state.devices.lock().values().find(|d| d.public_key == pair.target).cloned();
+        pair.phase = if device.is_some() { Phase::Scanning } else { Phase::Waiting };
+        let _ = app.emit("sync://status", pair.status());
+        device
+    };
+    let Some(device) = device else {
+        return true;
+    };
+
+    let (app, id) = (app.clone(), id.to_string());
+    state.tasks.spawn(tasks::Budget::Send, move || {
+        let result = sync_pair(&app, &id, &device);
+        if let Err(e) = &result {
+            eprintln!("Error syncing folder: {}", e);
+        }
+        update(&app, &id, |pair| {
+            pair.pending = 0;
+            match result {
+                Ok(()) => {
+                    pair.phase = Phase::Idle;
+                    pair.error = None;
+                    pair.last_synced = Some(chrono::Local::now().to_rfc3339());
+                }
+                Err(e) => {
+                    pair.phase = Phase::Failed;
+                    pair.error = Some(e.report());
+                }
+            }
+        });
+    });
+    true
+}
+
+// Change a pair, if it's still there, then save the pairs and show it
+fn update(app: &AppHandle, id: &str, change: impl FnOnce(&mut SyncPair)) {
+    let state = app.state::<AppState>();
+    let mut pairs = state.sync_pairs.lock();
+    let Some(pair) = pairs.iter_mut().find(|pair| pair.id == id) else {
+        return;
+    };
+    change(pair);
+    let _ = app.emit("sync://status", pair.status());
+    if let Err(e) = save_pairs(&pairs) {
+        eprintln!("Failed to save sync pairs: {}", e);
+    }
+}
+
+// A file on the other end, as listed. The hash is left out when the file
+// is as we last saw it.
+struct RemoteFile {
+    size: u64,
+    modified: i64,
+    file_hash: Option<String>,
+}
+
+// What a sync has to do, worked out before any of it is done
+#[derive(Default)]
+struct Plan {
+    push: Vec<String>,
+    // With the hash and modification time of the copy to take
+    pull: Vec<(String, String, i64)>,
+    // With the hash of the copy that was synced
+    delete_remote: Vec<(String, String)>,
+    delete_local: Vec<String>,
+    // Files the same on both ends, with what to record for them
+    agreed: Vec<(String, SyncedFile)>,
+    // Files gone from both ends
+    forget: Vec<String>,
+    // Files changed on both ends, to be renamed aside and kept as well
+    keep_both: Vec<(String, String, i64)>,
+    conflicts: Vec<SyncConflict>,
+}
+
+impl Plan {
+    fn len(&self) -> usize {
+        self.push.len() + self.pull.len() + self.delete_remote.len() + self.delete_local.len() + 2 * self.keep_both.len()
+    }
+}
+
+// Mirror what changed in a pair's folder, and for a two-way pair on the
+// other end, since its last sync
+fn sync_pair(app: &AppHandle, id: &str, device: &Device) -> Result<(), Error> {
+    let state = app.state::<AppState>();
+    let Some(pair) = state.sync_pairs.lock().iter().find(|pair| pair.id == id).cloned() else {
+        return Ok(());
+    };
+    let root = PathBuf::from(&pair.local_path);
+    let filter = Filter::new(&pair.rules);
+    let mut files = scan(&root, &filter).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", pair.local_path, e)))?;
+    let ctx = state.peer_context(app.clone());
+    let destination = || {
+        let mut destination = state.destination(device.ip.clone(), device.port, None, true);
+        destination.exact_paths = true;
+        destination.sync_folder = Some(pair.remote_folder.clone());
+        destination
+    };
+    let remote = match pair.mode {
+        SyncMode::OneWay => None,
+        SyncMode::TwoWay => Some(list_remote(&destination(), &ctx, &pair)?),
+    };
+
+    let plan = plan(&pair, &files, remote.as_ref(), &filter, &ctx);
+    let total = plan.len();
+    update(app, id, |pair| {
+        pair.phase = Phase::Syncing;
+        pair.pending = total;
+        pair.synced.extend(plan.agreed);
+        for relative in &plan.forget {
+            pair.synced.remove(relative);
+        }
+        pair.conflicts = plan.conflicts;
+    });
+    let mut failed = 0;
+    let mut done = 0;
+
+    // A deletion that's refused is given up on: the other end either no
+    // longer has the file or has changed it since, which the next sync
+    // takes care of
+    for (relative, file_hash) in plan.delete_remote {
+        match delete_remote(&destination(), &ctx, &pair.remote_path(&relative), &file_hash) {
+            Ok(refused) => {
+                if let Some(reason) = refused {
+                    eprintln!("Not deleting {} on {}: {}", relative, pair.target_name, reason);
+                }
+                done += 1;
+                update(app, id, |pair| {
+                    pair.synced.remove(&relative);
+                    pair.pending = total - done;
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to delete {} on {}: {}", relative, pair.target_name, e);
+                failed += 1;
+            }
+        }
+    }
+    let versions_kept = ctx.settings.lock().versions_kept;
+    for relative in plan.delete_local {
+        let path = root.join(&relative);
+        match versions::save(&path, versions_kept).and_then(|_| std::fs::remove_file(&path)) {
+            Ok(()) => remove_empty_folders(&path, &root),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Failed to delete {}: {}", path.display(), e);
+                failed += 1;
+                continue;
+            }
+        }
+        done += 1;
+        update(app, id, |pair| {
+            pair.synced.remove(&relative);
+            pair.pending = total - done;
+        });
+    }
+
+    // Our copy of a file changed on both ends moves aside under a new
+    // name, to go out as a new file, and theirs takes its place
+    let mut push = plan.push;
+    let mut pull = plan.pull;
+    for (relative, file_hash, modified) in plan.keep_both {
+        let aside = conflict_name(&relative, &ctx.device_name(), |name| files.contains_key(name));
+        let moved = std::fs::rename(root.join(&relative), root.join(&aside));
+        match moved.ok().and_then(|_| LocalFile::read(root.join(&aside))) {
+            Some(file) => {
+                files.remove(&relative);
+                files.insert(aside.clone(), file);
+                push.push(aside);
+                pull.push((relative, file_hash, modified));
+            }
+            None => failed += 2,
+        }
+    }
+
+    // Files are pulled one at a time, each sent back on its own connection
+    if !pull.is_empty() {
+        let reply_port = state.server_port()?;
+        for (relative, file_hash, remote_modified) in pull {
+            let path = root.join(&relative);
+            let pulled = pull_file(&destination(), &ctx, &pair.remote_path(&relative), &file_hash, &path, reply_port);
+            match pulled {
+                Ok(true) => {
+                    done += 1;
+                    let synced = LocalFile::read(path).map(|file| SyncedFile {
+                        remote_modified,
+                        ..file.synced(file_hash)
+                    });
+                    update(app, id, |pair| {
+                        if let Some(synced) = synced {
+                            pair.synced.insert(relative, synced);
+                        }
+                        pair.pending = total - done;
+                    });
+                }
+                Ok(false) => failed += 1,
+                Err(e) => {
+                    eprintln!("Failed to pull {} from {}: {}", relative, pair.target_name, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    // Everything new or modified here goes out over one connection
+    let send_to = destination();
+    let mut outgoing = Vec::new();
+    let mut sent = Vec::new();
+    for relative in push {
+        let file = &files[&relative];
+        let path = file.path.to_string_lossy().into_owned();
+        match queue_outgoing(path, Some(pair.remote_path(&relative)), None, &send_to, &ctx) {
+            Ok(queued) => {
+                sent.push((relative, queued.transfer_id.clone(), queued.file_hash.clone()));
+                outgoing.push(queued);
+            }
+            Err(e) => {
+                eprintln!("Skipping {} in sync: {}", relative, e);
+                failed += 1;
+            }
+        }
+    }
+    if !outgoing.is_empty() {
+        if let Err(e) = send_file_internal(outgoing, send_to, ctx.clone()) {
+            eprintln!("Error sending synced files: {}", e);
+        }
+    }
+
+    // Only files whose transfers completed count as synced; the rest go
+    // again next time. Their time on the other end isn't known until it's
+    // next listed.
+    let mut delivered = Vec::new();
+    for (relative, transfer_id, file_hash) in sent {
+        if transfer_completed(&ctx, &transfer_id) {
+            delivered.push((relative.clone(), files[&relative].synced(file_hash)));
+        } else {
+            failed += 1;
+        }
+    }
+    update(app, id, |pair| pair.synced.extend(delivered));
+
+    if failed > 0 {
+        return Err(Error::Internal(format!("{} of {} changes didn't go through", failed, total)));
+    }
+    Ok(())
+}
+
+// Work out what a sync has to do from the files here, those on the other
+// end of a two-way pair, and what was last synced
+fn plan(
+    pair: &SyncPair,
+    files: &BTreeMap<String, LocalFile>,
+    remote: Option<&HashMap<String, RemoteFile>>,
+    filter: &Filter,
+    ctx: &PeerContext,
+) -> Plan {
+    let local_hash = |file: &LocalFile| hashing::hash_file(&ctx.hashed_files, &file.path).ok().map(|h| h.file_hash);
+    let mut paths: BTreeSet<&String> = files.keys().chain(pair.synced.keys()).collect();
+    if let Some(remote) = remote {
+        paths.extend(remote.keys());
+    }
+
+    let mut plan = Plan::default();
+    for relative in paths {
+        let base = pair.synced.get(relative);
+        let local = files.get(relative);
+        // What the rules leave out isn't touched, here or there; the walks
+        // already left out most of it, but not what was synced before
+        let remote_size = remote.and_then(|remote| remote.get(relative)).map(|file| file.size);
+        let too_large = local.map(|file| file.size).into_iter().chain(remote_size).any(|size| filter.too_large(size));
+        if too_large || filter.ignores_path(relative) {
+            continue;
+        }
+        let local_changed = match (local, base) {
+            (Some(file), Some(base)) if file.size == base.size => {
+                file.modified != base.modified && local_hash(file).as_ref() != Some(&base.file_hash)
+            }
+            (None, None) => false,
+            _ => true,
+        };
+        // A one-way pair takes the other end to be as it was left
+        let (remote_file, remote_changed) = match (remote, base) {
+            (None, _) => (None, false),
+            (Some(remote), base) => {
+                let file = remote.get(relative);
+                let changed = match (file, base) {
+                    (Some(file), Some(base)) => file.file_hash.as_ref().is_some_and(|hash| *hash != base.file_hash),
+                    (None, None) => false,
+                    _ => true,
+                };
+                (file, changed)
+            }
+        };
+
+        match (local_changed, remote_changed) {
+            (false, false) => {
+                // Touched files get their new times recorded
+                if let (Some(file), Some(base)) = (local, base) {
+                    let remote_modified = remote_file.map_or(base.remote_modified, |remote| remote.modified);
+                    if file.modified != base.modified || remote_modified != base.remote_modified {
+                        plan.agreed.push((relative.clone(), SyncedFile { remote_modified, ..file.synced(base.file_hash.clone()) }));
+                    }
+                }
+            }
+            (true, false) => match (local, base) {
+                (Some(_), _) => plan.push.push(relative.clone()),
+                (None, Some(base)) => plan.delete_remote.push((relative.clone(), base.file_hash.clone())),
+                (None, None) => {}
+            },
+            (false, true) => match remote_file {
+                Some(remote) => plan.pull.push((relative.clone(), remote.file_hash.clone().unwrap_or_default(), remote.modified)),
+                None => plan.delete_local.push(relative.clone()),
+            },
+            // Changed on both ends; a change beats a deletion
+            (true, true) => match (local, remote_file) {
+                (None, None) => plan.forget.push(relative.clone()),
+                (Some(_), None) => plan.push.push(relative.clone()),
+                (None, Some(remote)) => plan.pull.push((relative.clone(), remote.file_hash.clone().unwrap_or_default(), remote.modified)),
+                (Some(file), Some(remote)) => {
+                    let remote_hash = remote.file_hash.clone().unwrap_or_default();
+                    let hash = local_hash(file);
+                    if hash.as_ref() == Some(&remote_hash) {
+                        plan.agreed.push((relative.clone(), SyncedFile { remote_modified: remote.modified, ..file.synced(remote_hash) }));
+                        continue;
+                    }
+                    let resolution = match pair.conflict_policy {
+                        ConflictPolicy::NewestWins if file.modified >= remote.modified => Some(Resolution::Local),
+                        ConflictPolicy::NewestWins => Some(Resolution::Remote),
+                        ConflictPolicy::KeepBoth => Some(Resolution::Both),
+                        ConflictPolicy::Ask => pair.conflicts.iter()
+                            .find(|conflict| conflict.path == *relative)
+                            .and_then(|conflict| conflict.resolution),
+                    };
+                    match resolution {
+                        Some(Resolution::Local) => plan.push.push(relative.clone()),
+                        Some(Resolution::Remote) => plan.pull.push((relative.clone(), remote_hash, remote.modified)),
+                        Some(Resolution::Both) => plan.keep_both.push((relative.clone(), remote_hash, remote.modified)),
+                        None => plan.conflicts.push(SyncConflict {
+                            path: relative.clone(),
+                            local_size: file.size,
+                            local_modified: file.modified,
+                            remote_size: remote.size,
+                            remote_modified: remote.modified,
+                            resolution: None,
+                        }),
+                    }
+                }
+            },
+        }
+    }
+    plan
+}
+
+// A name for our copy of a conflicting file, next to it, that isn't taken
+fn conflict_name(relative: &str, device: &str, taken: impl Fn(&str) -> bool) -> String {
+    let (folder, name) = relative.rsplit_once('/').map_or(("", relative), |(folder, name)| (folder, name));
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let date = chrono::Local::now().format("%Y-%m-%d");
+    (1..)
+        .map(|n| {
+            let copy = if n == 1 { String::new() } else { format!(" {}", n) };
+            let name = format!("{} (conflict from {} {}{}){}", stem, device, date, copy, extension);
+            if folder.is_empty() { name } else { format!("{}/{}", folder, name) }
+        })
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+fn transfer_completed(ctx: &PeerContext, transfer_id: &str) -> bool {
+    ctx.transfers.lock()
+        .iter()
+        .find(|t| t.id == transfer_id)
+        .is_some_and(|t| t.finished_at.is_some() && t.error.is_none())
+}
+
+// List the other end of a pair, telling it what we last saw there so it
+// only hashes what changed since
+fn list_remote(destination: &Destination, ctx: &PeerContext, pair: &SyncPair) -> io::Result<HashMap<String, RemoteFile>> {
+    let mut channel = connect_destination(destination, ctx)?;
+    if !channel.peer_supports(protocol::SYNC) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Device can't sync both ways"));
+    }
+    let request = PacketHeader {
+        packet_type: PACKET_SYNC_LIST.to_string(),
+        source: ctx.device_name(),
+        request_path: pair.remote_folder.clone(),
+        // The pair's rules, for the other end to walk the folder by
+        text: serde_json::to_string(&pair.rules)?,
+        entries: pair.synced.iter().map(|(relative, synced)| sync_entry(relative, synced.size, synced.remote_modified)).collect(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &request, &ctx.signing_key)?;
+
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type != PACKET_SYNC_FILES {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, response.reason));
+    }
+    if response.file_hashes.len() != response.entries.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed sync listing"));
+    }
+    Ok(response.entries.into_iter()
+        .zip(response.file_hashes)
+        .map(|(entry, file_hash)| {
+            let file = RemoteFile {
+                size: entry.size,
+                modified: entry.modified,
+                file_hash: (!file_hash.is_empty()).then_some(file_hash),
+            };
+            (entry.path, file)
+        })
+        .collect())
+}
+
+// Listings for a sync reuse the shape of a browsing listing, with times
+// in milliseconds
+fn sync_entry(relative: &str, size: u64, modified: i64) -> RemoteEntry {
+    RemoteEntry {
+        name: String::new(),
+        path: relative.to_string(),
+        is_dir: false,
+        size,
+        modified,
+    }
+}
+
+// Where a path under our Downloads that `peer` asks about really is, if
+// it's inside a folder `peer`'s syncs made here or the folder of one of
+// our own pairs with `peer`, and that folder
+fn locate(path: &Path, peer: &PublicKey, ctx: &PeerContext) -> Option<(PathBuf, PathBuf)> {
+    let peer = encode_public_key(peer);
+    let mut roots = ctx.synced_folders.lock().roots(&peer);
+    roots.extend(ctx.app.state::<AppState>().sync_pairs.lock()
+        .iter()
+        .filter(|pair| pair.target == peer)
+        .filter_map(|pair| std::fs::canonicalize(&pair.local_path).ok()));
+    locate_in(path, &roots)
+}
+
+// Where `path` really is, if that's inside one of `roots`, which are
+// resolved already, and which. A symlink inside a folder could still
+// point anywhere, so the path is resolved first.
+fn locate_in(path: &Path, roots: &[PathBuf]) -> Option<(PathBuf, PathBuf)> {
+    let real = std::fs::canonicalize(path).ok()?;
+    let root = roots.iter().find(|root| real.starts_with(root))?.clone();
+    Some((real, root))
+}
+
+// List a synced folder for the device that syncs it: each file with its
+// hash, left out for files the device says are as it last saw them. A
+// two-way pair lists the other end before sending anything, so a folder
+// that isn't there yet is listed empty rather than refused.
+pub fn handle_list(channel: &mut SecureChannel, header: &PacketHeader, trusted: bool, ctx: &PeerContext) -> io::Result<()> {
+    if !trusted {
+        return write_rejection(channel, "Only paired devices can sync", ctx);
+    }
+    let Some(path) = download_path_for(&header.request_path) else {
+        return write_rejection(channel, "Unsafe file path", ctx);
+    };
+    let files = if std::fs::symlink_metadata(&path).is_err() {
+        BTreeMap::new()
+    } else {
+        let Some(folder) = locate(&path, channel.peer_identity(), ctx).map(|(folder, _)| folder).filter(|folder| folder.is_dir()) else {
+            return write_rejection(channel, "Not a synced folder", ctx);
+        };
+        let rules: FileRules = serde_json::from_str(&header.text).unwrap_or_default();
+        scan(&folder, &Filter::new(&rules))?
+    };
+    let seen: HashMap<&str, (u64, i64)> = header.entries.iter()
+        .map(|entry| (entry.path.as_str(), (entry.size, entry.modified)))
+        .collect();
+
+    let mut entries = Vec::with_capacity(files.len());
+    let mut file_hashes = Vec::with_capacity(files.len());
+    for (relative, file) in files {
+        let file_hash = if seen.get(relative.as_str()) == Some(&(file.size, file.modified)) {
+            String::new()
+        } else {
+            // A file that can't be read now is left for the next listing
+            match hashing::hash_file(&ctx.hashed_files, &file.path) {
+                Ok(hashes) => hashes.file_hash,
+                Err(_) => continue,
+            }
+        };
+        entries.push(sync_entry(&relative, file.size, file.modified));
+        file_hashes.push(file_hash);
+    }
+
+    let response = PacketHeader {
+        packet_type: PACKET_SYNC_FILES.to_string(),
+        source: ctx.device_name(),
+        entries,
+        file_hashes,
+        ..Default::default()
+    };
+    write_header(channel, &response, &ctx.signing_key)
+}
+
+// A file asked for from the other end of a pair, by request id
+pub struct Pull {
+    from: PublicKey,
+    path: PathBuf,
+    // Set once it starts arriving
+    transfer_id: Option<String>,
+    done: mpsc::Sender<String>,
+}
+
+pub type Pulls = Arc<Mutex<HashMap<String, Pull>>>;
+
+// Where a file that's arriving goes, if it's one a sync pulled from the
+// device sending it
+pub fn pulled_path(pulls: &Pulls, request_id: &str, transfer_id: &str, from: &PublicKey) -> Option<PathBuf> {
+    let mut pulls = pulls.lock();
+    let pull = pulls.get_mut(request_id).filter(|pull| pull.from.as_bytes() == from.as_bytes())?;
+    pull.transfer_id = Some(transfer_id.to_string());
+    Some(pull.path.clone())
+}
+
+// Let the sync waiting on a pulled file know its transfer is over
+pub fn pull_finished(pulls: &Pulls, request_id: &str, transfer_id: &str) {
+    let mut pulls = pulls.lock();
+    if pulls.get(request_id).is_some_and(|pull| pull.transfer_id.as_deref() == Some(transfer_id)) {
+        if let Some(pull) = pulls.remove(request_id) {
+            let _ = pull.done.send(transfer_id.to_string());
+        }
+    }
+}
+
+// Have the other end send back its copy of a file, as long as it still
+// has the contents `file_hash`, and wait for it to be saved at `to`
+fn pull_file(
+    destination: &Destination,
+    ctx: &PeerContext,
+    path: &str,
+    file_hash: &str,
+    to: &Path,
+    reply_port: u16,
+) -> io::Result<bool> {
+    let mut channel = connect_destination(destination, ctx)?;
+    let request_id = Uuid::new_v4().to_string();
+    let (done_tx, done) = mpsc::channel();
+    let from = *channel.peer_identity();
+    ctx.pending_pulls.lock().insert(request_id.clone(), from);
+    ctx.sync_pulls.lock().insert(request_id.clone(), Pull {
+        from,
+        path: to.to_path_buf(),
+        transfer_id: None,
+        done: done_tx,
+    });
+    let forget = || {
+        ctx.pending_pulls.lock().remove(&request_id);
+        ctx.sync_pulls.lock().remove(&request_id);
+    };
+
+    let request = PacketHeader {
+        packet_type: PACKET_SYNC_FETCH.to_string(),
+        source: ctx.device_name(),
+        request_id: request_id.clone(),
+        request_path: path.to_string(),
+        file_hash: file_hash.to_string(),
+        reply_port,
+        ..Default::default()
+    };
+    let response = write_header(&mut channel, &request, &ctx.signing_key)
+        .and_then(|_| read_header(&mut channel))
+        .and_then(|response| {
+            signing::verify_header(&response, None)
+                .map(|_| response)
+                .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))
+        });
+    drop(channel);
+    match response {
+        Ok(response) if response.packet_type == PACKET_TRANSFER_ACCEPT => {}
+        Ok(response) => {
+            forget();
+            eprintln!("{} wasn't sent: {}", path, response.reason);
+            return Ok(false);
+        }
+        Err(e) => {
+            forget();
+            return Err(e);
+        }
+    }
+
+    // It has a while to start arriving, then as long as it takes
+    let asked = Instant::now();
+    loop {
+        match done.recv_timeout(POLL_INTERVAL) {
+            Ok(transfer_id) => return Ok(transfer_completed(ctx, &transfer_id)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(false),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let started = ctx.sync_pulls.lock().get(&request_id).is_some_and(|pull| pull.transfer_id.is_some());
+                if !started && asked.elapsed() >= PULL_START_TIMEOUT {
+                    forget();
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+// Send a synced file back to the device that syncs it, if it still has the
+// contents the device expects
+pub fn handle_fetch(mut channel: SecureChannel, header: PacketHeader, trusted: bool, ctx: PeerContext) -> io::Result<()> {
+    if !trusted {
+        return write_rejection(&mut channel, "Only paired devices can sync", &ctx);
+    }
+    let Some(requested) = download_path_for(&header.request_path) else {
+        return write_rejection(&mut channel, "Unsafe file path", &ctx);
+    };
+    let Some(path) = locate(&requested, channel.peer_identity(), &ctx).map(|(path, _)| path).filter(|path| path.is_file()) else {
+        return write_rejection(&mut channel, "Not found", &ctx);
+    };
+    if hashing::hash_file(&ctx.hashed_files, &path)?.file_hash != header.file_hash {
+        return write_rejection(&mut channel, "Changed on this device", &ctx);
+    }
+    write_response(&mut channel, PACKET_TRANSFER_ACCEPT, &ctx)?;
+
+    // It has to arrive even if the device has the same file elsewhere
+    let mut destination = reply_destination(&channel, header.reply_port)?;
+    destination.exact_paths = true;
+    drop(channel);
+    send_reply(path, header.request_id, destination, ctx);
+    Ok(())
+}
+
+// Ask the other end to delete its copy of a synced file, as long as it
+// still has the contents `file_hash`. Gives back the reason if it won't.
+fn delete_remote(destination: &Destination, ctx: &PeerContext, path: &str, file_hash: &str) -> io::Result<Option<String>> {
+    let mut channel = connect_destination(destination, ctx)?;
+    if !channel.peer_supports(protocol::SYNC) {
+        return Ok(Some("Device can't sync deletions".to_string()));
+    }
+    let request = PacketHeader {
+        packet_type: PACKET_SYNC_DELETE.to_string(),
+        source: ctx.device_name(),
+        relative_path: path.to_string(),
+        file_hash: file_hash.to_string(),
+        ..Default::default()
+    };
+    write_header(&mut channel, &request, &ctx.signing_key)?;
+
+    let response = read_header(&mut channel)?;
+    signing::verify_header(&response, None)
+        .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+    if response.packet_type == PACKET_SYNC_DELETED {
+        Ok(None)
+    } else {
+        Ok(Some(response.reason))
+    }
+}
+
+// Delete a synced file for the device that synced it, if it still has the
+// contents that were synced. Folders it leaves empty go with it.
+pub fn handle_delete(channel: &mut SecureChannel, header: &PacketHeader, trusted: bool, ctx: &PeerContext) -> io::Result<()> {
+    if !trusted {
+        return write_rejection(channel, "Only paired devices can sync", ctx);
+    }
+    let Some(requested) = download_path_for(&header.relative_path) else {
+        return write_rejection(channel, "Unsafe file path", ctx);
+    };
+    // The folder it's in is resolved rather than the file, which may be
+    // gone already, and a symlink is deleted rather than followed
+    let located = requested.parent()
+        .and_then(|folder| locate(folder, channel.peer_identity(), ctx))
+        .zip(requested.file_name())
+        .map(|((folder, root), name)| (folder.join(name), root));
+    let Some((path, root)) = located else {
+        return write_rejection(channel, "Not in a synced folder", ctx);
+    };
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return write_rejection(channel, "Not a file", ctx),
+        // Already gone is as good as deleted
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return write_response(channel, PACKET_SYNC_DELETED, ctx);
+        }
+        Err(e) => return Err(e),
+    }
+
+    let current = hashing::hash_file(&ctx.hashed_files, &path)?;
+    if current.file_hash != header.file_hash {
+        return write_rejection(channel, "Changed on this device", ctx);
+    }
+    let versions_kept = ctx.settings.lock().versions_kept;
+    versions::save(&path, versions_kept)?;
+    std::fs::remove_file(&path)?;
+    remove_empty_folders(&path, &root);
+    write_response(channel, PACKET_SYNC_DELETED, ctx)
+}
+
+// Remove the folders above a deleted file that it left empty, stopping at
+// `root`
+fn remove_empty_folders(path: &Path, root: &Path) {
+    let mut folder = path.parent();
+    while let Some(dir) = folder {
+        if dir == root || !dir.starts_with(root) || std::fs::remove_dir(dir).is_err() {
+            return;
+        }
+        folder = dir.parent();
+    }
+}
+
+// A file in a synced folder as it is now
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+    // Unix milliseconds
+    modified: i64,
+}
+
+impl LocalFile {
+    fn read(path: PathBuf) -> Option<Self> {
+        let metadata = std::fs::metadata(&path).ok()?;
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_millis() as i64);
+        Some(LocalFile { path, size: metadata.len(), modified })
+    }
+
+    fn synced(&self, file_hash: String) -> SyncedFile {
+        SyncedFile { size: self.size, modified: self.modified, remote_modified: 0, file_hash }
+    }
+}
+
+// Every file under `root` that `filter` lets in, by its path relative to
+// it with '/' between folders. Symlinks are skipped, as when sending a
+// folder, and so are kept versions and files still being received. Sizes
+// are left to the plan, which has to know of files too large to sync.
+fn scan(root: &Path, filter: &Filter) -> io::Result<BTreeMap<String, LocalFile>> {
+    let mut files = BTreeMap::new();
+    scan_folder(root, "", filter, &mut files)?;
+    Ok(files)
+}
+
+fn scan_folder(dir: &Path, relative: &str, filter: &Filter, files: &mut BTreeMap<String, LocalFile>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if file_type.is_dir() && name == versions::VERSIONS_DIR {
+            continue;
+        }
+        let relative = if relative.is_empty() { name } else { format!("{}/{}", relative, name) };
+        if filter.ignores(&relative, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            scan_folder(&entry.path(), &relative, filter, files)?;
+        } else if file_type.is_file() && !relative.ends_with(".part") {
+            // A file deleted while we look is left for the next scan
+            if let Some(file) = LocalFile::read(entry.path()) {
+                files.insert(relative, file);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // A new folder of its own under the temp folder, resolved
+    fn temp_folder() -> PathBuf {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::canonicalize(path).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn folders_belong_to_the_device_that_made_them(folder in "[a-z]{1,12}(/[a-z]{1,12}){0,2}") {
+            // A plain two-way pair, whose first sync made the folder
+            let mut folders = PeerFolders::default();
+            folders.claim("phone", folder_name(&format!("/{}/", folder.replace('/', "\\"))));
+            prop_assert!(folders.owns("phone", &folder));
+            prop_assert!(!folders.owns("laptop", &folder));
+
+            folders.claim("laptop", folder.clone());
+            prop_assert!(!folders.owns("phone", &folder));
+            prop_assert!(folders.owns("laptop", &folder));
+        }
+
+        #[test]
+        fn paths_are_only_located_inside_their_folder(name in "[a-z]{1,12}") {
+            let root = temp_folder();
+            let outside = temp_folder();
+            let file = root.join("sub").join(&name);
+            std::fs::create_dir(root.join("sub")).unwrap();
+            std::fs::write(&file, b"synced").unwrap();
+            std::fs::write(outside.join(&name), b"not synced").unwrap();
+            let roots = [root.clone()];
+
+            prop_assert_eq!(locate_in(&root, &roots), Some((root.clone(), root.clone())));
+            prop_assert_eq!(locate_in(&file, &roots), Some((file.clone(), root.clone())));
+            let escape = root.join("sub").join("..").join("..").join(outside.file_name().unwrap()).join(&name);
+            prop_assert_eq!(locate_in(&escape, &roots), None);
+            prop_assert_eq!(locate_in(&outside.join(&name), &roots), None);
+            prop_assert_eq!(locate_in(&root.join("missing"), &roots), None);
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+                prop_assert_eq!(locate_in(&root.join("link").join(&name), &roots), None);
+            }
+
+            std::fs::remove_dir_all(root).unwrap();
+            std::fs::remove_dir_all(outside).unwrap();
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// Where the networking runs
+//
+// Everything that talks to peers is a task on Tauri's tokio runtime: the
+// file server's accept loop, discovery, relaying and the sends and
+// requests we start. Each kind of work has a budget of how many may run at
+// once. The protocol itself still reads and writes SecureChannels in
+// blocking calls, so work that does runs on the runtime's blocking pool,
+// holding a permit from its budget until it's done.
+//
+// Permits are taken before work starts, not after: the server takes one
+// before accepting, so once its budget is spent new connections wait in
+// the listen backlog and their senders slow down, rather than a thread
+// being started for each. Everything started here gives up once
+// `shutdown` is cancelled.
+//
+// A connection to us is accepted under the handshake budget, which is
+// small, so peers that connect and never finish their handshake can't take
+// the room of those that do. Once it has said what it's for, it trades that
+// permit for one from the connection budget, or from the relay budget if
+// it's a relay, which may stay open for as long as the devices at its ends
+// like. Each address may also only have so many connections at once, at
+// any stage, so no one peer fills the budgets.
+//
+// A panic in any of it stays where it happened. Locks are parking_lot's,
+// which aren't poisoned by a panicking holder, so one bad transfer leaves
+// the state the rest of the app shares usable; and the long-lived loops
+// that keep discovery, routing and the like going are `supervise`d, and
+// started again if they panic.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Error;
+
+// Connections peers make to us, each handled until it closes
+pub const MAX_CONNECTIONS: usize = 64;
+// Connections to us still in their handshake
+pub const MAX_HANDSHAKES: usize = 16;
+// Connections we relay for other devices, within the user's relay limit
+pub const MAX_RELAYS: usize = 32;
+// Connections to us from one address at once
+pub const MAX_PER_PEER: usize = 8;
+// Files being sent, including those waiting their turn in the queue
+pub const MAX_SENDS: usize = 64;
+// Short exchanges we start: requests, messages, lookups and pairing
+pub const MAX_REQUESTS: usize = 32;
+
+// How long a supervised loop that panicked waits before starting again
+const RESTART_PAUSE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Budget {
+    Handshake,
+    Connection,
+    Relay,
+    Send,
+    Request,
+}
+
+#[derive(Clone)]
+pub struct Tasks {
+    handshakes: Arc<Semaphore>,
+    connections: Arc<Semaphore>,
+    relays: Arc<Semaphore>,
+    sends: Arc<Semaphore>,
+    requests: Arc<Semaphore>,
+    // Connections to us by the address they came from
+    peers: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    shutdown: CancellationToken,
+}
+
+// A connection to us, counted against its address while it's open, and
+// holding a permit from the budget of the stage it's at
+pub struct Admission {
+    tasks: Tasks,
+    ip: IpAddr,
+    permit: OwnedSemaphorePermit,
+}
+
+impl Admission {
+    // Move on from the handshake to `budget`, waiting for room there while
+    // still holding the handshake permit. False once we're shutting down.
+    pub fn promote(&mut self, budget: Budget) -> bool {
+        let tasks = self.tasks.clone();
+        match tauri::async_runtime::block_on(async move { tasks.acquire(budget).await }) {
+            Some(permit) => {
+                self.permit = permit;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut peers = self.tasks.peers.lock();
+        if let Some(count) = peers.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                peers.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Tasks {
+            handshakes: Arc::new(Semaphore::new(MAX_HANDSHAKES)),
+            connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            relays: Arc::new(Semaphore::new(MAX_RELAYS)),
+            sends: Arc::new(Semaphore::new(MAX_SENDS)),
+            requests: Arc::new(Semaphore::new(MAX_REQUESTS)),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    fn budget(&self, budget: Budget) -> Arc<Semaphore> {
+        match budget {
+            Budget::Handshake => self.handshakes.clone(),
+            Budget::Connection => self.connections.clone(),
+            Budget::Relay => self.relays.clone(),
+            Budget::Send => self.sends.clone(),
+            Budget::Request => self.requests.clone(),
+        }
+    }
+
+    // Count a connection accepted from `ip` under a handshake `permit`, or
+    // None when that address has as many open as it may
+    pub fn admit(&self, ip: IpAddr, permit: OwnedSemaphorePermit) -> Option<Admission> {
+        let mut peers = self.peers.lock();
+        let count = peers.entry(ip).or_insert(0);
+        if *count >= MAX_PER_PEER {
+            return None;
+        }
+        *count += 1;
+        Some(Admission { tasks: self.clone(), ip, permit })
+    }
+
+    // Wait for room in `budget`, or None once we're shutting down
+    pub async fn acquire(&self, budget: Budget) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.budget(budget);
+        tokio::select! {
+            permit = semaphore.acquire_owned() => permit.ok(),
+            _ = self.shutdown.cancelled() => None,
+        }
+    }
+
+    // Run blocking work in the background once `budget` has room. Work
+    // still waiting when we shut down never starts.
+    pub fn spawn<F>(&self, budget: Budget, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let tasks = self.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Some(permit) = tasks.acquire(budget).await {
+                if let Err(e) = run_blocking(permit, work).await {
+                    eprintln!("Background work failed: {}", e);
+                }
+            }
+        });
+    }
+
+    // Run blocking work from an async command once `budget` has room, and
+    // wait for its result
+    pub async fn run<T, F>(&self, budget: Budget, work: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self.acquire(budget).await.ok_or(Error::Cancelled)?;
+        run_blocking(permit, work).await.map_err(|e| Error::Internal(e.to_string()))?
+    }
+
+    // Run a future until it finishes or we shut down, whichever is first
+    pub async fn until_shutdown<F: Future>(&self, work: F) -> Option<F::Output> {
+        tokio::select! {
+            output = work => Some(output),
+            _ = self.shutdown.cancelled() => None,
+        }
+    }
+
+    // A token cancelled when we shut down, which can also be cancelled by
+    // itself to stop just the work it was handed to
+    pub fn child_token(&self) -> CancellationToken {
+        self.shutdown.child_token()
+    }
+
+    // Stop accepting connections, end relays and discovery, and start no
+    // more queued work
+    pub fn shut_down(&self) {
+        self.shutdown.cancel();
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    // Wait up to `grace` for the work already running to finish, giving
+    // back whether it all did
+    pub async fn wait_idle(&self, grace: Duration) -> bool {
+        let idle = async {
+            let _handshakes = self.handshakes.acquire_many(MAX_HANDSHAKES as u32).await;
+            let _connections = self.connections.acquire_many(MAX_CONNECTIONS as u32).await;
+            let _relays = self.relays.acquire_many(MAX_RELAYS as u32).await;
+            let _sends = self.sends.acquire_many(MAX_SENDS as u32).await;
+            let _requests = self.requests.acquire_many(MAX_REQUESTS as u32).await;
+        };
+        tokio::time::timeout(grace, idle).await.is_ok()
+    }
+}
+
+// Run a long-lived loop on a thread of its own, named `name`, starting it
+// again if it panics. It's over once it returns.
+pub fn supervise<F>(name: &'static str, work: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let spawned = std::thread::Builder::new().name(name.to_string()).spawn(move || loop {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(&work)) {
+            Ok(()) => return,
+            Err(_) => {
+                eprintln!("The {} loop panicked, restarting it", name);
+                std::thread::sleep(RESTART_PAUSE);
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        eprintln!("Failed to start the {} loop: {}", name, e);
+    }
+}
+
+// Run blocking work on the blocking pool, holding `permit` until it's done
+pub fn run_blocking<T, F>(permit: OwnedSemaphorePermit, work: F) -> tauri::async_runtime::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(move || {
+        let _permit = permit;
+        work()
+    })
+}
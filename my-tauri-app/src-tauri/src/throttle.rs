@@ -0,0 +1,104 @@
+// Bandwidth limiting
+//
+// Chunk loops pass every chunk through a token bucket before it goes on
+// the wire. One bucket is shared by all transfers; a transfer with its own
+// limit uses a private bucket instead.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+struct TokenBucket {
+    // Bytes per second, 0 for unlimited
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket { rate, tokens: rate as f64, last_refill: Instant::now() }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    // Take `bytes` from the bucket and return how long to wait before
+    // they may be sent. The bucket holds at most one second's worth, so
+    // bursts stay short.
+    fn take(&mut self, bytes: usize) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+pub struct Throttle {
+    global: Mutex<TokenBucket>,
+    per_transfer: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn new(global_rate: u64) -> Self {
+        Throttle {
+            global: Mutex::new(TokenBucket::new(global_rate)),
+            per_transfer: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_global_limit(&self, rate: u64) {
+        self.global.lock().set_rate(rate);
+    }
+
+    // Give a transfer its own limit, or put it back on the global one
+    pub fn set_transfer_limit(&self, transfer_id: &str, rate: Option<u64>) {
+        let mut per_transfer = self.per_transfer.lock();
+        match rate {
+            Some(rate) => {
+                per_transfer.entry(transfer_id.to_string())
+                    .and_modify(|bucket| bucket.set_rate(rate))
+                    .or_insert_with(|| TokenBucket::new(rate));
+            }
+            None => {
+                per_transfer.remove(transfer_id);
+            }
+        }
+    }
+
+    // Forget a finished transfer's override
+    pub fn remove_transfer(&self, transfer_id: &str) {
+        self.per_transfer.lock().remove(transfer_id);
+    }
+
+    // Block until `bytes` of this transfer may pass
+    pub fn consume(&self, transfer_id: &str, bytes: usize) {
+        let wait = self.reserve(transfer_id, bytes);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    // Take `bytes` of this transfer's allowance and return how long to
+    // wait before they may pass, for callers that can't block
+    pub fn reserve(&self, transfer_id: &str, bytes: usize) -> Duration {
+        match self.per_transfer.lock().get_mut(transfer_id) {
+            Some(bucket) => bucket.take(bytes),
+            None => self.global.lock().take(bytes),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// The mesh as far as this device can see it
+//
+// Nodes are ourselves, the devices we see directly and every device a
+// route leads to. Edges are the links we measure ourselves, plus the ones
+// neighbours tell us about in their route advertisements: a device a
+// neighbour advertises at one hop is one it sees directly. Beyond that
+// advertisements only give distances, so devices further away are tied in
+// by the paths we hold to them rather than by edges.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::links::{link_cost, LinkMetrics, Links};
+use crate::pairing::TrustedDevice;
+use crate::routing::{device_name, RoutingTable};
+use crate::Device;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopologyNode {
+    pub id: String,
+    pub name: String,
+    pub is_self: bool,
+    // Seen directly over mDNS
+    pub direct: bool,
+    pub trusted: bool,
+    // Fewest hops we know of to it, 0 for ourselves
+    pub hops: u32,
+}
+
+// A direct link between two devices. Only our own links have measurements.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopologyEdge {
+    pub from: String,
+    pub to: String,
+    pub cost: f64,
+    pub metrics: Option<LinkMetrics>,
+    // Part of the best path to some device
+    pub active: bool,
+}
+
+// One way to reach a device, through one of our neighbours
+#[derive(Debug, Clone, Serialize)]
+pub struct TopologyPath {
+    pub destination: String,
+    pub next_hop: String,
+    pub hops: u32,
+    pub cost: f64,
+    // The path connections to the destination take first
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+    pub edges: Vec<TopologyEdge>,
+    pub paths: Vec<TopologyPath>,
+}
+
+pub fn build(
+    own_id: &str,
+    own_name: &str,
+    table: &RoutingTable,
+    devices: &HashMap<String, Device>,
+    links: &Links,
+    trusted: &HashMap<String, TrustedDevice>,
+) -> Topology {
+    let trusted_ids: HashSet<&str> = trusted.values().map(|d| d.device_id.as_str()).collect();
+
+    // A device is reached directly unless a route to it is cheaper, in
+    // which case the route's first hop carries its traffic
+    let mut paths = Vec::new();
+    let mut used_hops: HashSet<(String, String)> = HashSet::new();
+    for route in table.routes() {
+        let active = table.best(&route.destination).is_some_and(|best| best.next_hop == route.next_hop);
+        if active {
+            used_hops.insert((own_id.to_string(), route.next_hop.clone()));
+            if route.hops == 2 {
+                used_hops.insert((route.next_hop.clone(), route.destination.clone()));
+            }
+        }
+        paths.push(TopologyPath {
+            destination: route.destination.clone(),
+            next_hop: route.next_hop.clone(),
+            hops: route.hops,
+            cost: route.cost,
+            active,
+        });
+    }
+    for id in devices.keys().filter(|id| table.best(id).is_none()) {
+        used_hops.insert((own_id.to_string(), id.clone()));
+    }
+
+    let mut edges: Vec<TopologyEdge> = devices
+        .keys()
+        .map(|id| TopologyEdge {
+            from: own_id.to_string(),
+            to: id.clone(),
+            cost: link_cost(links, id),
+            metrics: links.get(id).cloned(),
+            active: used_hops.contains(&(own_id.to_string(), id.clone())),
+        })
+        .collect();
+    for (neighbour, seen) in table.neighbour_links() {
+        for (id, cost) in seen.iter().filter(|(id, _)| id.as_str() != own_id) {
+            edges.push(TopologyEdge {
+                from: neighbour.clone(),
+                to: id.clone(),
+                cost: *cost,
+                metrics: None,
+                active: used_hops.contains(&(neighbour.clone(), id.clone())),
+            });
+        }
+    }
+
+    let mut hops: HashMap<String, u32> = devices.keys().map(|id| (id.clone(), 1)).collect();
+    for edge in edges.iter().filter(|edge| edge.from != own_id) {
+        hops.entry(edge.to.clone()).or_insert(2);
+    }
+    for route in table.routes() {
+        let known = hops.entry(route.destination.clone()).or_insert(route.hops);
+        *known = (*known).min(route.hops);
+    }
+
+    let mut nodes = vec![TopologyNode {
+        id: own_id.to_string(),
+        name: own_name.to_string(),
+        is_self: true,
+        direct: false,
+        trusted: false,
+        hops: 0,
+    }];
+    nodes.extend(hops.into_iter().map(|(id, hops)| TopologyNode {
+        name: device_name(&id, devices, trusted),
+        direct: devices.contains_key(&id),
+        trusted: trusted_ids.contains(id.as_str()),
+        is_self: false,
+        hops,
+        id,
+    }));
+
+    Topology { nodes, edges, paths }
+}
+
+// Check a route the user pinned can be followed: every relay has to be a
+// device we know of, the first one we see directly, and each device has
+// to see the next, as far as we know its links
+pub fn check_route(topology: &Topology, via: &[String], destination: &str) -> Result<(), String> {
+    let name = |id: &str| {
+        topology.nodes.iter().find(|node| node.id == id).map_or(id.to_string(), |node| node.name.clone())
+    };
+    let own_id = topology.nodes.iter().find(|node| node.is_self).map_or("", |node| node.id.as_str());
+    let mut seen = HashSet::new();
+    for relay in via {
+        if relay == own_id || relay == destination || !seen.insert(relay.as_str()) {
+            return Err(format!("{} can't be a relay on this route", name(relay)));
+        }
+        if !topology.nodes.iter().any(|node| node.id == *relay) {
+            return Err(format!("Unknown relay: {}", relay));
+        }
+    }
+
+    let hops = std::iter::once(own_id)
+        .chain(via.iter().map(String::as_str))
+        .zip(via.iter().map(String::as_str).chain(std::iter::once(destination)));
+    for (from, to) in hops {
+        let known: Vec<&TopologyEdge> = topology.edges.iter().filter(|edge| edge.from == from).collect();
+        let linked = known.iter().any(|edge| edge.to == to);
+        // Our own links are all known, other devices' only if they're neighbours
+        if !linked && (from == own_id || !known.is_empty()) {
+            return Err(format!("{} can't reach {} directly", name(from), name(to)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,434 @@
+// Encrypted transport for every TCP connection
+//
+// Connections run a Noise_XX handshake with each device's long-term X25519
+// identity key, giving mutual authentication and forward secrecy. The
+// handshake also carries each end's protocol hello, so the version a
+// connection runs at is settled before anything else. After the
+// handshake every byte, headers included, travels inside Noise transport
+// messages. Control messages are additionally padded to fixed-size blocks
+// so their length doesn't reveal filenames or packet types.
+//
+// Nothing a peer says about the length of what's coming is taken on
+// trust: messages over the limit for their kind are refused before
+// anything is allocated for them, and what is allocated grows only as the
+// bytes actually arrive. A channel can be given a deadline, which every
+// read until then counts down towards, so a peer can't hold a connection
+// open by trickling a handshake or header in a byte at a time.
+//
+// A relayed connection is carried inside the channel of each hop it takes
+// rather than passed along as it is, so the bytes on one link can't be
+// matched up with those on the next, and an observer of any single link
+// sees nothing of where the connection is going. `tunnel` turns a channel
+// into a local socket for the inner connection to run over.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::protocol::{self, Agreed, Hello};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// Noise caps a single message at 64 KiB including the 16-byte tag
+const MAX_NOISE_MESSAGE: usize = 65535;
+const MAX_NOISE_PAYLOAD: usize = MAX_NOISE_MESSAGE - 16;
+
+// Control messages are padded up to a multiple of this size
+const PADDING_BLOCK: usize = 1024;
+
+// Largest message other than a padded header: a chunk of a file, sealed
+// and perhaps compressed, with room to spare
+const MAX_MESSAGE: usize = 4 * 1024 * 1024;
+
+// Largest padded header
+const MAX_PADDED: usize = crate::codec::MAX_HEADER_SIZE + 4 + PADDING_BLOCK;
+
+// Bytes of a tunnelled connection read at a time
+const TUNNEL_BUFFER: usize = 64 * 1024;
+
+// How long a peer we connect to has to answer its part of the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn noise_error(e: snow::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Noise error: {}", e))
+}
+
+// An authenticated, encrypted connection to a peer
+pub struct SecureChannel {
+    stream: TcpStream,
+    sending: Cipher,
+    receiving: Cipher,
+    peer_identity: PublicKey,
+    handshake_hash: Vec<u8>,
+    agreed: Agreed,
+    deadline: Option<Instant>,
+}
+
+// One direction of a channel's transport encryption. Both directions share
+// the session, but count their own nonces, so they can be used from
+// different threads.
+struct Cipher {
+    noise: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn seal(&mut self, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut message = vec![0u8; payload.len() + 16];
+        let len = self.noise.write_message(self.nonce, payload, &mut message).map_err(noise_error)?;
+        self.nonce += 1;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    fn open(&mut self, message: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut payload = vec![0u8; message.len()];
+        let len = self.noise.read_message(self.nonce, message, &mut payload).map_err(noise_error)?;
+        self.nonce += 1;
+        payload.truncate(len);
+        Ok(payload)
+    }
+}
+
+impl SecureChannel {
+    // Connect to a peer and run the initiator side of the handshake
+    pub fn connect(addr: &str, identity: &StaticSecret) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Self::connect_over(stream, identity)
+    }
+
+    // Run the initiator side of the handshake over a connection that is
+    // already open, such as a tunnel through a relay. The peer's part of
+    // the handshake has to be in within HANDSHAKE_TIMEOUT; what it says
+    // after that may take as long as it takes.
+    pub fn connect_over(stream: TcpStream, identity: &StaticSecret) -> std::io::Result<Self> {
+        let mut channel = Self::handshake(stream, identity, true, Some(Instant::now() + HANDSHAKE_TIMEOUT))?;
+        channel.clear_deadline()?;
+        Ok(channel)
+    }
+
+    // Run the responder side of the handshake on an accepted connection.
+    // The handshake, and whatever is read after it until the deadline is
+    // cleared, has to be in by `deadline`.
+    pub fn accept(stream: TcpStream, identity: &StaticSecret, deadline: Instant) -> std::io::Result<Self> {
+        Self::handshake(stream, identity, false, Some(deadline))
+    }
+
+    fn handshake(
+        mut stream: TcpStream,
+        identity: &StaticSecret,
+        initiator: bool,
+        deadline: Option<Instant>,
+    ) -> std::io::Result<Self> {
+        let private_key = identity.to_bytes();
+        // Devices in different network groups can't complete a handshake
+        let prologue = crate::group::prologue();
+        let builder = Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?)
+            .local_private_key(&private_key)
+            .prologue(&prologue);
+        let mut noise = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(noise_error)?;
+
+        // XX: -> e, <- e ee s es, -> s se. Hellos go in the last two,
+        // which are encrypted; the initiator checks the responder's before
+        // sending its own, so it can give up with the reason.
+        let ours = Hello::ours();
+        let agreed = if initiator {
+            write_handshake_message(&mut stream, &mut noise, &[])?;
+            let theirs = Hello::decode(&read_handshake_message(&mut stream, &mut noise, deadline)?)?;
+            let agreed = protocol::negotiate(&ours, &theirs)?;
+            write_handshake_message(&mut stream, &mut noise, &ours.encode())?;
+            agreed
+        } else {
+            read_handshake_message(&mut stream, &mut noise, deadline)?;
+            write_handshake_message(&mut stream, &mut noise, &ours.encode())?;
+            let theirs = Hello::decode(&read_handshake_message(&mut stream, &mut noise, deadline)?)?;
+            protocol::negotiate(&ours, &theirs)?
+        };
+
+        let remote_static = noise.get_remote_static()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Peer sent no static key"))?;
+        let handshake_hash = noise.get_handshake_hash().to_vec();
+        let noise = Arc::new(noise.into_stateless_transport_mode().map_err(noise_error)?);
+
+        Ok(SecureChannel {
+            stream,
+            sending: Cipher { noise: noise.clone(), nonce: 0 },
+            receiving: Cipher { noise, nonce: 0 },
+            peer_identity: PublicKey::from(remote_static),
+            handshake_hash,
+            agreed,
+            deadline,
+        })
+    }
+
+    // Let reads take as long as they take again, once what the deadline
+    // was for is in
+    pub fn clear_deadline(&mut self) -> std::io::Result<()> {
+        self.deadline = None;
+        self.stream.set_read_timeout(None)
+    }
+
+    // Protocol version the connection runs at
+    pub fn protocol_version(&self) -> u32 {
+        self.agreed.version
+    }
+
+    // Whether headers on this connection are encoded in binary
+    pub fn binary_headers(&self) -> bool {
+        self.agreed.binary_headers
+    }
+
+    // Whether the peer said it can do something, one of the protocol
+    // capability bits
+    pub fn peer_supports(&self, capability: u64) -> bool {
+        self.agreed.capabilities & capability != 0
+    }
+
+    // The peer's authenticated long-term identity key
+    pub fn peer_identity(&self) -> &PublicKey {
+        &self.peer_identity
+    }
+
+    // Transcript hash of the handshake, identical on both ends of an
+    // unintercepted connection
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    // A second handle to the socket, used to close it from another thread
+    pub fn try_clone_stream(&self) -> std::io::Result<TcpStream> {
+        self.stream.try_clone()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    // Send one message of any size; it is split across Noise messages,
+    // the first of which carries the total length
+    pub fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        send_message(&mut self.stream, &mut self.sending, data)
+    }
+
+    // Receive one message sent with `send`
+    pub fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        recv_message(&mut self.stream, &mut self.receiving, MAX_MESSAGE, self.deadline)
+    }
+
+    // Send a control message padded to a whole number of blocks
+    pub fn send_padded(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let padded_len = (data.len() + 4).div_ceil(PADDING_BLOCK) * PADDING_BLOCK;
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        padded.extend_from_slice(data);
+        padded.resize(padded_len, 0);
+        self.send(&padded)
+    }
+
+    // Receive a control message sent with `send_padded`
+    pub fn recv_padded(&mut self) -> std::io::Result<Vec<u8>> {
+        let padded = recv_message(&mut self.stream, &mut self.receiving, MAX_PADDED, self.deadline)?;
+        let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed padded message");
+        let len_bytes = padded.get(..4).ok_or_else(malformed)?;
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        padded.get(4..4 + len).map(|data| data.to_vec()).ok_or_else(malformed)
+    }
+}
+
+fn send_message(stream: &mut TcpStream, cipher: &mut Cipher, data: &[u8]) -> std::io::Result<()> {
+    write_raw(stream, &cipher.seal(&(data.len() as u32).to_be_bytes())?)?;
+    for piece in data.chunks(MAX_NOISE_PAYLOAD) {
+        write_raw(stream, &cipher.seal(piece)?)?;
+    }
+    Ok(())
+}
+
+fn recv_message(
+    stream: &mut TcpStream,
+    cipher: &mut Cipher,
+    max_len: usize,
+    deadline: Option<Instant>,
+) -> std::io::Result<Vec<u8>> {
+    let len_bytes = cipher.open(&read_raw(stream, deadline)?)?;
+    let len_bytes = <[u8; 4]>::try_from(len_bytes.as_slice())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed message length"))?;
+    let total = u32::from_be_bytes(len_bytes) as usize;
+    if total > max_len {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Message too large"));
+    }
+
+    let mut data = Vec::with_capacity(total.min(MAX_NOISE_PAYLOAD));
+    while data.len() < total {
+        let piece = cipher.open(&read_raw(stream, deadline)?)?;
+        if piece.is_empty() || data.len() + piece.len() > total {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed message"));
+        }
+        data.extend_from_slice(&piece);
+    }
+    Ok(data)
+}
+
+// Carry a connection inside a channel. Whatever is written to the socket
+// handed back comes out at the far end of the channel, and what the far
+// end sends can be read from it; closing it closes the channel. The
+// channel is given over to a task on the async runtime, which carries
+// both directions until both have closed.
+pub fn tunnel(channel: SecureChannel) -> std::io::Result<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let outer = TcpStream::connect(listener.local_addr()?)?;
+    let (inner, from) = listener.accept()?;
+    // Only our own socket may take the place of the connection
+    if from != outer.local_addr()? {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Unexpected local connection"));
+    }
+
+    let SecureChannel { stream, mut sending, mut receiving, .. } = channel;
+    inner.set_nonblocking(true)?;
+    stream.set_nonblocking(true)?;
+    tauri::async_runtime::spawn(async move {
+        let (Ok(inner), Ok(stream)) = (tokio::net::TcpStream::from_std(inner), tokio::net::TcpStream::from_std(stream)) else {
+            return;
+        };
+        let (mut inner_read, mut inner_write) = inner.into_split();
+        let (mut stream_read, mut stream_write) = stream.into_split();
+
+        let out = async {
+            let mut buffer = vec![0u8; TUNNEL_BUFFER];
+            loop {
+                match inner_read.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        if send_message_async(&mut stream_write, &mut sending, &buffer[..read]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = stream_write.shutdown().await;
+        };
+        let back = async {
+            while let Ok(data) = recv_message_async(&mut stream_read, &mut receiving, MAX_MESSAGE).await {
+                if inner_write.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = inner_write.shutdown().await;
+        };
+        tokio::join!(out, back);
+    });
+
+    Ok(outer)
+}
+
+// send_message and recv_message for a tunnel's halves of the channel
+async fn send_message_async(stream: &mut OwnedWriteHalf, cipher: &mut Cipher, data: &[u8]) -> std::io::Result<()> {
+    write_raw_async(stream, &cipher.seal(&(data.len() as u32).to_be_bytes())?).await?;
+    for piece in data.chunks(MAX_NOISE_PAYLOAD) {
+        write_raw_async(stream, &cipher.seal(piece)?).await?;
+    }
+    Ok(())
+}
+
+async fn recv_message_async(stream: &mut OwnedReadHalf, cipher: &mut Cipher, max_len: usize) -> std::io::Result<Vec<u8>> {
+    let len_bytes = cipher.open(&read_raw_async(stream).await?)?;
+    let len_bytes = <[u8; 4]>::try_from(len_bytes.as_slice())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed message length"))?;
+    let total = u32::from_be_bytes(len_bytes) as usize;
+    if total > max_len {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Message too large"));
+    }
+
+    let mut data = Vec::with_capacity(total.min(MAX_NOISE_PAYLOAD));
+    while data.len() < total {
+        let piece = cipher.open(&read_raw_async(stream).await?)?;
+        if piece.is_empty() || data.len() + piece.len() > total {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed message"));
+        }
+        data.extend_from_slice(&piece);
+    }
+    Ok(data)
+}
+
+fn write_handshake_message(stream: &mut TcpStream, noise: &mut HandshakeState, payload: &[u8]) -> std::io::Result<()> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = noise.write_message(payload, &mut message).map_err(noise_error)?;
+    write_raw(stream, &message[..len])
+}
+
+fn read_handshake_message(
+    stream: &mut TcpStream,
+    noise: &mut HandshakeState,
+    deadline: Option<Instant>,
+) -> std::io::Result<Vec<u8>> {
+    let message = read_raw(stream, deadline)?;
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = noise.read_message(&message, &mut payload).map_err(noise_error)?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+// Noise messages go on the wire with a two-byte length prefix
+fn write_raw(stream: &mut TcpStream, message: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message)
+}
+
+async fn write_raw_async(stream: &mut OwnedWriteHalf, message: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(message.len() as u16).to_be_bytes()).await?;
+    stream.write_all(message).await
+}
+
+async fn read_raw_async(stream: &mut OwnedReadHalf) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+fn read_raw(stream: &mut TcpStream, deadline: Option<Instant>) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    read_exact_by(stream, &mut len_buf, deadline)?;
+    let mut message = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    read_exact_by(stream, &mut message, deadline)?;
+    Ok(message)
+}
+
+// Fill `buf`, giving up once `deadline` passes however much has come in,
+// rather than waiting afresh for every byte
+fn read_exact_by(stream: &mut TcpStream, buf: &mut [u8], deadline: Option<Instant>) -> std::io::Result<()> {
+    let Some(deadline) = deadline else {
+        return stream.read_exact(buf);
+    };
+    let mut filled = 0;
+    while filled < buf.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Peer too slow"));
+        }
+        stream.set_read_timeout(Some(left))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+// The tray icon, and running on without a window
+//
+// With `run_in_background` on, closing the window only hides it: the file
+// server and discovery carry on, and the app is there in the tray to bring
+// the window back, see what came in lately, pause receiving or quit. The
+// menu is built afresh whenever a transfer finishes or receiving is paused,
+// so it never shows anything stale.
+
+use tauri::menu::{CheckMenuItem, IsMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Manager, Wry};
+
+use crate::{received_file_path, settings, AppState, FileTransfer};
+
+const TRAY: &str = "main";
+// Finished transfers listed in the menu
+const RECENT_TRANSFERS: usize = 5;
+
+const SHOW: &str = "show";
+const PAUSE: &str = "pause";
+const QUIT: &str = "quit";
+// Followed by the transfer's id
+const TRANSFER: &str = "transfer:";
+
+pub fn create(app: &AppHandle) -> tauri::Result<()> {
+    let mut tray = TrayIconBuilder::with_id(TRAY)
+        .menu(&menu(app)?)
+        .tooltip("Reality")
+        .show_menu_on_left_click(false)
+        .on_menu_event(on_menu_event)
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click { button: MouseButton::Left, button_state: MouseButtonState::Up, .. } = event {
+                show_window(tray.app_handle());
+            }
+        });
+    if let Some(icon) = app.default_window_icon() {
+        tray = tray.icon(icon.clone());
+    }
+    tray.build(app)?;
+    Ok(())
+}
+
+// Rebuild the menu from the current transfers and settings
+pub fn refresh(app: &AppHandle) {
+    let Some(tray) = app.tray_by_id(TRAY) else {
+        return;
+    };
+    match menu(app) {
+        Ok(menu) => {
+            let _ = tray.set_menu(Some(menu));
+        }
+        Err(e) => eprintln!("Failed to update the tray menu: {}", e),
+    }
+}
+
+fn menu(app: &AppHandle) -> tauri::Result<Menu<Wry>> {
+    let state = app.state::<AppState>();
+    let recent: Vec<FileTransfer> = state.transfers.lock()
+        .iter()
+        .rev()
+        .filter(|t| t.finished_at.is_some())
+        .take(RECENT_TRANSFERS)
+        .cloned()
+        .collect();
+    let mut transfers = Vec::new();
+    for transfer in &recent {
+        let label = format!("{} — {}", transfer.filename, transfer.status);
+        let opens = transfer.saved_path.is_some();
+        transfers.push(MenuItem::with_id(app, format!("{}{}", TRANSFER, transfer.id), label, opens, None::<&str>)?);
+    }
+    if transfers.is_empty() {
+        transfers.push(MenuItem::with_id(app, "none", "Nothing yet", false, None::<&str>)?);
+    }
+    let transfers: Vec<&dyn IsMenuItem<Wry>> = transfers.iter().map(|item| item as &dyn IsMenuItem<Wry>).collect();
+
+    let paused = state.settings.lock().receiving_paused;
+    Menu::with_items(app, &[
+        &MenuItem::with_id(app, SHOW, "Open Reality", true, None::<&str>)?,
+        &Submenu::with_id_and_items(app, "recent", "Recent transfers", true, &transfers)?,
+        &CheckMenuItem::with_id(app, PAUSE, "Pause receiving", true, paused, None::<&str>)?,
+        &PredefinedMenuItem::separator(app)?,
+        &MenuItem::with_id(app, QUIT, "Quit", true, None::<&str>)?,
+    ])
+}
+
+fn on_menu_event(app: &AppHandle, event: MenuEvent) {
+    match event.id().as_ref() {
+        SHOW => show_window(app),
+        PAUSE => {
+            let state = app.state::<AppState>();
+            let mut settings = state.settings.lock();
+            settings.receiving_paused = !settings.receiving_paused;
+            if let Err(e) = settings::save_settings(&settings) {
+                eprintln!("Failed to save settings: {}", e);
+            }
+            drop(settings);
+            refresh(app);
+        }
+        QUIT => app.exit(0),
+        id => {
+            // A received file opens with its usual application
+            if let Some(transfer_id) = id.strip_prefix(TRANSFER) {
+                let opened = received_file_path(transfer_id, &app.state::<AppState>())
+                    .and_then(|path| open::that_detached(path).map_err(Into::into));
+                if let Err(e) = opened {
+                    eprintln!("Failed to open received file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+pub fn show_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
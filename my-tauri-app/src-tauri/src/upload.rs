@@ -0,0 +1,473 @@
+// Receiving files from a phone or any other device with a browser
+//
+// Upload mode starts a small HTTP server on a random port and gives back a
+// link to it, with a QR code, that has a one-off token in its path. The
+// link opens a form for picking files, which are posted back as an
+// ordinary multipart upload and read straight off the request as it comes
+// in, without holding any file in memory.
+//
+// Uploads go through the same steps as files sent by peers. A browser is
+// never a paired device, so each file is asked about before anything of it
+// touches the disk, and the user's size limits and quota apply. Accepted
+// files are written to a partial file that only takes its real name once
+// all of it is in, and every file shows up in the transfers list, whether
+// it was saved, declined or cut off.
+//
+// The token is good for one upload, of as many files as the form holds,
+// after which the page closes. Unused, it closes after PAGE_LIFETIME.
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tauri::Emitter;
+use tiny_http::{Method, Request, Response, Server};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::queue::Direction;
+use crate::{cancel, events, http, quota};
+use crate::{FileTransfer, PeerContext, TransferRequest, APPROVAL_TIMEOUT, THIS_DEVICE};
+
+const PAGE: &str = include_str!("upload_page.html");
+
+// How long the page waits for an upload
+const PAGE_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+// How often the server looks up from waiting for requests
+const SERVER_POLL: Duration = Duration::from_secs(1);
+
+// Longest line, and most lines, in the headers of one part of an upload
+const MAX_HEADER_LINE: usize = 8 * 1024;
+const MAX_HEADER_LINES: usize = 16;
+
+// How much of an upload is read at a time
+const READ_SIZE: usize = 64 * 1024;
+
+// How much of it is gathered before going to disk
+const WRITE_SIZE: usize = 1024 * 1024;
+
+// The upload page as shown to the user
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadPage {
+    pub id: String,
+    pub urls: Vec<String>,
+    pub qr_svg: String,
+    // Unix seconds after which the page closes if nothing was uploaded
+    pub expires_at: i64,
+}
+
+// Sent as `upload://finished` once the page has closed
+#[derive(Debug, Clone, Serialize)]
+struct UploadOutcome {
+    id: String,
+    saved: usize,
+}
+
+// The page while it's open
+pub struct OpenPage {
+    pub page: UploadPage,
+    stop: Arc<AtomicBool>,
+}
+
+impl OpenPage {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub type UploadSlot = Arc<Mutex<Option<OpenPage>>>;
+
+// Open an upload page, closing any that was open
+pub fn open(ctx: PeerContext, slot: UploadSlot) -> Result<UploadPage, String> {
+    let http::Page { server, path: page_path, urls, qr_svg } = http::serve_page("/u")?;
+    let page = UploadPage {
+        id: Uuid::new_v4().to_string(),
+        urls,
+        qr_svg,
+        expires_at: (chrono::Utc::now() + PAGE_LIFETIME).timestamp(),
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = slot.lock().replace(OpenPage { page: page.clone(), stop: stop.clone() }) {
+        previous.stop();
+    }
+
+    let id = page.id.clone();
+    std::thread::spawn(move || {
+        let saved = serve(&server, &page_path, &stop, &ctx);
+        let mut open = slot.lock();
+        if open.as_ref().is_some_and(|open| open.page.id == id) {
+            *open = None;
+        }
+        drop(open);
+        let _ = ctx.app.emit("upload://finished", UploadOutcome { id, saved });
+    });
+    Ok(page)
+}
+
+// Answer requests until an upload has been taken in, the page expires or
+// it is closed. Gives back how many files were saved.
+fn serve(server: &Server, page_path: &str, stop: &AtomicBool, ctx: &PeerContext) -> usize {
+    let expires = Instant::now() + PAGE_LIFETIME;
+    while !stop.load(Ordering::SeqCst) && Instant::now() < expires {
+        let request = match server.recv_timeout(SERVER_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(_) => break,
+        };
+        if request.url() != page_path {
+            let _ = request.respond(Response::from_string("Not found").with_status_code(404));
+            continue;
+        }
+        match request.method() {
+            Method::Get => {
+                let html = http::header("Content-Type", "text/html; charset=utf-8");
+                let _ = request.respond(Response::from_string(PAGE).with_header(html));
+            }
+            // The token is spent on the first upload, whatever comes of it
+            Method::Post => return receive_upload(request, ctx),
+            _ => {
+                let _ = request.respond(Response::from_string("Method not allowed").with_status_code(405));
+            }
+        }
+    }
+    0
+}
+
+// Take in every file of an upload, answering with how it went
+fn receive_upload(mut request: Request, ctx: &PeerContext) -> usize {
+    let from = format!("Browser ({})", request.remote_addr().map_or("unknown".to_string(), |addr| addr.ip().to_string()));
+    let boundary = request.headers()
+        .iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .and_then(|header| boundary(header.value.as_str()));
+    let Some(boundary) = boundary else {
+        let _ = request.respond(Response::from_string("Expected a multipart upload").with_status_code(400));
+        return 0;
+    };
+    let length = request.body_length().unwrap_or(0) as u64;
+
+    let mut parts = Multipart::new(request.as_reader(), &boundary);
+    let (mut saved, mut refused) = (0, 0);
+    let result = (|| -> std::io::Result<()> {
+        parts.skip_preamble()?;
+        while let Some(filename) = parts.next_part()? {
+            match filename {
+                Some(filename) => {
+                    if receive_file(&filename, &mut parts, length, &from, ctx)? {
+                        saved += 1;
+                    } else {
+                        refused += 1;
+                    }
+                }
+                // A form field rather than a file, or no file picked
+                None => parts.copy_part(&mut std::io::sink())?,
+            }
+        }
+        Ok(())
+    })();
+
+    let reply = match result {
+        Ok(()) if refused == 0 => format!("Sent {} file(s)", saved),
+        Ok(()) => format!("Sent {} file(s); {} weren't accepted", saved, refused),
+        Err(e) => {
+            eprintln!("Upload from {} failed: {}", from, e);
+            format!("The upload was cut off after {} file(s)", saved)
+        }
+    };
+    let _ = request.respond(Response::from_string(reply));
+    saved
+}
+
+// Receive one file of an upload, or skip past it if it isn't accepted.
+// Gives back whether it was saved; errors mean the upload itself broke.
+fn receive_file<R: Read>(
+    filename: &str,
+    parts: &mut Multipart<R>,
+    upload_size: u64,
+    from: &str,
+    ctx: &PeerContext,
+) -> std::io::Result<bool> {
+    // Parts carry no size, so the whole upload's stands in until the file
+    // is in
+    let transfer_id = Uuid::new_v4().to_string();
+    let transfer = FileTransfer {
+        id: transfer_id.clone(),
+        filename: filename.to_string(),
+        size: upload_size,
+        progress: 0,
+        status: "Awaiting approval ⏳".to_string(),
+        from_device: from.to_string(),
+        to_device: THIS_DEVICE.to_string(),
+        encrypted: false,
+        batch_id: None,
+        speed_bps: 0,
+        eta_seconds: None,
+        started_at: None,
+        compression_ratio: None,
+        file_hash: None,
+        finished_at: None,
+        saved_path: None,
+        error: None,
+    };
+    ctx.transfers.lock().push(transfer.clone());
+    events::emit_record(&ctx.app, events::STARTED, &transfer);
+
+    let refuse = |parts: &mut Multipart<R>, status: &str, error: Error| {
+        crate::fail_transfer(ctx, &transfer_id, status, error);
+        parts.copy_part(&mut std::io::sink()).map(|_| false)
+    };
+
+    let Some(download_path) = crate::download_path_for(filename) else {
+        return refuse(parts, "Rejected 🚫 (Unsafe file path)", Error::InvalidInput("Unsafe file path".to_string()));
+    };
+    let limits = {
+        let settings = ctx.settings.lock();
+        let usage = ctx.daily_usage.lock();
+        quota::check_incoming(&download_path, upload_size, upload_size, &usage, &settings)
+    };
+    if let Err(e) = limits {
+        return refuse(parts, &format!("Rejected 🚫 ({})", e), e);
+    }
+
+    // Ask the user before anything touches the disk; the browser waits
+    let (tx, rx) = mpsc::channel();
+    ctx.pending_approvals.lock().insert(transfer_id.clone(), tx);
+    let _ = ctx.app.emit("transfer://request", TransferRequest {
+        transfer_id: transfer_id.clone(),
+        filename: filename.to_string(),
+        size: upload_size,
+        from_device: from.to_string(),
+    });
+    let accepted = rx.recv_timeout(APPROVAL_TIMEOUT).unwrap_or(false);
+    ctx.pending_approvals.lock().remove(&transfer_id);
+    if !accepted {
+        return refuse(parts, "Rejected 🚫", Error::Rejected("Declined".to_string()));
+    }
+
+    let token = cancel::register(&ctx.cancel_tokens, &transfer_id);
+    crate::set_transfer_status(ctx, &transfer_id, "Queued ⏳");
+    let slot = ctx.queue.acquire(&transfer_id, Direction::Incoming, filename, || token.is_cancelled());
+    let result = match slot {
+        Some(_slot) => save_file(&download_path, parts, &transfer_id, &token, ctx),
+        // Cancelled while queued; the browser goes on sending, so skip it
+        None => parts.copy_part(&mut std::io::sink()).map(|_| None),
+    };
+    cancel::unregister(&ctx.cancel_tokens, &transfer_id);
+
+    match result {
+        Ok(Some((size, hash))) => {
+            ctx.daily_usage.lock().record(size);
+            if let Some(t) = ctx.transfers.lock().iter_mut().find(|t| t.id == transfer_id) {
+                t.size = size;
+                t.progress = size;
+                t.file_hash = Some(hash);
+                t.saved_path = Some(download_path.to_string_lossy().into_owned());
+            }
+            crate::complete_transfer(ctx, &transfer_id, "Completed ✅");
+            Ok(true)
+        }
+        Ok(None) => {
+            crate::fail_transfer(ctx, &transfer_id, "Cancelled ⛔", Error::Cancelled);
+            Ok(false)
+        }
+        Err(e) => {
+            crate::fail_transfer(ctx, &transfer_id, "Failed ❌ (Upload interrupted)", Error::PeerOffline(e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+// Write the current part to a partial file and give it its real name once
+// it's all in. Gives back its size and hash, or None if it was cancelled,
+// having read past the rest of it either way.
+fn save_file<R: Read>(
+    download_path: &Path,
+    parts: &mut Multipart<R>,
+    transfer_id: &str,
+    token: &cancel::CancelToken,
+    ctx: &PeerContext,
+) -> std::io::Result<Option<(u64, String)>> {
+    if let Some(parent) = download_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let part_path = crate::resume::part_path(download_path);
+    crate::set_transfer_status(ctx, transfer_id, "Receiving 📥");
+    let mut writer = ProgressWriter {
+        file: BufWriter::with_capacity(WRITE_SIZE, std::fs::File::create(&part_path)?),
+        hasher: blake3::Hasher::new(),
+        written: 0,
+        tracker: crate::start_progress(ctx, transfer_id, 0),
+        token,
+    };
+    let copied = parts.copy_part(&mut writer);
+    let ProgressWriter { file, hasher, written, .. } = writer;
+    let copied = copied.and_then(|_| file.into_inner().map_err(|e| e.into_error())?.sync_all());
+    if token.is_cancelled() || copied.is_err() {
+        let _ = std::fs::remove_file(&part_path);
+        return copied.map(|_| None);
+    }
+    crate::resume::commit(&part_path, download_path)?;
+    Ok(Some((written, hasher.finalize().to_hex().to_string())))
+}
+
+// Writes an uploaded file to disk, hashing it and keeping its transfer's
+// progress up to date. Once cancelled it takes in bytes without keeping
+// them, so the rest of the upload can still be read.
+struct ProgressWriter<'a> {
+    file: BufWriter<std::fs::File>,
+    hasher: blake3::Hasher,
+    written: u64,
+    tracker: crate::progress::ProgressTracker,
+    token: &'a cancel::CancelToken,
+}
+
+impl Write for ProgressWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        if self.token.is_cancelled() {
+            return Ok(bytes.len());
+        }
+        self.file.write_all(bytes)?;
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        self.tracker.record(self.written);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// The boundary in a multipart Content-Type
+fn boundary(content_type: &str) -> Option<String> {
+    let (kind, params) = content_type.split_once(';')?;
+    if !kind.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+// The file name in a part's Content-Disposition, without any folders a
+// browser put in front of it
+fn part_filename(disposition: &str) -> Option<String> {
+    let start = disposition.find("filename=\"")? + "filename=\"".len();
+    let end = disposition[start..].find('"')? + start;
+    let name = disposition[start..end].rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+fn malformed(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed upload: {}", what))
+}
+
+// Reads the parts of a multipart body one after another, as it arrives
+struct Multipart<R> {
+    reader: BufReader<R>,
+    // What ends each part: a line break and the boundary
+    delimiter: Vec<u8>,
+    // Bytes read but not yet handed on
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader: BufReader::with_capacity(READ_SIZE, reader),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first boundary comes without a line break before it
+            pending: b"\r\n".to_vec(),
+            finished: false,
+        }
+    }
+
+    // Read more of the body into `pending`; false once there's no more
+    fn fill(&mut self) -> std::io::Result<bool> {
+        let available = self.reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        let read = available.len();
+        self.pending.extend_from_slice(available);
+        self.reader.consume(read);
+        Ok(true)
+    }
+
+    fn skip_preamble(&mut self) -> std::io::Result<()> {
+        self.copy_part(&mut std::io::sink())
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        loop {
+            if let Some(end) = self.pending.windows(2).position(|pair| pair == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.pending[..end]).into_owned();
+                self.pending.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.pending.len() > MAX_HEADER_LINE {
+                return Err(malformed("header line too long"));
+            }
+            if !self.fill()? {
+                return Err(malformed("ends in a header"));
+            }
+        }
+    }
+
+    // Read the headers of the next part, giving back its file name if it
+    // is a file, or None once there are no more parts
+    fn next_part(&mut self) -> std::io::Result<Option<Option<String>>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut filename = None;
+        for _ in 0..MAX_HEADER_LINES {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(Some(filename));
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                    filename = part_filename(value);
+                }
+            }
+        }
+        Err(malformed("too many headers"))
+    }
+
+    // Copy the body of the current part to `out`, up to the next boundary.
+    // A boundary is followed by `--` after the last part, and by a line
+    // break before the next.
+    fn copy_part(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        let marker_len = self.delimiter.len() + 2;
+        loop {
+            let marker = self.pending.windows(marker_len).position(|window| {
+                window.starts_with(&self.delimiter) && (window.ends_with(b"--") || window.ends_with(b"\r\n"))
+            });
+            if let Some(end) = marker {
+                out.write_all(&self.pending[..end])?;
+                self.finished = self.pending[..end + marker_len].ends_with(b"--");
+                self.pending.drain(..end + marker_len);
+                return Ok(());
+            }
+            // Keep back what could be the start of a boundary
+            let keep = marker_len - 1;
+            if self.pending.len() > keep {
+                let ready = self.pending.len() - keep;
+                out.write_all(&self.pending[..ready])?;
+                self.pending.drain(..ready);
+            }
+            if !self.fill()? {
+                return Err(malformed("ends inside a part"));
+            }
+        }
+    }
+}
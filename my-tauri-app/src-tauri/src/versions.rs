@@ -0,0 +1,130 @@
+// Earlier versions of files that other devices overwrote
+//
+// Before a received or synced file takes the place of one that's already
+// there, or a sync deletes one, the old file is kept in a `.versions`
+// folder next to it, under its name and the time it was replaced:
+// `report.pdf` becomes `.versions/report~20260101-120000.pdf`. The old
+// file is hard linked there where the file system allows, so it costs no
+// copy and the file never goes missing while it's replaced. The newest
+// `versions_kept` of each file are kept.
+//
+// `.versions` folders are never sent or synced.
+
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const VERSIONS_DIR: &str = ".versions";
+
+// How versions are stamped with the time they were replaced
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+const STAMP_LEN: usize = 15;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileVersion {
+    // The version's name in `.versions`
+    pub id: String,
+    pub size: u64,
+    pub replaced_at: String,
+}
+
+// A file's name, split before its extension
+fn split_name(path: &Path) -> Option<(String, String)> {
+    let name = path.file_name()?.to_str()?;
+    Some(match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem.to_string(), format!(".{}", extension)),
+        _ => (name.to_string(), String::new()),
+    })
+}
+
+fn versions_dir(path: &Path) -> Option<PathBuf> {
+    path.parent().map(|dir| dir.join(VERSIONS_DIR))
+}
+
+// Keep what's at `path` as its newest version, if there's a file there,
+// and drop all but the newest `keep`. With `keep` at 0 nothing is kept.
+pub fn save(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 || !std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file()) {
+        return Ok(());
+    }
+    let (Some(dir), Some((stem, extension))) = (versions_dir(path), split_name(path)) else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(&dir)?;
+
+    // Two versions in the same second get numbered
+    let stamp = chrono::Local::now().format(STAMP_FORMAT).to_string();
+    let version = (1..)
+        .map(|n| {
+            let copy = if n == 1 { String::new() } else { format!("-{}", n) };
+            dir.join(format!("{}~{}{}{}", stem, stamp, copy, extension))
+        })
+        .find(|version| !version.exists())
+        .unwrap();
+    if std::fs::hard_link(path, &version).is_err() {
+        std::fs::copy(path, &version)?;
+    }
+
+    for old in list(path)?.into_iter().skip(keep) {
+        let _ = std::fs::remove_file(dir.join(old.id));
+    }
+    Ok(())
+}
+
+// The versions kept of the file at `path`, newest first
+pub fn list(path: &Path) -> io::Result<Vec<FileVersion>> {
+    let (Some(dir), Some((stem, extension))) = (versions_dir(path), split_name(path)) else {
+        return Ok(Vec::new());
+    };
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let prefix = format!("{}~", stem);
+    let mut versions = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Ok(id) = entry.file_name().into_string() else { continue };
+        let Some(stamp) = id.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(&extension)) else {
+            continue;
+        };
+        // The stamp, then a number if several were saved in its second
+        let Some((time, number)) = stamp.get(..STAMP_LEN).zip(stamp.get(STAMP_LEN..)) else {
+            continue;
+        };
+        let number = match number.strip_prefix('-') {
+            Some(number) => number.parse::<u32>().ok(),
+            None => number.is_empty().then_some(1),
+        };
+        let replaced_at = chrono::NaiveDateTime::parse_from_str(time, STAMP_FORMAT)
+            .ok()
+            .and_then(|time| time.and_local_timezone(chrono::Local).earliest());
+        let (Some(replaced_at), Some(number), Ok(metadata)) = (replaced_at, number, entry.metadata()) else {
+            continue;
+        };
+        let version = FileVersion { id, size: metadata.len(), replaced_at: replaced_at.to_rfc3339() };
+        versions.push(((replaced_at, number), version));
+    }
+    versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+    Ok(versions.into_iter().map(|(_, version)| version).collect())
+}
+
+// Put a kept version back in place of the file at `path`, keeping what's
+// there now as a version in turn
+pub fn restore(path: &Path, id: &str, keep: usize) -> io::Result<()> {
+    if !list(path)?.iter().any(|version| version.id == id) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Version not found"));
+    }
+    let version = versions_dir(path)
+        .map(|dir| dir.join(id))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Version not found"))?;
+
+    // Copied in beside the file first, so it's replaced in one step, and
+    // before saving the file, which may drop the version being restored
+    let temp = crate::resume::part_path(path);
+    std::fs::copy(&version, &temp)?;
+    save(path, keep.max(1))?;
+    std::fs::rename(&temp, path)
+}
@@ -0,0 +1,257 @@
+// Transfers over the internet, through a rendezvous server
+//
+// Devices that want to be reachable from outside the LAN keep a control
+// connection open to a server the user chose, registered under their
+// identity key. To reach a paired device we open a session: a second
+// connection to the server from a port we can bind again. The other device
+// is told about it over its control connection and opens one too, and the
+// server hands each of us the public address the other's NAT gave it. Both
+// ends then connect to each other from the port the session went out on,
+// at the same time, which most NATs take for an answer to their own
+// connection and let through. If that fails, both ask the server to relay
+// and the session connection itself carries the bytes.
+//
+// Either way the Noise handshake runs end to end over what we get, so the
+// server only ever passes on ciphertext, and a device that isn't who we
+// asked for is caught there. We only take sessions opened by paired
+// devices.
+//
+// The server speaks JSON, one message per line, tagged by `type`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use ed25519_dalek::Signer;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use uuid::Uuid;
+use x25519_dalek::PublicKey;
+
+use crate::tasks::Budget;
+use crate::transport::SecureChannel;
+use crate::{encode_public_key, handle_incoming_packet, signing, PeerContext};
+
+// How long to wait before reconnecting to the server
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often the control connection wakes to check it's still wanted
+const CONTROL_POLL: Duration = Duration::from_secs(30);
+
+// How long the server has to pair a session up
+const SESSION_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long to keep trying to connect straight to the other device, and
+// how long each attempt gets
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+const PUNCH_ATTEMPT: Duration = Duration::from_secs(1);
+const PUNCH_RETRY: Duration = Duration::from_millis(200);
+
+// Longest line we'll take from the server
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    // Us, on the control connection, signed with our signing key
+    Register { public_key: String, signing_key: String, timestamp: i64, signature: String },
+    // The server: our address as it sees it
+    Registered { endpoint: String },
+    // The server: a device wants a session with us
+    Incoming { session: String, from: String },
+    // Us, opening a session with the device with identity key `target`
+    Connect { session: String, target: String },
+    // Us, taking part in a session another device opened
+    Join { session: String },
+    // The server: the other end of the session as it sees it
+    Peer { endpoint: String },
+    // The server: the device asked for isn't registered
+    Offline,
+    // Us, when we couldn't connect straight to the other end
+    Relay,
+    // The server: from here on the session is relayed
+    Relaying,
+}
+
+fn write_message(stream: &mut TcpStream, message: &Message) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+// Read one line a byte at a time, so nothing after it is taken off a
+// session connection that may go on to carry the transfer
+fn read_message(stream: &mut TcpStream) -> std::io::Result<Message> {
+    read_message_into(stream, &mut Vec::new())
+}
+
+// Read one message, gathering it in `line`. A read that times out leaves
+// what came of the message there, for the next call to go on from.
+fn read_message_into(stream: &mut TcpStream, line: &mut Vec<u8>) -> std::io::Result<Message> {
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Rendezvous message too long"));
+        }
+        line.push(byte[0]);
+    }
+    let message = serde_json::from_slice(line);
+    line.clear();
+    Ok(message?)
+}
+
+fn unexpected(message: Message) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected rendezvous message: {:?}", message))
+}
+
+// A TCP socket bound so that other sockets can bind the same address
+fn reusable_socket(addr: &SocketAddr) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    Ok(socket)
+}
+
+fn resolve(server: &str) -> std::io::Result<SocketAddr> {
+    server.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Rendezvous server not found"))
+}
+
+// Connect to the server from a port we can connect from again, returning
+// the connection and that port's address
+fn session_connection(server: &str) -> std::io::Result<(TcpStream, SocketAddr)> {
+    let server = resolve(server)?;
+    let socket = reusable_socket(&server)?;
+    let any: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    socket.bind(&SockAddr::from(any))?;
+    socket.connect_timeout(&SockAddr::from(server), SESSION_TIMEOUT)?;
+    let stream = TcpStream::from(socket);
+    let local = stream.local_addr()?;
+    stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+    Ok((stream, local))
+}
+
+// Keep connecting from `local` to `peer` while the other end does the
+// same towards us, until one of the attempts gets through
+fn punch(local: SocketAddr, peer: SocketAddr) -> std::io::Result<TcpStream> {
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+    loop {
+        let socket = reusable_socket(&peer)?;
+        socket.bind(&SockAddr::from(local))?;
+        match socket.connect_timeout(&SockAddr::from(peer), PUNCH_ATTEMPT) {
+            Ok(()) => return Ok(TcpStream::from(socket)),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => thread::sleep(PUNCH_RETRY),
+        }
+    }
+}
+
+// Once the server has paired the session up, get a connection to the
+// other end: straight to it if we can, through the server if not
+fn establish(mut session: TcpStream, local: SocketAddr) -> std::io::Result<TcpStream> {
+    let endpoint = match read_message(&mut session)? {
+        Message::Peer { endpoint } => endpoint,
+        Message::Offline => {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "Device is not online"));
+        }
+        other => return Err(unexpected(other)),
+    };
+    let peer: SocketAddr = endpoint.parse()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed peer address"))?;
+
+    let stream = match punch(local, peer) {
+        Ok(direct) => direct,
+        Err(e) => {
+            eprintln!("Couldn't connect straight to {} ({}), relaying through the server", peer, e);
+            write_message(&mut session, &Message::Relay)?;
+            match read_message(&mut session)? {
+                Message::Relaying => session,
+                other => return Err(unexpected(other)),
+            }
+        }
+    };
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+// Reach the device with identity key `recipient` through the server
+pub fn connect(server: &str, recipient: &PublicKey, ctx: &PeerContext) -> std::io::Result<SecureChannel> {
+    let (mut session, local) = session_connection(server)?;
+    write_message(&mut session, &Message::Connect {
+        session: Uuid::new_v4().to_string(),
+        target: encode_public_key(recipient),
+    })?;
+    let stream = establish(session, local)?;
+
+    let channel = SecureChannel::connect_over(stream, &ctx.identity_key)?;
+    if channel.peer_identity() != recipient {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Reached the wrong device"));
+    }
+    Ok(channel)
+}
+
+// Take part in a session a paired device opened, handling the connection
+// it leads to as any other
+fn join(server: &str, session: String, ctx: PeerContext) -> std::io::Result<()> {
+    let (mut stream, local) = session_connection(server)?;
+    write_message(&mut stream, &Message::Join { session })?;
+    let stream = establish(stream, local)?;
+    handle_incoming_packet(stream, ctx, None)
+}
+
+// Register with the server and take sessions from paired devices until
+// the connection drops or `wanted` says the server is no longer ours
+pub fn serve(server: &str, wanted: &dyn Fn() -> bool, ctx: &PeerContext) -> std::io::Result<()> {
+    let mut control = TcpStream::connect(resolve(server)?)?;
+
+    // Only whoever holds our signing key can register under our identity
+    let public_key = encode_public_key(&PublicKey::from(&ctx.identity_key));
+    let timestamp = chrono::Utc::now().timestamp();
+    let signed = serde_json::to_vec(&(&public_key, timestamp))?;
+    let signature = ctx.signing_key.sign(&signed);
+    write_message(&mut control, &Message::Register {
+        public_key,
+        signing_key: signing::encode_verifying_key(&ctx.signing_key.verifying_key()),
+        timestamp,
+        signature: base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()),
+    })?;
+
+    control.set_read_timeout(Some(SESSION_TIMEOUT))?;
+    match read_message(&mut control)? {
+        Message::Registered { endpoint } => println!("🌍 Reachable through {} as {}", server, endpoint),
+        other => return Err(unexpected(other)),
+    }
+
+    // Polled, so a message can arrive split across several reads
+    control.set_read_timeout(Some(CONTROL_POLL))?;
+    let mut line = Vec::new();
+    while wanted() {
+        let message = match read_message_into(&mut control, &mut line) {
+            Ok(message) => message,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        let Message::Incoming { session, from } = message else {
+            continue;
+        };
+        if !ctx.trusted_devices.lock().contains_key(&from) {
+            eprintln!("Ignoring internet session from unpaired device");
+            continue;
+        }
+        let (server, ctx) = (server.to_string(), ctx.clone());
+        ctx.tasks.clone().spawn(Budget::Connection, move || {
+            if let Err(e) = join(&server, session, ctx) {
+                eprintln!("Internet session failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
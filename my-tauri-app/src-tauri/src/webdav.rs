@@ -0,0 +1,410 @@
+// Read-only WebDAV export of what we share
+//
+// NAS boxes, file managers and scripts can't speak our protocol, but most
+// of them can mount a WebDAV folder. When the export is on, our shares
+// appear at the root of a WebDAV server, each as a folder (or file) named
+// after it, and can be browsed with PROPFIND and downloaded with GET. Only
+// what the shares hold is reachable, the same as for peers browsing us,
+// and nothing can be changed: every method that would write is refused.
+//
+// Each program or device that may read the export gets its own user name
+// and password, so one can be revoked without touching the others. The
+// password is shown once, when it is made, and only a hash of it is kept.
+// Basic auth over plain HTTP is what every WebDAV client speaks, so the
+// export is for the local network, like everything else here.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tiny_http::{Method, Request, Response, Server, StatusCode};
+
+use crate::app_data_dir;
+use crate::http;
+use crate::sharing::{self, SharedItem};
+
+// Port the export listens on unless told otherwise
+pub const DEFAULT_PORT: u16 = 8890;
+
+// Requests handled at once
+const WORKERS: usize = 4;
+
+// How often idle workers look up to see whether the export was stopped
+const SERVER_POLL: Duration = Duration::from_secs(1);
+
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+// A user name and password for the export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DavCredential {
+    pub id: String,
+    // What the credential is for, e.g. "Living room NAS"
+    pub label: String,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: i64,
+}
+
+// A credential as shown to the user, without its hash
+#[derive(Debug, Clone, Serialize)]
+pub struct DavAccount {
+    pub id: String,
+    pub label: String,
+    pub username: String,
+    pub created_at: i64,
+}
+
+impl From<&DavCredential> for DavAccount {
+    fn from(credential: &DavCredential) -> Self {
+        DavAccount {
+            id: credential.id.clone(),
+            label: credential.label.clone(),
+            username: credential.username.clone(),
+            created_at: credential.created_at,
+        }
+    }
+}
+
+// A credential just made, with the only copy of its password
+#[derive(Debug, Clone, Serialize)]
+pub struct NewDavCredential {
+    pub account: DavAccount,
+    pub password: String,
+}
+
+fn hash_password(password: &str) -> String {
+    Sha256::new()
+        .chain_update(b"Reality WebDAV")
+        .chain_update(password.as_bytes())
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// A new credential for the program or device called `label`, with a user
+// name made from the label
+pub fn new_credential(label: &str, existing: &[DavCredential]) -> Result<(DavCredential, String), String> {
+    let label = label.trim();
+    let username: String = label.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if username.is_empty() {
+        return Err("Give the credential a name with letters or digits in it".to_string());
+    }
+    if existing.iter().any(|credential| credential.username == username) {
+        return Err(format!("There is already a credential for {}", username));
+    }
+    let password = http::token();
+    let credential = DavCredential {
+        id: uuid::Uuid::new_v4().to_string(),
+        label: label.to_string(),
+        username,
+        password_hash: hash_password(&password),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    Ok((credential, password))
+}
+
+fn credentials_path() -> PathBuf {
+    app_data_dir().join("webdav_credentials.json")
+}
+
+// Load the export's credentials, or none if none were made yet
+pub fn load_credentials() -> Vec<DavCredential> {
+    std::fs::read(credentials_path())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_credentials(credentials: &[DavCredential]) -> std::io::Result<()> {
+    std::fs::create_dir_all(app_data_dir())?;
+    let json = serde_json::to_vec_pretty(credentials)?;
+    std::fs::write(credentials_path(), json)
+}
+
+// Whether the export is running, and where
+#[derive(Debug, Clone, Serialize)]
+pub struct DavStatus {
+    pub running: bool,
+    pub port: u16,
+    pub urls: Vec<String>,
+}
+
+// The export while it runs
+pub struct DavServer {
+    pub port: u16,
+    server: Arc<Server>,
+    stop: Arc<AtomicBool>,
+}
+
+impl DavServer {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for _ in 0..WORKERS {
+            self.server.unblock();
+        }
+    }
+}
+
+// Start exporting the shares on `port`
+pub fn start(
+    port: u16,
+    shares: Arc<Mutex<Vec<SharedItem>>>,
+    credentials: Arc<Mutex<Vec<DavCredential>>>,
+) -> Result<DavServer, String> {
+    let server = Arc::new(Server::http(("0.0.0.0", port)).map_err(|e| format!("Couldn't serve WebDAV on port {}: {}", port, e))?);
+    let stop = Arc::new(AtomicBool::new(false));
+    for _ in 0..WORKERS {
+        let (server, stop) = (server.clone(), stop.clone());
+        let (shares, credentials) = (shares.clone(), credentials.clone());
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match server.recv_timeout(SERVER_POLL) {
+                    Ok(Some(request)) => handle(request, &shares, &credentials),
+                    Ok(None) => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+    Ok(DavServer { port, server, stop })
+}
+
+fn handle(request: Request, shares: &Mutex<Vec<SharedItem>>, credentials: &Mutex<Vec<DavCredential>>) {
+    if !authorized(&request, &credentials.lock()) {
+        let challenge = http::header("WWW-Authenticate", "Basic realm=\"Reality\", charset=\"UTF-8\"");
+        let _ = request.respond(Response::from_string("Unauthorized").with_status_code(401).with_header(challenge));
+        return;
+    }
+
+    let url = request.url().split(['?', '#']).next().unwrap_or("").to_string();
+    let Some(path) = http::percent_decode(&url) else {
+        let _ = request.respond(Response::from_string("Bad request").with_status_code(400));
+        return;
+    };
+    let shares = shares.lock().clone();
+
+    let result = match request.method() {
+        Method::Options => {
+            let response = Response::empty(200)
+                .with_header(http::header("DAV", "1"))
+                .with_header(http::header("Allow", ALLOWED_METHODS));
+            request.respond(response)
+        }
+        Method::Get | Method::Head => get(request, &shares, &path),
+        Method::NonStandard(method) if method.as_str() == "PROPFIND" => propfind(request, &shares, &path),
+        _ => {
+            let response = Response::from_string("The export is read-only")
+                .with_status_code(405)
+                .with_header(http::header("Allow", ALLOWED_METHODS));
+            request.respond(response)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("WebDAV response failed: {}", e);
+    }
+}
+
+// Whether basic auth gives the user name and password of a credential
+fn authorized(request: &Request, credentials: &[DavCredential]) -> bool {
+    let Some((username, password)) = http::basic_credentials(request) else {
+        return false;
+    };
+    // Compared in constant time, so how long it takes says nothing of how
+    // much of the hash was right
+    let hash = hash_password(&password);
+    credentials.iter().any(|credential| {
+        credential.username == username && bool::from(credential.password_hash.as_bytes().ct_eq(hash.as_bytes()))
+    })
+}
+
+// What a path in the export is: the root, or something in a share
+enum Target {
+    Root,
+    Shared {
+        // Path as `sharing` takes it, `<share id>/<path within it>`
+        requested: String,
+        path: PathBuf,
+    },
+}
+
+// Names shares appear under at the root: their own, with the start of
+// their id after any that two shares have in common
+fn share_names(shares: &[SharedItem]) -> Vec<(String, &SharedItem)> {
+    let base = |share: &SharedItem| {
+        std::path::Path::new(&share.path)
+            .file_name()
+            .map_or_else(|| share.id.clone(), |name| name.to_string_lossy().into_owned())
+    };
+    shares.iter()
+        .map(|share| {
+            let name = base(share);
+            let shared_name = shares.iter().filter(|other| base(other) == name).count() > 1;
+            if shared_name {
+                (format!("{} ({})", name, &share.id[..8.min(share.id.len())]), share)
+            } else {
+                (name, share)
+            }
+        })
+        .collect()
+}
+
+fn target(shares: &[SharedItem], path: &str) -> Option<Target> {
+    let mut parts = path.split('/').filter(|part| !part.is_empty());
+    let Some(first) = parts.next() else {
+        return Some(Target::Root);
+    };
+    let share = share_names(shares).into_iter().find(|(name, _)| name == first)?.1;
+    let requested = std::iter::once(share.id.as_str()).chain(parts).collect::<Vec<_>>().join("/");
+    let path = sharing::locate(shares, &requested)?;
+    Some(Target::Shared { requested, path })
+}
+
+fn not_found(request: Request) -> std::io::Result<()> {
+    request.respond(Response::from_string("Not found").with_status_code(404))
+}
+
+fn get(request: Request, shares: &[SharedItem], path: &str) -> std::io::Result<()> {
+    let Some(Target::Shared { path: file, .. }) = target(shares, path) else {
+        return request.respond(Response::from_string("Mount this address as a WebDAV folder to browse it"));
+    };
+    if !file.is_file() {
+        return request.respond(Response::from_string("Mount this address as a WebDAV folder to browse it"));
+    }
+    let Ok(opened) = std::fs::File::open(&file) else {
+        return not_found(request);
+    };
+    let metadata = opened.metadata()?;
+    let headers = vec![
+        http::header("Content-Type", "application/octet-stream"),
+        http::header("Last-Modified", &http_date(modified(&metadata))),
+    ];
+    request.respond(Response::new(200.into(), headers, opened, Some(metadata.len() as usize), None))
+}
+
+fn propfind(request: Request, shares: &[SharedItem], path: &str) -> std::io::Result<()> {
+    let depth = request.headers()
+        .iter()
+        .find(|header| header.field.equiv("Depth"))
+        .map_or("infinity".to_string(), |header| header.value.as_str().trim().to_lowercase());
+    // Listing a whole tree in one go is refused, as RFC 4918 allows
+    if depth != "0" && depth != "1" {
+        return request.respond(Response::from_string("Depth infinity is not supported").with_status_code(403));
+    }
+    let Some(target) = target(shares, path) else {
+        return not_found(request);
+    };
+
+    let href_base = format!("/{}", path.split('/').filter(|part| !part.is_empty()).map(http::percent_encode).collect::<Vec<_>>().join("/"));
+    let mut responses = Vec::new();
+    match target {
+        Target::Root => {
+            responses.push(Entry::folder("/".to_string(), String::new(), chrono::Utc::now().timestamp()));
+            if depth == "1" {
+                for (name, share) in share_names(shares) {
+                    if let Ok(metadata) = std::fs::metadata(&share.path) {
+                        responses.push(Entry::new(format!("/{}", http::percent_encode(&name)), name, &metadata));
+                    }
+                }
+            }
+        }
+        Target::Shared { requested, path: on_disk } => {
+            let Ok(metadata) = std::fs::metadata(&on_disk) else {
+                return not_found(request);
+            };
+            let name = path.rsplit('/').find(|part| !part.is_empty()).unwrap_or_default().to_string();
+            let is_dir = metadata.is_dir();
+            let href = if is_dir { format!("{}/", href_base) } else { href_base.clone() };
+            responses.push(Entry::new(href, name, &metadata));
+            if is_dir && depth == "1" {
+                for entry in sharing::list(shares, &requested).unwrap_or_default() {
+                    let href = format!("{}/{}{}", href_base, http::percent_encode(&entry.name), if entry.is_dir { "/" } else { "" });
+                    responses.push(Entry { href, name: entry.name, is_dir: entry.is_dir, size: entry.size, modified: entry.modified });
+                }
+            }
+        }
+    }
+
+    let body = multistatus(&responses);
+    let response = Response::from_string(body)
+        .with_status_code(StatusCode(207))
+        .with_header(http::header("Content-Type", "application/xml; charset=utf-8"));
+    request.respond(response)
+}
+
+// One resource in a PROPFIND answer
+struct Entry {
+    href: String,
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: i64,
+}
+
+impl Entry {
+    fn new(href: String, name: String, metadata: &std::fs::Metadata) -> Self {
+        let href = if metadata.is_dir() && !href.ends_with('/') { format!("{}/", href) } else { href };
+        Entry {
+            href,
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: modified(metadata),
+        }
+    }
+
+    fn folder(href: String, name: String, modified: i64) -> Self {
+        Entry { href, name, is_dir: true, size: 0, modified }
+    }
+}
+
+fn modified(metadata: &std::fs::Metadata) -> i64 {
+    metadata.modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+fn http_date(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn multistatus(entries: &[Entry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    for entry in entries {
+        let kind = if entry.is_dir { "<D:collection/>" } else { "" };
+        let length = if entry.is_dir { String::new() } else { format!("<D:getcontentlength>{}</D:getcontentlength>", entry.size) };
+        xml.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+             <D:displayname>{}</D:displayname><D:resourcetype>{}</D:resourcetype>{}\
+             <D:getlastmodified>{}</D:getlastmodified>\
+             </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            xml_escape(&entry.href),
+            xml_escape(&entry.name),
+            kind,
+            length,
+            http_date(entry.modified),
+        ));
+    }
+    xml.push_str("</D:multistatus>\n");
+    xml
+}